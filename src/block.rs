@@ -0,0 +1,131 @@
+//! Block devices, such as SD cards, for filesystems to build on.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// Size of a block in bytes.
+pub const BLOCK_SIZE: usize = 512;
+
+/// The contents of a block.
+pub type Block = [u8; BLOCK_SIZE];
+
+/// A device storing data in blocks of [`BLOCK_SIZE`] bytes.
+///
+/// Like [`AsyncRead`](crate::io::AsyncRead), the methods queue the current task for wakeup and
+/// return `Poll::Pending` while the device is busy. Once a method returned `Poll::Pending`, the
+/// operation is in progress, and the method must be called again with the same arguments until
+/// it returns `Poll::Ready`.
+pub trait BlockDevice {
+    type Error;
+
+    /// Returns the number of blocks of the device.
+    fn block_count(&self) -> u32;
+
+    /// Attempt to read block `index` into `buf`.
+    fn poll_read_block(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        index: u32,
+        buf: &mut Block,
+    ) -> Poll<Result<(), Self::Error>>;
+
+    /// Attempt to write `buf` to block `index`.
+    fn poll_write_block(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        index: u32,
+        buf: &Block,
+    ) -> Poll<Result<(), Self::Error>>;
+}
+
+impl<D: BlockDevice + Unpin + ?Sized> BlockDevice for &mut D {
+    type Error = D::Error;
+
+    fn block_count(&self) -> u32 {
+        (**self).block_count()
+    }
+
+    fn poll_read_block(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        index: u32,
+        buf: &mut Block,
+    ) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut **self).poll_read_block(cx, index, buf)
+    }
+
+    fn poll_write_block(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        index: u32,
+        buf: &Block,
+    ) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut **self).poll_write_block(cx, index, buf)
+    }
+}
+
+/// An extension trait which adds utility methods to `BlockDevice` types.
+pub trait BlockDeviceExt: BlockDevice {
+    /// Reads block `index` into `buf`.
+    fn read_block<'a>(&'a mut self, index: u32, buf: &'a mut Block) -> ReadBlock<'a, Self>
+    where
+        Self: Unpin,
+    {
+        ReadBlock {
+            device: self,
+            index,
+            buf,
+        }
+    }
+
+    /// Writes `buf` to block `index`.
+    fn write_block<'a>(&'a mut self, index: u32, buf: &'a Block) -> WriteBlock<'a, Self>
+    where
+        Self: Unpin,
+    {
+        WriteBlock {
+            device: self,
+            index,
+            buf,
+        }
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDeviceExt for D {}
+
+/// Future for the [`read_block`](BlockDeviceExt::read_block) method.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct ReadBlock<'a, D: ?Sized> {
+    device: &'a mut D,
+    index: u32,
+    buf: &'a mut Block,
+}
+
+impl<D: BlockDevice + Unpin + ?Sized> Future for ReadBlock<'_, D> {
+    type Output = Result<(), D::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        Pin::new(&mut *this.device).poll_read_block(cx, this.index, this.buf)
+    }
+}
+
+/// Future for the [`write_block`](BlockDeviceExt::write_block) method.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct WriteBlock<'a, D: ?Sized> {
+    device: &'a mut D,
+    index: u32,
+    buf: &'a Block,
+}
+
+impl<D: BlockDevice + Unpin + ?Sized> Future for WriteBlock<'_, D> {
+    type Output = Result<(), D::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        Pin::new(&mut *this.device).poll_write_block(cx, this.index, this.buf)
+    }
+}
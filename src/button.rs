@@ -0,0 +1,157 @@
+//! Debounced push buttons on pin change interrupts.
+//!
+//! ```ignore
+//! let mut button = Button::new(Input::new(Port::D, 2), Config::default());
+//! while let Some(event) = button.next().await {
+//!     match event {
+//!         Event::Pressed => led.toggle(),
+//!         Event::LongPress => power_off().await,
+//!         _ => {}
+//!     }
+//! }
+//! ```
+//!
+//! A change of the pin starts the debounce period, after which the level is sampled again, so
+//! bounces neither produce events nor delay a change which is already over. Timestamps of events
+//! are late by the debounce period.
+
+use crate::pcint::Input;
+use crate::time::{Duration, Instant, Timer};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Event {
+    Pressed,
+    Released,
+    /// The button has been held down for the long press time. Follows `Pressed`, and the
+    /// release is not counted as a click.
+    LongPress,
+    /// The button was pressed again within the double click time after a click. Follows
+    /// `Pressed`.
+    DoubleClick,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Config {
+    /// How long the pin must settle after a change before its level is sampled.
+    pub debounce: Duration,
+    pub long_press: Duration,
+    /// The longest time from releasing the button to pressing it again for a double click.
+    pub double_click: Duration,
+    /// Whether the pin is low while the button is pressed, as with a pull-up to the supply.
+    pub active_low: bool,
+}
+
+impl Default for Config {
+    /// 20 ms debounce, 1 s long press and 300 ms double click, for a button to ground.
+    fn default() -> Self {
+        Config {
+            debounce: Duration::from_millis(20),
+            long_press: Duration::from_secs(1),
+            double_click: Duration::from_millis(300),
+            active_low: true,
+        }
+    }
+}
+
+/// A button yielding an [`Event`] for each debounced change, never ending.
+pub struct Button {
+    input: Input,
+    config: Config,
+    pressed: bool,
+    debounce: Option<Timer>,
+    long_press: Option<Timer>,
+    /// Whether the current press lasted long enough for `LongPress`.
+    held: bool,
+    /// When the last click was released, while a double click is still possible.
+    clicked: Option<Instant>,
+    /// An event following the one just yielded.
+    pending: Option<Event>,
+}
+
+impl Button {
+    /// Creates a button on `input`, taking its current level as the initial state.
+    pub fn new(input: Input, config: Config) -> Self {
+        let mut button = Button {
+            input,
+            config,
+            pressed: false,
+            debounce: None,
+            long_press: None,
+            held: false,
+            clicked: None,
+            pending: None,
+        };
+        button.pressed = button.is_pressed();
+        button
+    }
+
+    pub fn free(self) -> Input {
+        self.input
+    }
+
+    /// Reads the current level of the pin, without debouncing.
+    pub fn is_pressed(&self) -> bool {
+        self.input.is_low() == self.config.active_low
+    }
+
+    fn press(&mut self) -> Event {
+        let now = Instant::now();
+        self.pressed = true;
+        self.held = false;
+        self.long_press = Some(Timer::at(now + self.config.long_press));
+        if let Some(released) = self.clicked.take() {
+            if now.duration_since(released) <= self.config.double_click {
+                self.pending = Some(Event::DoubleClick);
+            }
+        }
+        Event::Pressed
+    }
+
+    fn release(&mut self) -> Event {
+        self.pressed = false;
+        self.long_press = None;
+        self.clicked = if self.held {
+            None
+        } else {
+            Some(Instant::now())
+        };
+        Event::Released
+    }
+}
+
+impl Stream for Button {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        let this = self.get_mut();
+        if let Some(event) = this.pending.take() {
+            return Poll::Ready(Some(event));
+        }
+        if let Some(timer) = &mut this.long_press {
+            if Pin::new(timer).poll(cx).is_ready() {
+                this.long_press = None;
+                this.held = true;
+                return Poll::Ready(Some(Event::LongPress));
+            }
+        }
+        loop {
+            if let Some(timer) = &mut this.debounce {
+                futures_util::ready!(Pin::new(timer).poll(cx));
+                this.debounce = None;
+                // Bounces during the debounce period are over.
+                let _ = this.input.poll_changed(cx);
+                match (this.pressed, this.is_pressed()) {
+                    (false, true) => return Poll::Ready(Some(this.press())),
+                    (true, false) => return Poll::Ready(Some(this.release())),
+                    _ => {}
+                }
+            }
+            futures_util::ready!(this.input.poll_changed(cx));
+            this.debounce = Some(Timer::after(this.config.debounce));
+        }
+    }
+}
@@ -0,0 +1,361 @@
+//! The input capture unit of Timer1, on `ICP1` (`PB0`).
+//!
+//! ```ignore
+//! let mut capture = InputCapture::new(dp.TC1, Config::default());
+//! let width = capture.measure_pulse_width().await;
+//! // Ticks of 0.5 µs, and sound travels 0.343 mm/µs there and back.
+//! let distance_mm = width * 343 / 4000;
+//!
+//! let mut frequencies = capture.frequencies();
+//! while let Some(millihertz) = frequencies.next().await {
+//!     // ...
+//! }
+//! ```
+//!
+//! Timer1 counts freely, and its overflows are counted in an interrupt, which extends captured
+//! timestamps to 32 bits. The interrupt handler records each capture, so consecutive edges are
+//! measured exactly even if the task waiting for them runs late.
+//!
+//! The interrupt handlers are left to the application, which forwards them to this module:
+//!
+//! ```ignore
+//! #[avr_device::interrupt(atmega328p)]
+//! fn TIMER1_CAPT() {
+//!     async_avr::capture::on_capture_interrupt();
+//! }
+//!
+//! #[avr_device::interrupt(atmega328p)]
+//! fn TIMER1_OVF() {
+//!     async_avr::capture::on_overflow_interrupt();
+//! }
+//! ```
+
+use crate::power::{self, Busy, Peripheral};
+use crate::waker::WakerCell;
+use crate::CPU_FREQUENCY;
+use avr_device::atmega328p::{Peripherals, TC1};
+use avr_device::interrupt::{self, Mutex};
+use core::cell::Cell;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::future::poll_fn;
+use futures_util::stream::Stream;
+
+const TCCR1B_ICNC1: u8 = 1 << 7;
+const TCCR1B_ICES1: u8 = 1 << 6;
+const TIMSK1_ICIE1: u8 = 1 << 5;
+const TIMSK1_TOIE1: u8 = 1 << 0;
+const TIFR1_ICF1: u8 = 1 << 5;
+const TIFR1_TOV1: u8 = 1 << 0;
+
+/// The clock of Timer1, as a division of the CPU clock.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Prescaler {
+    /// 62.5 ns ticks, overflowing every 4.1 ms.
+    Div1,
+    /// 0.5 µs ticks, overflowing every 32.8 ms.
+    Div8,
+    /// 4 µs ticks, overflowing every 262 ms.
+    Div64,
+    /// 16 µs ticks, overflowing every 1.05 s.
+    Div256,
+    /// 64 µs ticks, overflowing every 4.2 s.
+    Div1024,
+}
+
+impl Prescaler {
+    fn bits(self) -> u8 {
+        match self {
+            Prescaler::Div1 => 0b001,
+            Prescaler::Div8 => 0b010,
+            Prescaler::Div64 => 0b011,
+            Prescaler::Div256 => 0b100,
+            Prescaler::Div1024 => 0b101,
+        }
+    }
+
+    fn divisor(self) -> u32 {
+        match self {
+            Prescaler::Div1 => 1,
+            Prescaler::Div8 => 8,
+            Prescaler::Div64 => 64,
+            Prescaler::Div256 => 256,
+            Prescaler::Div1024 => 1024,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+impl Edge {
+    fn opposite(self) -> Edge {
+        match self {
+            Edge::Rising => Edge::Falling,
+            Edge::Falling => Edge::Rising,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Config {
+    pub prescaler: Prescaler,
+    /// The edge which is captured, and which starts pulses.
+    pub edge: Edge,
+    /// Whether the input must be stable for 4 CPU cycles before an edge is detected, which
+    /// delays captures by that much.
+    pub noise_canceler: bool,
+}
+
+impl Default for Config {
+    /// Ticks of 0.5 µs, capturing rising edges without noise canceler.
+    fn default() -> Self {
+        Config {
+            prescaler: Prescaler::Div8,
+            edge: Edge::Rising,
+            noise_canceler: false,
+        }
+    }
+}
+
+/// A captured edge.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Capture {
+    /// The timestamp in timer ticks, wrapping around after 2³² ticks.
+    pub time: u32,
+    pub edge: Edge,
+}
+
+struct State {
+    overflows: Cell<u16>,
+    /// Incremented for each capture.
+    count: Cell<u8>,
+    latest: Cell<u32>,
+    previous: Cell<u32>,
+    latest_edge: Cell<Edge>,
+    /// Whether the edge is switched after each capture, to capture both edges of pulses.
+    toggle: Cell<bool>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    overflows: Cell::new(0),
+    count: Cell::new(0),
+    latest: Cell::new(0),
+    previous: Cell::new(0),
+    latest_edge: Cell::new(Edge::Rising),
+    toggle: Cell::new(false),
+});
+static WAKER: WakerCell = WakerCell::new();
+
+/// The input capture unit, owning Timer1.
+pub struct InputCapture {
+    tc1: TC1,
+    config: Config,
+    _busy: Busy,
+}
+
+impl InputCapture {
+    /// Starts Timer1 counting from 0 and capturing `config.edge`.
+    pub fn new(tc1: TC1, config: Config) -> Self {
+        let busy = power::busy(Peripheral::Timer1);
+        interrupt::free(|cs| {
+            let state = STATE.borrow(cs);
+            state.overflows.set(0);
+            state.toggle.set(false);
+            tc1.tccr1a.write(|w| unsafe { w.bits(0) });
+            tc1.tccr1b.write(|w| unsafe { w.bits(0) });
+            tc1.tcnt1.write(|w| unsafe { w.bits(0) });
+            tc1.tifr1
+                .write(|w| unsafe { w.bits(TIFR1_ICF1 | TIFR1_TOV1) });
+            tc1.timsk1
+                .write(|w| unsafe { w.bits(TIMSK1_ICIE1 | TIMSK1_TOIE1) });
+        });
+        let mut capture = InputCapture {
+            tc1,
+            config,
+            _busy: busy,
+        };
+        capture.set_edge(config.edge);
+        capture
+    }
+
+    /// Stops the timer and releases it.
+    pub fn free(self) -> TC1 {
+        self.tc1.timsk1.write(|w| unsafe { w.bits(0) });
+        self.tc1.tccr1b.write(|w| unsafe { w.bits(0) });
+        self.tc1
+    }
+
+    pub fn ticks_per_second(&self) -> u32 {
+        CPU_FREQUENCY / self.config.prescaler.divisor()
+    }
+
+    /// Selects the edge which is captured, and which starts pulses.
+    pub fn set_edge(&mut self, edge: Edge) {
+        self.config.edge = edge;
+        interrupt::free(|cs| {
+            STATE.borrow(cs).toggle.set(false);
+            select_edge(&self.tc1, self.config, edge);
+        })
+    }
+
+    /// Returns the current timestamp, comparable to the timestamps of captures.
+    pub fn now(&self) -> u32 {
+        interrupt::free(|cs| {
+            let count = self.tc1.tcnt1.read().bits();
+            extend(STATE.borrow(cs), count)
+        })
+    }
+
+    /// Waits for the next captured edge.
+    pub async fn next_edge(&mut self) -> Capture {
+        self.set_edge(self.config.edge);
+        let since = interrupt::free(|cs| STATE.borrow(cs).count.get());
+        let (time, _, edge) = poll_fn(|cx| poll_captures(cx, since, 1)).await;
+        Capture { time, edge }
+    }
+
+    /// Measures the ticks between the next two captured edges, which is the period of a
+    /// periodic signal.
+    pub async fn measure_period(&mut self) -> u32 {
+        self.set_edge(self.config.edge);
+        let since = interrupt::free(|cs| STATE.borrow(cs).count.get());
+        let (latest, previous, _) = poll_fn(|cx| poll_captures(cx, since, 2)).await;
+        latest.wrapping_sub(previous)
+    }
+
+    /// Measures the ticks from the next captured edge to the following opposite edge, such as
+    /// the width of a high pulse when capturing rising edges.
+    pub async fn measure_pulse_width(&mut self) -> u32 {
+        let start = self.config.edge;
+        let since = interrupt::free(|cs| {
+            let state = STATE.borrow(cs);
+            select_edge(&self.tc1, self.config, start);
+            state.toggle.set(true);
+            state.count.get()
+        });
+        let (end, begin, _) = poll_fn(|cx| match poll_captures(cx, since, 2) {
+            // A pulse ending in the last capture, as the edges alternate.
+            Poll::Ready(capture) if capture.2 == start.opposite() => Poll::Ready(capture),
+            _ => Poll::Pending,
+        })
+        .await;
+        self.set_edge(start);
+        end.wrapping_sub(begin)
+    }
+
+    /// Returns a stream of the frequencies of the signal in mHz, from the periods between
+    /// captured edges. Each item is measured from the last two edges captured before it is
+    /// polled.
+    pub fn frequencies(&mut self) -> Frequencies<'_> {
+        self.set_edge(self.config.edge);
+        let since = interrupt::free(|cs| STATE.borrow(cs).count.get());
+        Frequencies {
+            capture: self,
+            since,
+            needed: 2,
+        }
+    }
+}
+
+/// Stream for the [`frequencies`](InputCapture::frequencies) method.
+#[must_use = "streams do nothing unless polled"]
+pub struct Frequencies<'a> {
+    capture: &'a mut InputCapture,
+    since: u8,
+    /// The number of captures after `since` needed for the next item.
+    needed: u8,
+}
+
+impl Stream for Frequencies<'_> {
+    type Item = u32;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<u32>> {
+        let (latest, previous, _) =
+            futures_util::ready!(poll_captures(cx, self.since, self.needed));
+        self.since = interrupt::free(|cs| STATE.borrow(cs).count.get());
+        self.needed = 1;
+        let period = latest.wrapping_sub(previous).max(1) as u64;
+        let millihertz = self.capture.ticks_per_second() as u64 * 1000 / period;
+        Poll::Ready(Some(millihertz as u32))
+    }
+}
+
+/// Waits until at least `count` captures happened after the capture numbered `since`, and
+/// returns the last two timestamps and the last edge.
+fn poll_captures(cx: &mut Context<'_>, since: u8, count: u8) -> Poll<(u32, u32, Edge)> {
+    WAKER.register(cx.waker());
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs);
+        if state.count.get().wrapping_sub(since) >= count {
+            Poll::Ready((
+                state.latest.get(),
+                state.previous.get(),
+                state.latest_edge.get(),
+            ))
+        } else {
+            Poll::Pending
+        }
+    })
+}
+
+fn select_edge(tc1: &TC1, config: Config, edge: Edge) {
+    let mut bits = config.prescaler.bits();
+    if config.noise_canceler {
+        bits |= TCCR1B_ICNC1;
+    }
+    if edge == Edge::Rising {
+        bits |= TCCR1B_ICES1;
+    }
+    tc1.tccr1b.write(|w| unsafe { w.bits(bits) });
+    // Changing the edge may trigger a capture.
+    tc1.tifr1.write(|w| unsafe { w.bits(TIFR1_ICF1) });
+}
+
+/// Extends a count of the timer to 32 bits, accounting for an overflow which is still pending
+/// because interrupts are disabled.
+fn extend(state: &State, count: u16) -> u32 {
+    let dp = unsafe { Peripherals::steal() };
+    let mut overflows = state.overflows.get();
+    if dp.TC1.tifr1.read().bits() & TIFR1_TOV1 != 0 && count < 0x8000 {
+        overflows = overflows.wrapping_add(1);
+    }
+    (overflows as u32) << 16 | count as u32
+}
+
+/// Counts an overflow of Timer1. Must be called from the `TIMER1_OVF` interrupt handler.
+pub fn on_overflow_interrupt() {
+    interrupt::free(|cs| {
+        let overflows = &STATE.borrow(cs).overflows;
+        overflows.set(overflows.get().wrapping_add(1));
+    })
+}
+
+/// Records a capture. Must be called from the `TIMER1_CAPT` interrupt handler.
+pub fn on_capture_interrupt() {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs);
+        let dp = unsafe { Peripherals::steal() };
+        let time = extend(state, dp.TC1.icr1.read().bits());
+        let control = dp.TC1.tccr1b.read().bits();
+        let edge = if control & TCCR1B_ICES1 != 0 {
+            Edge::Rising
+        } else {
+            Edge::Falling
+        };
+        if state.toggle.get() {
+            dp.TC1
+                .tccr1b
+                .write(|w| unsafe { w.bits(control ^ TCCR1B_ICES1) });
+            dp.TC1.tifr1.write(|w| unsafe { w.bits(TIFR1_ICF1) });
+        }
+        state.previous.set(state.latest.get());
+        state.latest.set(time);
+        state.latest_edge.set(edge);
+        state.count.set(state.count.get().wrapping_add(1));
+    });
+    WAKER.wake();
+}
@@ -0,0 +1,56 @@
+//! Checksums shared by the protocol implementations.
+
+/// Computes the CRC-16 of `data` with the reflected polynomial `0xA001` and initial value
+/// `0xFFFF`, as used by Modbus.
+pub fn crc16(data: &[u8]) -> u16 {
+    crc16_update(0xffff, data)
+}
+
+/// Continues computing a [`crc16`] over `data`, starting from a previous result `crc`.
+pub fn crc16_update(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = crc >> 1 ^ 0xa001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// Computes the CRC-16 of `data` with the polynomial `0x1021` and initial value 0, as used by
+/// XMODEM.
+pub fn crc16_xmodem(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = crc << 1 ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// Computes the CRC-8 of `data` with the reflected polynomial `0x8C` and initial value 0, as
+/// used by 1-Wire devices. Including a correct CRC in `data` yields 0.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = crc >> 1 ^ 0x8c;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
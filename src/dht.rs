@@ -0,0 +1,304 @@
+//! DHT11 and DHT22 (AM2302) temperature and humidity sensors.
+//!
+//! ```ignore
+//! #[avr_device::interrupt(atmega328p)]
+//! fn PCINT2() {
+//!     async_avr::pcint::on_interrupt(Port::D);
+//! }
+//!
+//! let mut sensor = Dht::new(pins.d4.into_tri_state(&mut pins.ddr), Port::D, 4, Kind::Dht22);
+//! let reading = sensor.read().await?;
+//! ```
+//!
+//! The data pin is driven low for the start signal with an async delay, then released to the
+//! pull-up. The sensor answers with 40 bits, each a low pulse of 50 µs followed by a high pulse
+//! of 27 µs for zeros or 70 µs for ones. The pin change interrupt, which must be forwarded to
+//! [`pcint::on_interrupt`](crate::pcint::on_interrupt), timestamps the falling edges and
+//! decodes each bit from the time between them, so other tasks keep running during the 5 ms
+//! transfer.
+
+use crate::pcint::{self, Port};
+use crate::time::{delay, with_timeout, Duration, Instant};
+use crate::waker::WakerCell;
+use avr_device::interrupt::{self, CriticalSection, Mutex};
+use avr_hal_generic::hal::digital::v2::OutputPin;
+use core::cell::Cell;
+use core::task::Poll;
+use futures_util::future::poll_fn;
+
+/// The falling edges of a transfer: the start of the response, the start of the first bit and
+/// the end of each bit.
+const EDGES: u8 = 42;
+/// The time between falling edges above which a bit is a one, between the 77 µs of a zero and
+/// the 120 µs of a one.
+const ONE_THRESHOLD: Duration = Duration::from_micros(100);
+/// How long to wait for the transfer, about twice as long as it takes.
+const TIMEOUT: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Kind {
+    Dht11,
+    /// Also the AM2302.
+    Dht22,
+}
+
+impl Kind {
+    /// How long the start signal holds the pin low, rounded up for the resolution of timers.
+    fn start(self) -> Duration {
+        match self {
+            Kind::Dht11 => Duration::from_millis(20),
+            Kind::Dht22 => Duration::from_millis(2),
+        }
+    }
+
+    /// The shortest time between two readings.
+    fn interval(self) -> Duration {
+        match self {
+            Kind::Dht11 => Duration::from_secs(1),
+            Kind::Dht22 => Duration::from_secs(2),
+        }
+    }
+}
+
+/// An error of the sensor, wrapping the error type `P` of the pin.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Error<P> {
+    Pin(P),
+    /// The sensor did not answer, or stopped answering, which happens if it is disconnected.
+    Timeout,
+    /// The data was received with a wrong checksum.
+    Checksum,
+}
+
+impl<P> From<P> for Error<P> {
+    fn from(err: P) -> Self {
+        Error::Pin(err)
+    }
+}
+
+type DhtError<P> = Error<<P as OutputPin>::Error>;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Reading {
+    /// In tenths of a degree Celsius.
+    pub temperature: i16,
+    /// Relative humidity in tenths of a percent.
+    pub humidity: u16,
+}
+
+#[derive(Copy, Clone)]
+struct Transfer {
+    port: Option<Port>,
+    bit: u8,
+    /// The level of the pin after the last edge.
+    high: bool,
+    edges: u8,
+    /// The timestamp of the last falling edge, in Timer0 ticks.
+    last: u32,
+    data: [u8; 5],
+}
+
+impl Transfer {
+    const IDLE: Transfer = Transfer {
+        port: None,
+        bit: 0,
+        high: false,
+        edges: 0,
+        last: 0,
+        data: [0; 5],
+    };
+}
+
+static TRANSFER: Mutex<Cell<Transfer>> = Mutex::new(Cell::new(Transfer::IDLE));
+static WAKER: WakerCell = WakerCell::new();
+
+/// Ends a reading when it completes or is dropped: releases the pin if the start signal was
+/// still being sent, stops watching the pin and frees the transfer for other sensors.
+struct Active<'a, P: OutputPin> {
+    pin: &'a mut P,
+    port: Port,
+    bit: u8,
+    released: bool,
+    watching: bool,
+}
+
+impl<P: OutputPin> Drop for Active<'_, P> {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = self.pin.set_high();
+        }
+        if self.watching {
+            pcint::unwatch(self.port, 1 << self.bit);
+        }
+        interrupt::free(|cs| TRANSFER.borrow(cs).set(Transfer::IDLE));
+    }
+}
+
+/// A sensor on an open-drain pin with a pull-up, which is pin `bit` of `port`.
+///
+/// Only one sensor can be read at a time, so a reading first waits for the readings of other
+/// sensors to end.
+pub struct Dht<P> {
+    pin: P,
+    port: Port,
+    bit: u8,
+    kind: Kind,
+    last_read: Option<Instant>,
+}
+
+impl<P: OutputPin> Dht<P> {
+    /// Creates the driver and releases the pin.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bit` is greater than 7.
+    pub fn new(mut pin: P, port: Port, bit: u8, kind: Kind) -> Result<Self, DhtError<P>> {
+        assert!(bit < 8);
+        pin.set_high()?;
+        Ok(Dht {
+            pin,
+            port,
+            bit,
+            kind,
+            last_read: None,
+        })
+    }
+
+    pub fn free(self) -> P {
+        self.pin
+    }
+
+    /// Reads the temperature and humidity, first waiting until the sensor is ready for another
+    /// reading. The sensor measures on each reading, so the result of a DHT22 is up to two
+    /// seconds old.
+    pub async fn read(&mut self) -> Result<Reading, DhtError<P>> {
+        if let Some(last_read) = self.last_read {
+            let elapsed = last_read.elapsed();
+            if elapsed < self.kind.interval() {
+                delay(Duration::from_ticks(
+                    self.kind.interval().ticks() - elapsed.ticks(),
+                ))
+                .await;
+            }
+        }
+        let (port, bit) = (self.port, self.bit);
+        // Claim the transfer before the start signal, which would disturb another reading.
+        poll_fn(|cx| {
+            interrupt::free(|cs| {
+                let transfer = TRANSFER.borrow(cs);
+                if transfer.get().port.is_some() {
+                    // Readings are short and rare, so retry instead of queueing wakers.
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                transfer.set(Transfer {
+                    port: Some(port),
+                    bit,
+                    ..Transfer::IDLE
+                });
+                Poll::Ready(())
+            })
+        })
+        .await;
+        let mut active = Active {
+            pin: &mut self.pin,
+            port,
+            bit,
+            released: false,
+            watching: false,
+        };
+        active.pin.set_low()?;
+        delay(self.kind.start()).await;
+        self.last_read = Some(Instant::now());
+
+        let released = interrupt::free(|_| {
+            // The pin is still held low, so releasing it is the first, rising edge.
+            pcint::watch(port, 1 << bit, Some(on_change));
+            active.watching = true;
+            active.pin.set_high()
+        });
+        let result = match released {
+            Ok(()) => {
+                active.released = true;
+                let received = poll_fn(|cx| {
+                    WAKER.register(cx.waker());
+                    let transfer = interrupt::free(|cs| TRANSFER.borrow(cs).get());
+                    if transfer.edges >= EDGES {
+                        Poll::Ready(transfer.data)
+                    } else {
+                        Poll::Pending
+                    }
+                });
+                with_timeout(delay(TIMEOUT), received)
+                    .await
+                    .map_err(|_| Error::Timeout)
+            }
+            Err(err) => Err(Error::Pin(err)),
+        };
+        drop(active);
+        self.decode(result?)
+    }
+
+    fn decode(&self, data: [u8; 5]) -> Result<Reading, DhtError<P>> {
+        let sum = data[..4].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        if sum != data[4] {
+            return Err(Error::Checksum);
+        }
+        Ok(match self.kind {
+            // Integral and decimal parts, with the sign of the temperature in the decimal part
+            // on newer sensors.
+            Kind::Dht11 => {
+                let temperature = data[2] as i16 * 10 + (data[3] & 0x7f) as i16;
+                Reading {
+                    temperature: if data[3] & 0x80 != 0 {
+                        -temperature
+                    } else {
+                        temperature
+                    },
+                    humidity: data[0] as u16 * 10 + data[1] as u16,
+                }
+            }
+            // Tenths, with the temperature in sign and magnitude.
+            Kind::Dht22 => {
+                let temperature = ((data[2] & 0x7f) as i16) << 8 | data[3] as i16;
+                Reading {
+                    temperature: if data[2] & 0x80 != 0 {
+                        -temperature
+                    } else {
+                        temperature
+                    },
+                    humidity: (data[0] as u16) << 8 | data[1] as u16,
+                }
+            }
+        })
+    }
+}
+
+/// Decodes the bits of a transfer on `port`, whose pins changed to `pins`.
+pub(crate) fn on_change(cs: &CriticalSection, port: Port, pins: u8) {
+    let cell = TRANSFER.borrow(cs);
+    let mut transfer = cell.get();
+    // Changes of other pins of the port leave the level unchanged.
+    let high = pins & 1 << transfer.bit != 0;
+    if transfer.port != Some(port) || high == transfer.high {
+        return;
+    }
+    transfer.high = high;
+    if high || transfer.edges >= EDGES {
+        cell.set(transfer);
+        return;
+    }
+    let now = Instant::now().ticks();
+    if transfer.edges >= 2 {
+        let index = (transfer.edges - 2) as usize;
+        if now.wrapping_sub(transfer.last) > ONE_THRESHOLD.ticks() {
+            transfer.data[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+    transfer.last = now;
+    transfer.edges += 1;
+    cell.set(transfer);
+    if transfer.edges == EDGES {
+        WAKER.wake();
+    }
+}
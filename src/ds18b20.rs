@@ -0,0 +1,157 @@
+//! Maxim DS18B20 temperature sensors on a [`OneWire`] bus.
+//!
+//! ```ignore
+//! let sensor = Ds18b20::new(rom);
+//! let temperature = sensor.measure(&mut bus).await?;
+//! ufmt::uwriteln!(serial, "{}/16 °C", temperature)?;
+//! ```
+
+use crate::crc::crc8;
+use crate::onewire::{Error, OneWire, Rom};
+use crate::time::{delay, Duration};
+use avr_hal_generic::hal::digital::v2::{InputPin, OutputPin};
+
+/// The family code of DS18B20 addresses.
+pub const FAMILY: u8 = 0x28;
+
+const CONVERT_T: u8 = 0x44;
+const WRITE_SCRATCHPAD: u8 = 0x4e;
+const READ_SCRATCHPAD: u8 = 0xbe;
+const COPY_SCRATCHPAD: u8 = 0x48;
+
+/// How long copying the scratchpad into the EEPROM takes.
+const COPY_TIME: Duration = Duration::from_millis(10);
+
+type BusError<P> = Error<<P as OutputPin>::Error>;
+
+/// The resolution of measurements. Each bit doubles the conversion time, up to 750 ms.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Resolution {
+    /// 0.5 °C.
+    Bits9,
+    /// 0.25 °C.
+    Bits10,
+    /// 0.125 °C.
+    Bits11,
+    /// 0.0625 °C, the default of the sensor.
+    Bits12,
+}
+
+impl Resolution {
+    fn bits(self) -> u8 {
+        match self {
+            Resolution::Bits9 => 0b00,
+            Resolution::Bits10 => 0b01,
+            Resolution::Bits11 => 0b10,
+            Resolution::Bits12 => 0b11,
+        }
+    }
+
+    fn conversion_time(self) -> Duration {
+        match self {
+            Resolution::Bits9 => Duration::from_millis(94),
+            Resolution::Bits10 => Duration::from_millis(188),
+            Resolution::Bits11 => Duration::from_millis(375),
+            Resolution::Bits12 => Duration::from_millis(750),
+        }
+    }
+}
+
+/// A DS18B20 sensor with an external power supply.
+pub struct Ds18b20 {
+    rom: Option<Rom>,
+    resolution: Resolution,
+}
+
+impl Ds18b20 {
+    /// Creates a driver for the sensor with the address `rom`, assuming the default resolution.
+    pub fn new(rom: Rom) -> Self {
+        Ds18b20 {
+            rom: Some(rom),
+            resolution: Resolution::Bits12,
+        }
+    }
+
+    /// Creates a driver for the only device on the bus, without addressing it.
+    pub fn single() -> Self {
+        Ds18b20 {
+            rom: None,
+            resolution: Resolution::Bits12,
+        }
+    }
+
+    /// Sets the resolution, keeping the alarm thresholds. With `persist`, the configuration is
+    /// also stored in the EEPROM of the sensor, which keeps it across power cycles.
+    pub async fn set_resolution<P>(
+        &mut self,
+        bus: &mut OneWire<P>,
+        resolution: Resolution,
+        persist: bool,
+    ) -> Result<(), BusError<P>>
+    where
+        P: OutputPin + InputPin<Error = <P as OutputPin>::Error>,
+    {
+        let scratchpad = self.read_scratchpad(bus).await?;
+        bus.select(self.rom.as_ref()).await?;
+        let config = resolution.bits() << 5 | 0x1f;
+        bus.write(&[WRITE_SCRATCHPAD, scratchpad[2], scratchpad[3], config])
+            .await?;
+        self.resolution = resolution;
+        if persist {
+            bus.select(self.rom.as_ref()).await?;
+            bus.write(&[COPY_SCRATCHPAD]).await?;
+            delay(COPY_TIME).await;
+        }
+        Ok(())
+    }
+
+    /// Starts a temperature conversion, which takes up to 750 ms depending on the resolution.
+    ///
+    /// Without an address, this starts conversions on all sensors of the bus at once.
+    pub async fn start_conversion<P>(&self, bus: &mut OneWire<P>) -> Result<(), BusError<P>>
+    where
+        P: OutputPin + InputPin<Error = <P as OutputPin>::Error>,
+    {
+        bus.select(self.rom.as_ref()).await?;
+        bus.write(&[CONVERT_T]).await
+    }
+
+    /// Reads the result of the last conversion, in sixteenths of a degree Celsius.
+    ///
+    /// Sensors report 85 °C until their first conversion completes.
+    pub async fn read_temperature<P>(&self, bus: &mut OneWire<P>) -> Result<i16, BusError<P>>
+    where
+        P: OutputPin + InputPin<Error = <P as OutputPin>::Error>,
+    {
+        let scratchpad = self.read_scratchpad(bus).await?;
+        let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]);
+        // The low bits are undefined at lower resolutions.
+        let undefined = (0b111 >> self.resolution.bits()) as i16;
+        Ok(raw & !undefined)
+    }
+
+    /// Starts a conversion, waits for it to complete, and reads the temperature in sixteenths
+    /// of a degree Celsius.
+    pub async fn measure<P>(&self, bus: &mut OneWire<P>) -> Result<i16, BusError<P>>
+    where
+        P: OutputPin + InputPin<Error = <P as OutputPin>::Error>,
+    {
+        self.start_conversion(bus).await?;
+        delay(self.resolution.conversion_time()).await;
+        self.read_temperature(bus).await
+    }
+
+    async fn read_scratchpad<P>(&self, bus: &mut OneWire<P>) -> Result<[u8; 9], BusError<P>>
+    where
+        P: OutputPin + InputPin<Error = <P as OutputPin>::Error>,
+    {
+        bus.select(self.rom.as_ref()).await?;
+        bus.write(&[READ_SCRATCHPAD]).await?;
+        let mut scratchpad = [0; 9];
+        bus.read(&mut scratchpad).await?;
+        if crc8(&scratchpad) != 0 {
+            return Err(Error::Crc);
+        }
+        Ok(scratchpad)
+    }
+}
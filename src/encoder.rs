@@ -0,0 +1,146 @@
+//! Quadrature rotary encoders on pin change interrupts.
+//!
+//! ```ignore
+//! let mut encoder = Encoder::new(Port::D, 4, 5, 4);
+//! while let Some(steps) = encoder.next().await {
+//!     volume = volume.saturating_add(steps);
+//! }
+//! ```
+//!
+//! The transitions of both pins are decoded in the pin change interrupt, which must be
+//! forwarded to [`pcint::on_interrupt`](crate::pcint::on_interrupt), so no steps are lost while
+//! the task consuming them is busy. Invalid transitions, where both pins changed at once, are
+//! ignored, which also filters out most contact bounce.
+
+use crate::pcint::{self, Port};
+use crate::waker::WakerCell;
+use avr_device::interrupt::{self, CriticalSection, Mutex};
+use core::cell::Cell;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+
+/// The change of the count for each transition, indexed by the previous and the current
+/// levels of the pins A and B as `0bAB_AB`.
+const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+const SLOTS: usize = 2;
+
+#[derive(Copy, Clone)]
+struct Slot {
+    port: Option<Port>,
+    a: u8,
+    b: u8,
+    /// The levels of A and B as `0bAB`.
+    state: u8,
+    count: i16,
+}
+
+impl Slot {
+    const EMPTY: Slot = Slot {
+        port: None,
+        a: 0,
+        b: 0,
+        state: 0,
+        count: 0,
+    };
+
+    fn levels(&self, pins: u8) -> u8 {
+        (pins >> self.a & 1) << 1 | pins >> self.b & 1
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Cell<Slot> = Cell::new(Slot::EMPTY);
+static ENCODERS: Mutex<[Cell<Slot>; SLOTS]> = Mutex::new([EMPTY_SLOT; SLOTS]);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_WAKER: WakerCell = WakerCell::new();
+static WAKERS: [WakerCell; SLOTS] = [NO_WAKER; SLOTS];
+
+/// A rotary encoder with its pins A and B on the same port, yielding the steps turned since the
+/// last item. Steps are positive when A changes before B.
+///
+/// Up to two encoders can exist at the same time. Their pins must be configured as inputs.
+pub struct Encoder {
+    slot: usize,
+    steps_per_detent: i16,
+}
+
+impl Encoder {
+    /// Starts decoding the encoder on the pins `a` and `b` of `port`, which makes
+    /// `steps_per_detent` transitions per detent, typically 4, 2 or 1.
+    ///
+    /// # Panics
+    ///
+    /// Panics if two encoders exist already, or a pin is greater than 7.
+    pub fn new(port: Port, a: u8, b: u8, steps_per_detent: u8) -> Self {
+        assert!(a < 8 && b < 8);
+        let slot = interrupt::free(|cs| {
+            let encoders = ENCODERS.borrow(cs);
+            let slot = encoders
+                .iter()
+                .position(|slot| slot.get().port.is_none())
+                .expect("too many encoders");
+            let mut encoder = Slot {
+                port: Some(port),
+                a,
+                b,
+                ..Slot::EMPTY
+            };
+            encoder.state = encoder.levels(pcint::watch(port, 1 << a | 1 << b, Some(on_change)));
+            encoders[slot].set(encoder);
+            slot
+        });
+        Encoder {
+            slot,
+            steps_per_detent: steps_per_detent.max(1) as i16,
+        }
+    }
+}
+
+impl Stream for Encoder {
+    type Item = i16;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<i16>> {
+        WAKERS[self.slot].register(cx.waker());
+        interrupt::free(|cs| {
+            let cell = &ENCODERS.borrow(cs)[self.slot];
+            let mut encoder = cell.get();
+            // Division rounds towards zero, keeping partial detents in either direction.
+            let steps = encoder.count / self.steps_per_detent;
+            if steps == 0 {
+                return Poll::Pending;
+            }
+            encoder.count -= steps * self.steps_per_detent;
+            cell.set(encoder);
+            Poll::Ready(Some(steps))
+        })
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        let encoder = interrupt::free(|cs| ENCODERS.borrow(cs)[self.slot].replace(Slot::EMPTY));
+        if let Some(port) = encoder.port {
+            pcint::unwatch(port, 1 << encoder.a | 1 << encoder.b);
+        }
+    }
+}
+
+/// Decodes the encoders on `port`, whose pins changed to `pins`.
+pub(crate) fn on_change(cs: &CriticalSection, port: Port, pins: u8) {
+    for (cell, waker) in ENCODERS.borrow(cs).iter().zip(&WAKERS) {
+        let mut encoder = cell.get();
+        if encoder.port != Some(port) {
+            continue;
+        }
+        let state = encoder.levels(pins);
+        let change = TRANSITIONS[(encoder.state << 2 | state) as usize];
+        encoder.state = state;
+        encoder.count = encoder.count.saturating_add(change as i16);
+        cell.set(encoder);
+        if change != 0 {
+            waker.wake();
+        }
+    }
+}
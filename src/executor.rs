@@ -1,5 +1,14 @@
+#[cfg(feature = "trace")]
+use crate::io::{AsyncWrite, WriteAllError};
+use crate::power;
+#[cfg(feature = "trace")]
+use crate::trace;
+use avr_device::atmega328p::Peripherals;
+use avr_device::interrupt;
 use core::cell::UnsafeCell;
 use core::future::Future;
+use core::marker::PhantomData;
+use core::pin::Pin;
 use core::ptr;
 use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 use pin_utils::pin_mut;
@@ -41,6 +50,9 @@ static VTABLE: RawWakerVTable = {
 };
 
 /// Spawns a task and blocks until the future resolves, returning its result.
+///
+/// While the task is waiting to be woken, the MCU sleeps in the deepest mode the
+/// [power manager](crate::power) considers safe. Global interrupts are enabled for this.
 pub fn block_on<T>(task: impl Future<Output = T>) -> T {
     let ready = Volatile::new(true);
     let waker = unsafe { Waker::from_raw(RawWaker::new(&ready as *const _ as *const _, &VTABLE)) };
@@ -49,14 +61,400 @@ pub fn block_on<T>(task: impl Future<Output = T>) -> T {
     let mut task = task;
     loop {
         while ready.read() {
-            match task.as_mut().poll(&mut context) {
-                Poll::Ready(val) => {
-                    return val;
-                }
-                Poll::Pending => {
-                    // ready.write(false);
-                }
+            ready.write(false);
+            if let Poll::Ready(val) = task.as_mut().poll(&mut context) {
+                return val;
             }
         }
+        power::sleep_unless(|| ready.read());
     }
 }
+
+type Task<'a> = Pin<&'a mut dyn Future<Output = ()>>;
+
+struct TaskHeader {
+    ready: Volatile<bool>,
+    #[cfg(feature = "trace")]
+    trace: trace::TaskTrace,
+}
+
+impl TaskHeader {
+    fn wake(&self) {
+        #[cfg(feature = "trace")]
+        self.trace.woken();
+        self.ready.write(true);
+    }
+}
+
+// NOTE `*const ()` is &TaskHeader
+static TASK_VTABLE: RawWakerVTable = {
+    unsafe fn clone(p: *const ()) -> RawWaker {
+        RawWaker::new(p, &TASK_VTABLE)
+    }
+    unsafe fn wake(p: *const ()) {
+        wake_by_ref(p)
+    }
+    unsafe fn wake_by_ref(p: *const ()) {
+        (*(p as *const TaskHeader)).wake()
+    }
+    unsafe fn drop(_: *const ()) {
+        // no-op
+    }
+
+    RawWakerVTable::new(clone, wake, wake_by_ref, drop)
+};
+
+struct TaskSlot<'a> {
+    header: TaskHeader,
+    task: UnsafeCell<Option<Task<'a>>>,
+}
+
+impl<'a> TaskSlot<'a> {
+    // Only used to initialize task slot arrays.
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Self = TaskSlot {
+        header: TaskHeader {
+            ready: Volatile(UnsafeCell::new(false)),
+            #[cfg(feature = "trace")]
+            trace: trace::TaskTrace::new(),
+        },
+        task: UnsafeCell::new(None),
+    };
+}
+
+/// Polls every task in `slots` that has been woken, using wakers built from `vtable`.
+///
+/// Returns `true` if at least one task was polled.
+fn poll_ready(slots: &[TaskSlot<'_>], vtable: &'static RawWakerVTable) -> bool {
+    let mut polled = false;
+    for slot in slots {
+        let header = &slot.header;
+        if !header.ready.read() {
+            continue;
+        }
+        header.ready.write(false);
+        // Spawners may write to empty slots from interrupt handlers, so only look at the slot
+        // inside a critical section.
+        let task = interrupt::free(|_| unsafe { (*slot.task.get()).as_mut().map(|t| t as *mut _) });
+        if let Some(task) = task {
+            let task: &mut Task<'_> = unsafe { &mut *task };
+            let waker =
+                unsafe { Waker::from_raw(RawWaker::new(header as *const _ as *const _, vtable)) };
+            let mut context = Context::from_waker(&waker);
+            #[cfg(feature = "trace")]
+            let started = header.trace.poll_started();
+            let result = task.as_mut().poll(&mut context);
+            #[cfg(feature = "trace")]
+            header.trace.poll_finished(started);
+            if result.is_ready() {
+                interrupt::free(|_| unsafe { *slot.task.get() = None });
+            }
+            polled = true;
+        }
+    }
+    polled
+}
+
+/// The error returned by [`Spawner::spawn`] when all task slots of an executor are in use.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct SpawnError;
+
+/// A handle used to spawn tasks on an [`Executor`] or [`InterruptExecutor`].
+#[derive(Copy, Clone)]
+pub struct Spawner<'e, 'a> {
+    slots: &'e [TaskSlot<'a>],
+    pend: fn(),
+}
+
+impl<'e, 'a> Spawner<'e, 'a> {
+    /// Spawns `task` into a free task slot.
+    ///
+    /// The task is polled by the executor until it completes, after which its slot can be
+    /// reused.
+    pub fn spawn(&self, task: Task<'a>) -> Result<(), SpawnError> {
+        interrupt::free(|_| {
+            let slot = self
+                .slots
+                .iter()
+                .find(|slot| unsafe { (*slot.task.get()).is_none() })
+                .ok_or(SpawnError)?;
+            unsafe { *slot.task.get() = Some(task) };
+            #[cfg(feature = "trace")]
+            slot.header.trace.reset();
+            slot.header.wake();
+            Ok(())
+        })?;
+        (self.pend)();
+        Ok(())
+    }
+}
+
+fn pend_nothing() {}
+
+/// An executor running up to `N` tasks in thread mode.
+///
+/// Unlike [`block_on`], tasks are polled only when their own waker was woken. When no task is
+/// ready, the MCU sleeps in the deepest mode the [power manager](crate::power) considers safe.
+///
+/// ```ignore
+/// let a = async { /* ... */ };
+/// let b = async { /* ... */ };
+/// pin_mut!(a, b);
+/// let executor = Executor::<2>::new();
+/// let spawner = executor.spawner();
+/// spawner.spawn(a).unwrap();
+/// spawner.spawn(b).unwrap();
+/// executor.run()
+/// ```
+pub struct Executor<'a, const N: usize> {
+    slots: [TaskSlot<'a>; N],
+}
+
+impl<'a, const N: usize> Executor<'a, N> {
+    pub fn new() -> Self {
+        Executor {
+            slots: [TaskSlot::EMPTY; N],
+        }
+    }
+
+    /// Returns a handle to spawn tasks on this executor.
+    pub fn spawner(&self) -> Spawner<'_, 'a> {
+        Spawner {
+            slots: &self.slots,
+            pend: pend_nothing,
+        }
+    }
+
+    /// Returns the statistics of the task in slot `index`, if a task was ever spawned into it.
+    #[cfg(feature = "trace")]
+    pub fn stats(&self, index: usize) -> Option<trace::TaskStats> {
+        self.slots.get(index)?.header.trace.stats()
+    }
+
+    /// Writes the statistics of all tasks and the stack usage to `writer`.
+    #[cfg(feature = "trace")]
+    pub async fn report<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), WriteAllError<W::Error>> {
+        trace::report(writer, self.slots.iter().map(|slot| &slot.header.trace)).await
+    }
+
+    /// Runs the spawned tasks forever.
+    pub fn run(&self) -> ! {
+        loop {
+            while poll_ready(&self.slots, &TASK_VTABLE) {}
+            power::sleep_unless(|| self.slots.iter().any(|slot| slot.header.ready.read()));
+        }
+    }
+}
+
+impl<'a, const N: usize> Default for Executor<'a, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An interrupt that can be triggered from software, used to run an [`InterruptExecutor`].
+pub trait SoftwareInterrupt {
+    /// Configures the interrupt source so that [`pend`](SoftwareInterrupt::pend) triggers it,
+    /// and enables it.
+    fn init();
+
+    /// Makes the interrupt pending.
+    fn pend();
+
+    /// Stops the interrupt from firing. A [`pend`](SoftwareInterrupt::pend) while masked must be
+    /// remembered and fire once unmasked.
+    fn mask();
+
+    /// Allows the interrupt to fire again.
+    fn unmask();
+}
+
+struct InterruptWaker<S>(PhantomData<S>);
+
+impl<S: SoftwareInterrupt> InterruptWaker<S> {
+    // NOTE `*const ()` is &TaskHeader
+    const VTABLE: RawWakerVTable = RawWakerVTable::new(
+        Self::clone,
+        Self::wake,
+        Self::wake_by_ref,
+        Self::drop,
+    );
+
+    unsafe fn clone(p: *const ()) -> RawWaker {
+        RawWaker::new(p, &Self::VTABLE)
+    }
+    unsafe fn wake(p: *const ()) {
+        Self::wake_by_ref(p)
+    }
+    unsafe fn wake_by_ref(p: *const ()) {
+        (*(p as *const TaskHeader)).wake();
+        S::pend();
+    }
+    unsafe fn drop(_: *const ()) {
+        // no-op
+    }
+}
+
+/// An executor running up to `N` tasks inside the interrupt handler of `S`.
+///
+/// Tasks on an interrupt executor preempt tasks running on [`block_on`] or an [`Executor`], so
+/// time-critical work is not delayed by long-running tasks in thread mode. Global interrupts are
+/// enabled while tasks are polled, so other interrupt handlers (including those of other
+/// interrupt executors) are not delayed either; only the executor's own interrupt is masked to
+/// prevent it from re-entering itself.
+///
+/// Data shared with tasks at another level must be protected with
+/// [`interrupt::free`](avr_device::interrupt::free).
+///
+/// ```ignore
+/// static HIGH: InterruptExecutor<PinChangeB<0>, 2> = InterruptExecutor::new();
+///
+/// #[avr_device::interrupt(atmega328p)]
+/// fn PCINT0() {
+///     unsafe { HIGH.on_interrupt() }
+/// }
+/// ```
+pub struct InterruptExecutor<S, const N: usize> {
+    slots: [TaskSlot<'static>; N],
+    _interrupt: PhantomData<S>,
+}
+
+unsafe impl<S, const N: usize> Sync for InterruptExecutor<S, N> {}
+
+impl<S, const N: usize> InterruptExecutor<S, N> {
+    pub const fn new() -> Self {
+        InterruptExecutor {
+            slots: [TaskSlot::EMPTY; N],
+            _interrupt: PhantomData,
+        }
+    }
+}
+
+impl<S, const N: usize> Default for InterruptExecutor<S, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: SoftwareInterrupt, const N: usize> InterruptExecutor<S, N> {
+    /// Enables the interrupt of `S` and returns a handle to spawn tasks on this executor.
+    pub fn start(&'static self) -> Spawner<'static, 'static> {
+        S::init();
+        self.spawner()
+    }
+
+    /// Returns a handle to spawn tasks on this executor.
+    pub fn spawner(&'static self) -> Spawner<'static, 'static> {
+        Spawner {
+            slots: &self.slots,
+            pend: S::pend,
+        }
+    }
+
+    /// Spawns a task that does not live for `'static`.
+    ///
+    /// # Safety
+    ///
+    /// The task must not be moved or dropped while the executor may still poll it. This holds
+    /// for futures pinned in a `main` function that never returns.
+    pub unsafe fn spawn_unchecked(
+        &'static self,
+        task: Pin<&mut dyn Future<Output = ()>>,
+    ) -> Result<(), SpawnError> {
+        let task: Task<'static> = core::mem::transmute(task);
+        self.spawner().spawn(task)
+    }
+
+    /// Returns the statistics of the task in slot `index`, if a task was ever spawned into it.
+    #[cfg(feature = "trace")]
+    pub fn stats(&'static self, index: usize) -> Option<trace::TaskStats> {
+        self.slots.get(index)?.header.trace.stats()
+    }
+
+    /// Writes the statistics of all tasks and the stack usage to `writer`.
+    #[cfg(feature = "trace")]
+    pub async fn report<W: AsyncWrite + Unpin>(
+        &'static self,
+        writer: &mut W,
+    ) -> Result<(), WriteAllError<W::Error>> {
+        trace::report(writer, self.slots.iter().map(|slot| &slot.header.trace)).await
+    }
+
+    /// Polls all woken tasks. Must be called from the interrupt handler of `S`, and nowhere
+    /// else.
+    ///
+    /// # Safety
+    ///
+    /// Must not be called while another call to `on_interrupt` on the same executor is running.
+    pub unsafe fn on_interrupt(&'static self) {
+        S::mask();
+        interrupt::enable();
+        while poll_ready(&self.slots, &InterruptWaker::<S>::VTABLE) {}
+        interrupt::disable();
+        S::unmask();
+    }
+}
+
+macro_rules! pin_change_interrupt {
+    ($(#[$doc:meta])* $Name:ident, $PORT:ident, $port:ident, $ddr:ident, $pin:ident, $pcmsk:ident, $pcie:expr) => {
+        $(#[$doc])*
+        ///
+        /// The pin is configured as an output and toggled to trigger the interrupt, so it must not
+        /// be connected to anything. No other pin of the same port may use pin change interrupts.
+        pub struct $Name<const BIT: u8>;
+
+        impl<const BIT: u8> SoftwareInterrupt for $Name<BIT> {
+            fn init() {
+                interrupt::free(|_| {
+                    let dp = unsafe { Peripherals::steal() };
+                    dp.$PORT
+                        .$ddr
+                        .modify(|r, w| unsafe { w.bits(r.bits() | 1 << BIT) });
+                    dp.$PORT
+                        .$port
+                        .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << BIT)) });
+                    dp.EXINT
+                        .$pcmsk
+                        .modify(|r, w| unsafe { w.bits(r.bits() | 1 << BIT) });
+                    dp.EXINT.pcicr.modify(|r, w| unsafe { w.bits(r.bits() | $pcie) });
+                })
+            }
+
+            fn pend() {
+                // Writing a one to PINx toggles the output.
+                let dp = unsafe { Peripherals::steal() };
+                dp.$PORT.$pin.write(|w| unsafe { w.bits(1 << BIT) });
+            }
+
+            fn mask() {
+                interrupt::free(|_| {
+                    let dp = unsafe { Peripherals::steal() };
+                    dp.EXINT.pcicr.modify(|r, w| unsafe { w.bits(r.bits() & !$pcie) });
+                })
+            }
+
+            fn unmask() {
+                interrupt::free(|_| {
+                    let dp = unsafe { Peripherals::steal() };
+                    dp.EXINT.pcicr.modify(|r, w| unsafe { w.bits(r.bits() | $pcie) });
+                })
+            }
+        }
+    };
+}
+
+pin_change_interrupt!(
+    /// A software interrupt on pin `BIT` of port B, handled by `PCINT0`.
+    PinChangeB, PORTB, portb, ddrb, pinb, pcmsk0, 1 << 0
+);
+pin_change_interrupt!(
+    /// A software interrupt on pin `BIT` of port C, handled by `PCINT1`.
+    PinChangeC, PORTC, portc, ddrc, pinc, pcmsk1, 1 << 1
+);
+pin_change_interrupt!(
+    /// A software interrupt on pin `BIT` of port D, handled by `PCINT2`.
+    PinChangeD, PORTD, portd, ddrd, pind, pcmsk2, 1 << 2
+);
@@ -0,0 +1,168 @@
+//! The external interrupts `INT0` on `PD2` and `INT1` on `PD3`.
+//!
+//! ```ignore
+//! let mut irq = ExtInt::<0>::new(Sense::Falling);
+//! irq.wait().await;
+//! ```
+//!
+//! The interrupt handlers are left to the application, which forwards them to this module:
+//!
+//! ```ignore
+//! #[avr_device::interrupt(atmega328p)]
+//! fn INT0() {
+//!     async_avr::exti::on_interrupt(0);
+//! }
+//! ```
+//!
+//! Only a low level wakes the MCU from the deeper sleep modes, so while waiting for an edge, the
+//! executor stays in [`SleepMode::Idle`](crate::power::SleepMode::Idle).
+
+use crate::power::{self, WakeLock};
+use crate::waker::WakerCell;
+use avr_device::atmega328p::Peripherals;
+use avr_device::interrupt::{self, Mutex};
+use core::cell::Cell;
+use core::future::Future;
+use core::marker::PhantomData;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// Which level or edge of the pin triggers an interrupt.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Sense {
+    /// The pin is low. Unlike edges, this is detected even if the pin was already low when
+    /// waiting started.
+    Low,
+    Change,
+    Falling,
+    Rising,
+}
+
+impl Sense {
+    fn bits(self) -> u8 {
+        match self {
+            Sense::Low => 0b00,
+            Sense::Change => 0b01,
+            Sense::Falling => 0b10,
+            Sense::Rising => 0b11,
+        }
+    }
+}
+
+/// Bits of the interrupts which fired since their waits were armed.
+static TRIGGERED: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_WAKER: WakerCell = WakerCell::new();
+static WAKERS: [WakerCell; 2] = [NO_WAKER; 2];
+
+/// The external interrupt `INT0` (`N` = 0) or `INT1` (`N` = 1).
+///
+/// The pin must be configured as an input, and only one `ExtInt` may exist for each interrupt.
+pub struct ExtInt<const N: u8> {
+    sense: Sense,
+}
+
+impl<const N: u8> ExtInt<N> {
+    /// Configures the interrupt to trigger on `sense`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `N` is neither 0 nor 1.
+    pub fn new(sense: Sense) -> Self {
+        assert!(N < 2);
+        let mut ext_int = ExtInt { sense };
+        ext_int.set_sense(sense);
+        ext_int
+    }
+
+    pub fn set_sense(&mut self, sense: Sense) {
+        self.sense = sense;
+        interrupt::free(|_| {
+            let dp = unsafe { Peripherals::steal() };
+            dp.EXINT.eicra.modify(|r, w| unsafe {
+                w.bits(r.bits() & !(0b11 << (2 * N)) | sense.bits() << (2 * N))
+            });
+        })
+    }
+
+    pub fn sense(&self) -> Sense {
+        self.sense
+    }
+
+    /// Waits until the interrupt triggers. Only triggers after this call count, so events can
+    /// be checked for after creating the future, without missing one that happens in between.
+    pub fn wait(&mut self) -> Wait<'_, N> {
+        interrupt::free(|cs| {
+            let triggered = TRIGGERED.borrow(cs);
+            triggered.set(triggered.get() & !(1 << N));
+            let dp = unsafe { Peripherals::steal() };
+            // Discard an edge which was detected before.
+            dp.EXINT.eifr.write(|w| unsafe { w.bits(1 << N) });
+            dp.EXINT
+                .eimsk
+                .modify(|r, w| unsafe { w.bits(r.bits() | 1 << N) });
+        });
+        let lock = match self.sense {
+            Sense::Low => None,
+            _ => Some(power::stay_awake()),
+        };
+        Wait {
+            _ext_int: PhantomData,
+            _lock: lock,
+        }
+    }
+}
+
+/// Future for the [`wait`](ExtInt::wait) method.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Wait<'a, const N: u8> {
+    _ext_int: PhantomData<&'a mut ExtInt<N>>,
+    _lock: Option<WakeLock>,
+}
+
+impl<const N: u8> Future for Wait<'_, N> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        WAKERS[N as usize].register(cx.waker());
+        let triggered = interrupt::free(|cs| {
+            let triggered = TRIGGERED.borrow(cs);
+            let fired = triggered.get() & 1 << N != 0;
+            triggered.set(triggered.get() & !(1 << N));
+            fired
+        });
+        if triggered {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<const N: u8> Drop for Wait<'_, N> {
+    fn drop(&mut self) {
+        interrupt::free(|_| {
+            let dp = unsafe { Peripherals::steal() };
+            dp.EXINT
+                .eimsk
+                .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << N)) });
+        })
+    }
+}
+
+/// Handles the external interrupt `INTn`. Must be called from the `INT0` (`n` = 0) or `INT1`
+/// (`n` = 1) interrupt handler.
+///
+/// Disables the interrupt, which would otherwise fire continuously while a low level persists,
+/// and wakes the waiting task.
+pub fn on_interrupt(n: u8) {
+    interrupt::free(|cs| {
+        let dp = unsafe { Peripherals::steal() };
+        dp.EXINT
+            .eimsk
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << n)) });
+        let triggered = TRIGGERED.borrow(cs);
+        triggered.set(triggered.get() | 1 << n);
+    });
+    WAKERS[n as usize].wake();
+}
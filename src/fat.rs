@@ -0,0 +1,1062 @@
+//! Appending to files on FAT16 and FAT32 volumes, for example to log CSV records to an SD card.
+//!
+//! ```ignore
+//! let mut volume = Volume::mount(&mut sd).await?;
+//! let mut file = volume.open("LOGS/DATA.CSV").await?;
+//! file.write_all(b"12:00,21.5\r\n").await?;
+//! file.close().await?;
+//! ```
+//!
+//! Only short 8.3 file names are supported, and files can only be appended to. The [`Volume`]
+//! caches a single sector, which the open [`File`] borrows, so only one file can be open at a
+//! time. The FSInfo sector of FAT32 volumes is not updated, which operating systems tolerate.
+
+use crate::block::{Block, BlockDevice, BLOCK_SIZE};
+use crate::io::AsyncWrite;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::future::poll_fn;
+use futures_util::ready;
+
+const ENTRY_SIZE: usize = 32;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// Marks a deleted directory entry.
+const DELETED: u8 = 0xe5;
+/// 1980-01-01, the date of files written without a clock.
+const DATE: u16 = 1 << 5 | 1;
+
+/// An error of a [`Volume`] or [`File`], wrapping the error type `E` of the block device.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Error<E> {
+    Device(E),
+    /// The device does not contain a FAT volume, or the volume is corrupted.
+    InvalidFilesystem,
+    /// The volume is FAT12 or does not use 512 byte sectors.
+    Unsupported,
+    /// The path is not a file name in 8.3 format, optionally preceded by a directory.
+    InvalidName,
+    /// The directory of the path is a file.
+    NotADirectory,
+    /// The path is a directory.
+    IsDirectory,
+    /// The FAT16 root directory has no free entries.
+    DirectoryFull,
+    /// The volume has no free clusters.
+    DiskFull,
+    /// The file reached the maximum size of 4 GiB.
+    FileTooLarge,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/// A directory entry, either of the searched name or free.
+struct Slot {
+    sector: u32,
+    offset: usize,
+    found: bool,
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    buf[offset] as u16 | (buf[offset + 1] as u16) << 8
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    read_u16(buf, offset) as u32 | (read_u16(buf, offset + 2) as u32) << 16
+}
+
+fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset] = value as u8;
+    buf[offset + 1] = (value >> 8) as u8;
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    write_u16(buf, offset, value as u16);
+    write_u16(buf, offset + 2, (value >> 16) as u16);
+}
+
+fn is_boot_sector(buf: &Block) -> bool {
+    (buf[0] == 0xeb || buf[0] == 0xe9) && buf[510] == 0x55 && buf[511] == 0xaa
+}
+
+/// Converts a file name to the padded upper case form stored in directory entries.
+fn short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let parts = base.bytes().zip(0..).chain(ext.bytes().zip(8..));
+    for (byte, i) in parts {
+        if !byte.is_ascii_alphanumeric() && !b"!#$%&'()-@^_`{}~".contains(&byte) {
+            return None;
+        }
+        short[i] = byte.to_ascii_uppercase();
+    }
+    Some(short)
+}
+
+/// A mounted FAT16 or FAT32 volume on a block device.
+///
+/// The volume is either the whole device, or the first partition of an MBR partition table.
+pub struct Volume<D> {
+    device: D,
+    buf: Block,
+    /// The sector held by `buf`.
+    sector: Option<u32>,
+    dirty: bool,
+    /// How many copies of a modified FAT sector have been written back.
+    copies: u8,
+    fat_type: FatType,
+    fats: u8,
+    fat_start: u32,
+    fat_sectors: u32,
+    /// The first sector of the FAT16 root directory.
+    root_start: u32,
+    root_sectors: u32,
+    /// The first cluster of the root directory, which is 0 on FAT16.
+    root_cluster: u32,
+    data_start: u32,
+    /// Log2 of the sectors per cluster.
+    cluster_shift: u8,
+    clusters: u32,
+    /// No clusters before this one are free.
+    next_free: u32,
+}
+
+impl<D: BlockDevice + Unpin> Volume<D> {
+    /// Reads the boot sector of the volume.
+    pub async fn mount(device: D) -> Result<Self, Error<D::Error>> {
+        let mut volume = Volume {
+            device,
+            buf: [0; BLOCK_SIZE],
+            sector: None,
+            dirty: false,
+            copies: 0,
+            fat_type: FatType::Fat16,
+            fats: 0,
+            fat_start: 0,
+            fat_sectors: 0,
+            root_start: 0,
+            root_sectors: 0,
+            root_cluster: 0,
+            data_start: 0,
+            cluster_shift: 0,
+            clusters: 0,
+            next_free: 2,
+        };
+
+        volume.load(0, true).await?;
+        let start = if is_boot_sector(&volume.buf) {
+            0
+        } else if volume.buf[510..] != [0x55, 0xaa] {
+            return Err(Error::InvalidFilesystem);
+        } else {
+            let partition = &volume.buf[0x1be..0x1ce];
+            match partition[4] {
+                0x04 | 0x06 | 0x0b | 0x0c | 0x0e => read_u32(partition, 8),
+                0x01 => return Err(Error::Unsupported),
+                _ => return Err(Error::InvalidFilesystem),
+            }
+        };
+
+        volume.load(start, true).await?;
+        let bpb = &volume.buf;
+        if !is_boot_sector(bpb) {
+            return Err(Error::InvalidFilesystem);
+        }
+        if read_u16(bpb, 11) != BLOCK_SIZE as u16 {
+            return Err(Error::Unsupported);
+        }
+        let cluster_sectors = bpb[13];
+        let reserved = read_u16(bpb, 14) as u32;
+        let fats = bpb[16];
+        let root_entries = read_u16(bpb, 17) as u32;
+        let fat_sectors = match read_u16(bpb, 22) {
+            0 => read_u32(bpb, 36),
+            sectors => sectors as u32,
+        };
+        let sectors = match read_u16(bpb, 19) {
+            0 => read_u32(bpb, 32),
+            sectors => sectors as u32,
+        };
+        let root_cluster = read_u32(bpb, 44);
+        if !cluster_sectors.is_power_of_two() || fats == 0 || reserved == 0 {
+            return Err(Error::InvalidFilesystem);
+        }
+
+        volume.cluster_shift = cluster_sectors.trailing_zeros() as u8;
+        volume.fats = fats;
+        volume.fat_start = start + reserved;
+        volume.fat_sectors = fat_sectors;
+        volume.root_start = volume.fat_start + fats as u32 * fat_sectors;
+        volume.root_sectors =
+            (root_entries * ENTRY_SIZE as u32 + BLOCK_SIZE as u32 - 1) / BLOCK_SIZE as u32;
+        volume.data_start = volume.root_start + volume.root_sectors;
+        let data_sectors = (start + sectors)
+            .checked_sub(volume.data_start)
+            .ok_or(Error::InvalidFilesystem)?;
+        volume.clusters = data_sectors >> volume.cluster_shift;
+        if volume.clusters < 4085 {
+            return Err(Error::Unsupported);
+        } else if volume.clusters >= 65525 {
+            volume.fat_type = FatType::Fat32;
+            volume.root_cluster = root_cluster;
+        }
+        Ok(volume)
+    }
+
+    /// Writes back the cached sector and releases the block device.
+    pub async fn unmount(mut self) -> Result<D, Error<D::Error>> {
+        poll_fn(|cx| self.poll_flush(cx)).await?;
+        Ok(self.device)
+    }
+
+    /// Opens the file at `path` for appending, creating it if it does not exist.
+    ///
+    /// The path is a file name in the root directory, or a directory name in the root directory
+    /// and a file name separated by `/`. Missing directories are created as well.
+    pub async fn open<'a>(&'a mut self, path: &str) -> Result<File<'a, D>, Error<D::Error>> {
+        let (dir, name) = match path.find('/') {
+            Some(slash) => (Some(&path[..slash]), &path[slash + 1..]),
+            None => (None, path),
+        };
+        let name = short_name(name).ok_or(Error::InvalidName)?;
+        let dir = match dir {
+            Some(dir) => {
+                let dir = short_name(dir).ok_or(Error::InvalidName)?;
+                self.open_dir(&dir).await?
+            }
+            None => self.root_cluster,
+        };
+
+        let slot = self.find(dir, &name).await?;
+        self.load(slot.sector, true).await?;
+        if !slot.found {
+            self.write_entry(slot.offset, &name, ATTR_ARCHIVE, 0);
+        }
+        let entry = &self.buf[slot.offset..slot.offset + ENTRY_SIZE];
+        if entry[11] & ATTR_DIRECTORY != 0 {
+            return Err(Error::IsDirectory);
+        }
+        let first_cluster = self.entry_cluster(entry);
+        let size = read_u32(entry, 28);
+
+        // Find the last cluster of the file.
+        let mut cluster = first_cluster;
+        let mut cluster_offset = 0;
+        if first_cluster != 0 {
+            while size - cluster_offset > self.cluster_bytes() {
+                cluster = self.next_cluster(cluster).await?;
+                cluster_offset += self.cluster_bytes();
+            }
+        }
+        Ok(File {
+            volume: self,
+            entry_sector: slot.sector,
+            entry_offset: slot.offset,
+            first_cluster,
+            cluster,
+            cluster_offset,
+            new_cluster: None,
+            size,
+        })
+    }
+
+    /// Returns the first cluster of the directory `name` in the root directory, creating it if
+    /// it does not exist.
+    async fn open_dir(&mut self, name: &[u8; 11]) -> Result<u32, Error<D::Error>> {
+        let slot = self.find(self.root_cluster, name).await?;
+        if slot.found {
+            self.load(slot.sector, true).await?;
+            let entry = &self.buf[slot.offset..slot.offset + ENTRY_SIZE];
+            if entry[11] & ATTR_DIRECTORY == 0 {
+                return Err(Error::NotADirectory);
+            }
+            return Ok(self.entry_cluster(entry));
+        }
+
+        let cluster = self.allocate().await?;
+        self.clear_cluster(cluster).await?;
+        self.load(self.cluster_sector(cluster), true).await?;
+        self.write_entry(0, b".          ", ATTR_DIRECTORY, cluster);
+        // Subdirectories of the root refer to it as cluster 0, also on FAT32.
+        self.write_entry(ENTRY_SIZE, b"..         ", ATTR_DIRECTORY, 0);
+        self.load(slot.sector, true).await?;
+        self.write_entry(slot.offset, name, ATTR_DIRECTORY, cluster);
+        Ok(cluster)
+    }
+
+    /// Searches the directory starting at cluster `dir` for `name`, returning its entry or a
+    /// free one. Directories other than the FAT16 root are extended if they are full.
+    async fn find(&mut self, dir: u32, name: &[u8; 11]) -> Result<Slot, Error<D::Error>> {
+        let mut free = None;
+        let mut cluster = dir;
+        let (mut sector, mut count) = match dir {
+            0 => (self.root_start, self.root_sectors),
+            dir => (self.cluster_sector(dir), 1 << self.cluster_shift),
+        };
+        loop {
+            for sector in sector..sector + count {
+                self.load(sector, true).await?;
+                for offset in (0..BLOCK_SIZE).step_by(ENTRY_SIZE) {
+                    let entry = &self.buf[offset..offset + ENTRY_SIZE];
+                    let slot = Slot {
+                        sector,
+                        offset,
+                        found: false,
+                    };
+                    match entry[0] {
+                        // No entries follow.
+                        0 => return Ok(free.unwrap_or(slot)),
+                        DELETED => {
+                            free.get_or_insert(slot);
+                        }
+                        _ if entry[11] & ATTR_VOLUME_ID == 0 && entry[..11] == name[..] => {
+                            return Ok(Slot {
+                                found: true,
+                                ..slot
+                            });
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            if let Some(slot) = free {
+                return Ok(slot);
+            }
+            if cluster == 0 {
+                return Err(Error::DirectoryFull);
+            }
+            let next = self.entry(cluster).await?;
+            if self.is_end(next) {
+                let next = self.allocate().await?;
+                self.set_entry(cluster, next).await?;
+                self.clear_cluster(next).await?;
+                return Ok(Slot {
+                    sector: self.cluster_sector(next),
+                    offset: 0,
+                    found: false,
+                });
+            }
+            cluster = self.check_cluster(next)?;
+            sector = self.cluster_sector(cluster);
+            count = 1 << self.cluster_shift;
+        }
+    }
+
+    /// Initializes the directory entry at `offset` in the cached sector.
+    fn write_entry(&mut self, offset: usize, name: &[u8; 11], attr: u8, cluster: u32) {
+        let entry = &mut self.buf[offset..offset + ENTRY_SIZE];
+        for byte in entry.iter_mut() {
+            *byte = 0;
+        }
+        entry[..11].copy_from_slice(name);
+        entry[11] = attr;
+        for &date in &[16, 18, 24] {
+            write_u16(entry, date, DATE);
+        }
+        write_u16(entry, 20, (cluster >> 16) as u16);
+        write_u16(entry, 26, cluster as u16);
+        self.dirty = true;
+    }
+
+    fn entry_cluster(&self, entry: &[u8]) -> u32 {
+        let high = match self.fat_type {
+            FatType::Fat16 => 0,
+            FatType::Fat32 => read_u16(entry, 20) as u32,
+        };
+        high << 16 | read_u16(entry, 26) as u32
+    }
+
+    /// Zeroes all sectors of `cluster`.
+    async fn clear_cluster(&mut self, cluster: u32) -> Result<(), Error<D::Error>> {
+        let first = self.cluster_sector(cluster);
+        for sector in first..first + (1 << self.cluster_shift) {
+            self.load(sector, false).await?;
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn cluster_bytes(&self) -> u32 {
+        (BLOCK_SIZE as u32) << self.cluster_shift
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.data_start + ((cluster - 2) << self.cluster_shift)
+    }
+
+    fn is_end(&self, entry: u32) -> bool {
+        match self.fat_type {
+            FatType::Fat16 => entry >= 0xfff8,
+            FatType::Fat32 => entry >= 0x0fff_fff8,
+        }
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<u32, Error<D::Error>> {
+        if cluster < 2 || cluster >= self.clusters + 2 {
+            return Err(Error::InvalidFilesystem);
+        }
+        Ok(cluster)
+    }
+
+    async fn next_cluster(&mut self, cluster: u32) -> Result<u32, Error<D::Error>> {
+        let next = self.entry(cluster).await?;
+        self.check_cluster(next)
+    }
+
+    async fn load(&mut self, sector: u32, read: bool) -> Result<(), Error<D::Error>> {
+        poll_fn(|cx| self.poll_load(cx, sector, read)).await
+    }
+
+    async fn entry(&mut self, cluster: u32) -> Result<u32, Error<D::Error>> {
+        poll_fn(|cx| self.poll_entry(cx, cluster)).await
+    }
+
+    async fn set_entry(&mut self, cluster: u32, value: u32) -> Result<(), Error<D::Error>> {
+        poll_fn(|cx| self.poll_set_entry(cx, cluster, value)).await
+    }
+
+    async fn allocate(&mut self) -> Result<u32, Error<D::Error>> {
+        poll_fn(|cx| self.poll_allocate(cx)).await
+    }
+
+    /// Caches `sector`, writing back the previously cached sector if it was modified. If `read`
+    /// is false, the sector is about to be overwritten and is cleared instead of read.
+    fn poll_load(
+        &mut self,
+        cx: &mut Context<'_>,
+        sector: u32,
+        read: bool,
+    ) -> Poll<Result<(), Error<D::Error>>> {
+        if self.sector == Some(sector) {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(self.poll_flush(cx))?;
+        self.sector = None;
+        if read {
+            ready!(Pin::new(&mut self.device).poll_read_block(cx, sector, &mut self.buf))
+                .map_err(Error::Device)?;
+        } else {
+            self.buf = [0; BLOCK_SIZE];
+        }
+        self.sector = Some(sector);
+        Poll::Ready(Ok(()))
+    }
+
+    /// Writes back the cached sector if it was modified. FAT sectors are written to every copy
+    /// of the FAT.
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error<D::Error>>> {
+        while let (true, Some(sector)) = (self.dirty, self.sector) {
+            let copies = match sector.checked_sub(self.fat_start) {
+                Some(offset) if offset < self.fat_sectors => self.fats,
+                _ => 1,
+            };
+            let target = sector + self.copies as u32 * self.fat_sectors;
+            ready!(Pin::new(&mut self.device).poll_write_block(cx, target, &self.buf))
+                .map_err(Error::Device)?;
+            self.copies += 1;
+            if self.copies == copies {
+                self.copies = 0;
+                self.dirty = false;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Returns the sector and offset of the FAT entry of `cluster`.
+    fn fat_position(&self, cluster: u32) -> (u32, usize) {
+        let offset = match self.fat_type {
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        };
+        (
+            self.fat_start + offset / BLOCK_SIZE as u32,
+            offset as usize % BLOCK_SIZE,
+        )
+    }
+
+    /// Reads the FAT entry of `cluster`, which is the next cluster of its chain.
+    fn poll_entry(
+        &mut self,
+        cx: &mut Context<'_>,
+        cluster: u32,
+    ) -> Poll<Result<u32, Error<D::Error>>> {
+        let (sector, offset) = self.fat_position(cluster);
+        ready!(self.poll_load(cx, sector, true))?;
+        Poll::Ready(Ok(match self.fat_type {
+            FatType::Fat16 => read_u16(&self.buf, offset) as u32,
+            FatType::Fat32 => read_u32(&self.buf, offset) & 0x0fff_ffff,
+        }))
+    }
+
+    fn poll_set_entry(
+        &mut self,
+        cx: &mut Context<'_>,
+        cluster: u32,
+        value: u32,
+    ) -> Poll<Result<(), Error<D::Error>>> {
+        let (sector, offset) = self.fat_position(cluster);
+        ready!(self.poll_load(cx, sector, true))?;
+        match self.fat_type {
+            FatType::Fat16 => write_u16(&mut self.buf, offset, value as u16),
+            FatType::Fat32 => {
+                // The upper 4 bits are reserved.
+                let reserved = read_u32(&self.buf, offset) & 0xf000_0000;
+                write_u32(&mut self.buf, offset, reserved | value & 0x0fff_ffff)
+            }
+        }
+        self.dirty = true;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Finds a free cluster and marks it as the end of a chain.
+    fn poll_allocate(&mut self, cx: &mut Context<'_>) -> Poll<Result<u32, Error<D::Error>>> {
+        loop {
+            let cluster = self.next_free;
+            if cluster >= self.clusters + 2 {
+                return Poll::Ready(Err(Error::DiskFull));
+            }
+            if ready!(self.poll_entry(cx, cluster))? == 0 {
+                ready!(self.poll_set_entry(cx, cluster, 0x0fff_ffff))?;
+                self.next_free += 1;
+                return Poll::Ready(Ok(cluster));
+            }
+            self.next_free += 1;
+        }
+    }
+}
+
+/// A file opened for appending with [`Volume::open`].
+///
+/// Written data and the size of the file only reach the device when the file is flushed or
+/// closed, so call [`File::close`] instead of dropping it.
+pub struct File<'a, D> {
+    volume: &'a mut Volume<D>,
+    /// The location of the directory entry.
+    entry_sector: u32,
+    entry_offset: usize,
+    first_cluster: u32,
+    /// The last cluster of the file.
+    cluster: u32,
+    /// The position of `cluster` in the file.
+    cluster_offset: u32,
+    /// A cluster which was allocated, but not yet appended to the file.
+    new_cluster: Option<u32>,
+    size: u32,
+}
+
+impl<D: BlockDevice + Unpin> File<'_, D> {
+    /// Returns the size of the file in bytes.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Writes the file to the device and closes it.
+    pub async fn close(mut self) -> Result<(), Error<D::Error>> {
+        poll_fn(|cx| self.poll_sync(cx)).await
+    }
+
+    /// Updates the directory entry and writes back the cached sector.
+    fn poll_sync(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error<D::Error>>> {
+        let volume = &mut *self.volume;
+        ready!(volume.poll_load(cx, self.entry_sector, true))?;
+        let entry = &mut volume.buf[self.entry_offset..self.entry_offset + ENTRY_SIZE];
+        write_u16(entry, 20, (self.first_cluster >> 16) as u16);
+        write_u16(entry, 26, self.first_cluster as u16);
+        write_u32(entry, 28, self.size);
+        volume.dirty = true;
+        volume.poll_flush(cx)
+    }
+}
+
+impl<D: BlockDevice + Unpin> AsyncWrite for File<'_, D> {
+    type Error = Error<D::Error>;
+
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Self::Error>> {
+        let this = &mut *self;
+        let volume = &mut *this.volume;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if this.size == u32::MAX {
+            return Poll::Ready(Err(Error::FileTooLarge));
+        }
+
+        let cluster_bytes = volume.cluster_bytes();
+        if this.first_cluster == 0 || this.size - this.cluster_offset == cluster_bytes {
+            let cluster = match this.new_cluster {
+                Some(cluster) => cluster,
+                None => {
+                    let cluster = ready!(volume.poll_allocate(cx))?;
+                    this.new_cluster = Some(cluster);
+                    cluster
+                }
+            };
+            if this.first_cluster == 0 {
+                this.first_cluster = cluster;
+            } else {
+                ready!(volume.poll_set_entry(cx, this.cluster, cluster))?;
+                this.cluster_offset += cluster_bytes;
+            }
+            this.cluster = cluster;
+            this.new_cluster = None;
+        }
+
+        let offset = this.size as usize % BLOCK_SIZE;
+        let sector = volume.cluster_sector(this.cluster)
+            + (this.size - this.cluster_offset) / BLOCK_SIZE as u32;
+        // Sectors past the end of the file need not be read.
+        ready!(volume.poll_load(cx, sector, offset != 0))?;
+        let len = buf
+            .len()
+            .min(BLOCK_SIZE - offset)
+            .min((u32::MAX - this.size) as usize);
+        volume.buf[offset..offset + len].copy_from_slice(&buf[..len]);
+        volume.dirty = true;
+        this.size += len as u32;
+        Poll::Ready(Ok(len))
+    }
+
+    /// Updates the directory entry and writes back the cached sector.
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_sync(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_sync(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{AsyncWriteExt, WriteAllError};
+    use crate::test_util::{block_on, progress};
+    use std::vec::Vec;
+    use std::{format, vec};
+
+    /// A block device in memory, which is busy once before every access.
+    struct Ram {
+        data: Vec<u8>,
+        /// The access which returned `Poll::Pending`, as block index and whether it writes.
+        pending: Option<(u32, bool)>,
+    }
+
+    impl Ram {
+        /// Returns `Poll::Pending` for every other access, checking that the access is then
+        /// repeated with the same arguments.
+        fn poll_busy(&mut self, index: u32, write: bool) -> Poll<&mut [u8]> {
+            match self.pending.take() {
+                Some(pending) => assert_eq!(pending, (index, write), "access not repeated"),
+                None => {
+                    self.pending = Some((index, write));
+                    progress();
+                    return Poll::Pending;
+                }
+            }
+            let start = index as usize * BLOCK_SIZE;
+            Poll::Ready(&mut self.data[start..start + BLOCK_SIZE])
+        }
+    }
+
+    impl BlockDevice for Ram {
+        type Error = ();
+
+        fn block_count(&self) -> u32 {
+            (self.data.len() / BLOCK_SIZE) as u32
+        }
+
+        fn poll_read_block(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            index: u32,
+            buf: &mut Block,
+        ) -> Poll<Result<(), ()>> {
+            buf.copy_from_slice(ready!(self.poll_busy(index, false)));
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_write_block(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            index: u32,
+            buf: &Block,
+        ) -> Poll<Result<(), ()>> {
+            ready!(self.poll_busy(index, true)).copy_from_slice(buf);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// The layout of a volume with two FATs.
+    #[derive(Copy, Clone)]
+    struct Layout {
+        fat32: bool,
+        cluster_sectors: u32,
+        reserved: u32,
+        root_entries: u32,
+        fat_sectors: u32,
+        clusters: u32,
+    }
+
+    /// The smallest FAT16 volume, with 1 KiB clusters.
+    const FAT16: Layout = Layout {
+        fat32: false,
+        cluster_sectors: 2,
+        reserved: 1,
+        root_entries: 512,
+        fat_sectors: 17,
+        clusters: 4200,
+    };
+
+    /// The smallest FAT32 volume, with 512 byte clusters.
+    const FAT32: Layout = Layout {
+        fat32: true,
+        cluster_sectors: 1,
+        reserved: 32,
+        root_entries: 0,
+        fat_sectors: 513,
+        clusters: 65600,
+    };
+
+    impl Layout {
+        fn root_start(&self) -> u32 {
+            self.reserved + 2 * self.fat_sectors
+        }
+
+        fn data_start(&self) -> u32 {
+            self.root_start() + self.root_entries * ENTRY_SIZE as u32 / BLOCK_SIZE as u32
+        }
+
+        fn sectors(&self) -> u32 {
+            self.data_start() + self.clusters * self.cluster_sectors
+        }
+
+        fn cluster_bytes(&self) -> usize {
+            self.cluster_sectors as usize * BLOCK_SIZE
+        }
+
+        /// The first cluster of the root directory.
+        fn root(&self) -> u32 {
+            if self.fat32 {
+                2
+            } else {
+                0
+            }
+        }
+
+        /// Returns an empty volume.
+        fn format(&self) -> Ram {
+            let mut data = vec![0; self.sectors() as usize * BLOCK_SIZE];
+            let bpb = &mut data[..BLOCK_SIZE];
+            bpb[0] = 0xeb;
+            write_u16(bpb, 11, BLOCK_SIZE as u16);
+            bpb[13] = self.cluster_sectors as u8;
+            write_u16(bpb, 14, self.reserved as u16);
+            bpb[16] = 2;
+            write_u16(bpb, 17, self.root_entries as u16);
+            write_u32(bpb, 32, self.sectors());
+            if self.fat32 {
+                write_u32(bpb, 36, self.fat_sectors);
+                write_u32(bpb, 44, self.root());
+            } else {
+                write_u16(bpb, 22, self.fat_sectors as u16);
+            }
+            bpb[510] = 0x55;
+            bpb[511] = 0xaa;
+            for copy in 0..2 {
+                let fat = (self.reserved + copy * self.fat_sectors) as usize * BLOCK_SIZE;
+                // The reserved entries 0 and 1, and the FAT32 root directory.
+                if self.fat32 {
+                    write_u32(&mut data, fat, 0x0fff_fff8);
+                    write_u32(&mut data, fat + 4, 0x0fff_ffff);
+                    write_u32(&mut data, fat + 8, 0x0fff_ffff);
+                } else {
+                    write_u16(&mut data, fat, 0xfff8);
+                    write_u16(&mut data, fat + 2, 0xffff);
+                }
+            }
+            Ram {
+                data,
+                pending: None,
+            }
+        }
+    }
+
+    /// Reads back a volume written by the tests.
+    struct Image<'a> {
+        layout: Layout,
+        data: &'a [u8],
+    }
+
+    impl<'a> Image<'a> {
+        /// Checks that both FATs are equal, so every modified FAT sector was written twice.
+        fn new(layout: Layout, data: &'a [u8]) -> Self {
+            let start = layout.reserved as usize * BLOCK_SIZE;
+            let len = layout.fat_sectors as usize * BLOCK_SIZE;
+            assert!(
+                data[start..start + len] == data[start + len..start + 2 * len],
+                "FAT copies differ"
+            );
+            Image { layout, data }
+        }
+
+        fn entry(&self, cluster: u32) -> u32 {
+            let fat = &self.data[self.layout.reserved as usize * BLOCK_SIZE..];
+            if self.layout.fat32 {
+                read_u32(fat, cluster as usize * 4) & 0x0fff_ffff
+            } else {
+                read_u16(fat, cluster as usize * 2) as u32
+            }
+        }
+
+        /// Returns the clusters of the chain starting at `first`.
+        fn chain(&self, first: u32) -> Vec<u32> {
+            let end = if self.layout.fat32 {
+                0x0fff_fff8
+            } else {
+                0xfff8
+            };
+            let mut chain = vec![first];
+            loop {
+                let next = self.entry(*chain.last().unwrap());
+                if next >= end {
+                    return chain;
+                }
+                assert!(next >= 2 && next < self.layout.clusters + 2);
+                chain.push(next);
+            }
+        }
+
+        fn cluster(&self, cluster: u32) -> &[u8] {
+            let sector = self.layout.data_start() + (cluster - 2) * self.layout.cluster_sectors;
+            let start = sector as usize * BLOCK_SIZE;
+            &self.data[start..start + self.layout.cluster_bytes()]
+        }
+
+        fn read(&self, first: u32) -> Vec<u8> {
+            let chain = self.chain(first);
+            chain
+                .iter()
+                .flat_map(|&c| self.cluster(c).to_vec())
+                .collect()
+        }
+
+        fn dir(&self, cluster: u32) -> Vec<u8> {
+            if cluster == 0 {
+                let start = self.layout.root_start() as usize * BLOCK_SIZE;
+                let end = self.layout.data_start() as usize * BLOCK_SIZE;
+                self.data[start..end].to_vec()
+            } else {
+                self.read(cluster)
+            }
+        }
+
+        /// Returns the attributes, first cluster and size of `name` in the directory starting at
+        /// cluster `dir`.
+        fn lookup(&self, dir: u32, name: &[u8; 11]) -> (u8, u32, u32) {
+            let dir = self.dir(dir);
+            let entry = dir.chunks(ENTRY_SIZE).find(|entry| entry[..11] == name[..]);
+            let entry = entry.expect("file not found");
+            let cluster = (read_u16(entry, 20) as u32) << 16 | read_u16(entry, 26) as u32;
+            (entry[11], cluster, read_u32(entry, 28))
+        }
+
+        fn file(&self, dir: u32, name: &[u8; 11]) -> Vec<u8> {
+            let (attr, first, size) = self.lookup(dir, name);
+            assert_eq!(attr, ATTR_ARCHIVE);
+            let mut data = self.read(first);
+            assert!(data.len() - (size as usize) < self.layout.cluster_bytes());
+            data.truncate(size as usize);
+            data
+        }
+    }
+
+    fn short(name: &str) -> [u8; 11] {
+        short_name(name).unwrap()
+    }
+
+    fn append_across_clusters(layout: Layout) {
+        let mut ram = layout.format();
+        let mut lines = Vec::new();
+        let cluster = vec![0x5a; layout.cluster_bytes()];
+        // Every round continues in the partially filled last cluster of the remounted volume,
+        // or starts a new one if the last cluster is full.
+        for round in 0..3 {
+            block_on(async {
+                let mut volume = Volume::mount(&mut ram).await.unwrap();
+                let mut file = volume.open("LOGS/DATA.CSV").await.unwrap();
+                assert_eq!(file.size() as usize, lines.len());
+                for i in 0..300 {
+                    let line = format!("{},{}\r\n", round, i);
+                    file.write_all(line.as_bytes()).await.unwrap();
+                    lines.extend_from_slice(line.as_bytes());
+                }
+                file.close().await.unwrap();
+                let mut file = volume.open("FULL.BIN").await.unwrap();
+                file.write_all(&cluster).await.unwrap();
+                file.close().await.unwrap();
+                volume.unmount().await.unwrap();
+            });
+        }
+
+        let image = Image::new(layout, &ram.data);
+        let (attr, logs, _) = image.lookup(layout.root(), &short("LOGS"));
+        assert_eq!(attr, ATTR_DIRECTORY);
+        assert_eq!(image.file(logs, &short("DATA.CSV")), lines);
+        let full = image.file(layout.root(), &short("FULL.BIN"));
+        assert_eq!(full, [&cluster[..], &cluster, &cluster].concat());
+        let (_, first, _) = image.lookup(layout.root(), &short("FULL.BIN"));
+        assert_eq!(image.chain(first).len(), 3);
+    }
+
+    fn full_directory_grows(layout: Layout) {
+        let mut ram = layout.format();
+        // The first cluster holds `.`, `..` and all files but the last.
+        let files = layout.cluster_bytes() / ENTRY_SIZE - 1;
+        block_on(async {
+            let mut volume = Volume::mount(&mut ram).await.unwrap();
+            for i in 0..files {
+                let mut file = volume.open(&format!("LOGS/{}.TXT", i)).await.unwrap();
+                file.write_all(&[i as u8]).await.unwrap();
+                file.close().await.unwrap();
+            }
+            volume.unmount().await.unwrap();
+        });
+
+        let image = Image::new(layout, &ram.data);
+        let (_, logs, _) = image.lookup(layout.root(), &short("LOGS"));
+        let chain = image.chain(logs);
+        assert_eq!(chain.len(), 2);
+        // The new cluster was cleared, so the directory ends after the last file.
+        let last = image.cluster(chain[1]);
+        assert_eq!(last[..11], short(&format!("{}.TXT", files - 1)));
+        assert!(last[ENTRY_SIZE..].iter().all(|&byte| byte == 0));
+        for i in 0..files {
+            let name = short(&format!("{}.TXT", i));
+            assert_eq!(image.file(logs, &name), [i as u8]);
+        }
+    }
+
+    fn disk_full(layout: Layout) {
+        let mut ram = layout.format();
+        let cluster = vec![0x5a; layout.cluster_bytes()];
+        block_on(async {
+            let mut volume = Volume::mount(&mut ram).await.unwrap();
+            let mut file = volume.open("FULL.BIN").await.unwrap();
+            let full = loop {
+                if let Err(err) = file.write_all(&cluster).await {
+                    break err;
+                }
+            };
+            assert_eq!(full, WriteAllError::Other(Error::DiskFull));
+            file.close().await.unwrap();
+            // The full volume can still be mounted and the file opened.
+            let volume = volume.unmount().await.unwrap();
+            let mut volume = Volume::mount(volume).await.unwrap();
+            let mut file = volume.open("FULL.BIN").await.unwrap();
+            let full = file.write_all(&[0]).await;
+            assert_eq!(full, Err(WriteAllError::Other(Error::DiskFull)));
+            file.close().await.unwrap();
+            assert_eq!(
+                volume.open("NEW/FILE.BIN").await.err(),
+                Some(Error::DiskFull)
+            );
+            volume.unmount().await.unwrap();
+        });
+
+        let image = Image::new(layout, &ram.data);
+        let (_, first, size) = image.lookup(layout.root(), &short("FULL.BIN"));
+        // All clusters but the one of the FAT32 root directory.
+        let clusters = layout.clusters as usize - layout.fat32 as usize;
+        assert_eq!(size as usize, clusters * layout.cluster_bytes());
+        assert_eq!(image.chain(first).len(), clusters);
+        assert!(image.read(first).iter().all(|&byte| byte == 0x5a));
+    }
+
+    #[test]
+    fn fat16_append_across_clusters() {
+        append_across_clusters(FAT16);
+    }
+
+    #[test]
+    fn fat32_append_across_clusters() {
+        append_across_clusters(FAT32);
+    }
+
+    #[test]
+    fn fat16_full_directory_grows() {
+        full_directory_grows(FAT16);
+    }
+
+    #[test]
+    fn fat32_full_directory_grows() {
+        full_directory_grows(FAT32);
+    }
+
+    #[test]
+    fn fat16_root_directory_full() {
+        let mut ram = FAT16.format();
+        block_on(async {
+            let mut volume = Volume::mount(&mut ram).await.unwrap();
+            for i in 0..FAT16.root_entries {
+                let file = volume.open(&format!("{}.TXT", i)).await.unwrap();
+                file.close().await.unwrap();
+            }
+            let full = volume.open("FULL.TXT").await.err();
+            assert_eq!(full, Some(Error::DirectoryFull));
+        });
+    }
+
+    #[test]
+    fn fat16_disk_full() {
+        disk_full(FAT16);
+    }
+
+    #[test]
+    fn fat32_disk_full() {
+        disk_full(FAT32);
+    }
+
+    #[test]
+    fn mount_partition() {
+        // The volume starts at sector 63 of the device, as given by the MBR.
+        let volume = FAT16.format().data;
+        let mut data = vec![0; 63 * BLOCK_SIZE];
+        let partition = &mut data[0x1be..0x1ce];
+        partition[4] = 0x06;
+        write_u32(partition, 8, 63);
+        write_u32(partition, 12, FAT16.sectors());
+        data[510] = 0x55;
+        data[511] = 0xaa;
+        data.extend_from_slice(&volume);
+        let mut ram = Ram {
+            data,
+            pending: None,
+        };
+        block_on(async {
+            let mut volume = Volume::mount(&mut ram).await.unwrap();
+            let mut file = volume.open("DATA.CSV").await.unwrap();
+            file.write_all(b"12:00,21.5\r\n").await.unwrap();
+            file.close().await.unwrap();
+            volume.unmount().await.unwrap();
+        });
+
+        let image = Image::new(FAT16, &ram.data[63 * BLOCK_SIZE..]);
+        let data = image.file(FAT16.root(), &short("DATA.CSV"));
+        assert_eq!(data, b"12:00,21.5\r\n");
+    }
+}
@@ -1,7 +1,36 @@
 use core::pin::Pin;
 use core::task::{Context, Poll};
 pub use ext::*;
+pub use framed::{Framed, FramedError};
+pub(crate) mod bytes;
+pub mod cobs;
+pub mod codec;
 mod ext;
+mod framed;
+pub mod slip;
+
+/// An error of the frame codecs in [`cobs`] and [`slip`].
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum FrameError<E> {
+    Io(E),
+    /// The reader returned end of file in the middle of a frame.
+    UnexpectedEof,
+    /// The writer did not accept any more data.
+    WriteZero,
+    /// The received frame did not fit into the buffer. The rest of the frame was discarded.
+    Overflow,
+    /// The received frame was not encoded correctly.
+    InvalidFrame,
+}
+
+impl<E> From<WriteAllError<E>> for FrameError<E> {
+    fn from(err: WriteAllError<E>) -> Self {
+        match err {
+            WriteAllError::WriteZero => FrameError::WriteZero,
+            WriteAllError::Other(err) => FrameError::Io(err),
+        }
+    }
+}
 /// Read bytes asynchronously.
 ///
 /// This trait is analogous to the `std::io::Read` trait, but integrates
@@ -0,0 +1,53 @@
+use super::{AsyncRead, AsyncReadExt, FrameError};
+
+/// Reads single bytes from `R`, buffering a few bytes to avoid polling the reader for every byte.
+pub(crate) struct ByteReader<R> {
+    reader: R,
+    buf: [u8; 16],
+    pos: usize,
+    len: usize,
+}
+
+impl<R> ByteReader<R> {
+    pub fn new(reader: R) -> Self {
+        ByteReader {
+            reader,
+            buf: [0; 16],
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Returns the reader. Bytes that were read from it but not consumed yet are lost.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: AsyncRead + Unpin> ByteReader<R> {
+    pub async fn next(&mut self) -> Result<u8, FrameError<R::Error>> {
+        if self.pos == self.len {
+            let n = self
+                .reader
+                .read(&mut self.buf)
+                .await
+                .map_err(FrameError::Io)?;
+            if n == 0 {
+                return Err(FrameError::UnexpectedEof);
+            }
+            self.pos = 0;
+            self.len = n;
+        }
+        let byte = self.buf[self.pos];
+        self.pos += 1;
+        Ok(byte)
+    }
+}
@@ -0,0 +1,367 @@
+//! Consistent Overhead Byte Stuffing.
+//!
+//! COBS removes all zero bytes from a frame, so that a single zero byte can delimit frames on
+//! the wire. The overhead is at most one byte per 254 bytes of data, plus the delimiter.
+
+use super::bytes::ByteReader;
+use super::{AsyncRead, AsyncWrite, FrameError};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::future::poll_fn;
+use futures_util::ready;
+
+const DELIMITER: u8 = 0;
+/// Maximum number of data bytes in a block.
+const MAX_BLOCK: usize = 254;
+
+/// Encodes frames written to it with COBS and writes them to `W`.
+///
+/// Data written through the `AsyncWrite` implementation is appended to the current frame, and
+/// [`finish_frame`](CobsEncoder::finish_frame) ends it. The encoder buffers at most one block of
+/// 254 bytes, not the whole frame.
+pub struct CobsEncoder<W> {
+    writer: W,
+    /// The code byte, followed by up to 254 data bytes and the delimiter.
+    block: [u8; MAX_BLOCK + 2],
+    /// Number of data bytes in `block`.
+    len: usize,
+    /// Range of `block` that still has to be written to `writer`.
+    pending: (usize, usize),
+    /// Whether the previous block was a full block, which is not followed by an implicit zero.
+    after_full_block: bool,
+}
+
+impl<W> CobsEncoder<W> {
+    pub fn new(writer: W) -> Self {
+        CobsEncoder {
+            writer,
+            block: [0; MAX_BLOCK + 2],
+            len: 0,
+            pending: (0, 0),
+            after_full_block: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Returns the writer. Encoded data that was not written yet is lost.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Queues the current block for writing, followed by the delimiter if `end` is set.
+    fn emit_block(&mut self, end: bool) {
+        let len = self.len;
+        self.len = 0;
+        if end && len == 0 && self.after_full_block {
+            // A full block is not followed by an implicit zero, so there is nothing to encode.
+            self.block[1] = DELIMITER;
+            self.pending = (1, 2);
+            self.after_full_block = false;
+            return;
+        }
+        self.block[0] = len as u8 + 1;
+        self.pending = (0, len + 1);
+        if end {
+            self.block[len + 1] = DELIMITER;
+            self.pending.1 += 1;
+            self.after_full_block = false;
+        } else {
+            self.after_full_block = len == MAX_BLOCK;
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> CobsEncoder<W> {
+    /// Writes queued blocks to the writer.
+    fn poll_emit(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), FrameError<W::Error>>> {
+        while self.pending.0 < self.pending.1 {
+            let (start, end) = self.pending;
+            let n = ready!(Pin::new(&mut self.writer).poll_write(cx, &self.block[start..end]))
+                .map_err(FrameError::Io)?;
+            if n == 0 {
+                return Poll::Ready(Err(FrameError::WriteZero));
+            }
+            self.pending.0 += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_finish_frame(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), FrameError<W::Error>>> {
+        ready!(self.poll_emit(cx))?;
+        self.emit_block(true);
+        Poll::Ready(Ok(()))
+    }
+
+    /// Ends the current frame and writes the rest of it, including the delimiter.
+    pub async fn finish_frame(&mut self) -> Result<(), FrameError<W::Error>> {
+        poll_fn(|cx| self.poll_finish_frame(cx)).await?;
+        poll_fn(|cx| self.poll_emit(cx)).await
+    }
+
+    /// Encodes and writes a complete frame.
+    pub async fn send_frame(&mut self, frame: &[u8]) -> Result<(), FrameError<W::Error>> {
+        let mut frame = frame;
+        while !frame.is_empty() {
+            let n = poll_fn(|cx| Pin::new(&mut *self).poll_write(cx, frame)).await?;
+            frame = &frame[n..];
+        }
+        self.finish_frame().await
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CobsEncoder<W> {
+    type Error = FrameError<W::Error>;
+
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Self::Error>> {
+        let this = &mut *self;
+        ready!(this.poll_emit(cx))?;
+        let mut n = 0;
+        for &byte in buf {
+            n += 1;
+            if byte == DELIMITER {
+                this.emit_block(false);
+                break;
+            }
+            this.len += 1;
+            this.block[this.len] = byte;
+            if this.len == MAX_BLOCK {
+                this.emit_block(false);
+                break;
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    /// Writes all complete blocks. The current block is only written once it is full or the
+    /// frame is finished.
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_emit(cx))?;
+        Pin::new(&mut self.writer)
+            .poll_flush(cx)
+            .map_err(FrameError::Io)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_emit(cx))?;
+        Pin::new(&mut self.writer)
+            .poll_close(cx)
+            .map_err(FrameError::Io)
+    }
+}
+
+/// Reads COBS encoded frames from `R`.
+pub struct CobsDecoder<R> {
+    reader: ByteReader<R>,
+}
+
+impl<R> CobsDecoder<R> {
+    pub fn new(reader: R) -> Self {
+        CobsDecoder {
+            reader: ByteReader::new(reader),
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.reader.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.reader.get_mut()
+    }
+
+    /// Returns the reader. Data that was read from it but not decoded yet is lost.
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
+}
+
+impl<R: AsyncRead + Unpin> CobsDecoder<R> {
+    /// Receives the next frame into `buf`, returning its length.
+    ///
+    /// Delimiters without any data in between are skipped.
+    ///
+    /// If the frame does not fit into `buf` or is not encoded correctly, the rest of the frame is
+    /// skipped and an error is returned, so the next call starts with the next frame.
+    pub async fn recv_frame(&mut self, buf: &mut [u8]) -> Result<usize, FrameError<R::Error>> {
+        let mut len = 0;
+        // Data bytes left in the current block, and whether the block is followed by an
+        // implicit zero.
+        let mut remaining = 0;
+        let mut implicit_zero = false;
+        let mut started = false;
+        let mut error = None;
+        loop {
+            let byte = self.reader.next().await?;
+            if byte == DELIMITER {
+                if !started {
+                    // Empty frame.
+                    continue;
+                }
+                if error.is_none() && remaining != 0 {
+                    error = Some(FrameError::InvalidFrame);
+                }
+                return match error {
+                    Some(error) => Err(error),
+                    None => Ok(len),
+                };
+            }
+            started = true;
+            if error.is_some() {
+                continue;
+            }
+            let data = if remaining == 0 {
+                let zero = implicit_zero;
+                remaining = byte - 1;
+                implicit_zero = byte as usize != MAX_BLOCK + 1;
+                if !zero {
+                    continue;
+                }
+                0
+            } else {
+                remaining -= 1;
+                byte
+            };
+            match buf.get_mut(len) {
+                Some(slot) => {
+                    *slot = data;
+                    len += 1;
+                }
+                None => error = Some(FrameError::Overflow),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::test_util::{block_on, line};
+    use core::convert::Infallible;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// Returns the bytes `frame` is encoded to.
+    fn encode(frame: &[u8]) -> Vec<u8> {
+        let (a, mut b) = line();
+        let mut encoder = CobsEncoder::new(a);
+        block_on(async {
+            encoder.send_frame(frame).await.unwrap();
+            let mut buf = [0; 1024];
+            let n = b.read(&mut buf).await.unwrap();
+            buf[..n].to_vec()
+        })
+    }
+
+    /// Sends `frames` over a line and receives them into buffers of `len` bytes.
+    fn round_trip(frames: &[Vec<u8>], len: usize) -> Vec<Result<Vec<u8>, FrameError<Infallible>>> {
+        let (a, b) = line();
+        let mut encoder = CobsEncoder::new(a);
+        let mut decoder = CobsDecoder::new(b);
+        block_on(async {
+            for frame in frames {
+                encoder.send_frame(frame).await.unwrap();
+            }
+            let mut received = Vec::new();
+            for _ in frames {
+                let mut buf = vec![0; len];
+                let result = decoder.recv_frame(&mut buf).await;
+                received.push(result.map(|n| buf[..n].to_vec()));
+            }
+            received
+        })
+    }
+
+    #[test]
+    fn encodes_zeros_as_block_codes() {
+        assert_eq!(
+            encode(&[0x11, 0x22, 0x00, 0x33]),
+            [3, 0x11, 0x22, 2, 0x33, 0]
+        );
+        assert_eq!(encode(&[0]), [1, 1, 0]);
+        assert_eq!(encode(&[]), [1, 0]);
+    }
+
+    #[test]
+    fn full_block_has_no_implicit_zero() {
+        let data: Vec<u8> = (1..=254).collect();
+        let mut expected = vec![0xff];
+        expected.extend(&data);
+        expected.push(0);
+        assert_eq!(encode(&data), expected);
+
+        let data: Vec<u8> = (1..=255).collect();
+        let mut expected = vec![0xff];
+        expected.extend(1..=254);
+        expected.extend(&[2, 255, 0]);
+        assert_eq!(encode(&data), expected);
+    }
+
+    #[test]
+    fn frames_survive_round_trip() {
+        let mut long: Vec<u8> = (1..=254).collect();
+        long.push(0);
+        long.extend(1..=254);
+        let frames = vec![
+            b"hello".to_vec(),
+            vec![0, 1, 0, 0, 2, 0],
+            (1..=254).collect(),
+            (0..=255).cycle().take(600).collect(),
+            long,
+        ];
+        let received = round_trip(&frames, 1024);
+        let expected: Vec<_> = frames.into_iter().map(Ok).collect();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn overflow_skips_rest_of_frame() {
+        let frames = vec![vec![7; 20], b"next".to_vec()];
+        assert_eq!(
+            round_trip(&frames, 10),
+            [Err(FrameError::Overflow), Ok(b"next".to_vec())]
+        );
+    }
+
+    #[test]
+    fn truncated_block_is_invalid() {
+        let (mut a, b) = line();
+        let mut decoder = CobsDecoder::new(b);
+        block_on(async {
+            a.write_all(&[5, 1, 2, 0, 3, 1, 2, 0]).await.unwrap();
+            let mut buf = [0; 8];
+            assert_eq!(
+                decoder.recv_frame(&mut buf).await,
+                Err(FrameError::InvalidFrame)
+            );
+            assert_eq!(decoder.recv_frame(&mut buf).await, Ok(2));
+            assert_eq!(buf[..2], [1, 2]);
+        });
+    }
+
+    #[test]
+    fn empty_delimiters_are_skipped() {
+        let (mut a, b) = line();
+        let mut decoder = CobsDecoder::new(b);
+        block_on(async {
+            a.write_all(&[0, 0, 2, 9, 0]).await.unwrap();
+            let mut buf = [0; 8];
+            assert_eq!(decoder.recv_frame(&mut buf).await, Ok(1));
+            assert_eq!(buf[0], 9);
+        });
+    }
+}
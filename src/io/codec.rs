@@ -0,0 +1,536 @@
+//! Encoding and decoding of messages for [`Framed`](super::Framed).
+//!
+//! A codec only converts between messages and bytes in a buffer, so the same codec works over
+//! any transport that implements [`AsyncRead`](super::AsyncRead) and
+//! [`AsyncWrite`](super::AsyncWrite).
+
+use crate::crc::crc16;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
+/// The number of bytes consumed by [`Decoder::decode`], and the decoded message or error, if any.
+pub type Decoded<T, E> = (usize, Option<Result<T, E>>);
+
+/// Decodes messages from received bytes.
+pub trait Decoder {
+    type Item;
+    type Error;
+
+    /// Decodes a message from the start of `buf`, which holds the bytes received so far.
+    ///
+    /// Returns the number of bytes consumed from `buf`, together with the decoded message or an
+    /// error, or `None` if more data is needed. Bytes can also be consumed without returning
+    /// anything, for example to skip the rest of a frame after an error.
+    fn decode(&mut self, buf: &[u8]) -> Decoded<Self::Item, Self::Error>;
+
+    /// Called when the bytes `buf`, which the decoder left in a full buffer, were discarded.
+    ///
+    /// They are the start of a frame that did not fit, so a decoder that can find the end of
+    /// the frame skips its rest, instead of decoding the rest as the next frame.
+    fn discard(&mut self, buf: &[u8]) {
+        let _ = buf;
+    }
+}
+
+/// Encodes messages of type `Item` into bytes.
+pub trait Encoder<Item> {
+    type Error;
+
+    /// Encodes `item` into `buf`, returning the number of bytes used.
+    fn encode(&mut self, item: Item, buf: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+/// An error of the codecs in this module.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum CodecError {
+    /// A frame was longer than the codec or the buffer allows. When decoding, the rest of the
+    /// frame is skipped.
+    TooLong,
+    /// The CRC of a received frame did not match.
+    InvalidCrc,
+}
+
+/// A frame of up to `N` bytes, stored inline.
+///
+/// The codecs of this module decode frames, and encode them as well as byte slices, so a
+/// [`Framed`](super::Framed) can send the frames it receives.
+#[derive(Copy, Clone)]
+pub struct Frame<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Frame<N> {
+    pub fn new() -> Self {
+        Frame {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Creates a frame holding a copy of `data`.
+    pub fn from_slice(data: &[u8]) -> Result<Self, CodecError> {
+        let mut frame = Self::new();
+        frame.extend_from_slice(data)?;
+        Ok(frame)
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Appends `data` to the frame.
+    pub fn extend_from_slice(&mut self, data: &[u8]) -> Result<(), CodecError> {
+        let end = self.len + data.len();
+        if end > N {
+            return Err(CodecError::TooLong);
+        }
+        self.buf[self.len..end].copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    /// Shortens the frame to `len` bytes. Has no effect if the frame is not longer.
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for Frame<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Deref for Frame<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<const N: usize> DerefMut for Frame<N> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.len]
+    }
+}
+
+impl<const N: usize> AsRef<[u8]> for Frame<N> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<const N: usize> PartialEq for Frame<N> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<const N: usize> Eq for Frame<N> {}
+
+impl<const N: usize> fmt::Debug for Frame<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Frames of up to `N` bytes, each preceded by its length as a big endian `u16`.
+#[derive(Debug, Default)]
+pub struct LengthDelimited<const N: usize> {
+    /// Bytes left to skip of a frame that was too long.
+    skip: usize,
+}
+
+impl<const N: usize> LengthDelimited<N> {
+    pub fn new() -> Self {
+        LengthDelimited { skip: 0 }
+    }
+}
+
+impl<const N: usize> Decoder for LengthDelimited<N> {
+    type Item = Frame<N>;
+    type Error = CodecError;
+
+    fn decode(&mut self, buf: &[u8]) -> Decoded<Frame<N>, CodecError> {
+        if self.skip > 0 {
+            let n = self.skip.min(buf.len());
+            self.skip -= n;
+            return (n, None);
+        }
+        if buf.len() < 2 {
+            return (0, None);
+        }
+        let len = (buf[0] as usize) << 8 | buf[1] as usize;
+        if len > N {
+            self.skip = len;
+            return (2, Some(Err(CodecError::TooLong)));
+        }
+        match buf.get(2..2 + len) {
+            Some(data) => (2 + len, Some(Frame::from_slice(data))),
+            None => (0, None),
+        }
+    }
+
+    fn discard(&mut self, buf: &[u8]) {
+        if let [high, low, ..] = *buf {
+            let len = (high as usize) << 8 | low as usize;
+            self.skip = (2 + len).saturating_sub(buf.len());
+        }
+    }
+}
+
+impl<'a, const N: usize> Encoder<&'a [u8]> for LengthDelimited<N> {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: &'a [u8], buf: &mut [u8]) -> Result<usize, CodecError> {
+        let len = item.len();
+        if len > N || 2 + len > buf.len() {
+            return Err(CodecError::TooLong);
+        }
+        buf[0] = (len >> 8) as u8;
+        buf[1] = len as u8;
+        buf[2..2 + len].copy_from_slice(item);
+        Ok(2 + len)
+    }
+}
+
+impl<const N: usize> Encoder<Frame<N>> for LengthDelimited<N> {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: Frame<N>, buf: &mut [u8]) -> Result<usize, CodecError> {
+        self.encode(&*item, buf)
+    }
+}
+
+impl<'a, const N: usize> Encoder<&'a Frame<N>> for LengthDelimited<N> {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: &'a Frame<N>, buf: &mut [u8]) -> Result<usize, CodecError> {
+        self.encode(&**item, buf)
+    }
+}
+
+/// Frames of up to `N` bytes, each terminated by a delimiter byte, such as lines of text.
+///
+/// The delimiter is not part of the decoded frames. Frames to encode must not contain the
+/// delimiter.
+#[derive(Debug)]
+pub struct Delimited<const N: usize> {
+    delimiter: u8,
+    /// Whether the rest of a frame that was too long is being skipped.
+    skipping: bool,
+}
+
+impl<const N: usize> Delimited<N> {
+    pub fn new(delimiter: u8) -> Self {
+        Delimited {
+            delimiter,
+            skipping: false,
+        }
+    }
+}
+
+impl<const N: usize> Decoder for Delimited<N> {
+    type Item = Frame<N>;
+    type Error = CodecError;
+
+    fn decode(&mut self, buf: &[u8]) -> Decoded<Frame<N>, CodecError> {
+        match buf.iter().position(|&byte| byte == self.delimiter) {
+            Some(end) if self.skipping => {
+                self.skipping = false;
+                (end + 1, None)
+            }
+            Some(end) => (end + 1, Some(Frame::from_slice(&buf[..end]))),
+            None if self.skipping => (buf.len(), None),
+            None if buf.len() > N => {
+                self.skipping = true;
+                (buf.len(), Some(Err(CodecError::TooLong)))
+            }
+            None => (0, None),
+        }
+    }
+
+    fn discard(&mut self, _buf: &[u8]) {
+        self.skipping = true;
+    }
+}
+
+impl<'a, const N: usize> Encoder<&'a [u8]> for Delimited<N> {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: &'a [u8], buf: &mut [u8]) -> Result<usize, CodecError> {
+        let len = item.len();
+        if len > N || len + 1 > buf.len() {
+            return Err(CodecError::TooLong);
+        }
+        buf[..len].copy_from_slice(item);
+        buf[len] = self.delimiter;
+        Ok(len + 1)
+    }
+}
+
+impl<const N: usize> Encoder<Frame<N>> for Delimited<N> {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: Frame<N>, buf: &mut [u8]) -> Result<usize, CodecError> {
+        self.encode(&*item, buf)
+    }
+}
+
+impl<'a, const N: usize> Encoder<&'a Frame<N>> for Delimited<N> {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: &'a Frame<N>, buf: &mut [u8]) -> Result<usize, CodecError> {
+        self.encode(&**item, buf)
+    }
+}
+
+/// Adds a [`crc16`] to the frames of the codec `C`, which holds frames of up to `N` bytes.
+///
+/// The CRC is appended to each frame in little endian byte order before it is encoded by `C`,
+/// and checked and removed after `C` decoded a frame, so frames carry up to `N - 2` bytes of
+/// data.
+#[derive(Debug, Default)]
+pub struct Crc16<C, const N: usize> {
+    inner: C,
+}
+
+impl<C, const N: usize> Crc16<C, N> {
+    pub fn new(inner: C) -> Self {
+        Crc16 { inner }
+    }
+
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C, const N: usize> Decoder for Crc16<C, N>
+where
+    C: Decoder<Item = Frame<N>, Error = CodecError>,
+{
+    type Item = Frame<N>;
+    type Error = CodecError;
+
+    fn decode(&mut self, buf: &[u8]) -> Decoded<Frame<N>, CodecError> {
+        let (n, result) = self.inner.decode(buf);
+        let result = result.map(|frame| {
+            let mut frame = frame?;
+            if frame.len() < 2 {
+                return Err(CodecError::InvalidCrc);
+            }
+            let len = frame.len() - 2;
+            let crc = frame[len] as u16 | (frame[len + 1] as u16) << 8;
+            if crc16(&frame[..len]) != crc {
+                return Err(CodecError::InvalidCrc);
+            }
+            frame.truncate(len);
+            Ok(frame)
+        });
+        (n, result)
+    }
+
+    fn discard(&mut self, buf: &[u8]) {
+        self.inner.discard(buf);
+    }
+}
+
+impl<'a, C, const N: usize> Encoder<&'a [u8]> for Crc16<C, N>
+where
+    C: for<'b> Encoder<&'b [u8], Error = CodecError>,
+{
+    type Error = CodecError;
+
+    fn encode(&mut self, item: &'a [u8], buf: &mut [u8]) -> Result<usize, CodecError> {
+        let crc = crc16(item);
+        let mut frame = Frame::<N>::from_slice(item)?;
+        frame.extend_from_slice(&[crc as u8, (crc >> 8) as u8])?;
+        self.inner.encode(&frame, buf)
+    }
+}
+
+impl<C, const N: usize> Encoder<Frame<N>> for Crc16<C, N>
+where
+    C: for<'b> Encoder<&'b [u8], Error = CodecError>,
+{
+    type Error = CodecError;
+
+    fn encode(&mut self, item: Frame<N>, buf: &mut [u8]) -> Result<usize, CodecError> {
+        self.encode(&*item, buf)
+    }
+}
+
+impl<'a, C, const N: usize> Encoder<&'a Frame<N>> for Crc16<C, N>
+where
+    C: for<'b> Encoder<&'b [u8], Error = CodecError>,
+{
+    type Error = CodecError;
+
+    fn encode(&mut self, item: &'a Frame<N>, buf: &mut [u8]) -> Result<usize, CodecError> {
+        self.encode(&**item, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// Decodes `data` like [`Framed`](super::super::Framed), consuming bytes until the decoder
+    /// needs more, and returns the decoded frames and errors.
+    fn decode_all<D: Decoder>(decoder: &mut D, data: &[u8]) -> Vec<Result<D::Item, D::Error>> {
+        let mut pos = 0;
+        let mut decoded = Vec::new();
+        loop {
+            let (n, result) = decoder.decode(&data[pos..]);
+            pos += n;
+            match result {
+                Some(result) => decoded.push(result),
+                None if n == 0 => return decoded,
+                None => {}
+            }
+        }
+    }
+
+    fn frame<const N: usize>(data: &[u8]) -> Result<Frame<N>, CodecError> {
+        Frame::from_slice(data)
+    }
+
+    #[test]
+    fn length_delimited_round_trip() {
+        let mut codec = LengthDelimited::<8>::new();
+        let mut buf = [0; 16];
+        let n = codec.encode(&b"hello"[..], &mut buf).unwrap();
+        assert_eq!(buf[..n], *b"\x00\x05hello");
+        assert_eq!(codec.decode(&buf[..n - 1]), (0, None));
+        assert_eq!(codec.decode(&buf[..n]), (n, Some(frame(b"hello"))));
+        assert_eq!(codec.decode(b"\x00\x00"), (2, Some(frame(b""))));
+    }
+
+    #[test]
+    fn length_delimited_skips_long_frames() {
+        let mut codec = LengthDelimited::<4>::new();
+        let data = b"\x00\x06abcdef\x00\x01g";
+        assert_eq!(
+            decode_all(&mut codec, data),
+            [Err(CodecError::TooLong), frame(b"g")]
+        );
+        let mut buf = [0; 16];
+        assert_eq!(
+            codec.encode(&b"abcde"[..], &mut buf),
+            Err(CodecError::TooLong)
+        );
+        assert_eq!(
+            codec.encode(&b"abcd"[..], &mut buf[..5]),
+            Err(CodecError::TooLong)
+        );
+    }
+
+    #[test]
+    fn length_delimited_skips_rest_of_discarded_frame() {
+        let mut codec = LengthDelimited::<16>::new();
+        codec.discard(b"\x00\x0aabcd");
+        assert_eq!(decode_all(&mut codec, b"efghij\x00\x01k"), [frame(b"k")]);
+    }
+
+    #[test]
+    fn delimited_round_trip() {
+        let mut codec = Delimited::<8>::new(b'\n');
+        let mut buf = [0; 16];
+        let n = codec.encode(&b"line"[..], &mut buf).unwrap();
+        assert_eq!(buf[..n], *b"line\n");
+        assert_eq!(codec.decode(b"li"), (0, None));
+        assert_eq!(
+            decode_all(&mut codec, b"line\n\nmore\n"),
+            [frame(b"line"), frame(b""), frame(b"more")]
+        );
+    }
+
+    #[test]
+    fn delimited_skips_long_frames() {
+        let mut codec = Delimited::<4>::new(b'\n');
+        // A frame of exactly `N` bytes still fits.
+        assert_eq!(codec.decode(b"abcd"), (0, None));
+        assert_eq!(codec.decode(b"abcd\n"), (5, Some(frame(b"abcd"))));
+        assert_eq!(
+            decode_all(&mut codec, b"abcdef\nok\n"),
+            [Err(CodecError::TooLong), frame(b"ok")]
+        );
+        // The rest of a long frame arrives later.
+        assert_eq!(
+            decode_all(&mut codec, b"abcdef"),
+            [Err(CodecError::TooLong)]
+        );
+        assert_eq!(decode_all(&mut codec, b"gh\nok\n"), [frame(b"ok")]);
+        let mut buf = [0; 16];
+        assert_eq!(
+            codec.encode(&b"abcde"[..], &mut buf),
+            Err(CodecError::TooLong)
+        );
+    }
+
+    #[test]
+    fn delimited_skips_rest_of_discarded_frame() {
+        let mut codec = Delimited::<16>::new(b'\n');
+        codec.discard(b"abcd");
+        assert_eq!(decode_all(&mut codec, b"efgh\nok\n"), [frame(b"ok")]);
+    }
+
+    #[test]
+    fn crc16_checks_frames() {
+        let mut codec = Crc16::<_, 8>::new(LengthDelimited::<8>::new());
+        let mut buf = [0; 16];
+        let n = codec.encode(&b"data"[..], &mut buf).unwrap();
+        let crc = crc16(b"data");
+        assert_eq!(
+            buf[..n],
+            [0, 6, b'd', b'a', b't', b'a', crc as u8, (crc >> 8) as u8]
+        );
+        assert_eq!(codec.decode(&buf[..n]), (n, Some(frame(b"data"))));
+
+        buf[3] ^= 1;
+        assert_eq!(
+            codec.decode(&buf[..n]),
+            (n, Some(Err(CodecError::InvalidCrc)))
+        );
+        assert_eq!(
+            codec.decode(b"\x00\x01a"),
+            (3, Some(Err(CodecError::InvalidCrc)))
+        );
+        // The CRC takes two bytes of the frame.
+        assert_eq!(
+            codec.encode(&b"1234567"[..], &mut buf),
+            Err(CodecError::TooLong)
+        );
+    }
+
+    #[test]
+    fn frames_encode_like_slices() {
+        let mut codec = Crc16::<_, 8>::new(Delimited::<8>::new(0));
+        let frame = Frame::<8>::from_slice(b"abc").unwrap();
+        let mut expected = [0; 16];
+        let n = codec.encode(&frame[..], &mut expected).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(codec.encode(&frame, &mut buf), Ok(n));
+        assert_eq!(buf, expected);
+        let mut buf = [0; 16];
+        assert_eq!(codec.encode(frame, &mut buf), Ok(n));
+        assert_eq!(buf, expected);
+        assert_eq!(decode_all(&mut codec, &buf[..n]), vec![Ok(frame)]);
+    }
+}
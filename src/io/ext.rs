@@ -1,6 +1,6 @@
 use super::{AsyncRead, AsyncWrite};
-use read::*;
-use write::*;
+pub use read::*;
+pub use write::*;
 
 mod read;
 mod write;
@@ -413,7 +413,7 @@ pub trait AsyncWriteExt: AsyncWrite {
     #[cfg(feature = "sink")]
     fn into_sink<Item: AsRef<[u8]>>(self) -> IntoSink<Self, Item>
     where
-        Self: Sized,
+        Self: Sized + Unpin,
     {
         IntoSink::new(self)
     }
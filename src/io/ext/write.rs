@@ -124,3 +124,69 @@ impl<W: AsyncWrite + ?Sized + Unpin> Future for Close<'_, W> {
         Pin::new(&mut *self.writer).poll_close(cx)
     }
 }
+
+/// Sink for the [`into_sink`](super::AsyncWriteExt::into_sink) method.
+#[cfg(feature = "sink")]
+#[derive(Debug)]
+#[must_use = "sinks do nothing unless polled"]
+pub struct IntoSink<W, Item> {
+    writer: W,
+    /// The item being written, and how many of its bytes have been written already.
+    buffer: Option<(Item, usize)>,
+}
+
+#[cfg(feature = "sink")]
+impl<W: AsyncWrite + Unpin, Item: AsRef<[u8]>> IntoSink<W, Item> {
+    pub(super) fn new(writer: W) -> Self {
+        IntoSink {
+            writer,
+            buffer: None,
+        }
+    }
+
+    /// Writes the outstanding item to the writer, without flushing it.
+    fn poll_flush_buffer(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), WriteAllError<W::Error>>> {
+        if let Some((item, offset)) = &mut self.buffer {
+            let bytes = item.as_ref();
+            while *offset < bytes.len() {
+                let n = ready!(Pin::new(&mut self.writer).poll_write(cx, &bytes[*offset..]))?;
+                if n == 0 {
+                    return Poll::Ready(Err(WriteAllError::WriteZero));
+                }
+                *offset += n;
+            }
+        }
+        self.buffer = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "sink")]
+impl<W: AsyncWrite + Unpin, Item: AsRef<[u8]> + Unpin> futures_sink::Sink<Item>
+    for IntoSink<W, Item>
+{
+    type Error = WriteAllError<W::Error>;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush_buffer(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Item) -> Result<(), Self::Error> {
+        debug_assert!(self.buffer.is_none());
+        self.buffer = Some((item, 0));
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_flush_buffer(cx))?;
+        Poll::Ready(Ok(ready!(Pin::new(&mut self.writer).poll_flush(cx))?))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_flush_buffer(cx))?;
+        Poll::Ready(Ok(ready!(Pin::new(&mut self.writer).poll_close(cx))?))
+    }
+}
@@ -0,0 +1,335 @@
+use super::codec::{Decoder, Encoder};
+use super::{AsyncRead, AsyncWrite};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::future::poll_fn;
+use futures_util::ready;
+use futures_util::stream::Stream;
+
+/// An error of a [`Framed`] transport.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum FramedError<E, C> {
+    Io(E),
+    /// The codec failed to encode or decode a message.
+    Codec(C),
+    /// The transport returned end of file in the middle of a message.
+    UnexpectedEof,
+    /// The transport did not accept any more data.
+    WriteZero,
+    /// The read buffer filled up without the codec decoding a message. The buffered data was
+    /// discarded, and the codec skips the rest of the message if it can find its end.
+    Overflow,
+}
+
+/// Turns a byte transport `T` into a [`Stream`] of decoded messages and a sink of messages to
+/// encode, using the codec `C`.
+///
+/// Received bytes are collected in a buffer of `N` bytes until the codec decodes a message, and
+/// each message is encoded into a second buffer of `N` bytes before it is written. With the
+/// `sink` feature, `Framed` also implements [`Sink`](futures_sink::Sink) for every item type the
+/// codec can encode.
+///
+/// `N` must hold the largest encoded message, including its framing: `N + 1` bytes for
+/// [`Delimited<N>`](super::codec::Delimited) and `N + 2` bytes for
+/// [`LengthDelimited<N>`](super::codec::LengthDelimited). Received messages that do not fit
+/// fail with [`FramedError::Overflow`] instead of the
+/// [`TooLong`](super::codec::CodecError::TooLong) error of the codec.
+///
+/// ```ignore
+/// let mut framed = Framed::<_, _, 34>::new(serial, Crc16::<_, 32>::new(LengthDelimited::new()));
+/// framed.send(&b"hello"[..]).await?;
+/// while let Some(frame) = framed.next().await {
+///     handle(&frame?);
+/// }
+/// ```
+pub struct Framed<T, C, const N: usize> {
+    inner: T,
+    codec: C,
+    read_buf: [u8; N],
+    read_len: usize,
+    eof: bool,
+    write_buf: [u8; N],
+    /// Range of `write_buf` that still has to be written to `inner`.
+    write_pos: usize,
+    write_len: usize,
+}
+
+impl<T, C, const N: usize> Framed<T, C, N> {
+    pub fn new(inner: T, codec: C) -> Self {
+        Framed {
+            inner,
+            codec,
+            read_buf: [0; N],
+            read_len: 0,
+            eof: false,
+            write_buf: [0; N],
+            write_pos: 0,
+            write_len: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    pub fn codec_mut(&mut self) -> &mut C {
+        &mut self.codec
+    }
+
+    /// Returns the transport and the codec. Buffered data is lost.
+    pub fn into_inner(self) -> (T, C) {
+        (self.inner, self.codec)
+    }
+}
+
+impl<T: AsyncWrite + Unpin, C, const N: usize> Framed<T, C, N> {
+    /// Writes the encoded message in the write buffer to the transport.
+    fn poll_write_buf<E>(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), FramedError<T::Error, E>>> {
+        while self.write_pos < self.write_len {
+            let buf = &self.write_buf[self.write_pos..self.write_len];
+            let n =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, buf)).map_err(FramedError::Io)?;
+            if n == 0 {
+                return Poll::Ready(Err(FramedError::WriteZero));
+            }
+            self.write_pos += n;
+        }
+        self.write_pos = 0;
+        self.write_len = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Encodes `item` into the write buffer, which must be empty.
+    fn start_send_item<I>(
+        &mut self,
+        item: I,
+    ) -> Result<(), FramedError<T::Error, <C as Encoder<I>>::Error>>
+    where
+        C: Encoder<I>,
+    {
+        self.write_len = self
+            .codec
+            .encode(item, &mut self.write_buf)
+            .map_err(FramedError::Codec)?;
+        Ok(())
+    }
+
+    fn poll_flush_inner<E>(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), FramedError<T::Error, E>>> {
+        ready!(self.poll_write_buf(cx))?;
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(FramedError::Io)
+    }
+
+    /// Encodes and sends `item`, then flushes the transport.
+    pub async fn send<I>(
+        &mut self,
+        item: I,
+    ) -> Result<(), FramedError<T::Error, <C as Encoder<I>>::Error>>
+    where
+        C: Encoder<I>,
+    {
+        poll_fn(|cx| self.poll_write_buf(cx)).await?;
+        self.start_send_item(item)?;
+        poll_fn(|cx| self.poll_flush_inner(cx)).await
+    }
+}
+
+impl<T, C, const N: usize> Stream for Framed<T, C, N>
+where
+    T: AsyncRead + Unpin,
+    C: Decoder + Unpin,
+{
+    type Item = Result<C::Item, FramedError<T::Error, <C as Decoder>::Error>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            let (n, result) = this.codec.decode(&this.read_buf[..this.read_len]);
+            if n > 0 {
+                this.read_buf.copy_within(n..this.read_len, 0);
+                this.read_len -= n;
+            }
+            if let Some(result) = result {
+                return Poll::Ready(Some(result.map_err(FramedError::Codec)));
+            }
+            if n > 0 {
+                continue;
+            }
+            if this.eof {
+                if this.read_len == 0 {
+                    return Poll::Ready(None);
+                }
+                this.read_len = 0;
+                return Poll::Ready(Some(Err(FramedError::UnexpectedEof)));
+            }
+            if this.read_len == N {
+                this.codec.discard(&this.read_buf);
+                this.read_len = 0;
+                return Poll::Ready(Some(Err(FramedError::Overflow)));
+            }
+            let n = ready!(
+                Pin::new(&mut this.inner).poll_read(cx, &mut this.read_buf[this.read_len..])
+            )
+            .map_err(FramedError::Io)?;
+            if n == 0 {
+                this.eof = true;
+            }
+            this.read_len += n;
+        }
+    }
+}
+
+#[cfg(feature = "sink")]
+impl<T, C, I, const N: usize> futures_sink::Sink<I> for Framed<T, C, N>
+where
+    T: AsyncWrite + Unpin,
+    C: Encoder<I> + Unpin,
+{
+    type Error = FramedError<T::Error, <C as Encoder<I>>::Error>;
+
+    /// Waits until the previous message has been written to the transport.
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_write_buf(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: I) -> Result<(), Self::Error> {
+        self.start_send_item(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush_inner(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_flush_inner(cx))?;
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(FramedError::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::codec::{CodecError, Delimited, Frame, LengthDelimited};
+    use crate::io::AsyncReadExt;
+    use crate::test_util::{block_on, line};
+    use core::convert::Infallible;
+    use futures_util::StreamExt;
+    use std::vec::Vec;
+
+    /// Received data, read a few bytes at a time and followed by end of file.
+    struct Input {
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    impl AsyncRead for Input {
+        type Error = Infallible;
+
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<Result<usize, Infallible>> {
+            let rest = &self.data[self.pos..];
+            let n = buf.len().min(rest.len()).min(3);
+            buf[..n].copy_from_slice(&rest[..n]);
+            self.pos += n;
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    type Received<C> = Vec<Result<<C as Decoder>::Item, FramedError<Infallible, CodecError>>>;
+
+    /// Decodes all messages of `data` with a buffer of `N` bytes.
+    fn receive<C, const N: usize>(codec: C, data: &[u8]) -> Received<C>
+    where
+        C: Decoder<Error = CodecError> + Unpin,
+    {
+        let input = Input {
+            data: data.to_vec(),
+            pos: 0,
+        };
+        let framed = Framed::<_, _, N>::new(input, codec);
+        block_on(framed.collect())
+    }
+
+    fn frame<const N: usize>(data: &[u8]) -> Result<Frame<N>, FramedError<Infallible, CodecError>> {
+        Ok(Frame::from_slice(data).unwrap())
+    }
+
+    #[test]
+    fn decodes_frames_split_over_reads() {
+        let data = b"\x00\x05hello\x00\x00\x00\x03abc";
+        assert_eq!(
+            receive::<_, 7>(LengthDelimited::<5>::new(), data),
+            [frame(b"hello"), frame(b""), frame(b"abc")]
+        );
+    }
+
+    #[test]
+    fn eof_in_frame_is_unexpected() {
+        assert_eq!(
+            receive::<_, 8>(Delimited::<6>::new(b'\n'), b"one\ntw"),
+            [frame(b"one"), Err(FramedError::UnexpectedEof)]
+        );
+    }
+
+    #[test]
+    fn overflow_skips_rest_of_line() {
+        // The frame fits the codec, but not the buffer.
+        assert_eq!(
+            receive::<_, 8>(Delimited::<16>::new(b'\n'), b"0123456789abcdef\nok\n"),
+            [Err(FramedError::Overflow), frame(b"ok")]
+        );
+        // A frame of `N` bytes needs a buffer of `N + 1` bytes.
+        assert_eq!(
+            receive::<_, 5>(Delimited::<4>::new(b'\n'), b"abcd\n"),
+            [frame(b"abcd")]
+        );
+    }
+
+    #[test]
+    fn overflow_skips_rest_of_length_delimited_frame() {
+        let data = b"\x00\x0a0123456789\x00\x02ok";
+        assert_eq!(
+            receive::<_, 8>(LengthDelimited::<16>::new(), data),
+            [Err(FramedError::Overflow), frame(b"ok")]
+        );
+    }
+
+    #[test]
+    fn sends_slices_and_frames() {
+        let (a, mut b) = line();
+        let mut framed = Framed::<_, _, 8>::new(a, LengthDelimited::<6>::new());
+        block_on(async {
+            framed.send(&b"hi"[..]).await.unwrap();
+            let frame = Frame::<6>::from_slice(b"there").unwrap();
+            framed.send(&frame).await.unwrap();
+            framed.send(frame).await.unwrap();
+            assert_eq!(
+                framed.send(&b"toolong"[..]).await,
+                Err(FramedError::Codec(CodecError::TooLong))
+            );
+            let mut buf = [0; 32];
+            let n = b.read(&mut buf).await.unwrap();
+            assert_eq!(buf[..n], *b"\x00\x02hi\x00\x05there\x00\x05there");
+        });
+    }
+}
@@ -0,0 +1,334 @@
+//! Serial Line Internet Protocol framing (RFC 1055).
+//!
+//! SLIP delimits frames with an `END` byte and escapes `END` and `ESC` bytes inside frames, so
+//! a frame grows by at most a factor of two.
+
+use super::bytes::ByteReader;
+use super::{AsyncRead, AsyncWrite, FrameError};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::future::poll_fn;
+use futures_util::ready;
+
+const END: u8 = 0xc0;
+const ESC: u8 = 0xdb;
+const ESC_END: u8 = 0xdc;
+const ESC_ESC: u8 = 0xdd;
+
+/// Encodes frames written to it with SLIP and writes them to `W`.
+///
+/// Data written through the `AsyncWrite` implementation is appended to the current frame, and
+/// [`finish_frame`](SlipEncoder::finish_frame) ends it. Every frame is also preceded by an `END`
+/// byte, which flushes any line noise out of the receiver.
+pub struct SlipEncoder<W> {
+    writer: W,
+    /// Encoded bytes that still have to be written to `writer`.
+    pending: [u8; 2],
+    pending_len: usize,
+    in_frame: bool,
+}
+
+impl<W> SlipEncoder<W> {
+    pub fn new(writer: W) -> Self {
+        SlipEncoder {
+            writer,
+            pending: [0; 2],
+            pending_len: 0,
+            in_frame: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Returns the writer. Encoded data that was not written yet is lost.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Queues `bytes` at the end of `pending`, so that the unwritten bytes are always the last
+    /// `pending_len` ones.
+    fn queue(&mut self, bytes: &[u8]) {
+        let start = self.pending.len() - bytes.len();
+        self.pending[start..].copy_from_slice(bytes);
+        self.pending_len = bytes.len();
+    }
+}
+
+impl<W: AsyncWrite + Unpin> SlipEncoder<W> {
+    /// Writes queued bytes to the writer.
+    fn poll_emit(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), FrameError<W::Error>>> {
+        while self.pending_len > 0 {
+            let start = self.pending.len() - self.pending_len;
+            let n = ready!(Pin::new(&mut self.writer).poll_write(cx, &self.pending[start..]))
+                .map_err(FrameError::Io)?;
+            if n == 0 {
+                return Poll::Ready(Err(FrameError::WriteZero));
+            }
+            self.pending_len -= n;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Starts a new frame if there is none yet.
+    fn poll_start_frame(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), FrameError<W::Error>>> {
+        if !self.in_frame {
+            self.queue(&[END]);
+            self.in_frame = true;
+        }
+        self.poll_emit(cx)
+    }
+
+    /// Ends the current frame and writes the rest of it, including the trailing `END`.
+    pub async fn finish_frame(&mut self) -> Result<(), FrameError<W::Error>> {
+        poll_fn(|cx| self.poll_start_frame(cx)).await?;
+        self.queue(&[END]);
+        self.in_frame = false;
+        poll_fn(|cx| self.poll_emit(cx)).await
+    }
+
+    /// Encodes and writes a complete frame.
+    pub async fn send_frame(&mut self, frame: &[u8]) -> Result<(), FrameError<W::Error>> {
+        let mut frame = frame;
+        while !frame.is_empty() {
+            let n = poll_fn(|cx| Pin::new(&mut *self).poll_write(cx, frame)).await?;
+            frame = &frame[n..];
+        }
+        self.finish_frame().await
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for SlipEncoder<W> {
+    type Error = FrameError<W::Error>;
+
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Self::Error>> {
+        let this = &mut *self;
+        ready!(this.poll_start_frame(cx))?;
+        // Write a run of bytes that need no escaping directly, then queue the escaped byte.
+        let plain = buf
+            .iter()
+            .position(|&byte| byte == END || byte == ESC)
+            .unwrap_or(buf.len());
+        if plain > 0 {
+            let n = ready!(Pin::new(&mut this.writer).poll_write(cx, &buf[..plain]))
+                .map_err(FrameError::Io)?;
+            if n == 0 {
+                return Poll::Ready(Err(FrameError::WriteZero));
+            }
+            return Poll::Ready(Ok(n));
+        }
+        match buf.first() {
+            Some(&END) => this.queue(&[ESC, ESC_END]),
+            Some(&ESC) => this.queue(&[ESC, ESC_ESC]),
+            _ => return Poll::Ready(Ok(0)),
+        }
+        Poll::Ready(Ok(1))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_emit(cx))?;
+        Pin::new(&mut self.writer)
+            .poll_flush(cx)
+            .map_err(FrameError::Io)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_emit(cx))?;
+        Pin::new(&mut self.writer)
+            .poll_close(cx)
+            .map_err(FrameError::Io)
+    }
+}
+
+/// Reads SLIP encoded frames from `R`.
+pub struct SlipDecoder<R> {
+    reader: ByteReader<R>,
+}
+
+impl<R> SlipDecoder<R> {
+    pub fn new(reader: R) -> Self {
+        SlipDecoder {
+            reader: ByteReader::new(reader),
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.reader.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.reader.get_mut()
+    }
+
+    /// Returns the reader. Data that was read from it but not decoded yet is lost.
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
+}
+
+impl<R: AsyncRead + Unpin> SlipDecoder<R> {
+    /// Receives the next non-empty frame into `buf`, returning its length.
+    ///
+    /// If the frame does not fit into `buf` or contains an invalid escape sequence, the rest of
+    /// the frame is skipped and an error is returned, so the next call starts with the next frame.
+    pub async fn recv_frame(&mut self, buf: &mut [u8]) -> Result<usize, FrameError<R::Error>> {
+        let mut len = 0;
+        let mut escaped = false;
+        let mut error = None;
+        loop {
+            let byte = self.reader.next().await?;
+            if byte == END {
+                if escaped && error.is_none() {
+                    error = Some(FrameError::InvalidFrame);
+                }
+                match error {
+                    Some(error) => return Err(error),
+                    None if len > 0 => return Ok(len),
+                    None => continue,
+                }
+            }
+            if error.is_some() {
+                continue;
+            }
+            let data = if escaped {
+                escaped = false;
+                match byte {
+                    ESC_END => END,
+                    ESC_ESC => ESC,
+                    _ => {
+                        error = Some(FrameError::InvalidFrame);
+                        continue;
+                    }
+                }
+            } else if byte == ESC {
+                escaped = true;
+                continue;
+            } else {
+                byte
+            };
+            match buf.get_mut(len) {
+                Some(slot) => {
+                    *slot = data;
+                    len += 1;
+                }
+                None => error = Some(FrameError::Overflow),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::test_util::{block_on, line};
+    use core::convert::Infallible;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// Returns the bytes `frame` is encoded to.
+    fn encode(frame: &[u8]) -> Vec<u8> {
+        let (a, mut b) = line();
+        let mut encoder = SlipEncoder::new(a);
+        block_on(async {
+            encoder.send_frame(frame).await.unwrap();
+            let mut buf = [0; 1024];
+            let n = b.read(&mut buf).await.unwrap();
+            buf[..n].to_vec()
+        })
+    }
+
+    /// Decodes frames from the raw bytes `data` into buffers of `len` bytes, until `count`
+    /// frames or errors were received.
+    fn decode(
+        data: &[u8],
+        len: usize,
+        count: usize,
+    ) -> Vec<Result<Vec<u8>, FrameError<Infallible>>> {
+        let (mut a, b) = line();
+        let mut decoder = SlipDecoder::new(b);
+        block_on(async {
+            a.write_all(data).await.unwrap();
+            let mut received = Vec::new();
+            for _ in 0..count {
+                let mut buf = vec![0; len];
+                let result = decoder.recv_frame(&mut buf).await;
+                received.push(result.map(|n| buf[..n].to_vec()));
+            }
+            received
+        })
+    }
+
+    #[test]
+    fn escapes_end_and_esc() {
+        assert_eq!(
+            encode(b"a\xc0b\xdbc"),
+            b"\xc0a\xdb\xdcb\xdb\xddc\xc0".to_vec()
+        );
+        assert_eq!(encode(b"\xdb\xdc"), b"\xc0\xdb\xdd\xdc\xc0".to_vec());
+    }
+
+    #[test]
+    fn frames_survive_round_trip() {
+        let frames = vec![
+            b"hello".to_vec(),
+            vec![END, ESC, ESC_END, ESC_ESC, END, END],
+            (0..=255).cycle().take(600).collect(),
+        ];
+        let (a, b) = line();
+        let mut encoder = SlipEncoder::new(a);
+        let mut decoder = SlipDecoder::new(b);
+        block_on(async {
+            for frame in &frames {
+                encoder.send_frame(frame).await.unwrap();
+            }
+            for frame in &frames {
+                let mut buf = [0; 1024];
+                let n = decoder.recv_frame(&mut buf).await.unwrap();
+                assert_eq!(&buf[..n], &frame[..]);
+            }
+        });
+    }
+
+    #[test]
+    fn empty_frames_are_skipped() {
+        assert_eq!(decode(b"\xc0\xc0\xc0ab\xc0", 8, 1), [Ok(b"ab".to_vec())]);
+    }
+
+    #[test]
+    fn overflow_skips_rest_of_frame() {
+        let mut data = vec![END];
+        data.extend(&[7; 20]);
+        data.extend(b"\xc0next\xc0");
+        assert_eq!(
+            decode(&data, 10, 2),
+            [Err(FrameError::Overflow), Ok(b"next".to_vec())]
+        );
+        // An escaped byte counts once.
+        assert_eq!(
+            decode(b"\xc0\xdb\xdc\xdb\xdd\xc0", 2, 1),
+            [Ok(vec![END, ESC])]
+        );
+    }
+
+    #[test]
+    fn invalid_escape_skips_rest_of_frame() {
+        assert_eq!(
+            decode(b"\xc0a\xdbb\xdb\xdcc\xc0ok\xc0\xdb\xc0", 8, 3),
+            [
+                Err(FrameError::InvalidFrame),
+                Ok(b"ok".to_vec()),
+                Err(FrameError::InvalidFrame)
+            ]
+        );
+    }
+}
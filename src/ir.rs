@@ -0,0 +1,376 @@
+//! Infrared remote control receiver, decoding the NEC and RC5 protocols.
+//!
+//! ```ignore
+//! #[avr_device::interrupt(atmega328p)]
+//! fn PCINT0() {
+//!     async_avr::pcint::on_interrupt(Port::B);
+//! }
+//!
+//! let mut remote = IrReceiver::new(Port::B, 0);
+//! while let Some(code) = remote.next().await {
+//!     match (code.address, code.command) {
+//!         // ...
+//!     }
+//! }
+//! ```
+//!
+//! The receiver is a demodulating module such as a TSOP38238, whose output is low while the
+//! carrier is received. The pin change interrupt, which must be forwarded to
+//! [`pcint::on_interrupt`](crate::pcint::on_interrupt), measures the time since the previous
+//! edge and feeds it to a state machine for each protocol. Both protocols are decoded at the
+//! same time, and decoded codes are queued until the stream is polled.
+
+use crate::pcint::{self, Port};
+use crate::time::{Duration, Instant};
+use crate::waker::WakerCell;
+use avr_device::interrupt::{self, CriticalSection, Mutex};
+use core::cell::Cell;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+
+const NEC_LEADER_MARK: Duration = Duration::from_micros(9000);
+const NEC_LEADER_SPACE: Duration = Duration::from_micros(4500);
+const NEC_REPEAT_SPACE: Duration = Duration::from_micros(2250);
+const NEC_BIT_MARK: Duration = Duration::from_micros(562);
+const NEC_ZERO_SPACE: Duration = Duration::from_micros(562);
+const NEC_ONE_SPACE: Duration = Duration::from_micros(1687);
+/// The longest time from a code to a repeat code, which follow each other every 108 ms.
+const NEC_REPEAT_WINDOW: Duration = Duration::from_millis(150);
+const RC5_HALF_BIT: Duration = Duration::from_micros(889);
+/// The longest time between frames of a held key, which are sent every 114 ms.
+const RC5_REPEAT_WINDOW: Duration = Duration::from_millis(250);
+/// How many decoded codes are kept until the stream is polled.
+const QUEUE: usize = 4;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Protocol {
+    Nec,
+    Rc5,
+}
+
+/// A code received from a remote control.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Code {
+    pub protocol: Protocol,
+    /// 8 bits, or 16 bits for extended NEC, or 5 bits for RC5.
+    pub address: u16,
+    /// 8 bits for NEC, or 7 bits for RC5 with the inverted second start bit of RC5X as bit 6.
+    pub command: u8,
+    /// Whether the code repeats the previous one because the key is held, from a NEC repeat
+    /// code or an RC5 frame with an unchanged toggle bit.
+    pub repeat: bool,
+}
+
+/// Checks whether `ticks` are within 25% of `expected`, a tolerance which also covers the
+/// marks of receiver modules being stretched by up to a carrier period or two.
+fn near(ticks: u32, expected: Duration) -> bool {
+    let expected = expected.ticks();
+    ticks >= expected - expected / 4 && ticks <= expected + expected / 4
+}
+
+#[derive(Copy, Clone)]
+enum NecState {
+    Idle,
+    /// After the leader mark.
+    Leader,
+    /// Receiving the bits, least significant first.
+    Bits {
+        count: u8,
+        value: u32,
+    },
+}
+
+#[derive(Copy, Clone)]
+struct Nec {
+    state: NecState,
+    /// The last code, and when it or its last repeat was received.
+    last: Option<(Code, u32)>,
+}
+
+impl Nec {
+    /// Feeds the mark or space of `ticks` which ended at `now`.
+    fn edge(&mut self, mark: bool, ticks: u32, now: u32) -> Option<Code> {
+        let (state, code) = match (self.state, mark) {
+            (_, true) if near(ticks, NEC_LEADER_MARK) => (NecState::Leader, None),
+            (NecState::Leader, false) if near(ticks, NEC_LEADER_SPACE) => {
+                (NecState::Bits { count: 0, value: 0 }, None)
+            }
+            (NecState::Leader, false) if near(ticks, NEC_REPEAT_SPACE) => {
+                let code = match self.last {
+                    Some((code, time)) if now.wrapping_sub(time) <= NEC_REPEAT_WINDOW.ticks() => {
+                        self.last = Some((code, now));
+                        Some(Code {
+                            repeat: true,
+                            ..code
+                        })
+                    }
+                    _ => None,
+                };
+                (NecState::Idle, code)
+            }
+            (state @ NecState::Bits { .. }, true) if near(ticks, NEC_BIT_MARK) => (state, None),
+            (NecState::Bits { count, value }, false) => {
+                let value = if near(ticks, NEC_ONE_SPACE) {
+                    value | 1 << count
+                } else if near(ticks, NEC_ZERO_SPACE) {
+                    value
+                } else {
+                    self.state = NecState::Idle;
+                    return None;
+                };
+                if count < 31 {
+                    (
+                        NecState::Bits {
+                            count: count + 1,
+                            value,
+                        },
+                        None,
+                    )
+                } else {
+                    let code = Nec::decode(value);
+                    self.last = code.map(|code| (code, now));
+                    (NecState::Idle, code)
+                }
+            }
+            _ => (NecState::Idle, None),
+        };
+        self.state = state;
+        code
+    }
+
+    fn decode(value: u32) -> Option<Code> {
+        let [address, address_inverse, command, command_inverse] = value.to_le_bytes();
+        if command != !command_inverse {
+            return None;
+        }
+        let address = if address == !address_inverse {
+            address as u16
+        } else {
+            // Extended NEC uses both bytes for the address.
+            (address_inverse as u16) << 8 | address as u16
+        };
+        Some(Code {
+            protocol: Protocol::Nec,
+            address,
+            command,
+            repeat: false,
+        })
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Rc5 {
+    /// The half bits received so far, with marks as ones and the latest in the least
+    /// significant bit.
+    halves: u32,
+    count: u8,
+    /// The toggle bit of the last frame, and when it was received.
+    last: Option<(bool, u32)>,
+}
+
+impl Rc5 {
+    const HALVES: u8 = 28;
+
+    /// Feeds the mark or space of `ticks` which ended at `now`.
+    fn edge(&mut self, mark: bool, ticks: u32, now: u32) -> Option<Code> {
+        let halves = if near(ticks, RC5_HALF_BIT) {
+            1
+        } else if near(ticks, Duration::from_ticks(2 * RC5_HALF_BIT.ticks())) {
+            2
+        } else {
+            0
+        };
+        if self.count == 0 {
+            // A frame starts with the mark in the second half of the first start bit, whose
+            // first half cannot be told apart from the idle line.
+            if !mark {
+                return None;
+            }
+            self.count = 1;
+            self.halves = 0;
+        }
+        if halves == 0 {
+            self.count = 0;
+            return None;
+        }
+        for _ in 0..halves {
+            self.halves = self.halves << 1 | mark as u32;
+            self.count += 1;
+        }
+        if self.count == Rc5::HALVES - 1 {
+            // The last half bit is the opposite of the one before, and if it is a space it
+            // merges with the idle line.
+            self.halves = self.halves << 1 | (!self.halves & 1);
+            self.count += 1;
+        }
+        if self.count < Rc5::HALVES {
+            return None;
+        }
+        self.count = 0;
+        let mut bits = 0u16;
+        for i in (0..Rc5::HALVES).step_by(2).rev() {
+            bits = match self.halves >> i & 0b11 {
+                0b01 => bits << 1 | 1,
+                0b10 => bits << 1,
+                _ => return None,
+            };
+        }
+        // S1 S2 T A4..A0 C5..C0
+        if bits & 1 << 13 == 0 {
+            return None;
+        }
+        let toggle = bits & 1 << 11 != 0;
+        let repeat = match self.last {
+            Some((last, time)) => {
+                last == toggle && now.wrapping_sub(time) <= RC5_REPEAT_WINDOW.ticks()
+            }
+            None => false,
+        };
+        self.last = Some((toggle, now));
+        Some(Code {
+            protocol: Protocol::Rc5,
+            address: bits >> 6 & 0x1f,
+            command: (bits & 0x3f) as u8 | ((bits & 1 << 12 == 0) as u8) << 6,
+            repeat,
+        })
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Decoder {
+    port: Option<Port>,
+    bit: u8,
+    /// The level of the pin, and the time of its last edge.
+    high: bool,
+    last: u32,
+    nec: Nec,
+    rc5: Rc5,
+    queue: [Code; QUEUE],
+    queued: u8,
+}
+
+const EMPTY_CODE: Code = Code {
+    protocol: Protocol::Nec,
+    address: 0,
+    command: 0,
+    repeat: false,
+};
+
+static DECODER: Mutex<Cell<Decoder>> = Mutex::new(Cell::new(Decoder {
+    port: None,
+    bit: 0,
+    high: true,
+    last: 0,
+    nec: Nec {
+        state: NecState::Idle,
+        last: None,
+    },
+    rc5: Rc5 {
+        halves: 0,
+        count: 0,
+        last: None,
+    },
+    queue: [EMPTY_CODE; QUEUE],
+    queued: 0,
+}));
+static WAKER: WakerCell = WakerCell::new();
+
+/// A receiver on pin `bit` of a port, yielding the codes received, never ending.
+///
+/// Only one receiver can exist at a time. Codes received while the queue is full are dropped.
+pub struct IrReceiver {
+    port: Port,
+    bit: u8,
+}
+
+impl IrReceiver {
+    /// Starts receiving on pin `bit` of `port`, which must be configured as an input.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a receiver exists already, or `bit` is greater than 7.
+    pub fn new(port: Port, bit: u8) -> Self {
+        assert!(bit < 8);
+        interrupt::free(|cs| {
+            let cell = DECODER.borrow(cs);
+            let mut decoder = cell.get();
+            assert!(decoder.port.is_none(), "IR receiver already in use");
+            decoder.port = Some(port);
+            decoder.bit = bit;
+            decoder.high = pcint::watch(port, 1 << bit, Some(on_change)) & 1 << bit != 0;
+            decoder.last = Instant::now().ticks();
+            decoder.nec.state = NecState::Idle;
+            decoder.rc5.count = 0;
+            decoder.queued = 0;
+            cell.set(decoder);
+        });
+        IrReceiver { port, bit }
+    }
+}
+
+impl Stream for IrReceiver {
+    type Item = Code;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Code>> {
+        WAKER.register(cx.waker());
+        interrupt::free(|cs| {
+            let cell = DECODER.borrow(cs);
+            let mut decoder = cell.get();
+            if decoder.queued == 0 {
+                return Poll::Pending;
+            }
+            let code = decoder.queue[0];
+            decoder.queue.copy_within(1.., 0);
+            decoder.queued -= 1;
+            cell.set(decoder);
+            Poll::Ready(Some(code))
+        })
+    }
+}
+
+impl Drop for IrReceiver {
+    fn drop(&mut self) {
+        pcint::unwatch(self.port, 1 << self.bit);
+        interrupt::free(|cs| {
+            let cell = DECODER.borrow(cs);
+            let mut decoder = cell.get();
+            decoder.port = None;
+            cell.set(decoder);
+        });
+    }
+}
+
+/// Decodes the edges of the receiver on `port`, whose pins changed to `pins`.
+pub(crate) fn on_change(cs: &CriticalSection, port: Port, pins: u8) {
+    let cell = DECODER.borrow(cs);
+    let mut decoder = cell.get();
+    if decoder.port != Some(port) {
+        return;
+    }
+    // Changes of other pins of the port leave the level unchanged.
+    let high = pins & 1 << decoder.bit != 0;
+    if high == decoder.high {
+        return;
+    }
+    let now = Instant::now().ticks();
+    let ticks = now.wrapping_sub(decoder.last);
+    decoder.high = high;
+    decoder.last = now;
+    // The output is low during marks, so a rising edge ends a mark.
+    let mark = high;
+    let nec = decoder.nec.edge(mark, ticks, now);
+    let rc5 = decoder.rc5.edge(mark, ticks, now);
+    let mut woken = false;
+    for code in nec.into_iter().chain(rc5) {
+        if (decoder.queued as usize) < QUEUE {
+            decoder.queue[decoder.queued as usize] = code;
+            decoder.queued += 1;
+            woken = true;
+        }
+    }
+    cell.set(decoder);
+    if woken {
+        WAKER.wake();
+    }
+}
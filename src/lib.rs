@@ -6,13 +6,59 @@ use core::task::{Context, Poll};
 use avr_hal_generic::hal;
 use avr_hal_generic::nb;
 
+pub mod block;
+pub mod button;
+pub mod capture;
+pub mod crc;
+pub mod dht;
+pub mod ds18b20;
+pub mod encoder;
 mod executor;
+pub mod exti;
+pub mod fat;
 pub mod io;
+pub mod ir;
+pub mod mcp2515;
+pub mod modbus;
+pub mod nrf24;
+pub mod onewire;
+pub mod pcint;
+pub mod power;
+pub mod pwm;
+pub mod reliable;
+pub mod sd;
+pub mod serial;
+pub mod shell;
 mod spi;
-pub use executor::block_on;
+pub mod sx127x;
+pub mod time;
+#[cfg(feature = "trace")]
+pub mod trace;
+pub mod twi_slave;
+pub mod w25q;
+mod waker;
+pub mod ws2812;
+pub mod xmodem;
+pub use executor::{
+    block_on, Executor, InterruptExecutor, PinChangeB, PinChangeC, PinChangeD, SoftwareInterrupt,
+    SpawnError, Spawner,
+};
 use futures_util::future::Future;
-pub use spi::AsyncSpi;
+pub use spi::{AsyncSpi, SharedSpi, SpiBus, SpiDevice};
 
+#[cfg(test)]
+extern crate std;
+#[cfg(test)]
+mod test_util;
+
+/// Clock frequency of the MCU.
+pub(crate) const CPU_FREQUENCY: u32 = 16_000_000;
+
+/// An `avr-hal` serial port as [`AsyncRead`](io::AsyncRead) and [`AsyncWrite`](io::AsyncWrite).
+///
+/// The port has no interrupt to wait for, so while a read or write cannot make progress, the
+/// task is woken again right away and the executor keeps polling instead of sleeping. The
+/// interrupt-driven [`serial::Serial`] lets the MCU sleep while waiting.
 pub struct AsyncSerial<T>(T);
 
 impl<T> AsyncSerial<T> {
@@ -32,7 +78,7 @@ impl<T: hal::serial::Read<u8> + Unpin> io::AsyncRead for AsyncSerial<T> {
 
     fn poll_read(
         mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<Result<usize, T::Error>> {
         if let Some(ptr) = buf.first_mut() {
@@ -41,7 +87,10 @@ impl<T: hal::serial::Read<u8> + Unpin> io::AsyncRead for AsyncSerial<T> {
                     *ptr = byte;
                     Poll::Ready(Ok(1))
                 }
-                Err(nb::Error::WouldBlock) => Poll::Pending,
+                Err(nb::Error::WouldBlock) => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
                 Err(nb::Error::Other(err)) => Poll::Ready(Err(err)),
             }
         } else {
@@ -55,13 +104,16 @@ impl<T: hal::serial::Write<u8> + Unpin> io::AsyncWrite for AsyncSerial<T> {
 
     fn poll_write(
         mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, T::Error>> {
         if let Some(byte) = buf.first() {
             match self.0.write(*byte) {
                 Ok(()) => Poll::Ready(Ok(1)),
-                Err(nb::Error::WouldBlock) => Poll::Pending,
+                Err(nb::Error::WouldBlock) => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
                 Err(nb::Error::Other(err)) => Poll::Ready(Err(err)),
             }
         } else {
@@ -69,10 +121,13 @@ impl<T: hal::serial::Write<u8> + Unpin> io::AsyncWrite for AsyncSerial<T> {
         }
     }
 
-    fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
         match self.0.flush() {
             Ok(()) => Poll::Ready(Ok(())),
-            Err(nb::Error::WouldBlock) => Poll::Pending,
+            Err(nb::Error::WouldBlock) => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
             Err(nb::Error::Other(err)) => Poll::Ready(Err(err)),
         }
     }
@@ -93,11 +148,12 @@ impl Default for Yield {
 impl Future for Yield {
     type Output = ();
 
-    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         if self.0 {
             Poll::Ready(())
         } else {
             self.0 = true;
+            cx.waker().wake_by_ref();
             Poll::Pending
         }
     }
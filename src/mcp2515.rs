@@ -0,0 +1,580 @@
+//! Microchip MCP2515 CAN controllers.
+//!
+//! ```ignore
+//! let mut can = Mcp2515::new(AsyncSpi::new(spi), cs, ExtInt::<0>::new(Sense::Low));
+//! can.init(&Config {
+//!     bit_timing: BitTiming::new(16_000_000, 500_000).unwrap(),
+//!     ..Config::default()
+//! })
+//! .await?;
+//! can.set_mask(0, Id::Standard(0x7f0)).await?;
+//! can.set_filter(0, Id::Standard(0x120)).await?;
+//! can.send(&Frame::new(Id::Standard(0x100), &[1, 2, 3]).unwrap()).await?;
+//! let frame = can.recv().await?;
+//! ```
+//!
+//! Like [`nrf24`](crate::nrf24), the driver takes an [`AsyncSpi`](crate::AsyncSpi) for a bus
+//! of its own, or a [`SharedSpi`](crate::SharedSpi) to share the bus with other drivers, which
+//! is locked for each register transaction. Waiting for a frame or a free transmit buffer is
+//! asynchronous, on the `INT` pin of the controller, which must be connected to `INT0` or
+//! `INT1`.
+
+use crate::exti::{ExtInt, Sense};
+use crate::spi::{SpiDevice, Transaction};
+use crate::time::{delay, Duration};
+use avr_hal_generic::hal::digital::v2::OutputPin;
+
+const RXF0SIDH: u8 = 0x00;
+const RXF3SIDH: u8 = 0x10;
+const CANSTAT: u8 = 0x0e;
+const CANCTRL: u8 = 0x0f;
+const TEC: u8 = 0x1c;
+const RXM0SIDH: u8 = 0x20;
+const CNF3: u8 = 0x28;
+const CANINTE: u8 = 0x2b;
+const CANINTF: u8 = 0x2c;
+const EFLG: u8 = 0x2d;
+const TXB0CTRL: u8 = 0x30;
+const RXB0CTRL: u8 = 0x60;
+const RXB1CTRL: u8 = 0x70;
+
+const INSTRUCTION_WRITE: u8 = 0x02;
+const INSTRUCTION_READ: u8 = 0x03;
+const INSTRUCTION_BIT_MODIFY: u8 = 0x05;
+const INSTRUCTION_LOAD_TX_BUFFER: u8 = 0x40;
+const INSTRUCTION_RTS: u8 = 0x80;
+const INSTRUCTION_READ_RX_BUFFER: u8 = 0x90;
+const INSTRUCTION_READ_STATUS: u8 = 0xa0;
+const INSTRUCTION_RESET: u8 = 0xc0;
+
+const MODE_NORMAL: u8 = 0b000 << 5;
+const MODE_LOOPBACK: u8 = 0b010 << 5;
+const MODE_LISTEN_ONLY: u8 = 0b011 << 5;
+const MODE_CONFIGURATION: u8 = 0b100 << 5;
+const MODE_MASK: u8 = 0b111 << 5;
+
+const INT_ERR: u8 = 1 << 5;
+const INT_TX2: u8 = 1 << 4;
+const INT_TX1: u8 = 1 << 3;
+const INT_TX0: u8 = 1 << 2;
+const INT_RX1: u8 = 1 << 1;
+const INT_RX0: u8 = 1 << 0;
+
+const EFLG_RX1OVR: u8 = 1 << 7;
+const EFLG_RX0OVR: u8 = 1 << 6;
+const EFLG_TXBO: u8 = 1 << 5;
+const EFLG_TXEP: u8 = 1 << 4;
+const EFLG_RXEP: u8 = 1 << 3;
+const EFLG_EWARN: u8 = 1 << 0;
+
+const STATUS_RX0IF: u8 = 1 << 0;
+const STATUS_RX1IF: u8 = 1 << 1;
+
+const TXBCTRL_TXREQ: u8 = 1 << 3;
+const TXBCTRL_TXP: u8 = 0b11;
+const RXB0CTRL_BUKT: u8 = 1 << 2;
+const SIDL_EXIDE: u8 = 1 << 3;
+const SIDL_SRR: u8 = 1 << 4;
+const DLC_RTR: u8 = 1 << 6;
+const CNF2_BTLMODE: u8 = 1 << 7;
+
+/// How long the oscillator takes to start after a reset.
+const RESET_DELAY: Duration = Duration::from_millis(1);
+/// How often the mode is checked while waiting for a mode change, which waits for the bus to
+/// become idle.
+const MODE_POLL_INTERVAL: Duration = Duration::from_micros(100);
+const MODE_POLLS: u8 = 100;
+
+/// An error of an [`Mcp2515`] controller, wrapping the error types `S` of the SPI and `P` of
+/// the chip select pin.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Error<S, P> {
+    Spi(S),
+    Pin(P),
+    /// The controller did not answer, or did not enter the requested mode.
+    NotFound,
+    /// The controller disconnected itself from the bus after too many transmit errors. It
+    /// reconnects by itself after the bus has been idle for 128 × 11 bits.
+    BusOff,
+    /// Frames were lost because both receive buffers were full.
+    Overflow,
+    /// The mask or filter does not exist.
+    InvalidFilter,
+}
+
+impl<S, P> From<S> for Error<S, P> {
+    fn from(err: S) -> Self {
+        Error::Spi(err)
+    }
+}
+
+type CanError<T, CS> = Error<<T as SpiDevice>::Error, <CS as OutputPin>::Error>;
+
+/// A CAN identifier. Bits beyond the 11 or 29 bits of the identifier are ignored.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Id {
+    Standard(u16),
+    Extended(u32),
+}
+
+impl Id {
+    /// The `SIDH`, `SIDL`, `EID8` and `EID0` registers of a buffer, filter or mask.
+    fn to_registers(self) -> [u8; 4] {
+        match self {
+            Id::Standard(id) => [(id >> 3) as u8, (id << 5) as u8, 0, 0],
+            Id::Extended(id) => {
+                let sid = id >> 18;
+                [
+                    (sid >> 3) as u8,
+                    (sid << 5) as u8 | SIDL_EXIDE | (id >> 16) as u8 & 0b11,
+                    (id >> 8) as u8,
+                    id as u8,
+                ]
+            }
+        }
+    }
+
+    fn from_registers(registers: &[u8]) -> Self {
+        let sid = (registers[0] as u16) << 3 | (registers[1] >> 5) as u16;
+        if registers[1] & SIDL_EXIDE == 0 {
+            Id::Standard(sid)
+        } else {
+            Id::Extended(
+                (sid as u32) << 18
+                    | ((registers[1] & 0b11) as u32) << 16
+                    | (registers[2] as u32) << 8
+                    | registers[3] as u32,
+            )
+        }
+    }
+}
+
+/// A data or remote frame.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Frame {
+    id: Id,
+    remote: bool,
+    dlc: u8,
+    data: [u8; 8],
+}
+
+impl Frame {
+    /// Creates a data frame, or returns `None` if `data` is longer than 8 bytes.
+    pub fn new(id: Id, data: &[u8]) -> Option<Self> {
+        if data.len() > 8 {
+            return None;
+        }
+        let mut frame = Frame {
+            id,
+            remote: false,
+            dlc: data.len() as u8,
+            data: [0; 8],
+        };
+        frame.data[..data.len()].copy_from_slice(data);
+        Some(frame)
+    }
+
+    /// Creates a remote frame requesting `dlc` bytes, or returns `None` if `dlc` is greater
+    /// than 8.
+    pub fn remote(id: Id, dlc: u8) -> Option<Self> {
+        if dlc > 8 {
+            return None;
+        }
+        Some(Frame {
+            id,
+            remote: true,
+            dlc,
+            data: [0; 8],
+        })
+    }
+
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn is_remote(&self) -> bool {
+        self.remote
+    }
+
+    /// The data length code, which is the number of data bytes, or the number of requested
+    /// bytes of a remote frame.
+    pub fn dlc(&self) -> u8 {
+        self.dlc
+    }
+
+    /// The data, which is empty for remote frames.
+    pub fn data(&self) -> &[u8] {
+        if self.remote {
+            &[]
+        } else {
+            &self.data[..self.dlc as usize]
+        }
+    }
+}
+
+/// The `CNF1` to `CNF3` registers, which set the bit rate and the sample point.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct BitTiming {
+    pub cnf1: u8,
+    pub cnf2: u8,
+    pub cnf3: u8,
+}
+
+impl BitTiming {
+    /// Calculates the timing for `bitrate` with an oscillator of `oscillator` Hz, such as 8 or
+    /// 16 MHz and 50 kbit/s to 1 Mbit/s, sampling at about 75 % of the bit. Returns `None` if
+    /// the bit rate cannot be derived exactly from the oscillator.
+    pub fn new(oscillator: u32, bitrate: u32) -> Option<Self> {
+        // A bit has 8 to 25 time quanta, each 2 to 128 oscillator clocks long. More quanta
+        // allow for a more accurate sample point.
+        (8..=16u32).rev().find_map(|quanta| {
+            let clocks = 2 * bitrate * quanta;
+            let prescaler = oscillator.checked_div(clocks)?;
+            if prescaler * clocks != oscillator || prescaler == 0 || prescaler > 64 {
+                return None;
+            }
+            let prescaler = (prescaler - 1) as u8;
+            let phase2 = (quanta / 4).max(2) as u8;
+            let rest = quanta as u8 - 1 - phase2;
+            let phase1 = rest / 2;
+            let propagation = rest - phase1;
+            Some(BitTiming {
+                // A synchronization jump width of 1.
+                cnf1: prescaler,
+                cnf2: CNF2_BTLMODE | (phase1 - 1) << 3 | (propagation - 1),
+                cnf3: phase2 - 1,
+            })
+        })
+    }
+}
+
+/// The operation mode after configuration.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Mode {
+    Normal,
+    /// Receives without acknowledging frames or sending error frames.
+    ListenOnly,
+    /// Receives the frames it sends, without sending them to the bus.
+    Loopback,
+}
+
+impl Mode {
+    fn bits(self) -> u8 {
+        match self {
+            Mode::Normal => MODE_NORMAL,
+            Mode::ListenOnly => MODE_LISTEN_ONLY,
+            Mode::Loopback => MODE_LOOPBACK,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Config {
+    pub bit_timing: BitTiming,
+    pub mode: Mode,
+}
+
+impl Default for Config {
+    /// 500 kbit/s with an 8 MHz crystal, as on most modules, in normal mode.
+    fn default() -> Self {
+        Config {
+            bit_timing: BitTiming::new(8_000_000, 500_000).unwrap(),
+            mode: Mode::Normal,
+        }
+    }
+}
+
+/// The fault confinement state, from the error counters.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ErrorState {
+    Active,
+    /// An error counter reached 96.
+    Warning,
+    /// An error counter reached 128, so the controller only sends passive error frames.
+    Passive,
+    /// The transmit error counter reached 256, so the controller is off the bus.
+    BusOff,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct ErrorCounters {
+    pub transmit: u8,
+    pub receive: u8,
+    pub state: ErrorState,
+}
+
+/// An MCP2515 on the SPI device `T`, with the chip select pin `CS` and its `INT` pin connected
+/// to the external interrupt `INT<N>`.
+///
+/// Received frames roll over from the first receive buffer into the second, so masks and
+/// filters of both buffers should accept the same frames.
+pub struct Mcp2515<T, CS, const N: u8> {
+    spi: T,
+    cs: CS,
+    int: ExtInt<N>,
+    mode: Mode,
+}
+
+impl<T, CS, const N: u8> Mcp2515<T, CS, N>
+where
+    T: SpiDevice,
+    CS: OutputPin,
+{
+    /// Creates a driver, switching the interrupt to trigger on a low level of the `INT` pin.
+    pub fn new(spi: T, cs: CS, mut int: ExtInt<N>) -> Self {
+        int.set_sense(Sense::Low);
+        Mcp2515 {
+            spi,
+            cs,
+            int,
+            mode: Mode::Normal,
+        }
+    }
+
+    /// Releases the SPI, the pin and the interrupt.
+    pub fn free(self) -> (T, CS, ExtInt<N>) {
+        (self.spi, self.cs, self.int)
+    }
+
+    /// Resets and configures the controller, accepting all frames until masks and filters are
+    /// set.
+    pub async fn init(&mut self, config: &Config) -> Result<(), CanError<T, CS>> {
+        self.cs.set_high().map_err(Error::Pin)?;
+        self.command(&[INSTRUCTION_RESET]).await?;
+        delay(RESET_DELAY).await;
+        if self.read_register(CANSTAT).await? & MODE_MASK != MODE_CONFIGURATION {
+            return Err(Error::NotFound);
+        }
+        let timing = config.bit_timing;
+        self.write_registers(CNF3, &[timing.cnf3, timing.cnf2, timing.cnf1])
+            .await?;
+        self.write_registers(RXB0CTRL, &[RXB0CTRL_BUKT]).await?;
+        self.write_registers(RXB1CTRL, &[0]).await?;
+        self.write_registers(CANINTE, &[0]).await?;
+        self.mode = config.mode;
+        self.enter_mode(self.mode.bits()).await
+    }
+
+    /// Switches to another operation mode.
+    pub async fn set_mode(&mut self, mode: Mode) -> Result<(), CanError<T, CS>> {
+        self.mode = mode;
+        self.enter_mode(mode.bits()).await
+    }
+
+    /// Sets the acceptance mask 0 of the first receive buffer, or 1 of the second. Only
+    /// identifier bits set in the mask are compared with the filters. A standard mask compares
+    /// the upper 11 bits of extended identifiers.
+    ///
+    /// Waits until the frame being transferred completes, and discards pending frames.
+    pub async fn set_mask(&mut self, mask: u8, id: Id) -> Result<(), CanError<T, CS>> {
+        if mask > 1 {
+            return Err(Error::InvalidFilter);
+        }
+        let mut registers = id.to_registers();
+        registers[1] &= !SIDL_EXIDE;
+        self.configure(RXM0SIDH + 4 * mask, &registers).await
+    }
+
+    /// Sets the acceptance filter 0 or 1 of the first receive buffer, or 2 to 5 of the second.
+    /// A frame is accepted if its identifier matches a filter in the bits of the mask, and its
+    /// type matches the filter.
+    ///
+    /// Waits until the frame being transferred completes, and discards pending frames.
+    pub async fn set_filter(&mut self, filter: u8, id: Id) -> Result<(), CanError<T, CS>> {
+        let address = match filter {
+            0..=2 => RXF0SIDH + 4 * filter,
+            3..=5 => RXF3SIDH + 4 * (filter - 3),
+            _ => return Err(Error::InvalidFilter),
+        };
+        self.configure(address, &id.to_registers()).await
+    }
+
+    /// Queues a frame in a free transmit buffer, waiting until one is free. The controller
+    /// retries sending until the frame is acknowledged.
+    ///
+    /// Frames are sent in the order they were queued. The controller sends the pending buffer
+    /// of the highest priority first, so each frame gets a lower priority than the frames
+    /// already waiting, and waits for them if the lowest priority is taken.
+    pub async fn send(&mut self, frame: &Frame) -> Result<(), CanError<T, CS>> {
+        loop {
+            // Clear the flags before checking the buffers, so a buffer becoming free in between
+            // triggers the interrupt.
+            self.modify_register(CANINTF, INT_TX0 | INT_TX1 | INT_TX2 | INT_ERR, 0)
+                .await?;
+            if self.read_register(EFLG).await? & EFLG_TXBO != 0 {
+                return Err(Error::BusOff);
+            }
+            let mut free = None;
+            let mut priority = TXBCTRL_TXP + 1;
+            for buffer in 0..3 {
+                let control = self.read_register(TXB0CTRL + 0x10 * buffer).await?;
+                if control & TXBCTRL_TXREQ == 0 {
+                    free = free.or(Some(buffer));
+                } else {
+                    priority = priority.min(control & TXBCTRL_TXP);
+                }
+            }
+            if let (Some(buffer), Some(priority)) = (free, priority.checked_sub(1)) {
+                self.write_registers(TXB0CTRL + 0x10 * buffer, &[priority])
+                    .await?;
+                return self.load(buffer, frame).await;
+            }
+            self.write_registers(CANINTE, &[INT_TX0 | INT_TX1 | INT_TX2 | INT_ERR])
+                .await?;
+            self.int.wait().await;
+        }
+    }
+
+    /// Receives a frame.
+    ///
+    /// Returns [`Error::Overflow`] once if frames were lost since the last call.
+    pub async fn recv(&mut self) -> Result<Frame, CanError<T, CS>> {
+        loop {
+            if self.read_register(EFLG).await? & (EFLG_RX0OVR | EFLG_RX1OVR) != 0 {
+                self.modify_register(EFLG, EFLG_RX0OVR | EFLG_RX1OVR, 0)
+                    .await?;
+                return Err(Error::Overflow);
+            }
+            let status = self.status().await?;
+            if status & STATUS_RX0IF != 0 {
+                return self.read_frame(0).await;
+            }
+            if status & STATUS_RX1IF != 0 {
+                return self.read_frame(1).await;
+            }
+            self.write_registers(CANINTE, &[INT_RX0 | INT_RX1]).await?;
+            self.int.wait().await;
+        }
+    }
+
+    /// Returns the error counters and the resulting state.
+    pub async fn error_counters(&mut self) -> Result<ErrorCounters, CanError<T, CS>> {
+        let mut counters = [0; 2];
+        self.read_registers(TEC, &mut counters).await?;
+        let flags = self.read_register(EFLG).await?;
+        let state = if flags & EFLG_TXBO != 0 {
+            ErrorState::BusOff
+        } else if flags & (EFLG_TXEP | EFLG_RXEP) != 0 {
+            ErrorState::Passive
+        } else if flags & EFLG_EWARN != 0 {
+            ErrorState::Warning
+        } else {
+            ErrorState::Active
+        };
+        Ok(ErrorCounters {
+            transmit: counters[0],
+            receive: counters[1],
+            state,
+        })
+    }
+
+    /// Writes masks or filters, which is only possible in configuration mode.
+    async fn configure(&mut self, address: u8, registers: &[u8]) -> Result<(), CanError<T, CS>> {
+        self.enter_mode(MODE_CONFIGURATION).await?;
+        self.write_registers(address, registers).await?;
+        self.enter_mode(self.mode.bits()).await
+    }
+
+    /// Requests the operation mode `bits`, and waits until the controller enters it.
+    async fn enter_mode(&mut self, bits: u8) -> Result<(), CanError<T, CS>> {
+        self.modify_register(CANCTRL, MODE_MASK, bits).await?;
+        for _ in 0..MODE_POLLS {
+            if self.read_register(CANSTAT).await? & MODE_MASK == bits {
+                return Ok(());
+            }
+            delay(MODE_POLL_INTERVAL).await;
+        }
+        Err(Error::NotFound)
+    }
+
+    async fn load(&mut self, buffer: u8, frame: &Frame) -> Result<(), CanError<T, CS>> {
+        let id = frame.id.to_registers();
+        let rtr = if frame.remote { DLC_RTR } else { 0 };
+        let mut data = [0; 14];
+        data[0] = INSTRUCTION_LOAD_TX_BUFFER | buffer << 1;
+        data[1..5].copy_from_slice(&id);
+        data[5] = rtr | frame.dlc;
+        let len = if frame.remote { 0 } else { frame.dlc as usize };
+        data[6..6 + len].copy_from_slice(&frame.data[..len]);
+        self.command(&data[..6 + len]).await?;
+        self.command(&[INSTRUCTION_RTS | 1 << buffer]).await
+    }
+
+    /// Reads a receive buffer, which also frees it.
+    async fn read_frame(&mut self, buffer: u8) -> Result<Frame, CanError<T, CS>> {
+        let mut registers = [0; 13];
+        let command = [INSTRUCTION_READ_RX_BUFFER | buffer << 2];
+        self.transaction(&command, &[], &mut registers).await?;
+        let id = Id::from_registers(&registers[..4]);
+        let remote = match id {
+            Id::Standard(_) => registers[1] & SIDL_SRR != 0,
+            Id::Extended(_) => registers[4] & DLC_RTR != 0,
+        };
+        let dlc = (registers[4] & 0x0f).min(8);
+        let mut data = [0; 8];
+        data.copy_from_slice(&registers[5..]);
+        Ok(Frame {
+            id,
+            remote,
+            dlc,
+            data,
+        })
+    }
+
+    async fn status(&mut self) -> Result<u8, CanError<T, CS>> {
+        let mut status = [0];
+        self.transaction(&[INSTRUCTION_READ_STATUS], &[], &mut status)
+            .await?;
+        Ok(status[0])
+    }
+
+    async fn read_register(&mut self, address: u8) -> Result<u8, CanError<T, CS>> {
+        let mut value = [0];
+        self.read_registers(address, &mut value).await?;
+        Ok(value[0])
+    }
+
+    async fn read_registers(&mut self, address: u8, buf: &mut [u8]) -> Result<(), CanError<T, CS>> {
+        self.transaction(&[INSTRUCTION_READ, address], &[], buf)
+            .await
+    }
+
+    async fn write_registers(&mut self, address: u8, data: &[u8]) -> Result<(), CanError<T, CS>> {
+        self.transaction(&[INSTRUCTION_WRITE, address], data, &mut [])
+            .await
+    }
+
+    /// Changes the bits `mask` of a register to those of `value`.
+    async fn modify_register(
+        &mut self,
+        address: u8,
+        mask: u8,
+        value: u8,
+    ) -> Result<(), CanError<T, CS>> {
+        self.command(&[INSTRUCTION_BIT_MODIFY, address, mask, value])
+            .await
+    }
+
+    async fn command(&mut self, data: &[u8]) -> Result<(), CanError<T, CS>> {
+        self.transaction(data, &[], &mut []).await
+    }
+
+    /// Sends `command` followed by `data`, then receives into `buf`, with the controller
+    /// selected and the bus locked throughout.
+    async fn transaction(
+        &mut self,
+        command: &[u8],
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> Result<(), CanError<T, CS>> {
+        let mut transaction = Transaction::begin(&mut self.spi, &mut self.cs)
+            .await
+            .map_err(Error::Pin)?;
+        transaction.send_all(command).await?;
+        transaction.send_all(data).await?;
+        for byte in buf {
+            *byte = transaction.transfer(0).await?;
+        }
+        transaction.end().map_err(Error::Pin)
+    }
+}
@@ -0,0 +1,408 @@
+//! Modbus RTU slave and master.
+//!
+//! Both ends run over any port implementing [`AsyncRead`] and [`AsyncWrite`] with a common error
+//! type, such as [`serial::Serial`](crate::serial::Serial) or
+//! [`serial::Rs485`](crate::serial::Rs485). Frames are delimited by 3.5 character times of
+//! silence, measured with a [`Delay`].
+//!
+//! ```ignore
+//! let mut slave = Slave::new(port, TimerDelay, 17, 19200);
+//! loop {
+//!     slave.handle_request(&mut registers).await?;
+//! }
+//! ```
+
+use crate::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteAllError};
+use crate::time::{with_timeout, Delay, Duration};
+
+pub use crate::crc::crc16;
+pub use master::Master;
+pub use slave::{RegisterMap, Slave};
+
+mod master;
+mod slave;
+
+/// Maximum size of an RTU frame: address, function code, 252 data bytes and the CRC.
+const MAX_FRAME: usize = 256;
+
+const READ_COILS: u8 = 0x01;
+const READ_DISCRETE_INPUTS: u8 = 0x02;
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_COILS: u8 = 0x0f;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+const EXCEPTION_FLAG: u8 = 0x80;
+const BROADCAST: u8 = 0;
+
+const MAX_READ_BITS: u16 = 2000;
+const MAX_READ_REGISTERS: u16 = 125;
+const MAX_WRITE_BITS: u16 = 1968;
+const MAX_WRITE_REGISTERS: u16 = 123;
+
+/// A Modbus exception code.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Exception {
+    IllegalFunction,
+    IllegalDataAddress,
+    IllegalDataValue,
+    ServerDeviceFailure,
+    Acknowledge,
+    ServerDeviceBusy,
+    /// An exception code not defined above.
+    Other(u8),
+}
+
+impl Exception {
+    fn code(self) -> u8 {
+        match self {
+            Exception::IllegalFunction => 0x01,
+            Exception::IllegalDataAddress => 0x02,
+            Exception::IllegalDataValue => 0x03,
+            Exception::ServerDeviceFailure => 0x04,
+            Exception::Acknowledge => 0x05,
+            Exception::ServerDeviceBusy => 0x06,
+            Exception::Other(code) => code,
+        }
+    }
+
+    fn from_code(code: u8) -> Self {
+        match code {
+            0x01 => Exception::IllegalFunction,
+            0x02 => Exception::IllegalDataAddress,
+            0x03 => Exception::IllegalDataValue,
+            0x04 => Exception::ServerDeviceFailure,
+            0x05 => Exception::Acknowledge,
+            0x06 => Exception::ServerDeviceBusy,
+            code => Exception::Other(code),
+        }
+    }
+}
+
+/// A Modbus error, wrapping the error type `E` of the port.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Error<E> {
+    Io(E),
+    /// The port returned end of file.
+    UnexpectedEof,
+    /// The port did not accept any more data.
+    WriteZero,
+    /// No response arrived in time, even after all retries.
+    Timeout,
+    /// A frame was too short, too long, or had an invalid CRC.
+    InvalidFrame,
+    /// The response did not match the request.
+    UnexpectedResponse,
+    /// The slave answered with an exception.
+    Exception(Exception),
+}
+
+impl<E> From<WriteAllError<E>> for Error<E> {
+    fn from(err: WriteAllError<E>) -> Self {
+        match err {
+            WriteAllError::WriteZero => Error::WriteZero,
+            WriteAllError::Other(err) => Error::Io(err),
+        }
+    }
+}
+
+/// Returns the 3.5 character times of silence that end a frame at `baudrate`, assuming 11 bits
+/// per character. Above 19200 baud, the fixed value of 1.75 ms recommended by the specification
+/// is used.
+pub fn frame_silence(baudrate: u32) -> Duration {
+    if baudrate > 19200 {
+        Duration::from_micros(1750)
+    } else {
+        Duration::from_micros(38_500_000 / baudrate)
+    }
+}
+
+/// Appends the CRC to the first `len` bytes of `frame` and returns the length of the frame.
+fn seal(frame: &mut [u8], len: usize) -> usize {
+    let crc = crc16(&frame[..len]);
+    frame[len] = crc as u8;
+    frame[len + 1] = (crc >> 8) as u8;
+    len + 2
+}
+
+/// Checks the CRC of `frame` and returns the length of the frame without it.
+fn unseal(frame: &[u8]) -> Option<usize> {
+    // Address, function code and CRC.
+    if frame.len() < 4 {
+        return None;
+    }
+    let len = frame.len() - 2;
+    let crc = frame[len] as u16 | (frame[len + 1] as u16) << 8;
+    if crc16(&frame[..len]) == crc {
+        Some(len)
+    } else {
+        None
+    }
+}
+
+/// Receives a frame into `buf`, returning its length.
+///
+/// Waits for the first byte for at most `timeout`, if given, and considers the frame complete
+/// once the line has been silent for `silence`.
+async fn read_frame<S, D>(
+    port: &mut S,
+    delay: &mut D,
+    silence: Duration,
+    timeout: Option<Duration>,
+    buf: &mut [u8],
+) -> Result<usize, Error<S::Error>>
+where
+    S: AsyncRead + Unpin,
+    D: Delay,
+{
+    let n = match timeout {
+        Some(timeout) => with_timeout(delay.delay(timeout), port.read(buf))
+            .await
+            .map_err(|_| Error::Timeout)?,
+        None => port.read(buf).await,
+    }
+    .map_err(Error::Io)?;
+    if n == 0 {
+        return Err(Error::UnexpectedEof);
+    }
+    let mut len = n;
+    let mut overflow = false;
+    loop {
+        let mut discard = [0; 8];
+        let full = len == buf.len();
+        let rest = if full {
+            &mut discard[..]
+        } else {
+            &mut buf[len..]
+        };
+        let n = match with_timeout(delay.delay(silence), port.read(rest)).await {
+            Ok(n) => n.map_err(Error::Io)?,
+            Err(_) => break,
+        };
+        if n == 0 {
+            return Err(Error::UnexpectedEof);
+        }
+        // A frame filling the buffer exactly still fits, so only data beyond it overflows.
+        if full {
+            overflow = true;
+        } else {
+            len += n;
+        }
+    }
+    if overflow {
+        Err(Error::InvalidFrame)
+    } else {
+        Ok(len)
+    }
+}
+
+/// Sends `frame` and waits until it has been transmitted.
+async fn write_frame<S>(port: &mut S, frame: &[u8]) -> Result<(), Error<S::Error>>
+where
+    S: AsyncWrite + Unpin,
+{
+    port.write_all(frame).await?;
+    port.flush().await.map_err(Error::Io)
+}
+
+fn get_u16(buf: &[u8], at: usize) -> u16 {
+    (buf[at] as u16) << 8 | buf[at + 1] as u16
+}
+
+fn put_u16(buf: &mut [u8], at: usize, value: u16) {
+    buf[at] = (value >> 8) as u8;
+    buf[at + 1] = value as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{block_on, line, now, End, ManualDelay};
+    use core::convert::Infallible;
+    use core::future::Future;
+    use futures_util::future::{select, Either};
+    use pin_utils::pin_mut;
+    use std::vec;
+    use std::vec::Vec;
+
+    const BAUDRATE: u32 = 19200;
+    const SLAVE: u8 = 17;
+
+    /// Coils and holding registers, without discrete inputs or input registers.
+    struct Map {
+        coils: Vec<bool>,
+        registers: Vec<u16>,
+    }
+
+    impl Map {
+        fn new() -> Self {
+            Map {
+                coils: vec![false; 2000],
+                registers: vec![0; 200],
+            }
+        }
+    }
+
+    impl RegisterMap for Map {
+        fn read_coil(&mut self, address: u16) -> Result<bool, Exception> {
+            let coil = self.coils.get(address as usize);
+            coil.copied().ok_or(Exception::IllegalDataAddress)
+        }
+
+        fn read_holding_register(&mut self, address: u16) -> Result<u16, Exception> {
+            let register = self.registers.get(address as usize);
+            register.copied().ok_or(Exception::IllegalDataAddress)
+        }
+
+        fn write_coil(&mut self, address: u16, value: bool) -> Result<(), Exception> {
+            let coil = self.coils.get_mut(address as usize);
+            *coil.ok_or(Exception::IllegalDataAddress)? = value;
+            Ok(())
+        }
+
+        fn write_register(&mut self, address: u16, value: u16) -> Result<(), Exception> {
+            let register = self.registers.get_mut(address as usize);
+            *register.ok_or(Exception::IllegalDataAddress)? = value;
+            Ok(())
+        }
+    }
+
+    fn pair() -> (Master<End, ManualDelay>, Slave<End, ManualDelay>) {
+        let (master, slave) = line();
+        (
+            Master::new(master, ManualDelay, BAUDRATE),
+            Slave::new(slave, ManualDelay, SLAVE, BAUDRATE),
+        )
+    }
+
+    /// Runs `client` while `slave` serves `map`.
+    fn run<F: Future>(slave: &mut Slave<End, ManualDelay>, map: &mut Map, client: F) -> F::Output {
+        block_on(async {
+            let serve = async {
+                loop {
+                    slave.handle_request(map).await.unwrap();
+                }
+            };
+            pin_mut!(client, serve);
+            match select(client, serve).await {
+                Either::Left((output, _)) => output,
+                Either::Right(_) => unreachable!(),
+            }
+        })
+    }
+
+    #[test]
+    fn read_write_round_trip() {
+        let (mut master, mut slave) = pair();
+        let mut map = Map::new();
+        let (registers, coils) = run(&mut slave, &mut map, async {
+            master.write_single_register(SLAVE, 5, 0x1234).await?;
+            master.write_single_coil(SLAVE, 3, true).await?;
+            master
+                .write_multiple_coils(SLAVE, 10, &[true, false, true])
+                .await?;
+            master
+                .write_multiple_registers(SLAVE, 6, &[0xbeef, 7])
+                .await?;
+            let mut registers = [0; 4];
+            master
+                .read_holding_registers(SLAVE, 4, &mut registers)
+                .await?;
+            let mut coils = [false; 10];
+            master.read_coils(SLAVE, 3, &mut coils).await?;
+            Ok::<_, Error<Infallible>>((registers, coils))
+        })
+        .unwrap();
+        assert_eq!(registers, [0, 0x1234, 0xbeef, 7]);
+        let expected = [
+            true, false, false, false, false, false, false, true, false, true,
+        ];
+        assert_eq!(coils, expected);
+        assert_eq!(map.registers[5], 0x1234);
+    }
+
+    #[test]
+    fn exception_response() {
+        let (mut master, mut slave) = pair();
+        let mut map = Map::new();
+        let (missing, out_of_range) = run(&mut slave, &mut map, async {
+            let mut values = [0; 2];
+            let missing = master.read_input_registers(SLAVE, 0, &mut values).await;
+            let out_of_range = master.write_single_register(SLAVE, 200, 1).await;
+            (missing, out_of_range)
+        });
+        let exception = Err(Error::Exception(Exception::IllegalDataAddress));
+        assert_eq!(missing, exception);
+        assert_eq!(out_of_range, exception);
+    }
+
+    #[test]
+    fn timeout_is_retried() {
+        let (master, mut slave) = pair();
+        let mut map = Map::new();
+        // Lose the first request of 8 bytes.
+        let (mut port, delay) = master.free();
+        let mut lost = 0;
+        port.set_fault(move |byte| {
+            lost += 1;
+            if lost > 8 {
+                Some(byte)
+            } else {
+                None
+            }
+        });
+        let mut master = Master::new(port, delay, BAUDRATE);
+        let result = run(&mut slave, &mut map, async {
+            master.write_single_register(SLAVE, 1, 42).await
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(map.registers[1], 42);
+        assert!(now() >= Duration::from_secs(1).ticks());
+    }
+
+    #[test]
+    fn timeout_without_slave() {
+        let (mut master, _slave) = pair();
+        master.set_retries(1);
+        let result = block_on(master.write_single_register(SLAVE, 1, 42));
+        assert_eq!(result, Err(Error::Timeout));
+        assert!(now() >= Duration::from_secs(2).ticks());
+    }
+
+    #[test]
+    fn long_write_is_retried() {
+        let (master, mut slave) = pair();
+        let mut map = Map::new();
+        let values: Vec<u16> = (0..MAX_WRITE_REGISTERS).map(|i| i * 3 + 1).collect();
+        // Lose the first request, the longest there is with 255 bytes, so it must be sent again
+        // after the master waited for a response.
+        let (mut port, delay) = master.free();
+        let mut lost = 0;
+        port.set_fault(move |byte| {
+            lost += 1;
+            if lost > MAX_FRAME - 1 {
+                Some(byte)
+            } else {
+                None
+            }
+        });
+        let mut master = Master::new(port, delay, BAUDRATE);
+        let coils: Vec<bool> = (0..MAX_WRITE_BITS).map(|i| i % 3 == 0).collect();
+        let (registers, read_coils) = run(&mut slave, &mut map, async {
+            master.write_multiple_registers(SLAVE, 40, &values).await?;
+            master.write_multiple_coils(SLAVE, 0, &coils).await?;
+            let mut registers = vec![0; values.len()];
+            master
+                .read_holding_registers(SLAVE, 40, &mut registers)
+                .await?;
+            let mut read_coils = vec![false; coils.len()];
+            master.read_coils(SLAVE, 0, &mut read_coils).await?;
+            Ok::<_, Error<Infallible>>((registers, read_coils))
+        })
+        .unwrap();
+        assert_eq!(registers, values);
+        assert_eq!(read_coils, coils);
+    }
+}
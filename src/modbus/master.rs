@@ -0,0 +1,315 @@
+use super::*;
+
+/// A Modbus RTU master.
+///
+/// Every request waits for the response for at most the response timeout and is retried on
+/// timeouts and corrupted responses. Exception responses are returned as
+/// [`Error::Exception`] without retrying. Requests to the broadcast address 0 are not
+/// answered by slaves, so they complete as soon as they have been sent.
+pub struct Master<S, D> {
+    port: S,
+    delay: D,
+    silence: Duration,
+    timeout: Duration,
+    retries: u8,
+    buf: [u8; MAX_FRAME],
+}
+
+impl<S, D> Master<S, D>
+where
+    S: AsyncRead + AsyncWrite<Error = <S as AsyncRead>::Error> + Unpin,
+    D: Delay,
+{
+    /// Creates a master communicating over `port` at `baudrate`, with a response timeout of one
+    /// second and two retries.
+    pub fn new(port: S, delay: D, baudrate: u32) -> Self {
+        Master {
+            port,
+            delay,
+            silence: frame_silence(baudrate),
+            timeout: Duration::from_secs(1),
+            retries: 2,
+            buf: [0; MAX_FRAME],
+        }
+    }
+
+    /// Sets how long to wait for the start of a response.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sets how often a request is repeated after the first attempt failed.
+    pub fn set_retries(&mut self, retries: u8) {
+        self.retries = retries;
+    }
+
+    /// Releases the port and delay.
+    pub fn free(self) -> (S, D) {
+        (self.port, self.delay)
+    }
+
+    /// Reads `values.len()` coils starting at `address`.
+    pub async fn read_coils(
+        &mut self,
+        slave: u8,
+        address: u16,
+        values: &mut [bool],
+    ) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        self.read_bits(slave, READ_COILS, address, values).await
+    }
+
+    /// Reads `values.len()` discrete inputs starting at `address`.
+    pub async fn read_discrete_inputs(
+        &mut self,
+        slave: u8,
+        address: u16,
+        values: &mut [bool],
+    ) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        self.read_bits(slave, READ_DISCRETE_INPUTS, address, values)
+            .await
+    }
+
+    /// Reads `values.len()` holding registers starting at `address`.
+    pub async fn read_holding_registers(
+        &mut self,
+        slave: u8,
+        address: u16,
+        values: &mut [u16],
+    ) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        self.read_registers(slave, READ_HOLDING_REGISTERS, address, values)
+            .await
+    }
+
+    /// Reads `values.len()` input registers starting at `address`.
+    pub async fn read_input_registers(
+        &mut self,
+        slave: u8,
+        address: u16,
+        values: &mut [u16],
+    ) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        self.read_registers(slave, READ_INPUT_REGISTERS, address, values)
+            .await
+    }
+
+    pub async fn write_single_coil(
+        &mut self,
+        slave: u8,
+        address: u16,
+        value: bool,
+    ) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        let value = if value { 0xff00 } else { 0x0000 };
+        self.write_single(slave, WRITE_SINGLE_COIL, address, value)
+            .await
+    }
+
+    pub async fn write_single_register(
+        &mut self,
+        slave: u8,
+        address: u16,
+        value: u16,
+    ) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        self.write_single(slave, WRITE_SINGLE_REGISTER, address, value)
+            .await
+    }
+
+    /// Writes `values` to the coils starting at `address`.
+    pub async fn write_multiple_coils(
+        &mut self,
+        slave: u8,
+        address: u16,
+        values: &[bool],
+    ) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        if values.is_empty() || values.len() > MAX_WRITE_BITS as usize {
+            return Err(Error::Exception(Exception::IllegalDataValue));
+        }
+        let bytes = (values.len() + 7) / 8;
+        self.start_request(slave, WRITE_MULTIPLE_COILS, address, values.len() as u16);
+        self.buf[6] = bytes as u8;
+        for byte in &mut self.buf[7..7 + bytes] {
+            *byte = 0;
+        }
+        for (i, &value) in values.iter().enumerate() {
+            if value {
+                self.buf[7 + i / 8] |= 1 << (i % 8);
+            }
+        }
+        self.write_multiple(slave, 7 + bytes, address, values.len() as u16)
+            .await
+    }
+
+    /// Writes `values` to the holding registers starting at `address`.
+    pub async fn write_multiple_registers(
+        &mut self,
+        slave: u8,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        if values.is_empty() || values.len() > MAX_WRITE_REGISTERS as usize {
+            return Err(Error::Exception(Exception::IllegalDataValue));
+        }
+        self.start_request(slave, WRITE_MULTIPLE_REGISTERS, address, values.len() as u16);
+        self.buf[6] = (2 * values.len()) as u8;
+        for (i, &value) in values.iter().enumerate() {
+            put_u16(&mut self.buf, 7 + 2 * i, value);
+        }
+        self.write_multiple(slave, 7 + 2 * values.len(), address, values.len() as u16)
+            .await
+    }
+
+    /// Writes the address, function code and the two 16 bit fields common to all supported
+    /// requests.
+    fn start_request(&mut self, slave: u8, function: u8, address: u16, value: u16) {
+        self.buf[0] = slave;
+        self.buf[1] = function;
+        put_u16(&mut self.buf, 2, address);
+        put_u16(&mut self.buf, 4, value);
+    }
+
+    async fn read_bits(
+        &mut self,
+        slave: u8,
+        function: u8,
+        address: u16,
+        values: &mut [bool],
+    ) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        if values.is_empty() || values.len() > MAX_READ_BITS as usize {
+            return Err(Error::Exception(Exception::IllegalDataValue));
+        }
+        self.start_request(slave, function, address, values.len() as u16);
+        let bytes = (values.len() + 7) / 8;
+        let len = self.transact(6).await?;
+        if len != 3 + bytes || self.buf[2] as usize != bytes {
+            return Err(Error::UnexpectedResponse);
+        }
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = self.buf[3 + i / 8] & 1 << (i % 8) != 0;
+        }
+        Ok(())
+    }
+
+    async fn read_registers(
+        &mut self,
+        slave: u8,
+        function: u8,
+        address: u16,
+        values: &mut [u16],
+    ) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        if values.is_empty() || values.len() > MAX_READ_REGISTERS as usize {
+            return Err(Error::Exception(Exception::IllegalDataValue));
+        }
+        self.start_request(slave, function, address, values.len() as u16);
+        let len = self.transact(6).await?;
+        let bytes = 2 * values.len();
+        if len != 3 + bytes || self.buf[2] as usize != bytes {
+            return Err(Error::UnexpectedResponse);
+        }
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = get_u16(&self.buf, 3 + 2 * i);
+        }
+        Ok(())
+    }
+
+    async fn write_single(
+        &mut self,
+        slave: u8,
+        function: u8,
+        address: u16,
+        value: u16,
+    ) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        self.start_request(slave, function, address, value);
+        let len = self.transact(6).await?;
+        if slave != BROADCAST
+            && (len != 6 || get_u16(&self.buf, 2) != address || get_u16(&self.buf, 4) != value)
+        {
+            return Err(Error::UnexpectedResponse);
+        }
+        Ok(())
+    }
+
+    async fn write_multiple(
+        &mut self,
+        slave: u8,
+        len: usize,
+        address: u16,
+        quantity: u16,
+    ) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        let len = self.transact(len).await?;
+        if slave != BROADCAST
+            && (len != 6
+                || get_u16(&self.buf, 2) != address
+                || get_u16(&self.buf, 4) != quantity)
+        {
+            return Err(Error::UnexpectedResponse);
+        }
+        Ok(())
+    }
+
+    /// Sends the request in the first `len` bytes of the buffer and receives the response into
+    /// the buffer, returning its length without CRC.
+    ///
+    /// For broadcast requests, returns 0 once the request has been sent.
+    async fn transact(&mut self, len: usize) -> Result<usize, Error<<S as AsyncRead>::Error>> {
+        let slave = self.buf[0];
+        let function = self.buf[1];
+        let len = seal(&mut self.buf, len);
+        // The request must survive receiving a response for retries. All supported requests are
+        // either at most 8 bytes long, or are writes answered with at most 8 bytes, so either keep
+        // a copy of a short request, or receive the response to a long one separately.
+        let mut spare = [0; 8];
+        let short = len <= spare.len();
+        if short {
+            spare[..len].copy_from_slice(&self.buf[..len]);
+        }
+
+        let mut attempts = self.retries as u16 + 1;
+        loop {
+            attempts -= 1;
+            if short {
+                self.buf[..len].copy_from_slice(&spare[..len]);
+            }
+            // Make sure the previous frame has ended before starting a new one.
+            self.delay.delay(self.silence).await;
+            write_frame(&mut self.port, &self.buf[..len]).await?;
+            if slave == BROADCAST {
+                return Ok(0);
+            }
+            let buf = if short {
+                &mut self.buf[..]
+            } else {
+                &mut spare[..]
+            };
+            let result = read_frame(
+                &mut self.port,
+                &mut self.delay,
+                self.silence,
+                Some(self.timeout),
+                buf,
+            )
+            .await
+            .and_then(|n| unseal(&buf[..n]).ok_or(Error::InvalidFrame));
+            let n = match result {
+                Ok(n) => n,
+                Err(Error::Timeout) | Err(Error::InvalidFrame) if attempts > 0 => continue,
+                Err(err) => return Err(err),
+            };
+            let response = &buf[..n];
+            if response[0] != slave {
+                // A late response to an earlier request, or a frame from another master.
+                if attempts > 0 {
+                    continue;
+                }
+                return Err(Error::UnexpectedResponse);
+            }
+            if response[1] == function | EXCEPTION_FLAG && n == 3 {
+                return Err(Error::Exception(Exception::from_code(response[2])));
+            }
+            if response[1] != function {
+                return Err(Error::UnexpectedResponse);
+            }
+            if !short {
+                self.buf[..n].copy_from_slice(&spare[..n]);
+            }
+            return Ok(n);
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use super::*;
+
+/// The coils and registers served by a [`Slave`].
+///
+/// Every method defaults to answering with [`Exception::IllegalDataAddress`], so only the
+/// tables a device actually has need to be implemented.
+pub trait RegisterMap {
+    fn read_coil(&mut self, _address: u16) -> Result<bool, Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+
+    fn read_discrete_input(&mut self, _address: u16) -> Result<bool, Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+
+    fn read_holding_register(&mut self, _address: u16) -> Result<u16, Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+
+    fn read_input_register(&mut self, _address: u16) -> Result<u16, Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+
+    fn write_coil(&mut self, _address: u16, _value: bool) -> Result<(), Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+
+    fn write_register(&mut self, _address: u16, _value: u16) -> Result<(), Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+}
+
+/// A Modbus RTU slave, answering requests for a [`RegisterMap`].
+///
+/// Supports function codes 1 to 6, 15 and 16. Requests for other function codes are answered
+/// with [`Exception::IllegalFunction`]. Writes to multiple coils or registers are applied one at
+/// a time, so a write failing halfway through leaves the earlier values written.
+pub struct Slave<S, D> {
+    port: S,
+    delay: D,
+    address: u8,
+    silence: Duration,
+    buf: [u8; MAX_FRAME],
+}
+
+impl<S, D> Slave<S, D>
+where
+    S: AsyncRead + AsyncWrite<Error = <S as AsyncRead>::Error> + Unpin,
+    D: Delay,
+{
+    /// Creates a slave with the given `address`, communicating over `port` at `baudrate`.
+    pub fn new(port: S, delay: D, address: u8, baudrate: u32) -> Self {
+        Slave {
+            port,
+            delay,
+            address,
+            silence: frame_silence(baudrate),
+            buf: [0; MAX_FRAME],
+        }
+    }
+
+    /// Waits for the next request addressed to this slave, answers it, and returns the function
+    /// code of the request.
+    ///
+    /// Frames with an invalid CRC and frames for other slaves are ignored. Broadcast requests are
+    /// processed, but not answered.
+    pub async fn handle_request<M: RegisterMap>(
+        &mut self,
+        map: &mut M,
+    ) -> Result<u8, Error<<S as AsyncRead>::Error>> {
+        loop {
+            let len = match read_frame(
+                &mut self.port,
+                &mut self.delay,
+                self.silence,
+                None,
+                &mut self.buf,
+            )
+            .await
+            {
+                Ok(len) => len,
+                Err(Error::InvalidFrame) => continue,
+                Err(err) => return Err(err),
+            };
+            let len = match unseal(&self.buf[..len]) {
+                Some(len) => len,
+                None => continue,
+            };
+            let address = self.buf[0];
+            if address != self.address && address != BROADCAST {
+                continue;
+            }
+            let function = self.buf[1];
+            let response_len = match process(map, &mut self.buf, len) {
+                Ok(len) => len,
+                Err(exception) => {
+                    self.buf[1] = function | EXCEPTION_FLAG;
+                    self.buf[2] = exception.code();
+                    3
+                }
+            };
+            if address != BROADCAST {
+                let len = seal(&mut self.buf, response_len);
+                write_frame(&mut self.port, &self.buf[..len]).await?;
+            }
+            return Ok(function);
+        }
+    }
+
+    /// Releases the port and delay.
+    pub fn free(self) -> (S, D) {
+        (self.port, self.delay)
+    }
+}
+
+/// Checks that `quantity` items starting at `address` are allowed and fit into the address space.
+fn check_range(address: u16, quantity: u16, max: u16) -> Result<(), Exception> {
+    if quantity == 0 || quantity > max {
+        Err(Exception::IllegalDataValue)
+    } else if address as u32 + quantity as u32 > 0x1_0000 {
+        Err(Exception::IllegalDataAddress)
+    } else {
+        Ok(())
+    }
+}
+
+/// Processes the request in the first `len` bytes of `frame` and replaces it with the response,
+/// returning the length of the response without CRC.
+fn process<M: RegisterMap>(
+    map: &mut M,
+    frame: &mut [u8; MAX_FRAME],
+    len: usize,
+) -> Result<usize, Exception> {
+    let function = frame[1];
+    match function {
+        READ_COILS | READ_DISCRETE_INPUTS => {
+            if len != 6 {
+                return Err(Exception::IllegalDataValue);
+            }
+            let address = get_u16(frame, 2);
+            let quantity = get_u16(frame, 4);
+            check_range(address, quantity, MAX_READ_BITS)?;
+            let bytes = (quantity as usize + 7) / 8;
+            for byte in &mut frame[3..3 + bytes] {
+                *byte = 0;
+            }
+            for i in 0..quantity {
+                let address = address + i;
+                let value = if function == READ_COILS {
+                    map.read_coil(address)?
+                } else {
+                    map.read_discrete_input(address)?
+                };
+                if value {
+                    frame[3 + i as usize / 8] |= 1 << (i % 8);
+                }
+            }
+            frame[2] = bytes as u8;
+            Ok(3 + bytes)
+        }
+        READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+            if len != 6 {
+                return Err(Exception::IllegalDataValue);
+            }
+            let address = get_u16(frame, 2);
+            let quantity = get_u16(frame, 4);
+            check_range(address, quantity, MAX_READ_REGISTERS)?;
+            for i in 0..quantity {
+                let address = address + i;
+                let value = if function == READ_HOLDING_REGISTERS {
+                    map.read_holding_register(address)?
+                } else {
+                    map.read_input_register(address)?
+                };
+                put_u16(frame, 3 + 2 * i as usize, value);
+            }
+            frame[2] = (2 * quantity) as u8;
+            Ok(3 + 2 * quantity as usize)
+        }
+        WRITE_SINGLE_COIL => {
+            if len != 6 {
+                return Err(Exception::IllegalDataValue);
+            }
+            let value = match get_u16(frame, 4) {
+                0xff00 => true,
+                0x0000 => false,
+                _ => return Err(Exception::IllegalDataValue),
+            };
+            map.write_coil(get_u16(frame, 2), value)?;
+            // The response echoes the request.
+            Ok(6)
+        }
+        WRITE_SINGLE_REGISTER => {
+            if len != 6 {
+                return Err(Exception::IllegalDataValue);
+            }
+            map.write_register(get_u16(frame, 2), get_u16(frame, 4))?;
+            Ok(6)
+        }
+        WRITE_MULTIPLE_COILS => {
+            if len < 7 {
+                return Err(Exception::IllegalDataValue);
+            }
+            let address = get_u16(frame, 2);
+            let quantity = get_u16(frame, 4);
+            check_range(address, quantity, MAX_WRITE_BITS)?;
+            let bytes = frame[6] as usize;
+            if bytes != (quantity as usize + 7) / 8 || len != 7 + bytes {
+                return Err(Exception::IllegalDataValue);
+            }
+            for i in 0..quantity {
+                let value = frame[7 + i as usize / 8] & 1 << (i % 8) != 0;
+                map.write_coil(address + i, value)?;
+            }
+            Ok(6)
+        }
+        WRITE_MULTIPLE_REGISTERS => {
+            if len < 7 {
+                return Err(Exception::IllegalDataValue);
+            }
+            let address = get_u16(frame, 2);
+            let quantity = get_u16(frame, 4);
+            check_range(address, quantity, MAX_WRITE_REGISTERS)?;
+            let bytes = frame[6] as usize;
+            if bytes != 2 * quantity as usize || len != 7 + bytes {
+                return Err(Exception::IllegalDataValue);
+            }
+            for i in 0..quantity {
+                let value = get_u16(frame, 7 + 2 * i as usize);
+                map.write_register(address + i, value)?;
+            }
+            Ok(6)
+        }
+        _ => Err(Exception::IllegalFunction),
+    }
+}
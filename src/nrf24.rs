@@ -0,0 +1,474 @@
+//! Nordic nRF24L01+ 2.4 GHz radios.
+//!
+//! ```ignore
+//! let mut radio = Nrf24::new(AsyncSpi::new(spi), csn, ce, ExtInt::<0>::new(Sense::Low));
+//! radio.init(&Config::default()).await?;
+//! radio.set_tx_address(b"node1").await?;
+//! radio.set_rx_address(1, b"node2").await?;
+//! radio.send(b"hello").await?;
+//! let (pipe, len) = radio.recv(&mut buf).await?;
+//! ```
+//!
+//! The driver is generic over [`SpiDevice`], so it takes an [`AsyncSpi`](crate::AsyncSpi) for a
+//! bus of its own, or a [`SharedSpi`](crate::SharedSpi) to share the bus with other drivers.
+//! The bus is locked for each register transaction. Waiting for a transmission or a packet is
+//! asynchronous, on the IRQ pin of the radio, which must be connected to `INT0` or `INT1`.
+
+use crate::exti::{ExtInt, Sense};
+use crate::spi::{SpiDevice, Transaction};
+use crate::time::{delay, Duration};
+use avr_hal_generic::hal::digital::v2::OutputPin;
+
+const CONFIG: u8 = 0x00;
+const EN_AA: u8 = 0x01;
+const EN_RXADDR: u8 = 0x02;
+const SETUP_AW: u8 = 0x03;
+const SETUP_RETR: u8 = 0x04;
+const RF_CH: u8 = 0x05;
+const RF_SETUP: u8 = 0x06;
+const STATUS: u8 = 0x07;
+const RX_ADDR_P0: u8 = 0x0a;
+const TX_ADDR: u8 = 0x10;
+const RX_PW_P0: u8 = 0x11;
+const FIFO_STATUS: u8 = 0x17;
+const DYNPD: u8 = 0x1c;
+const FEATURE: u8 = 0x1d;
+
+const R_REGISTER: u8 = 0x00;
+const W_REGISTER: u8 = 0x20;
+const R_RX_PL_WID: u8 = 0x60;
+const R_RX_PAYLOAD: u8 = 0x61;
+const W_TX_PAYLOAD: u8 = 0xa0;
+const FLUSH_TX: u8 = 0xe1;
+const FLUSH_RX: u8 = 0xe2;
+const NOP: u8 = 0xff;
+
+const CONFIG_MASK_RX_DR: u8 = 1 << 6;
+const CONFIG_MASK_TX_DS: u8 = 1 << 5;
+const CONFIG_MASK_MAX_RT: u8 = 1 << 4;
+const CONFIG_EN_CRC: u8 = 1 << 3;
+const CONFIG_CRCO: u8 = 1 << 2;
+const CONFIG_PWR_UP: u8 = 1 << 1;
+const CONFIG_PRIM_RX: u8 = 1 << 0;
+
+const STATUS_RX_DR: u8 = 1 << 6;
+const STATUS_TX_DS: u8 = 1 << 5;
+const STATUS_MAX_RT: u8 = 1 << 4;
+
+const FIFO_RX_EMPTY: u8 = 1 << 0;
+const FEATURE_EN_DPL: u8 = 1 << 2;
+
+/// The largest payload of a packet.
+pub const MAX_PAYLOAD: usize = 32;
+
+/// How long the oscillator takes to start when powering up.
+const POWER_UP_DELAY: Duration = Duration::from_millis(2);
+
+/// An error of an [`Nrf24`] radio, wrapping the error types `S` of the SPI and `P` of the pins.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Error<S, P> {
+    Spi(S),
+    Pin(P),
+    /// The radio did not answer, or kept a different configuration than the one written.
+    NotFound,
+    /// The packet was not acknowledged after the configured number of retries.
+    MaxRetries,
+    /// The payload is empty, longer than [`MAX_PAYLOAD`] bytes or longer than the static payload
+    /// size.
+    PayloadSize,
+    /// The received payload did not fit into the buffer. The rest was discarded.
+    BufferTooSmall,
+    /// The address does not match the configured address width, or the pipe does not exist.
+    InvalidAddress,
+    /// The configuration enables dynamic payloads without auto acknowledgement, which the
+    /// radio requires for them.
+    InvalidConfig,
+}
+
+impl<S, P> From<S> for Error<S, P> {
+    fn from(err: S) -> Self {
+        Error::Spi(err)
+    }
+}
+
+type RadioError<T, CSN> = Error<<T as SpiDevice>::Error, <CSN as OutputPin>::Error>;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum DataRate {
+    Kbps250,
+    Mbps1,
+    Mbps2,
+}
+
+/// Transmit power.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Power {
+    /// -18 dBm.
+    Min,
+    /// -12 dBm.
+    Low,
+    /// -6 dBm.
+    High,
+    /// 0 dBm.
+    Max,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Crc {
+    Disabled,
+    OneByte,
+    TwoBytes,
+}
+
+/// Radio parameters, which must match on both ends of a link.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Config {
+    /// The frequency is 2400 MHz plus the channel, up to 125.
+    pub channel: u8,
+    pub data_rate: DataRate,
+    pub power: Power,
+    pub crc: Crc,
+    /// Length of the addresses, from 3 to 5 bytes.
+    pub address_width: u8,
+    /// Whether received packets are acknowledged, and sent packets are repeated until they are.
+    /// Requires CRC.
+    pub auto_ack: bool,
+    /// How often an unacknowledged packet is repeated, up to 15.
+    pub retries: u8,
+    /// The time to wait for an acknowledgement, in steps of 250 µs starting at 250 µs, up to 15.
+    pub retry_delay: u8,
+    /// Whether the length is transferred with each packet, which requires `auto_ack`.
+    /// Otherwise, all packets have `payload_size` bytes.
+    pub dynamic_payloads: bool,
+    pub payload_size: u8,
+}
+
+impl Default for Config {
+    /// Channel 76 at 1 Mbps and full power, 2 byte CRC, 5 byte addresses, auto
+    /// acknowledgement with 15 retries every 1.5 ms, and dynamic payloads.
+    fn default() -> Self {
+        Config {
+            channel: 76,
+            data_rate: DataRate::Mbps1,
+            power: Power::Max,
+            crc: Crc::TwoBytes,
+            address_width: 5,
+            auto_ack: true,
+            retries: 15,
+            retry_delay: 5,
+            dynamic_payloads: true,
+            payload_size: MAX_PAYLOAD as u8,
+        }
+    }
+}
+
+/// An nRF24L01+ radio on the SPI device `T`, with the chip select pin `CSN`, the chip enable
+/// pin `CE` and its IRQ pin connected to the external interrupt `INT<N>`.
+///
+/// Pipe 0 receives the acknowledgements of sent packets, so packets are received on pipes 1
+/// to 5.
+pub struct Nrf24<T, CSN, CE, const N: u8> {
+    spi: T,
+    csn: CSN,
+    ce: CE,
+    irq: ExtInt<N>,
+    /// The value of the `CONFIG` register, except for the mode bits.
+    config: u8,
+    address_width: u8,
+    /// The payload size, or 0 for dynamic payloads.
+    payload_size: u8,
+    listening: bool,
+}
+
+impl<T, CSN, CE, const N: u8> Nrf24<T, CSN, CE, N>
+where
+    T: SpiDevice,
+    CSN: OutputPin,
+    CE: OutputPin<Error = CSN::Error>,
+{
+    /// Creates a driver, switching the interrupt to trigger on a low level of the IRQ pin.
+    pub fn new(spi: T, csn: CSN, ce: CE, mut irq: ExtInt<N>) -> Self {
+        irq.set_sense(Sense::Low);
+        Nrf24 {
+            spi,
+            csn,
+            ce,
+            irq,
+            config: 0,
+            address_width: 5,
+            payload_size: 0,
+            listening: false,
+        }
+    }
+
+    /// Releases the SPI, the pins and the interrupt.
+    pub fn free(self) -> (T, CSN, CE, ExtInt<N>) {
+        (self.spi, self.csn, self.ce, self.irq)
+    }
+
+    /// Configures the radio and powers it up in standby mode.
+    ///
+    /// The radio needs 100 ms after power on before it can be configured.
+    pub async fn init(&mut self, config: &Config) -> Result<(), RadioError<T, CSN>> {
+        if config.dynamic_payloads && !config.auto_ack {
+            return Err(Error::InvalidConfig);
+        }
+        self.ce.set_low().map_err(Error::Pin)?;
+        self.csn.set_high().map_err(Error::Pin)?;
+        self.listening = false;
+
+        self.config = match config.crc {
+            Crc::Disabled => 0,
+            Crc::OneByte => CONFIG_EN_CRC,
+            Crc::TwoBytes => CONFIG_EN_CRC | CONFIG_CRCO,
+        };
+        self.write_register(CONFIG, self.config).await?;
+        let width = config.address_width.clamp(3, 5);
+        self.write_register(SETUP_AW, width - 2).await?;
+        if self.read_register(SETUP_AW).await? != width - 2 {
+            return Err(Error::NotFound);
+        }
+        self.address_width = width;
+
+        let retry = (config.retry_delay.min(15)) << 4 | config.retries.min(15);
+        self.write_register(SETUP_RETR, retry).await?;
+        self.write_register(RF_CH, config.channel.min(125)).await?;
+        let data_rate = match config.data_rate {
+            DataRate::Kbps250 => 1 << 5,
+            DataRate::Mbps1 => 0,
+            DataRate::Mbps2 => 1 << 3,
+        };
+        let power = match config.power {
+            Power::Min => 0b00,
+            Power::Low => 0b01,
+            Power::High => 0b10,
+            Power::Max => 0b11,
+        };
+        self.write_register(RF_SETUP, data_rate | power << 1)
+            .await?;
+        self.write_register(EN_AA, if config.auto_ack { 0x3f } else { 0 })
+            .await?;
+        // Pipe 0 for acknowledgements; the others are enabled with their address.
+        self.write_register(EN_RXADDR, 1 << 0).await?;
+
+        if config.dynamic_payloads {
+            self.payload_size = 0;
+            self.write_register(FEATURE, FEATURE_EN_DPL).await?;
+            self.write_register(DYNPD, 0x3f).await?;
+        } else {
+            self.payload_size = config.payload_size.clamp(1, MAX_PAYLOAD as u8);
+            self.write_register(FEATURE, 0).await?;
+            self.write_register(DYNPD, 0).await?;
+            for pipe in 0..6 {
+                self.write_register(RX_PW_P0 + pipe, self.payload_size)
+                    .await?;
+            }
+        }
+
+        self.command(FLUSH_TX, &mut []).await?;
+        self.command(FLUSH_RX, &mut []).await?;
+        self.clear_status(STATUS_RX_DR | STATUS_TX_DS | STATUS_MAX_RT)
+            .await?;
+        self.power_up().await
+    }
+
+    /// Sets the address packets are sent to, which is also the address acknowledgements are
+    /// received on.
+    pub async fn set_tx_address(&mut self, address: &[u8]) -> Result<(), RadioError<T, CSN>> {
+        if address.len() != self.address_width as usize {
+            return Err(Error::InvalidAddress);
+        }
+        self.write_command(W_REGISTER | TX_ADDR, address, 0).await?;
+        self.write_command(W_REGISTER | RX_ADDR_P0, address, 0)
+            .await?;
+        Ok(())
+    }
+
+    /// Sets the address of the receive `pipe` from 1 to 5, and enables the pipe.
+    ///
+    /// Pipe 1 takes a full address. The other pipes share all but the last byte with pipe 1,
+    /// so only the last byte of `address` is used for them.
+    pub async fn set_rx_address(
+        &mut self,
+        pipe: u8,
+        address: &[u8],
+    ) -> Result<(), RadioError<T, CSN>> {
+        let address = match pipe {
+            1 if address.len() == self.address_width as usize => address,
+            2..=5 if !address.is_empty() => &address[address.len() - 1..],
+            _ => return Err(Error::InvalidAddress),
+        };
+        self.write_command(W_REGISTER | (RX_ADDR_P0 + pipe), address, 0)
+            .await?;
+        let enabled = self.read_register(EN_RXADDR).await?;
+        self.write_register(EN_RXADDR, enabled | 1 << pipe).await
+    }
+
+    /// Disables the receive `pipe`.
+    pub async fn close_pipe(&mut self, pipe: u8) -> Result<(), RadioError<T, CSN>> {
+        if pipe == 0 || pipe > 5 {
+            return Err(Error::InvalidAddress);
+        }
+        let enabled = self.read_register(EN_RXADDR).await?;
+        self.write_register(EN_RXADDR, enabled & !(1 << pipe)).await
+    }
+
+    /// Sends a packet, waiting until it has been acknowledged, or only until it has been sent
+    /// if auto acknowledgement is disabled.
+    ///
+    /// Stops listening. Packets received while listening remain in the receive FIFO.
+    ///
+    /// The radio reports the end of the transfer on the `IRQ` pin, which is awaited without a
+    /// timeout, so this never completes if the pin is not connected. Wrap it in
+    /// [`with_timeout`](crate::time::with_timeout) to detect a missing radio.
+    pub async fn send(&mut self, payload: &[u8]) -> Result<(), RadioError<T, CSN>> {
+        let padding = match self.payload_size {
+            0 if !payload.is_empty() && payload.len() <= MAX_PAYLOAD => 0,
+            size if size != 0 && payload.len() <= size as usize => size as usize - payload.len(),
+            _ => return Err(Error::PayloadSize),
+        };
+        self.standby()?;
+        self.write_register(CONFIG, self.config | CONFIG_PWR_UP | CONFIG_MASK_RX_DR)
+            .await?;
+        self.clear_status(STATUS_TX_DS | STATUS_MAX_RT).await?;
+        self.write_command(W_TX_PAYLOAD, payload, padding).await?;
+        self.ce.set_high().map_err(Error::Pin)?;
+        let status = loop {
+            self.irq.wait().await;
+            let status = self.status().await?;
+            if status & (STATUS_TX_DS | STATUS_MAX_RT) != 0 {
+                break status;
+            }
+        };
+        self.ce.set_low().map_err(Error::Pin)?;
+        self.clear_status(STATUS_TX_DS | STATUS_MAX_RT).await?;
+        if status & STATUS_MAX_RT != 0 {
+            self.command(FLUSH_TX, &mut []).await?;
+            return Err(Error::MaxRetries);
+        }
+        Ok(())
+    }
+
+    /// Receives a packet into `buf`, returning the pipe it arrived on and its length.
+    ///
+    /// Starts listening, and keeps listening after returning until the next [`send`] or
+    /// [`standby`], so that no packets are missed between calls.
+    ///
+    /// [`send`]: Nrf24::send
+    /// [`standby`]: Nrf24::standby
+    pub async fn recv(&mut self, buf: &mut [u8]) -> Result<(u8, usize), RadioError<T, CSN>> {
+        if !self.listening {
+            let config = self.config | CONFIG_PWR_UP | CONFIG_PRIM_RX;
+            self.write_register(CONFIG, config | CONFIG_MASK_TX_DS | CONFIG_MASK_MAX_RT)
+                .await?;
+            self.ce.set_high().map_err(Error::Pin)?;
+            self.listening = true;
+        }
+        loop {
+            // Clear the flag before checking the FIFO, so a packet arriving in between triggers
+            // the interrupt.
+            self.clear_status(STATUS_RX_DR).await?;
+            if self.read_register(FIFO_STATUS).await? & FIFO_RX_EMPTY == 0 {
+                return self.read_payload(buf).await;
+            }
+            self.irq.wait().await;
+        }
+    }
+
+    /// Reads the oldest packet of the receive FIFO.
+    async fn read_payload(&mut self, buf: &mut [u8]) -> Result<(u8, usize), RadioError<T, CSN>> {
+        let pipe = (self.status().await? >> 1) & 0b111;
+        let len = match self.payload_size {
+            0 => {
+                let mut len = [0];
+                self.command(R_RX_PL_WID, &mut len).await?;
+                len[0] as usize
+            }
+            size => size as usize,
+        };
+        if len > MAX_PAYLOAD {
+            // A corrupted packet, which the datasheet requires to be flushed.
+            self.command(FLUSH_RX, &mut []).await?;
+            return Err(Error::PayloadSize);
+        }
+        let mut payload = [NOP; MAX_PAYLOAD];
+        self.command(R_RX_PAYLOAD, &mut payload[..len]).await?;
+        let received = len.min(buf.len());
+        buf[..received].copy_from_slice(&payload[..received]);
+        if received < len {
+            return Err(Error::BufferTooSmall);
+        }
+        Ok((pipe, len))
+    }
+
+    /// Stops listening or sending, keeping the radio powered up.
+    pub fn standby(&mut self) -> Result<(), RadioError<T, CSN>> {
+        self.ce.set_low().map_err(Error::Pin)?;
+        self.listening = false;
+        Ok(())
+    }
+
+    /// Powers the radio down, until [`power_up`](Nrf24::power_up).
+    pub async fn power_down(&mut self) -> Result<(), RadioError<T, CSN>> {
+        self.standby()?;
+        self.write_register(CONFIG, self.config).await
+    }
+
+    /// Powers the radio up into standby mode.
+    pub async fn power_up(&mut self) -> Result<(), RadioError<T, CSN>> {
+        self.standby()?;
+        self.write_register(CONFIG, self.config | CONFIG_PWR_UP)
+            .await?;
+        delay(POWER_UP_DELAY).await;
+        Ok(())
+    }
+
+    async fn status(&mut self) -> Result<u8, RadioError<T, CSN>> {
+        self.command(NOP, &mut []).await
+    }
+
+    /// Clears the interrupt flags `bits`, which are cleared by writing ones.
+    async fn clear_status(&mut self, bits: u8) -> Result<(), RadioError<T, CSN>> {
+        self.write_register(STATUS, bits).await
+    }
+
+    async fn read_register(&mut self, register: u8) -> Result<u8, RadioError<T, CSN>> {
+        let mut value = [NOP];
+        self.command(R_REGISTER | register, &mut value).await?;
+        Ok(value[0])
+    }
+
+    async fn write_register(&mut self, register: u8, value: u8) -> Result<(), RadioError<T, CSN>> {
+        self.write_command(W_REGISTER | register, &[value], 0).await
+    }
+
+    /// Sends `command` followed by `data`, replacing `data` with the bytes received, and returns
+    /// the status register.
+    async fn command(&mut self, command: u8, data: &mut [u8]) -> Result<u8, RadioError<T, CSN>> {
+        let mut transaction = Transaction::begin(&mut self.spi, &mut self.csn)
+            .await
+            .map_err(Error::Pin)?;
+        let status = transaction.transfer(command).await?;
+        transaction.transfer_in_place(data).await?;
+        transaction.end().map_err(Error::Pin)?;
+        Ok(status)
+    }
+
+    /// Sends `command` followed by `data` and `padding` zeros.
+    async fn write_command(
+        &mut self,
+        command: u8,
+        data: &[u8],
+        padding: usize,
+    ) -> Result<(), RadioError<T, CSN>> {
+        let mut transaction = Transaction::begin(&mut self.spi, &mut self.csn)
+            .await
+            .map_err(Error::Pin)?;
+        transaction.transfer(command).await?;
+        transaction.send_all(data).await?;
+        for _ in 0..padding {
+            transaction.transfer(0).await?;
+        }
+        transaction.end().map_err(Error::Pin)
+    }
+}
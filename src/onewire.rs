@@ -0,0 +1,297 @@
+//! 1-Wire bus master on a GPIO pin.
+//!
+//! ```ignore
+//! let mut bus = OneWire::new(pin);
+//! let mut search = Search::new();
+//! while let Some(rom) = bus.search(&mut search).await? {
+//!     // ...
+//! }
+//! ```
+//!
+//! Each time slot is timed by busy-waiting with interrupts disabled, for at most the 70 µs of
+//! a slot, so interrupts are only delayed for that long. Other tasks run between the bytes of a
+//! transfer and during the long reset pulse. The bus needs an external pull-up resistor, and
+//! parasitically powered devices are not supported.
+
+use crate::crc::crc8;
+use crate::time::{delay, Duration};
+use crate::Yield;
+use avr_device::interrupt;
+use avr_hal_generic::clock::MHz16;
+use avr_hal_generic::delay::Delay;
+use avr_hal_generic::hal::blocking::delay::DelayUs;
+use avr_hal_generic::hal::digital::v2::{InputPin, OutputPin};
+
+const READ_ROM: u8 = 0x33;
+const MATCH_ROM: u8 = 0x55;
+const SKIP_ROM: u8 = 0xcc;
+const SEARCH_ROM: u8 = 0xf0;
+const ALARM_SEARCH: u8 = 0xec;
+
+/// How long the reset pulse holds the bus low, a little more than the minimum of 480 µs to
+/// account for the resolution of [`Duration`].
+const RESET_LOW: Duration = Duration::from_micros(500);
+/// When presence pulses are sampled after the reset pulse.
+const PRESENCE_SAMPLE_US: u16 = 70;
+/// The rest of the presence detection after sampling.
+const PRESENCE_END: Duration = Duration::from_micros(420);
+const WRITE_1_LOW_US: u16 = 6;
+const WRITE_1_HIGH_US: u16 = 64;
+const WRITE_0_LOW_US: u16 = 60;
+const WRITE_0_HIGH_US: u16 = 10;
+const READ_LOW_US: u16 = 3;
+const READ_SAMPLE_US: u16 = 10;
+const READ_HIGH_US: u16 = 53;
+
+/// An error of the 1-Wire bus, wrapping the error type `P` of the pin.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Error<P> {
+    Pin(P),
+    /// No device answered the reset pulse.
+    NoPresence,
+    /// Data was received with a wrong CRC.
+    Crc,
+}
+
+impl<P> From<P> for Error<P> {
+    fn from(err: P) -> Self {
+        Error::Pin(err)
+    }
+}
+
+type BusError<P> = Error<<P as OutputPin>::Error>;
+
+/// The unique 64-bit address of a device: its family code, serial number and CRC.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Rom(pub [u8; 8]);
+
+impl Rom {
+    /// The family code, which identifies the type of device.
+    pub fn family(&self) -> u8 {
+        self.0[0]
+    }
+}
+
+/// The state of a search for devices on the bus with [`OneWire::search`].
+#[derive(Debug, Clone)]
+pub struct Search {
+    command: u8,
+    rom: [u8; 8],
+    /// The bit at which the last search took the 0 branch, and the next takes the 1 branch.
+    last_discrepancy: u8,
+    done: bool,
+}
+
+impl Search {
+    /// Creates a search for all devices.
+    pub fn new() -> Self {
+        Search {
+            command: SEARCH_ROM,
+            rom: [0; 8],
+            last_discrepancy: 0,
+            done: false,
+        }
+    }
+
+    /// Creates a search for devices with an alarm condition, such as a DS18B20 outside its
+    /// configured temperature range.
+    pub fn alarms() -> Self {
+        Search {
+            command: ALARM_SEARCH,
+            ..Search::new()
+        }
+    }
+}
+
+impl Default for Search {
+    fn default() -> Self {
+        Search::new()
+    }
+}
+
+/// A 1-Wire bus on a pin which pulls the bus low when set low, and releases it when set high,
+/// such as an AVR pin in tri-state mode.
+pub struct OneWire<P> {
+    pin: P,
+    delay: Delay<MHz16>,
+}
+
+impl<P> OneWire<P>
+where
+    P: OutputPin + InputPin<Error = <P as OutputPin>::Error>,
+{
+    pub fn new(pin: P) -> Self {
+        OneWire {
+            pin,
+            delay: Delay::new(),
+        }
+    }
+
+    /// Releases the pin.
+    pub fn free(self) -> P {
+        self.pin
+    }
+
+    /// Sends a reset pulse and returns whether any device answered with a presence pulse.
+    pub async fn reset(&mut self) -> Result<bool, BusError<P>> {
+        self.pin.set_low()?;
+        delay(RESET_LOW).await;
+        let pin = &mut self.pin;
+        let delay_us = &mut self.delay;
+        let present = interrupt::free(|_| -> Result<bool, BusError<P>> {
+            pin.set_high()?;
+            delay_us.delay_us(PRESENCE_SAMPLE_US);
+            Ok(pin.is_low()?)
+        })?;
+        delay(PRESENCE_END).await;
+        Ok(present)
+    }
+
+    /// Resets the bus and addresses the device `rom`, or all devices if `rom` is `None`.
+    pub async fn select(&mut self, rom: Option<&Rom>) -> Result<(), BusError<P>> {
+        if !self.reset().await? {
+            return Err(Error::NoPresence);
+        }
+        match rom {
+            Some(rom) => {
+                self.write(&[MATCH_ROM]).await?;
+                self.write(&rom.0).await
+            }
+            None => self.write(&[SKIP_ROM]).await,
+        }
+    }
+
+    /// Reads the address of the only device on the bus.
+    pub async fn read_rom(&mut self) -> Result<Rom, BusError<P>> {
+        if !self.reset().await? {
+            return Err(Error::NoPresence);
+        }
+        self.write(&[READ_ROM]).await?;
+        let mut rom = [0; 8];
+        self.read(&mut rom).await?;
+        if crc8(&rom) != 0 {
+            return Err(Error::Crc);
+        }
+        Ok(Rom(rom))
+    }
+
+    /// Finds the next device of `search`, or returns `None` once all devices have been found.
+    pub async fn search(&mut self, search: &mut Search) -> Result<Option<Rom>, BusError<P>> {
+        if search.done {
+            return Ok(None);
+        }
+        if !self.reset().await? {
+            *search = Search {
+                command: search.command,
+                ..Search::new()
+            };
+            return Ok(None);
+        }
+        self.write(&[search.command]).await?;
+        let mut last_zero = 0;
+        for bit in 1..=64u8 {
+            let index = (bit - 1) as usize / 8;
+            let mask = 1 << ((bit - 1) & 7);
+            // All participating devices send their bit, then its complement.
+            let id_bit = self.read_bit()?;
+            let complement = self.read_bit()?;
+            let direction = match (id_bit, complement) {
+                // No device is participating anymore.
+                (true, true) => {
+                    search.done = true;
+                    return Ok(None);
+                }
+                (true, false) => true,
+                (false, true) => false,
+                // Devices differ in this bit.
+                (false, false) => {
+                    let direction = if bit < search.last_discrepancy {
+                        search.rom[index] & mask != 0
+                    } else {
+                        bit == search.last_discrepancy
+                    };
+                    if !direction {
+                        last_zero = bit;
+                    }
+                    direction
+                }
+            };
+            if direction {
+                search.rom[index] |= mask;
+            } else {
+                search.rom[index] &= !mask;
+            }
+            // Devices whose bit differs stop participating.
+            self.write_bit(direction)?;
+            if bit & 7 == 0 {
+                Yield::default().await;
+            }
+        }
+        search.last_discrepancy = last_zero;
+        search.done = last_zero == 0;
+        if crc8(&search.rom) != 0 {
+            return Err(Error::Crc);
+        }
+        Ok(Some(Rom(search.rom)))
+    }
+
+    /// Writes bytes, least significant bit first.
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), BusError<P>> {
+        for &byte in data {
+            for i in 0..8 {
+                self.write_bit(byte & 1 << i != 0)?;
+            }
+            Yield::default().await;
+        }
+        Ok(())
+    }
+
+    /// Reads bytes, least significant bit first.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<(), BusError<P>> {
+        for byte in buf {
+            *byte = 0;
+            for i in 0..8 {
+                if self.read_bit()? {
+                    *byte |= 1 << i;
+                }
+            }
+            Yield::default().await;
+        }
+        Ok(())
+    }
+
+    /// Writes a bit in a single time slot.
+    pub fn write_bit(&mut self, bit: bool) -> Result<(), BusError<P>> {
+        let (low, high) = if bit {
+            (WRITE_1_LOW_US, WRITE_1_HIGH_US)
+        } else {
+            (WRITE_0_LOW_US, WRITE_0_HIGH_US)
+        };
+        let pin = &mut self.pin;
+        let delay_us = &mut self.delay;
+        interrupt::free(|_| -> Result<(), BusError<P>> {
+            pin.set_low()?;
+            delay_us.delay_us(low);
+            pin.set_high()?;
+            Ok(())
+        })?;
+        // The recovery time may be extended by interrupts.
+        self.delay.delay_us(high);
+        Ok(())
+    }
+
+    /// Reads a bit in a single time slot.
+    pub fn read_bit(&mut self) -> Result<bool, BusError<P>> {
+        let pin = &mut self.pin;
+        let delay_us = &mut self.delay;
+        let bit = interrupt::free(|_| -> Result<bool, BusError<P>> {
+            pin.set_low()?;
+            delay_us.delay_us(READ_LOW_US);
+            pin.set_high()?;
+            delay_us.delay_us(READ_SAMPLE_US);
+            Ok(pin.is_high()?)
+        })?;
+        self.delay.delay_us(READ_HIGH_US);
+        Ok(bit)
+    }
+}
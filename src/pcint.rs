@@ -0,0 +1,209 @@
+//! Inputs watched with pin change interrupts.
+//!
+//! The pin change interrupts of a port are shared by all its pins, so their handlers are left
+//! to the application, which forwards them to [`on_interrupt`]:
+//!
+//! ```ignore
+//! #[avr_device::interrupt(atmega328p)]
+//! fn PCINT2() {
+//!     async_avr::pcint::on_interrupt(Port::D);
+//! }
+//!
+//! let mut input = Input::new(Port::D, 4);
+//! input.changed().await;
+//! ```
+//!
+//! A port can either watch inputs or wake an [`InterruptExecutor`](crate::InterruptExecutor)
+//! through [`PinChangeB`](crate::PinChangeB) and its siblings, not both.
+
+use crate::waker::WakerCell;
+use avr_device::atmega328p::Peripherals;
+use avr_device::interrupt::{self, CriticalSection, Mutex};
+use core::cell::Cell;
+use core::task::{Context, Poll};
+use futures_util::future::poll_fn;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Port {
+    /// `PCINT0` to `PCINT7`, handled by the `PCINT0` interrupt.
+    B,
+    /// `PCINT8` to `PCINT14`, handled by the `PCINT1` interrupt.
+    C,
+    /// `PCINT16` to `PCINT23`, handled by the `PCINT2` interrupt.
+    D,
+}
+
+impl Port {
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Reads the levels of all pins of the port.
+    pub(crate) fn read(self) -> u8 {
+        let dp = unsafe { Peripherals::steal() };
+        match self {
+            Port::B => dp.PORTB.pinb.read().bits(),
+            Port::C => dp.PORTC.pinc.read().bits(),
+            Port::D => dp.PORTD.pind.read().bits(),
+        }
+    }
+
+    fn set_mask(self, mask: u8, enabled: bool) {
+        let dp = unsafe { Peripherals::steal() };
+        let bits = |r: u8| if enabled { r | mask } else { r & !mask };
+        match self {
+            Port::B => dp
+                .EXINT
+                .pcmsk0
+                .modify(|r, w| unsafe { w.bits(bits(r.bits())) }),
+            Port::C => dp
+                .EXINT
+                .pcmsk1
+                .modify(|r, w| unsafe { w.bits(bits(r.bits())) }),
+            Port::D => dp
+                .EXINT
+                .pcmsk2
+                .modify(|r, w| unsafe { w.bits(bits(r.bits())) }),
+        }
+        if enabled {
+            dp.EXINT
+                .pcicr
+                .modify(|r, w| unsafe { w.bits(r.bits() | 1 << self.index()) });
+        }
+    }
+}
+
+/// The levels of each port when its pins were last read.
+static LEVELS: Mutex<[Cell<u8>; 3]> = Mutex::new([Cell::new(0), Cell::new(0), Cell::new(0)]);
+/// The pins of each port which changed since their inputs last checked.
+static CHANGED: Mutex<[Cell<u8>; 3]> = Mutex::new([Cell::new(0), Cell::new(0), Cell::new(0)]);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_WAKER: WakerCell = WakerCell::new();
+static WAKERS: [WakerCell; 24] = [NO_WAKER; 24];
+
+/// Decodes the pins of a port in its pin change interrupt, given the port and the levels of all
+/// its pins.
+pub(crate) type Hook = fn(&CriticalSection, Port, u8);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HOOK: Cell<Option<Hook>> = Cell::new(None);
+/// The hook of each watched pin, called when the pin changed.
+static HOOKS: Mutex<[Cell<Option<Hook>>; 24]> = Mutex::new([NO_HOOK; 24]);
+
+/// Enables the pin change interrupt of the pins `mask` of `port`, and returns the current levels
+/// of the port.
+///
+/// `hook` is called from the interrupt for each of the pins that changed, so a hook watching
+/// several pins is called once per changed pin and must ignore repeated levels.
+pub(crate) fn watch(port: Port, mask: u8, hook: Option<Hook>) -> u8 {
+    interrupt::free(|cs| {
+        let levels = port.read();
+        // Other pins keep their last levels, so their pending changes are still detected.
+        let last = &LEVELS.borrow(cs)[port.index()];
+        last.set(last.get() & !mask | levels & mask);
+        let changed = &CHANGED.borrow(cs)[port.index()];
+        changed.set(changed.get() & !mask);
+        let hooks = &HOOKS.borrow(cs)[port.index() * 8..][..8];
+        for (bit, slot) in hooks.iter().enumerate() {
+            if mask & 1 << bit != 0 {
+                slot.set(hook);
+            }
+        }
+        port.set_mask(mask, true);
+        levels
+    })
+}
+
+/// Disables the pin change interrupt of the pins `mask` of `port`, and removes their hooks.
+pub(crate) fn unwatch(port: Port, mask: u8) {
+    interrupt::free(|cs| {
+        port.set_mask(mask, false);
+        let hooks = &HOOKS.borrow(cs)[port.index() * 8..][..8];
+        for (bit, slot) in hooks.iter().enumerate() {
+            if mask & 1 << bit != 0 {
+                slot.set(None);
+            }
+        }
+    })
+}
+
+/// Handles the pin change interrupt of `port`. Must be called from its interrupt handler.
+pub fn on_interrupt(port: Port) {
+    let changed = interrupt::free(|cs| {
+        let levels = port.read();
+        let last = LEVELS.borrow(cs)[port.index()].replace(levels);
+        let changed = levels ^ last;
+        let pending = &CHANGED.borrow(cs)[port.index()];
+        pending.set(pending.get() | changed);
+        let hooks = &HOOKS.borrow(cs)[port.index() * 8..][..8];
+        for (bit, hook) in hooks.iter().enumerate() {
+            match hook.get() {
+                Some(hook) if changed & 1 << bit != 0 => hook(cs, port, levels),
+                _ => {}
+            }
+        }
+        changed
+    });
+    for bit in 0..8 {
+        if changed & 1 << bit != 0 {
+            WAKERS[port.index() * 8 + bit].wake();
+        }
+    }
+}
+
+/// A pin watched for changes.
+///
+/// The pin must be configured as an input, and only one `Input` may exist for each pin.
+pub struct Input {
+    port: Port,
+    bit: u8,
+}
+
+impl Input {
+    /// Enables the pin change interrupt of pin `bit` of `port`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bit` is greater than 7.
+    pub fn new(port: Port, bit: u8) -> Self {
+        assert!(bit < 8);
+        watch(port, 1 << bit, None);
+        Input { port, bit }
+    }
+
+    pub fn is_high(&self) -> bool {
+        self.port.read() & 1 << self.bit != 0
+    }
+
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+
+    /// Checks whether the pin changed since the last call returned `Poll::Ready`, or since the
+    /// input was created.
+    pub fn poll_changed(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        WAKERS[self.port.index() * 8 + self.bit as usize].register(cx.waker());
+        let changed = interrupt::free(|cs| {
+            let changed = &CHANGED.borrow(cs)[self.port.index()];
+            let bits = changed.get();
+            changed.set(bits & !(1 << self.bit));
+            bits & 1 << self.bit != 0
+        });
+        if changed {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Waits until the pin changed since the last check.
+    pub async fn changed(&mut self) {
+        poll_fn(|cx| self.poll_changed(cx)).await
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        unwatch(self.port, 1 << self.bit);
+    }
+}
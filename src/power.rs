@@ -0,0 +1,419 @@
+//! Sleep modes and power reduction.
+//!
+//! Drivers hold a [`Busy`] token for a peripheral while one of their futures is outstanding. When
+//! no task is ready to run, the executor asks [`deepest_sleep_mode`] which sleep mode keeps every
+//! busy peripheral running and puts the MCU to sleep in that mode. Peripherals that have been
+//! handed to the power manager with [`manage`] additionally have their power reduction bit set
+//! in `PRR` while nobody is using them.
+//!
+//! Tasks that need the CPU clocks to keep running for reasons the power manager cannot see (for
+//! example, while bit-banging a protocol) can hold a [`WakeLock`] from [`stay_awake`].
+//!
+//! Futures which wake their task again right away instead of waiting for an interrupt, such as
+//! those of [`AsyncSerial`](crate::AsyncSerial) and [`AsyncSpi`](crate::AsyncSpi), keep the
+//! executor polling, so it never gets to sleep while one of them is pending.
+
+use crate::waker::WakerCell;
+use avr_device::atmega328p::Peripherals;
+use avr_device::interrupt::{self, Mutex};
+use core::cell::Cell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+const SMCR_SE: u8 = 1 << 0;
+const ASSR_AS2: u8 = 1 << 5;
+
+const MCUSR_WDRF: u8 = 1 << 3;
+const WDTCSR_WDIE: u8 = 1 << 6;
+const WDTCSR_WDCE: u8 = 1 << 4;
+const WDTCSR_WDE: u8 = 1 << 3;
+
+/// A sleep mode of the ATmega328P, ordered from the shallowest to the deepest.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
+pub enum SleepMode {
+    /// Only the CPU and flash clocks are stopped; every peripheral keeps running.
+    Idle,
+    /// The I/O clock is stopped as well, leaving the ADC, TWI address match, Timer2 and external
+    /// interrupts running.
+    AdcNoiseReduction,
+    /// Only Timer2 in asynchronous mode, the watchdog, TWI address match and external interrupts
+    /// can wake the MCU.
+    PowerSave,
+    /// Only the watchdog, TWI address match and external interrupts can wake the MCU.
+    PowerDown,
+}
+
+impl SleepMode {
+    fn bits(self) -> u8 {
+        let sm = match self {
+            SleepMode::Idle => 0b000,
+            SleepMode::AdcNoiseReduction => 0b001,
+            SleepMode::PowerDown => 0b010,
+            SleepMode::PowerSave => 0b011,
+        };
+        sm << 1
+    }
+}
+
+/// A peripheral whose use is tracked by the power manager.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Peripheral {
+    Usart0,
+    Spi,
+    Twi,
+    Adc,
+    Timer0,
+    Timer1,
+    Timer2,
+}
+
+const PERIPHERALS: usize = 7;
+
+impl Peripheral {
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The bit of this peripheral in the `PRR` register.
+    fn prr_bit(self) -> u8 {
+        match self {
+            Peripheral::Adc => 1 << 0,
+            Peripheral::Usart0 => 1 << 1,
+            Peripheral::Spi => 1 << 2,
+            Peripheral::Timer1 => 1 << 3,
+            Peripheral::Timer0 => 1 << 5,
+            Peripheral::Timer2 => 1 << 6,
+            Peripheral::Twi => 1 << 7,
+        }
+    }
+
+    /// The deepest sleep mode in which this peripheral can still make progress.
+    fn deepest_sleep_mode(self) -> SleepMode {
+        match self {
+            Peripheral::Usart0
+            | Peripheral::Spi
+            | Peripheral::Twi
+            | Peripheral::Timer0
+            | Peripheral::Timer1 => SleepMode::Idle,
+            Peripheral::Adc => SleepMode::AdcNoiseReduction,
+            Peripheral::Timer2 => {
+                let dp = unsafe { Peripherals::steal() };
+                if dp.TC2.assr.read().bits() & ASSR_AS2 != 0 {
+                    SleepMode::PowerSave
+                } else {
+                    SleepMode::Idle
+                }
+            }
+        }
+    }
+
+    fn all() -> [Peripheral; PERIPHERALS] {
+        [
+            Peripheral::Usart0,
+            Peripheral::Spi,
+            Peripheral::Twi,
+            Peripheral::Adc,
+            Peripheral::Timer0,
+            Peripheral::Timer1,
+            Peripheral::Timer2,
+        ]
+    }
+}
+
+struct State {
+    /// Number of outstanding [`Busy`] tokens, indexed by [`Peripheral::index`].
+    users: [Cell<u8>; PERIPHERALS],
+    /// Number of outstanding [`WakeLock`]s.
+    wake_locks: Cell<u8>,
+    /// `PRR` bits the power manager is allowed to set.
+    managed: Cell<u8>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    users: [
+        Cell::new(0),
+        Cell::new(0),
+        Cell::new(0),
+        Cell::new(0),
+        Cell::new(0),
+        Cell::new(0),
+        Cell::new(0),
+    ],
+    wake_locks: Cell::new(0),
+    managed: Cell::new(0),
+});
+
+/// A token marking a peripheral as in use.
+///
+/// While any token for a peripheral exists, the peripheral is powered and the executor will not
+/// enter a sleep mode that stops it. Created with [`busy`].
+#[derive(Debug)]
+#[must_use = "the peripheral is released as soon as the token is dropped"]
+pub struct Busy(Peripheral);
+
+/// Marks `peripheral` as in use until the returned token is dropped.
+///
+/// If the peripheral was powered down by the power manager, it is powered up again before this
+/// function returns.
+pub fn busy(peripheral: Peripheral) -> Busy {
+    interrupt::free(|cs| {
+        let users = &STATE.borrow(cs).users[peripheral.index()];
+        users.set(users.get().checked_add(1).expect("too many Busy tokens"));
+        let dp = unsafe { Peripherals::steal() };
+        dp.CPU
+            .prr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !peripheral.prr_bit()) });
+    });
+    Busy(peripheral)
+}
+
+impl Busy {
+    /// The peripheral this token keeps awake.
+    pub fn peripheral(&self) -> Peripheral {
+        self.0
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        interrupt::free(|cs| {
+            let users = &STATE.borrow(cs).users[self.0.index()];
+            users.set(users.get() - 1);
+        })
+    }
+}
+
+/// A lock that keeps the executor from entering any sleep mode deeper than
+/// [`SleepMode::Idle`]. Created with [`stay_awake`].
+#[derive(Debug)]
+#[must_use = "the lock is released as soon as it is dropped"]
+pub struct WakeLock(());
+
+/// Keeps all clocks running until the returned lock is dropped.
+pub fn stay_awake() -> WakeLock {
+    interrupt::free(|cs| {
+        let locks = &STATE.borrow(cs).wake_locks;
+        locks.set(locks.get().checked_add(1).expect("too many wake locks"));
+    });
+    WakeLock(())
+}
+
+impl Drop for WakeLock {
+    fn drop(&mut self) {
+        interrupt::free(|cs| {
+            let locks = &STATE.borrow(cs).wake_locks;
+            locks.set(locks.get() - 1);
+        })
+    }
+}
+
+/// Allows the power manager to power down `peripheral` while no [`Busy`] token for it exists.
+///
+/// Only hand over peripherals whose drivers take a [`Busy`] token for as long as they need the
+/// peripheral; a peripheral powered down through `PRR` loses its clock, so for example a USART
+/// stops receiving.
+pub fn manage(peripheral: Peripheral) {
+    interrupt::free(|cs| {
+        let managed = &STATE.borrow(cs).managed;
+        managed.set(managed.get() | peripheral.prr_bit());
+    })
+}
+
+/// Returns `peripheral` to the application, powering it up if the power manager had powered it
+/// down.
+pub fn unmanage(peripheral: Peripheral) {
+    interrupt::free(|cs| {
+        let managed = &STATE.borrow(cs).managed;
+        managed.set(managed.get() & !peripheral.prr_bit());
+        let dp = unsafe { Peripherals::steal() };
+        dp.CPU
+            .prr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !peripheral.prr_bit()) });
+    })
+}
+
+/// Sets the `PRR` bits of all managed peripherals that are not in use.
+pub fn reduce_power() {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs);
+        let mut unused = 0;
+        for peripheral in Peripheral::all().iter() {
+            if state.users[peripheral.index()].get() == 0 {
+                unused |= peripheral.prr_bit();
+            }
+        }
+        let unused = unused & state.managed.get();
+        let dp = unsafe { Peripherals::steal() };
+        dp.CPU
+            .prr
+            .modify(|r, w| unsafe { w.bits(r.bits() | unused) });
+    })
+}
+
+/// Returns the deepest sleep mode that keeps every busy peripheral running.
+pub fn deepest_sleep_mode() -> SleepMode {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs);
+        if state.wake_locks.get() > 0 {
+            return SleepMode::Idle;
+        }
+        let mut mode = SleepMode::PowerDown;
+        for peripheral in Peripheral::all().iter() {
+            if state.users[peripheral.index()].get() > 0 {
+                mode = mode.min(peripheral.deepest_sleep_mode());
+            }
+        }
+        mode
+    })
+}
+
+/// Puts the MCU to sleep in `mode` until an interrupt occurs.
+///
+/// Global interrupts are enabled before sleeping, as otherwise the MCU would never wake up.
+pub fn sleep(mode: SleepMode) {
+    interrupt::disable();
+    sleep_with_interrupts_disabled(mode);
+}
+
+/// Sleeps in the deepest safe mode unless `ready` returns `true`.
+///
+/// `ready` is checked with interrupts disabled, so a wakeup from an interrupt handler cannot be
+/// lost between the check and going to sleep.
+pub(crate) fn sleep_unless(ready: impl Fn() -> bool) {
+    interrupt::disable();
+    if ready() {
+        unsafe { interrupt::enable() };
+        return;
+    }
+    reduce_power();
+    sleep_with_interrupts_disabled(deepest_sleep_mode());
+}
+
+fn sleep_with_interrupts_disabled(mode: SleepMode) {
+    let dp = unsafe { Peripherals::steal() };
+    dp.CPU.smcr.write(|w| unsafe { w.bits(mode.bits() | SMCR_SE) });
+    // The instruction following `sei` is always executed before any pending interrupt, so an
+    // interrupt arriving after the check in `sleep_unless` will wake us from `sleep`.
+    unsafe { interrupt::enable() };
+    avr_device::asm::sleep();
+    dp.CPU.smcr.write(|w| unsafe { w.bits(0) });
+}
+
+/// Timeout of the watchdog timer when used as a wakeup source.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum WatchdogTimeout {
+    Ms16,
+    Ms32,
+    Ms64,
+    Ms125,
+    Ms250,
+    Ms500,
+    S1,
+    S2,
+    S4,
+    S8,
+}
+
+impl WatchdogTimeout {
+    /// The `WDP3:0` bits of `WDTCSR` for this timeout.
+    fn bits(self) -> u8 {
+        let wdp = self as u8;
+        (wdp & 0b0111) | ((wdp & 0b1000) << 2)
+    }
+}
+
+static WATCHDOG_FIRED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static WATCHDOG_WAKER: WakerCell = WakerCell::new();
+
+/// Future for the [`watchdog_sleep`] function.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct WatchdogSleep {
+    timeout: WatchdogTimeout,
+    armed: bool,
+}
+
+/// Creates a future which resolves after the watchdog timer expires.
+///
+/// The watchdog keeps running in every sleep mode, so unlike timer based delays this lets the
+/// executor use [`SleepMode::PowerDown`] while waiting. The watchdog is stopped again when the
+/// future resolves or is dropped. Only one `WatchdogSleep` may be in use at a time.
+///
+/// The `WDT` interrupt handler must call [`on_watchdog_interrupt`]:
+///
+/// ```ignore
+/// #[avr_device::interrupt(atmega328p)]
+/// fn WDT() {
+///     async_avr::power::on_watchdog_interrupt();
+/// }
+/// ```
+pub fn watchdog_sleep(timeout: WatchdogTimeout) -> WatchdogSleep {
+    WatchdogSleep {
+        timeout,
+        armed: false,
+    }
+}
+
+impl Future for WatchdogSleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        WATCHDOG_WAKER.register(cx.waker());
+        if !self.armed {
+            self.armed = true;
+            let bits = self.timeout.bits();
+            interrupt::free(|cs| {
+                WATCHDOG_FIRED.borrow(cs).set(false);
+                let dp = unsafe { Peripherals::steal() };
+                avr_device::asm::wdr();
+                dp.CPU
+                    .mcusr
+                    .modify(|r, w| unsafe { w.bits(r.bits() & !MCUSR_WDRF) });
+                // Timed sequence: WDE and the prescaler may only change within four cycles of
+                // setting WDCE.
+                dp.WDT
+                    .wdtcsr
+                    .modify(|r, w| unsafe { w.bits(r.bits() | WDTCSR_WDCE | WDTCSR_WDE) });
+                dp.WDT
+                    .wdtcsr
+                    .write(|w| unsafe { w.bits(WDTCSR_WDIE | bits) });
+            });
+            return Poll::Pending;
+        }
+        if interrupt::free(|cs| WATCHDOG_FIRED.borrow(cs).get()) {
+            self.armed = false;
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for WatchdogSleep {
+    fn drop(&mut self) {
+        if self.armed {
+            interrupt::free(|_| stop_watchdog());
+        }
+    }
+}
+
+fn stop_watchdog() {
+    let dp = unsafe { Peripherals::steal() };
+    avr_device::asm::wdr();
+    dp.CPU
+        .mcusr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !MCUSR_WDRF) });
+    dp.WDT
+        .wdtcsr
+        .modify(|r, w| unsafe { w.bits(r.bits() | WDTCSR_WDCE | WDTCSR_WDE) });
+    dp.WDT.wdtcsr.write(|w| unsafe { w.bits(0) });
+}
+
+/// Handles the watchdog interrupt for [`watchdog_sleep`]. Must be called from the `WDT`
+/// interrupt handler.
+pub fn on_watchdog_interrupt() {
+    stop_watchdog();
+    interrupt::free(|cs| WATCHDOG_FIRED.borrow(cs).set(true));
+    WATCHDOG_WAKER.wake();
+}
@@ -0,0 +1,510 @@
+//! PWM outputs of Timer0, Timer1 and Timer2, with duty ramps and servos.
+//!
+//! ```ignore
+//! let (mut motor, _) = pwm::timer2(dp.TC2, 8_000);
+//! motor.ramp_to(motor.max_duty() / 2, Duration::from_secs(2)).await;
+//!
+//! let (servo, _) = pwm::timer1(dp.TC1, 50);
+//! let mut servo = Servo::new(servo);
+//! servo.move_to(90, Duration::from_millis(500)).await;
+//! ```
+//!
+//! | Channel | Pin   | Frequency                                  | Resolution    |
+//! |---------|-------|--------------------------------------------|---------------|
+//! | `OC0A`  | `PD6` | 976 Hz, shared with [`time`](crate::time)  | 8 bits        |
+//! | `OC0B`  | `PD5` |                                            |               |
+//! | `OC1A`  | `PB1` | 1 Hz to 8 MHz                              | up to 16 bits |
+//! | `OC1B`  | `PB2` |                                            |               |
+//! | `OC2A`  | `PB3` | 62.5 kHz / 1, 8, 32, 64, 128, 256 or 1024  | 8 bits        |
+//! | `OC2B`  | `PD3` |                                            |               |
+//!
+//! The pins must be configured as outputs and set low. A duty of 0 disconnects the pin from the
+//! timer, so it is really off instead of emitting the short spike of fast PWM.
+//!
+//! Ramps move the duty in steps of at most 1 kHz from the overflow interrupt of the timer, so
+//! they continue smoothly while other tasks run. The interrupt handlers are left to the
+//! application, which forwards the overflows of the timers it ramps. Timer0 keeps its handler
+//! for [`time`](crate::time), and Timer1 shares its handler with [`capture`](crate::capture)
+//! if the application uses both:
+//!
+//! ```ignore
+//! #[avr_device::interrupt(atmega328p)]
+//! fn TIMER0_OVF() {
+//!     async_avr::time::on_interrupt();
+//!     async_avr::pwm::on_overflow_interrupt(0);
+//! }
+//!
+//! #[avr_device::interrupt(atmega328p)]
+//! fn TIMER2_OVF() {
+//!     async_avr::pwm::on_overflow_interrupt(2);
+//! }
+//! ```
+
+use crate::power::{self, Busy, Peripheral};
+use crate::time::Duration;
+use crate::waker::WakerCell;
+use crate::CPU_FREQUENCY;
+use avr_device::atmega328p::{Peripherals, TC1, TC2};
+use avr_device::interrupt::{self, CriticalSection, Mutex};
+use core::cell::Cell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+const TCCR1A_WGM11: u8 = 1 << 1;
+const TCCR1B_WGM13: u8 = 1 << 4;
+const TCCR1B_WGM12: u8 = 1 << 3;
+const TCCR2A_WGM: u8 = 0b11;
+const TIMSK_TOIE: u8 = 1 << 0;
+
+/// Non-inverting mode of the `COMnA` bits, which are shifted right by 2 for `COMnB`.
+const COM_A_CLEAR: u8 = 0b10 << 6;
+const COM_A_MASK: u8 = 0b11 << 6;
+
+const TIMER1_PRESCALERS: [u32; 5] = [1, 8, 64, 256, 1024];
+const TIMER2_PRESCALERS: [u32; 7] = [1, 8, 32, 64, 128, 256, 1024];
+const TIMER0_PRESCALER: u32 = 64;
+
+/// The highest rate at which ramps step.
+const MAX_STEP_RATE: u32 = 1000;
+
+const CHANNELS: usize = 6;
+
+/// A ramp in progress, updated from the overflow interrupt.
+#[derive(Copy, Clone)]
+struct Ramp {
+    /// Steps left, or 0 if no ramp is in progress.
+    remaining: u16,
+    /// Overflows per step.
+    divider: u8,
+    overflows: u8,
+    /// The duty as a 16.16 fixed point number.
+    duty: u32,
+    step: i32,
+    target: u16,
+}
+
+impl Ramp {
+    const IDLE: Ramp = Ramp {
+        remaining: 0,
+        divider: 1,
+        overflows: 0,
+        duty: 0,
+        step: 0,
+        target: 0,
+    };
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const IDLE_RAMP: Cell<Ramp> = Cell::new(Ramp::IDLE);
+static RAMPS: Mutex<[Cell<Ramp>; CHANNELS]> = Mutex::new([IDLE_RAMP; CHANNELS]);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_WAKER: WakerCell = WakerCell::new();
+static WAKERS: [WakerCell; CHANNELS] = [NO_WAKER; CHANNELS];
+/// Whether Timer1 runs PWM, so its overflow interrupt is only needed for ramps. Otherwise it
+/// may count overflows for [`capture`](crate::capture).
+static TIMER1_PWM: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Configures the output compare channels `OC0A` and `OC0B` of Timer0, which keeps running in
+/// fast PWM mode at 976 Hz as configured by [`time::init`](crate::time::init). Both channels
+/// start off.
+///
+/// Must be called only once.
+pub fn timer0() -> (Pwm, Pwm) {
+    let clock = CPU_FREQUENCY / TIMER0_PRESCALER;
+    (Pwm::new(0, 256, clock), Pwm::new(1, 256, clock))
+}
+
+/// Configures Timer1 for fast PWM at the frequency closest to `frequency` Hz, with as much
+/// resolution as possible, and returns its channels `OC1A` and `OC1B`. Both channels start off.
+pub fn timer1(tc1: TC1, frequency: u32) -> (Pwm, Pwm) {
+    let frequency = frequency.max(1);
+    let (bits, prescaler) = (1..)
+        .zip(&TIMER1_PRESCALERS)
+        .find(|&(_, &prescaler)| CPU_FREQUENCY / (prescaler * frequency) <= 0xffff)
+        .unwrap_or((5, &1024));
+    let period = (CPU_FREQUENCY / (prescaler * frequency)).clamp(2, 0xffff);
+    let _busy = power::busy(Peripheral::Timer1);
+    interrupt::free(|cs| {
+        TIMER1_PWM.borrow(cs).set(true);
+        tc1.tccr1a.write(|w| unsafe { w.bits(TCCR1A_WGM11) });
+        tc1.tccr1b
+            .write(|w| unsafe { w.bits(TCCR1B_WGM13 | TCCR1B_WGM12) });
+        tc1.icr1.write(|w| unsafe { w.bits(period as u16 - 1) });
+        tc1.tcnt1.write(|w| unsafe { w.bits(0) });
+        tc1.tccr1b
+            .write(|w| unsafe { w.bits(TCCR1B_WGM13 | TCCR1B_WGM12 | bits) });
+    });
+    let clock = CPU_FREQUENCY / prescaler;
+    (
+        Pwm::new(2, period as u16, clock),
+        Pwm::new(3, period as u16, clock),
+    )
+}
+
+/// Configures Timer2 for fast PWM at the available frequency closest to `frequency` Hz, and
+/// returns its channels `OC2A` and `OC2B`. Both channels start off.
+pub fn timer2(tc2: TC2, frequency: u32) -> (Pwm, Pwm) {
+    let (bits, prescaler) = (1..)
+        .zip(&TIMER2_PRESCALERS)
+        .min_by_key(|&(_, &prescaler)| {
+            let available = CPU_FREQUENCY / 256 / prescaler;
+            (available as i32 - frequency as i32).abs()
+        })
+        .unwrap();
+    let _busy = power::busy(Peripheral::Timer2);
+    interrupt::free(|_| {
+        tc2.tccr2a.write(|w| unsafe { w.bits(TCCR2A_WGM) });
+        tc2.tcnt2.write(|w| unsafe { w.bits(0) });
+        tc2.tccr2b.write(|w| unsafe { w.bits(bits) });
+    });
+    let clock = CPU_FREQUENCY / prescaler;
+    (Pwm::new(4, 256, clock), Pwm::new(5, 256, clock))
+}
+
+/// A PWM output, whose duty is the number of timer ticks per period the pin is high.
+pub struct Pwm {
+    channel: u8,
+    period: u16,
+    /// The frequency of the timer ticks.
+    clock: u32,
+    _busy: Busy,
+}
+
+impl Pwm {
+    fn new(channel: u8, period: u16, clock: u32) -> Self {
+        let timer = match channel / 2 {
+            0 => Peripheral::Timer0,
+            1 => Peripheral::Timer1,
+            _ => Peripheral::Timer2,
+        };
+        let pwm = Pwm {
+            channel,
+            period,
+            clock,
+            _busy: power::busy(timer),
+        };
+        write_duty(channel, 0);
+        pwm
+    }
+
+    /// The duty at which the pin is always high.
+    pub fn max_duty(&self) -> u16 {
+        self.period
+    }
+
+    /// The PWM frequency in Hz.
+    pub fn frequency(&self) -> u32 {
+        self.clock / self.period as u32
+    }
+
+    /// The duty at which the pin is high for `micros` µs per period, up to the maximum duty.
+    pub fn duty_for_micros(&self, micros: u32) -> u16 {
+        let ticks = micros as u64 * self.clock as u64 / 1_000_000;
+        ticks.min(self.period as u64) as u16
+    }
+
+    pub fn duty(&self) -> u16 {
+        read_duty(self.channel)
+    }
+
+    /// Sets the duty, up to [`max_duty`](Pwm::max_duty). The new duty takes effect with the next
+    /// period.
+    pub fn set_duty(&mut self, duty: u16) {
+        write_duty(self.channel, duty.min(self.period));
+    }
+
+    /// Changes the duty linearly from the current one to `duty`, over `duration`.
+    ///
+    /// Dropping the future stops the ramp at the duty reached so far.
+    pub fn ramp_to(&mut self, duty: u16, duration: Duration) -> RampTo<'_> {
+        RampTo {
+            target: duty.min(self.period),
+            duration,
+            started: false,
+            pwm: self,
+        }
+    }
+
+    /// Starts a ramp, or returns `false` if it is too short to take a step.
+    fn start_ramp(&mut self, target: u16, duration: Duration) -> bool {
+        let frequency = self.frequency();
+        let divider = ((frequency + MAX_STEP_RATE - 1) / MAX_STEP_RATE).clamp(1, 255);
+        let steps = (duration.as_micros() as u64 * (frequency / divider) as u64 / 1_000_000)
+            .min(u16::MAX as u64) as u16;
+        if steps < 2 {
+            self.set_duty(target);
+            return false;
+        }
+        let current = self.duty();
+        let step = (((target as i64 - current as i64) << 16) / steps as i64) as i32;
+        let channel = self.channel;
+        interrupt::free(|cs| {
+            RAMPS.borrow(cs)[channel as usize].set(Ramp {
+                remaining: steps,
+                divider: divider as u8,
+                overflows: 0,
+                duty: (current as u32) << 16,
+                step,
+                target,
+            });
+            if channel >= 2 {
+                set_overflow_interrupt(channel / 2, true);
+            }
+        });
+        true
+    }
+}
+
+/// Future for the [`ramp_to`](Pwm::ramp_to) method.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct RampTo<'a> {
+    pwm: &'a mut Pwm,
+    target: u16,
+    duration: Duration,
+    started: bool,
+}
+
+impl Future for RampTo<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let channel = self.pwm.channel as usize;
+        if !self.started {
+            self.started = true;
+            let (target, duration) = (self.target, self.duration);
+            if !self.pwm.start_ramp(target, duration) {
+                return Poll::Ready(());
+            }
+        }
+        WAKERS[channel].register(cx.waker());
+        let remaining = interrupt::free(|cs| RAMPS.borrow(cs)[channel].get().remaining);
+        if remaining == 0 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for RampTo<'_> {
+    fn drop(&mut self) {
+        let channel = self.pwm.channel as usize;
+        interrupt::free(|cs| {
+            RAMPS.borrow(cs)[channel].set(Ramp::IDLE);
+            release_overflow_interrupt(cs, channel as u8 / 2);
+        });
+    }
+}
+
+/// A hobby servo, positioned by the width of a pulse repeated every 20 ms.
+///
+/// Timer1 at 50 Hz gives a resolution of 0.5 µs. The 8 bit timers only run at 61 Hz, with steps
+/// of 64 µs, or about 12° of a typical servo.
+pub struct Servo {
+    pwm: Pwm,
+    min_pulse: u16,
+    max_pulse: u16,
+    max_angle: u16,
+}
+
+impl Servo {
+    /// Creates a servo turning 180° for pulses from 1000 to 2000 µs.
+    pub fn new(pwm: Pwm) -> Self {
+        Self::with_range(pwm, 1000, 2000, 180)
+    }
+
+    /// Creates a servo turning `max_angle` degrees for pulses from `min_pulse` to `max_pulse`
+    /// µs.
+    pub fn with_range(pwm: Pwm, min_pulse: u16, max_pulse: u16, max_angle: u16) -> Self {
+        Servo {
+            pwm,
+            min_pulse,
+            max_pulse,
+            max_angle: max_angle.max(1),
+        }
+    }
+
+    /// Releases the PWM output, which keeps sending the last pulse width.
+    pub fn free(self) -> Pwm {
+        self.pwm
+    }
+
+    /// Moves to `angle` degrees, up to the maximum angle, as fast as the servo can.
+    pub fn set_angle(&mut self, angle: u16) {
+        let duty = self.duty(angle);
+        self.pwm.set_duty(duty);
+    }
+
+    /// Moves to `angle` degrees at a constant speed over `duration`.
+    pub fn move_to(&mut self, angle: u16, duration: Duration) -> RampTo<'_> {
+        let duty = self.duty(angle);
+        self.pwm.ramp_to(duty, duration)
+    }
+
+    /// Stops sending pulses, so the servo no longer holds its position.
+    pub fn detach(&mut self) {
+        self.pwm.set_duty(0);
+    }
+
+    fn duty(&self, angle: u16) -> u16 {
+        let angle = angle.min(self.max_angle) as u32;
+        let range = self.max_pulse as u32 - self.min_pulse as u32;
+        let pulse = self.min_pulse as u32 + range * angle / self.max_angle as u32;
+        self.pwm.duty_for_micros(pulse)
+    }
+}
+
+fn set_overflow_interrupt(timer: u8, enabled: bool) {
+    let dp = unsafe { Peripherals::steal() };
+    let bits = |r: u8| {
+        if enabled {
+            r | TIMSK_TOIE
+        } else {
+            r & !TIMSK_TOIE
+        }
+    };
+    match timer {
+        1 => dp
+            .TC1
+            .timsk1
+            .modify(|r, w| unsafe { w.bits(bits(r.bits())) }),
+        _ => dp
+            .TC2
+            .timsk2
+            .modify(|r, w| unsafe { w.bits(bits(r.bits())) }),
+    }
+}
+
+/// Disables the overflow interrupt of `timer` once none of its channels ramps. Timer0 keeps its
+/// interrupt for the time base, and Timer1 for input capture unless it runs PWM.
+fn release_overflow_interrupt(cs: &CriticalSection, timer: u8) {
+    let ramps = RAMPS.borrow(cs);
+    let active =
+        (2 * timer..2 * timer + 2).any(|channel| ramps[channel as usize].get().remaining != 0);
+    let owned = match timer {
+        0 => false,
+        1 => TIMER1_PWM.borrow(cs).get(),
+        _ => true,
+    };
+    if !active && owned {
+        set_overflow_interrupt(timer, false);
+    }
+}
+
+/// The `COM` bits of `channel` in its `TCCRnA` register.
+fn com_bits(channel: u8, bits: u8) -> u8 {
+    bits >> (2 * (channel & 1))
+}
+
+fn read_duty(channel: u8) -> u16 {
+    let dp = unsafe { Peripherals::steal() };
+    let (control, compare) = match channel {
+        0 => (
+            dp.TC0.tccr0a.read().bits(),
+            dp.TC0.ocr0a.read().bits() as u16,
+        ),
+        1 => (
+            dp.TC0.tccr0a.read().bits(),
+            dp.TC0.ocr0b.read().bits() as u16,
+        ),
+        2 => (dp.TC1.tccr1a.read().bits(), dp.TC1.ocr1a.read().bits()),
+        3 => (dp.TC1.tccr1a.read().bits(), dp.TC1.ocr1b.read().bits()),
+        4 => (
+            dp.TC2.tccr2a.read().bits(),
+            dp.TC2.ocr2a.read().bits() as u16,
+        ),
+        _ => (
+            dp.TC2.tccr2a.read().bits(),
+            dp.TC2.ocr2b.read().bits() as u16,
+        ),
+    };
+    if control & com_bits(channel, COM_A_MASK) == 0 {
+        0
+    } else {
+        compare + 1
+    }
+}
+
+/// Sets the pin high for `duty` ticks per period. In fast PWM mode, the pin is high for one
+/// tick more than the compare value.
+fn write_duty(channel: u8, duty: u16) {
+    let dp = unsafe { Peripherals::steal() };
+    let compare = duty.saturating_sub(1);
+    let com = if duty == 0 {
+        0
+    } else {
+        com_bits(channel, COM_A_CLEAR)
+    };
+    let mask = com_bits(channel, COM_A_MASK);
+    let control = |r: u8| r & !mask | com;
+    interrupt::free(|_| match channel {
+        0 | 1 => {
+            if channel == 0 {
+                dp.TC0.ocr0a.write(|w| unsafe { w.bits(compare as u8) });
+            } else {
+                dp.TC0.ocr0b.write(|w| unsafe { w.bits(compare as u8) });
+            }
+            dp.TC0
+                .tccr0a
+                .modify(|r, w| unsafe { w.bits(control(r.bits())) });
+        }
+        2 | 3 => {
+            if channel == 2 {
+                dp.TC1.ocr1a.write(|w| unsafe { w.bits(compare) });
+            } else {
+                dp.TC1.ocr1b.write(|w| unsafe { w.bits(compare) });
+            }
+            dp.TC1
+                .tccr1a
+                .modify(|r, w| unsafe { w.bits(control(r.bits())) });
+        }
+        _ => {
+            if channel == 4 {
+                dp.TC2.ocr2a.write(|w| unsafe { w.bits(compare as u8) });
+            } else {
+                dp.TC2.ocr2b.write(|w| unsafe { w.bits(compare as u8) });
+            }
+            dp.TC2
+                .tccr2a
+                .modify(|r, w| unsafe { w.bits(control(r.bits())) });
+        }
+    })
+}
+
+/// Advances the ramps of the channels of `timer`. Must be called from the `TIMERn_OVF`
+/// interrupt handler of each timer whose channels ramp.
+pub fn on_overflow_interrupt(timer: u8) {
+    let mut finished = 0u8;
+    interrupt::free(|cs| {
+        let ramps = RAMPS.borrow(cs);
+        for channel in 2 * timer..2 * timer + 2 {
+            let cell = &ramps[channel as usize];
+            let mut ramp = cell.get();
+            if ramp.remaining == 0 {
+                continue;
+            }
+            ramp.overflows += 1;
+            if ramp.overflows >= ramp.divider {
+                ramp.overflows = 0;
+                ramp.remaining -= 1;
+                ramp.duty = ramp.duty.wrapping_add(ramp.step as u32);
+                if ramp.remaining == 0 {
+                    write_duty(channel, ramp.target);
+                    finished |= 1 << channel;
+                } else {
+                    write_duty(channel, (ramp.duty >> 16) as u16);
+                }
+            }
+            cell.set(ramp);
+        }
+        if finished != 0 {
+            release_overflow_interrupt(cs, timer);
+        }
+    });
+    for (channel, waker) in WAKERS.iter().enumerate() {
+        if finished & 1 << channel != 0 {
+            waker.wake();
+        }
+    }
+}
@@ -0,0 +1,536 @@
+//! Reliable delivery of datagrams over a byte stream.
+//!
+//! Every datagram is sent as a packet carrying a sequence number, its length and a CRC-16:
+//!
+//! ```text
+//! SYNC  kind  seq  len  payload...  crc (little endian)
+//! ```
+//!
+//! The receiver acknowledges packets cumulatively and answers out of order packets with a NAK
+//! for the packet it expects, upon which the sender retransmits all unacknowledged packets
+//! (go-back-N). Unacknowledged packets are also retransmitted when nothing was received for the
+//! retransmit timeout. Duplicates caused by lost acknowledgements are acknowledged again and
+//! dropped.
+//!
+//! The link only makes progress while [`Link::send`], [`Link::recv`] or [`Link::flush`] run, so
+//! both ends should keep receiving while they are idle. The port and the [`Delay`] can be
+//! anything, so the protocol also runs on the host, for example over a simulated lossy link.
+//!
+//! ```ignore
+//! let mut link = Link::<_, _, 38>::new(serial, TimerDelay, Config::default());
+//! link.send(b"ping").await?;
+//! let len = link.recv(&mut buf).await?;
+//! ```
+
+use crate::crc::crc16;
+use crate::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteAllError};
+use crate::time::{with_timeout, Delay, Duration};
+
+const SYNC: u8 = 0x7e;
+const DATA: u8 = 0x01;
+const ACK: u8 = 0x02;
+const NAK: u8 = 0x03;
+
+/// Bytes added to the payload of every packet: sync, kind, sequence number, length and CRC.
+pub const OVERHEAD: usize = 6;
+/// Maximum number of unacknowledged packets.
+pub const MAX_WINDOW: u8 = 4;
+
+/// An error of a [`Link`], wrapping the error type `E` of the port.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Error<E> {
+    Io(E),
+    /// The port returned end of file.
+    UnexpectedEof,
+    /// The port did not accept any more data.
+    WriteZero,
+    /// The peer did not acknowledge a packet after all retransmissions. The packets stay queued,
+    /// so they are retransmitted again by the next call.
+    Timeout,
+    /// The datagram to send does not fit into a packet, or the received datagram did not fit
+    /// into the buffer and was dropped.
+    TooLong,
+}
+
+impl<E> From<WriteAllError<E>> for Error<E> {
+    fn from(err: WriteAllError<E>) -> Self {
+        match err {
+            WriteAllError::WriteZero => Error::WriteZero,
+            WriteAllError::Other(err) => Error::Io(err),
+        }
+    }
+}
+
+/// Parameters of a [`Link`].
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Config {
+    /// How long to wait for a packet before retransmitting unacknowledged packets.
+    pub timeout: Duration,
+    /// How often unacknowledged packets are retransmitted before [`Error::Timeout`] is returned.
+    pub retries: u8,
+    /// Number of packets that may be sent before the first of them is acknowledged, from 1 to
+    /// [`MAX_WINDOW`].
+    pub window: u8,
+}
+
+impl Default for Config {
+    /// A timeout of 200 ms, 5 retries and a window of 2 packets.
+    fn default() -> Self {
+        Config {
+            timeout: Duration::from_millis(200),
+            retries: 5,
+            window: 2,
+        }
+    }
+}
+
+/// A received packet, stored at the start of a [`PacketReader`]'s buffer.
+#[derive(Copy, Clone)]
+struct Packet {
+    kind: u8,
+    seq: u8,
+    len: usize,
+}
+
+/// Collects received bytes in a buffer of `N` bytes and finds valid packets in them.
+struct PacketReader<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    /// Length of the packet returned by the last call to `read`, which is dropped by the next.
+    packet: usize,
+}
+
+impl<const N: usize> PacketReader<N> {
+    fn new() -> Self {
+        PacketReader {
+            buf: [0; N],
+            len: 0,
+            packet: 0,
+        }
+    }
+
+    fn drop_front(&mut self, n: usize) {
+        self.buf.copy_within(n..self.len, 0);
+        self.len -= n;
+    }
+
+    fn payload(&self, packet: &Packet) -> &[u8] {
+        &self.buf[4..4 + packet.len]
+    }
+
+    /// Receives the next valid packet. Bytes that do not belong to a valid packet are skipped
+    /// one at a time, so the reader resynchronizes on the next packet after corrupted data.
+    async fn read<S: AsyncRead + Unpin>(
+        &mut self,
+        port: &mut S,
+    ) -> Result<Packet, Error<S::Error>> {
+        let packet = self.packet;
+        self.packet = 0;
+        self.drop_front(packet);
+        loop {
+            match self.buf[..self.len].iter().position(|&byte| byte == SYNC) {
+                Some(start) => self.drop_front(start),
+                None => self.len = 0,
+            }
+            if self.len >= 4 {
+                let len = self.buf[3] as usize;
+                if len + OVERHEAD > N {
+                    self.drop_front(1);
+                    continue;
+                }
+                if self.len >= len + OVERHEAD {
+                    let end = 4 + len;
+                    let crc = self.buf[end] as u16 | (self.buf[end + 1] as u16) << 8;
+                    if crc16(&self.buf[1..end]) != crc {
+                        self.drop_front(1);
+                        continue;
+                    }
+                    self.packet = len + OVERHEAD;
+                    return Ok(Packet {
+                        kind: self.buf[1],
+                        seq: self.buf[2],
+                        len,
+                    });
+                }
+            }
+            let n = port
+                .read(&mut self.buf[self.len..])
+                .await
+                .map_err(Error::Io)?;
+            if n == 0 {
+                return Err(Error::UnexpectedEof);
+            }
+            self.len += n;
+        }
+    }
+}
+
+/// Writes a packet with `payload` into `buf`, returning its length.
+fn encode(buf: &mut [u8], kind: u8, seq: u8, payload: &[u8]) -> usize {
+    let end = 4 + payload.len();
+    buf[0] = SYNC;
+    buf[1] = kind;
+    buf[2] = seq;
+    buf[3] = payload.len() as u8;
+    buf[4..end].copy_from_slice(payload);
+    let crc = crc16(&buf[1..end]);
+    buf[end] = crc as u8;
+    buf[end + 1] = (crc >> 8) as u8;
+    end + 2
+}
+
+/// A reliable datagram link with packets of up to `N` bytes, carrying up to `N - OVERHEAD` bytes
+/// of payload.
+///
+/// Up to [`MAX_WINDOW`] sent packets and one received datagram are buffered, each in `N` bytes.
+pub struct Link<S, D, const N: usize> {
+    port: S,
+    delay: D,
+    config: Config,
+    reader: PacketReader<N>,
+    /// Encoded packets that have not been acknowledged yet, indexed by sequence number.
+    tx: [[u8; N]; MAX_WINDOW as usize],
+    tx_len: [usize; MAX_WINDOW as usize],
+    /// Sequence number of the oldest unacknowledged packet.
+    tx_base: u8,
+    /// Sequence number of the next packet to send.
+    tx_next: u8,
+    retries: u8,
+    /// Sequence number of the next packet expected from the peer.
+    rx_next: u8,
+    /// A received datagram that has not been returned by `recv` yet.
+    rx: [u8; N],
+    rx_len: Option<usize>,
+}
+
+impl<S, D, const N: usize> Link<S, D, N>
+where
+    S: AsyncRead + AsyncWrite<Error = <S as AsyncRead>::Error> + Unpin,
+    D: Delay,
+{
+    pub fn new(port: S, delay: D, config: Config) -> Self {
+        assert!(N > OVERHEAD && N - OVERHEAD <= u8::MAX as usize);
+        let mut config = config;
+        config.window = config.window.clamp(1, MAX_WINDOW);
+        Link {
+            port,
+            delay,
+            config,
+            reader: PacketReader::new(),
+            tx: [[0; N]; MAX_WINDOW as usize],
+            tx_len: [0; MAX_WINDOW as usize],
+            tx_base: 0,
+            tx_next: 0,
+            retries: config.retries,
+            rx_next: 0,
+            rx: [0; N],
+            rx_len: None,
+        }
+    }
+
+    /// Releases the port and delay.
+    pub fn free(self) -> (S, D) {
+        (self.port, self.delay)
+    }
+
+    fn in_flight(&self) -> u8 {
+        self.tx_next.wrapping_sub(self.tx_base)
+    }
+
+    /// Queues `data` for sending, waiting while the window is full.
+    ///
+    /// Returns once the packet has been sent for the first time. Use [`flush`](Link::flush) to
+    /// wait until all packets have been acknowledged.
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        if data.len() > N - OVERHEAD {
+            return Err(Error::TooLong);
+        }
+        while self.in_flight() >= self.config.window {
+            self.step().await?;
+        }
+        if self.in_flight() == 0 {
+            self.retries = self.config.retries;
+        }
+        let seq = self.tx_next;
+        let slot = (seq % MAX_WINDOW) as usize;
+        self.tx_len[slot] = encode(&mut self.tx[slot], DATA, seq, data);
+        self.tx_next = seq.wrapping_add(1);
+        self.port
+            .write_all(&self.tx[slot][..self.tx_len[slot]])
+            .await?;
+        self.port.flush().await.map_err(Error::Io)
+    }
+
+    /// Waits until all sent packets have been acknowledged.
+    pub async fn flush(&mut self) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        while self.in_flight() > 0 {
+            self.step().await?;
+        }
+        Ok(())
+    }
+
+    /// Receives the next datagram into `buf`, returning its length.
+    pub async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Error<<S as AsyncRead>::Error>> {
+        loop {
+            if let Some(len) = self.rx_len.take() {
+                let dest = buf.get_mut(..len).ok_or(Error::TooLong)?;
+                dest.copy_from_slice(&self.rx[..len]);
+                return Ok(len);
+            }
+            self.step().await?;
+        }
+    }
+
+    /// Handles the next received packet, or retransmits unacknowledged packets if none arrives
+    /// within the timeout.
+    async fn step(&mut self) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        let packet = match with_timeout(
+            self.delay.delay(self.config.timeout),
+            self.reader.read(&mut self.port),
+        )
+        .await
+        {
+            Ok(packet) => packet?,
+            Err(_) => {
+                if self.in_flight() > 0 {
+                    if self.retries == 0 {
+                        self.retries = self.config.retries;
+                        return Err(Error::Timeout);
+                    }
+                    self.retries -= 1;
+                    self.retransmit().await?;
+                }
+                return Ok(());
+            }
+        };
+        match packet.kind {
+            DATA => {
+                let ahead = packet.seq.wrapping_sub(self.rx_next);
+                if ahead == 0 {
+                    if self.rx_len.is_some() {
+                        // No room for it yet, so let the peer retransmit it later.
+                        return Ok(());
+                    }
+                    self.rx[..packet.len].copy_from_slice(self.reader.payload(&packet));
+                    self.rx_len = Some(packet.len);
+                    self.rx_next = self.rx_next.wrapping_add(1);
+                    self.send_control(ACK, self.rx_next).await
+                } else if ahead < 0x80 {
+                    // An earlier packet was lost.
+                    self.send_control(NAK, self.rx_next).await
+                } else {
+                    // A duplicate, whose acknowledgement was lost.
+                    self.send_control(ACK, self.rx_next).await
+                }
+            }
+            ACK | NAK => {
+                // Both acknowledge all packets before `seq`.
+                let acked = packet.seq.wrapping_sub(self.tx_base);
+                if acked > self.in_flight() {
+                    return Ok(());
+                }
+                if acked > 0 {
+                    self.tx_base = packet.seq;
+                    self.retries = self.config.retries;
+                }
+                if packet.kind == NAK && self.in_flight() > 0 {
+                    self.retransmit().await?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn retransmit(&mut self) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        let mut seq = self.tx_base;
+        while seq != self.tx_next {
+            let slot = (seq % MAX_WINDOW) as usize;
+            self.port
+                .write_all(&self.tx[slot][..self.tx_len[slot]])
+                .await?;
+            seq = seq.wrapping_add(1);
+        }
+        self.port.flush().await.map_err(Error::Io)
+    }
+
+    async fn send_control(
+        &mut self,
+        kind: u8,
+        seq: u8,
+    ) -> Result<(), Error<<S as AsyncRead>::Error>> {
+        let mut buf = [0; OVERHEAD];
+        let len = encode(&mut buf, kind, seq, &[]);
+        self.port.write_all(&buf[..len]).await?;
+        self.port.flush().await.map_err(Error::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{block_on, line, now, End, ManualDelay};
+    use core::convert::Infallible;
+    use futures_util::future::{select, Either};
+    use pin_utils::pin_mut;
+    use std::vec::Vec;
+
+    const N: usize = 38;
+
+    type TestLink = Link<End, ManualDelay, N>;
+
+    fn link(port: End, config: Config) -> TestLink {
+        Link::new(port, ManualDelay, config)
+    }
+
+    /// Sends `messages` from `sender` and waits until they are acknowledged, while `receiver`
+    /// collects everything it receives.
+    fn deliver(
+        sender: &mut TestLink,
+        receiver: &mut TestLink,
+        messages: &[Vec<u8>],
+    ) -> (Result<(), Error<Infallible>>, Vec<Vec<u8>>) {
+        let mut received = Vec::new();
+        let result = block_on(async {
+            let send = async {
+                for message in messages {
+                    sender.send(message).await?;
+                }
+                sender.flush().await
+            };
+            let recv = async {
+                let mut buf = [0; N];
+                loop {
+                    let len = receiver.recv(&mut buf).await.unwrap();
+                    received.push(buf[..len].to_vec());
+                }
+            };
+            pin_mut!(send, recv);
+            match select(send, recv).await {
+                Either::Left((result, _)) => result,
+                Either::Right(_) => unreachable!(),
+            }
+        });
+        (result, received)
+    }
+
+    /// Loses the first `n` bytes written.
+    fn lose_first(n: usize) -> impl FnMut(u8) -> Option<u8> {
+        let mut seen = 0;
+        move |byte| {
+            seen += 1;
+            if seen > n {
+                Some(byte)
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Loses or flips about one in 128 bytes, in a fixed pseudo-random pattern.
+    fn noise(seed: u32) -> impl FnMut(u8) -> Option<u8> {
+        let mut state = seed;
+        move |byte| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            match (state >> 16) % 256 {
+                0 => None,
+                1 => Some(byte ^ 0x10),
+                _ => Some(byte),
+            }
+        }
+    }
+
+    fn messages(count: usize) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|i| {
+                (0..i % (N - OVERHEAD + 1))
+                    .map(|j| (i * 7 + j) as u8)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lossy_link_delivers_in_order_exactly_once() {
+        let config = Config {
+            timeout: Duration::from_millis(200),
+            retries: 10,
+            window: MAX_WINDOW,
+        };
+        let (mut a, mut b) = line();
+        a.set_fault(noise(1));
+        b.set_fault(noise(2));
+        let (mut a, mut b) = (link(a, config), link(b, config));
+        let sent = messages(200);
+        let (result, received) = deliver(&mut a, &mut b, &sent);
+        assert_eq!(result, Ok(()));
+        assert_eq!(received, sent);
+        // The same links carry data the other way afterwards.
+        let (result, received) = deliver(&mut b, &mut a, &sent[..50]);
+        assert_eq!(result, Ok(()));
+        assert_eq!(received, &sent[..50]);
+    }
+
+    #[test]
+    fn lost_ack_is_answered_as_duplicate() {
+        let config = Config::default();
+        let (a, mut b) = line();
+        // Lose the acknowledgement of the first packet.
+        b.set_fault(lose_first(OVERHEAD));
+        let (mut a, mut b) = (link(a, config), link(b, config));
+        // A single packet, so no later acknowledgement covers it.
+        let sent = messages(2)[1..].to_vec();
+        let (result, received) = deliver(&mut a, &mut b, &sent);
+        assert_eq!(result, Ok(()));
+        assert_eq!(received, sent);
+        // The packet was retransmitted after the timeout and dropped as duplicate.
+        assert!(now() >= config.timeout.ticks());
+    }
+
+    #[test]
+    fn lost_packet_is_naked() {
+        let config = Config::default();
+        let (mut a, b) = line();
+        let sent = messages(4);
+        // Lose the first packet, so the receiver answers the second with a NAK.
+        a.set_fault(lose_first(sent[0].len() + OVERHEAD));
+        let (mut a, mut b) = (link(a, config), link(b, config));
+        let (result, received) = deliver(&mut a, &mut b, &sent);
+        assert_eq!(result, Ok(()));
+        assert_eq!(received, sent);
+        // The NAK caused the retransmission, not the timeout.
+        assert!(now() < config.timeout.ticks());
+    }
+
+    #[test]
+    fn timeout_keeps_packets_queued() {
+        let config = Config {
+            retries: 2,
+            ..Config::default()
+        };
+        let (a, b) = line();
+        let (mut a, mut b) = (link(a, config), link(b, config));
+        let sent = messages(2);
+        // Nobody receives, so the packets are sent three times before giving up.
+        let result = block_on(async {
+            for message in &sent {
+                a.send(message).await?;
+            }
+            a.flush().await
+        });
+        assert_eq!(result, Err(Error::Timeout));
+        assert_eq!(now(), 3 * config.timeout.ticks());
+        // All copies arrive once the peer receives, but each packet is delivered once.
+        let (result, received) = deliver(&mut a, &mut b, &[]);
+        assert_eq!(result, Ok(()));
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn too_long() {
+        let (a, _b) = line();
+        let mut a = link(a, Config::default());
+        let result = block_on(a.send(&[0; N - OVERHEAD + 1]));
+        assert_eq!(result, Err(Error::TooLong));
+    }
+}
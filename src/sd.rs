@@ -0,0 +1,646 @@
+//! SD and SDHC cards in SPI mode.
+//!
+//! ```ignore
+//! let mut sd = Sd::new(AsyncSpi::new(spi), cs);
+//! sd.init(false).await?;
+//! let mut block = [0; BLOCK_SIZE];
+//! sd.read_block(0, &mut block).await?;
+//! ```
+//!
+//! The SPI clock must not exceed 400 kHz during [`Sd::init`]. Afterwards, the SPI peripheral can
+//! be taken back with [`Sd::free`] and reconfigured for up to 25 MHz.
+//!
+//! Like [`nrf24`](crate::nrf24), the driver takes an [`AsyncSpi`](crate::AsyncSpi) for a bus
+//! of its own, or a [`SharedSpi`](crate::SharedSpi) to share the bus with other drivers, which
+//! is locked from selecting the card until it released the bus after an operation.
+
+use crate::block::{Block, BlockDevice, BLOCK_SIZE};
+use crate::crc::crc16_xmodem;
+use crate::spi::SpiDevice;
+use crate::time::{Duration, Instant};
+use crate::Yield;
+use avr_hal_generic::hal::digital::v2::OutputPin;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::future::poll_fn;
+use futures_util::ready;
+
+const CMD0: u8 = 0;
+const CMD8: u8 = 8;
+const CMD9: u8 = 9;
+const CMD12: u8 = 12;
+const CMD16: u8 = 16;
+const CMD17: u8 = 17;
+const CMD18: u8 = 18;
+const CMD24: u8 = 24;
+const CMD25: u8 = 25;
+const CMD55: u8 = 55;
+const CMD58: u8 = 58;
+const CMD59: u8 = 59;
+const ACMD41: u8 = 41;
+
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+
+/// Starts a data block, except for blocks written with CMD25.
+const DATA_TOKEN: u8 = 0xfe;
+const WRITE_MULTIPLE_TOKEN: u8 = 0xfc;
+const STOP_TRAN_TOKEN: u8 = 0xfd;
+const DATA_ACCEPTED: u8 = 0x05;
+
+const INIT_TIMEOUT: Duration = Duration::from_secs(1);
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+const WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// An error of an [`Sd`] card, wrapping the error types `S` of the SPI and `P` of the chip select
+/// pin.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Error<S, P> {
+    Spi(S),
+    Pin(P),
+    /// The card did not answer in time.
+    Timeout,
+    /// The card answered a command with the given R1 error flags.
+    Command(u8),
+    /// The card answered a read with the given error token.
+    Read(u8),
+    /// The card rejected written data with the given data response.
+    Write(u8),
+    /// The CRC of received data did not match.
+    Crc,
+    /// The card is not an SD card, or does not support 3.3 V.
+    UnsupportedCard,
+    /// The CSD register of the card has an unknown version or describes an impossible capacity.
+    InvalidCsd,
+}
+
+impl<S, P> From<S> for Error<S, P> {
+    fn from(err: S) -> Self {
+        Error::Spi(err)
+    }
+}
+
+type SdError<T, CS> = Error<<T as SpiDevice>::Error, <CS as OutputPin>::Error>;
+
+/// The kind of card, as detected by [`Sd::init`].
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum CardType {
+    /// A version 1 standard capacity card.
+    Sd1,
+    /// A version 2 standard capacity card.
+    Sd2,
+    /// A high or extended capacity card, which is addressed in blocks instead of bytes.
+    Sdhc,
+}
+
+/// Progress of the current operation.
+#[derive(Copy, Clone)]
+enum Phase {
+    Idle,
+    /// Sending a command, preceded by a byte of clocks.
+    Command {
+        pos: u8,
+    },
+    Response {
+        tries: u8,
+    },
+    /// Waiting for the token starting a data block.
+    Token,
+    /// Receiving a data block, followed by its CRC.
+    Receive {
+        pos: usize,
+    },
+    SendToken,
+    /// Sending a data block, followed by its CRC.
+    Send {
+        pos: usize,
+    },
+    DataResponse,
+    /// Waiting until the card finished writing.
+    Busy,
+    /// Clocking out a byte after releasing the card, so that it releases MISO.
+    Release,
+}
+
+/// Returns the CRC7 of a command.
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        for i in 0..8 {
+            crc <<= 1;
+            if ((byte << i) ^ crc) & 0x80 != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc & 0x7f
+}
+
+fn command_bytes(cmd: u8, arg: u32) -> [u8; 6] {
+    let mut bytes = [
+        0x40 | cmd,
+        (arg >> 24) as u8,
+        (arg >> 16) as u8,
+        (arg >> 8) as u8,
+        arg as u8,
+        0,
+    ];
+    bytes[5] = crc7(&bytes[..5]) << 1 | 1;
+    bytes
+}
+
+/// An SD card connected to SPI, selected by the chip select pin `CS`.
+///
+/// Implements [`BlockDevice`] for single block access, and additionally provides reads and
+/// writes of consecutive blocks with a single command.
+///
+/// The futures of the block device keep the progress of an operation in the driver, so they
+/// must be polled until they complete. The async methods release the card and the bus when
+/// their futures are dropped, and discard an abandoned operation of the block device.
+pub struct Sd<T, CS> {
+    spi: T,
+    cs: CS,
+    /// Whether this driver holds the lock of the bus.
+    locked: bool,
+    card: CardType,
+    blocks: u32,
+    crc: bool,
+    phase: Phase,
+    /// When waiting for a data token or for the end of a write started.
+    started: Instant,
+    /// CRC of the data block being received or sent.
+    data_crc: [u8; 2],
+}
+
+impl<T, CS> Sd<T, CS>
+where
+    T: SpiDevice,
+    CS: OutputPin,
+{
+    pub fn new(spi: T, cs: CS) -> Self {
+        Sd {
+            spi,
+            cs,
+            locked: false,
+            card: CardType::Sd1,
+            blocks: 0,
+            crc: false,
+            phase: Phase::Idle,
+            started: Instant::now(),
+            data_crc: [0; 2],
+        }
+    }
+
+    /// Releases the SPI and the chip select pin.
+    pub fn free(self) -> (T, CS) {
+        (self.spi, self.cs)
+    }
+
+    /// Returns the kind of card.
+    pub fn card_type(&self) -> CardType {
+        self.card
+    }
+
+    /// Resets and initializes the card, enabling CRC checks of all transfers if `crc` is set.
+    pub async fn init(&mut self, crc: bool) -> Result<CardType, SdError<T, CS>> {
+        let mut sd = Operation::new(self);
+        sd.crc = crc;
+        // At least 74 clocks with the card deselected enter SPI mode.
+        sd.cs.set_high().map_err(Error::Pin)?;
+        poll_fn(|cx| sd.poll_lock(cx)).await;
+        for _ in 0..10 {
+            sd.transfer(0xff).await?;
+        }
+        let result = sd.init_card().await;
+        sd.finish(result).await
+    }
+
+    async fn init_card(&mut self) -> Result<CardType, SdError<T, CS>> {
+        let mut tries = 0;
+        while self.command(CMD0, 0).await? != R1_IDLE {
+            tries += 1;
+            if tries == 10 {
+                return Err(Error::UnsupportedCard);
+            }
+        }
+
+        let r1 = self.command(CMD8, 0x1aa).await?;
+        let mut card = if r1 & R1_ILLEGAL_COMMAND != 0 {
+            CardType::Sd1
+        } else {
+            let mut r7 = [0; 4];
+            self.receive_into(&mut r7).await?;
+            if r7[3] != 0xaa {
+                return Err(Error::UnsupportedCard);
+            }
+            CardType::Sd2
+        };
+
+        if self.crc {
+            self.expect(CMD59, 1, R1_IDLE).await?;
+        }
+
+        let start = Instant::now();
+        let hcs = if card == CardType::Sd1 { 0 } else { 1 << 30 };
+        loop {
+            self.expect(CMD55, 0, R1_IDLE).await?;
+            match self.command(ACMD41, hcs).await? {
+                0 => break,
+                R1_IDLE if start.elapsed() < INIT_TIMEOUT => Yield::default().await,
+                R1_IDLE => return Err(Error::Timeout),
+                r1 => return Err(Error::Command(r1)),
+            }
+        }
+
+        if card == CardType::Sd2 {
+            self.expect(CMD58, 0, 0).await?;
+            let mut ocr = [0; 4];
+            self.receive_into(&mut ocr).await?;
+            if ocr[0] & 0x40 != 0 {
+                card = CardType::Sdhc;
+            }
+        }
+        if card != CardType::Sdhc {
+            self.expect(CMD16, BLOCK_SIZE as u32, 0).await?;
+        }
+        self.card = card;
+
+        self.expect(CMD9, 0, 0).await?;
+        let mut csd = [0; 16];
+        self.receive_data(&mut csd).await?;
+        let blocks = match csd[0] >> 6 {
+            0 => {
+                let size =
+                    ((csd[6] as u32 & 0x03) << 10) | (csd[7] as u32) << 2 | (csd[8] as u32) >> 6;
+                let mult = ((csd[9] as u32 & 0x03) << 1) | (csd[10] as u32) >> 7;
+                let block_len = csd[5] as u32 & 0x0f;
+                // The capacity is (size + 1) << (mult + 2 + block_len) bytes, in blocks of 512
+                // bytes.
+                (mult + 2 + block_len)
+                    .checked_sub(9)
+                    .and_then(|shift| (size + 1).checked_shl(shift))
+            }
+            1 => {
+                let size = ((csd[7] as u32 & 0x3f) << 16) | (csd[8] as u32) << 8 | csd[9] as u32;
+                // The capacity is (size + 1) * 512 KiB.
+                (size + 1).checked_mul(1024)
+            }
+            // Reserved versions.
+            _ => None,
+        };
+        self.blocks = blocks.ok_or(Error::InvalidCsd)?;
+        Ok(card)
+    }
+
+    /// Reads consecutive blocks starting at `index` into `bufs`.
+    pub async fn read_blocks(
+        &mut self,
+        index: u32,
+        bufs: &mut [Block],
+    ) -> Result<(), SdError<T, CS>> {
+        let mut sd = Operation::new(self);
+        let result = sd.read_multiple(index, bufs).await;
+        sd.finish(result).await
+    }
+
+    async fn read_multiple(
+        &mut self,
+        index: u32,
+        bufs: &mut [Block],
+    ) -> Result<(), SdError<T, CS>> {
+        self.expect(CMD18, self.address(index), 0).await?;
+        for buf in bufs {
+            self.receive_data(buf).await?;
+        }
+        self.expect(CMD12, 0, 0).await?;
+        self.wait_busy().await
+    }
+
+    /// Writes `bufs` to consecutive blocks starting at `index`.
+    pub async fn write_blocks(&mut self, index: u32, bufs: &[Block]) -> Result<(), SdError<T, CS>> {
+        let mut sd = Operation::new(self);
+        let result = sd.write_multiple(index, bufs).await;
+        sd.finish(result).await
+    }
+
+    async fn write_multiple(&mut self, index: u32, bufs: &[Block]) -> Result<(), SdError<T, CS>> {
+        self.expect(CMD25, self.address(index), 0).await?;
+        for buf in bufs {
+            self.phase = Phase::SendToken;
+            poll_fn(|cx| self.poll_send_data(cx, WRITE_MULTIPLE_TOKEN, buf)).await?;
+        }
+        self.transfer(STOP_TRAN_TOKEN).await?;
+        self.transfer(0xff).await?;
+        self.wait_busy().await
+    }
+
+    fn address(&self, index: u32) -> u32 {
+        match self.card {
+            CardType::Sdhc => index,
+            _ => index * BLOCK_SIZE as u32,
+        }
+    }
+
+    async fn transfer(&mut self, byte: u8) -> Result<u8, T::Error> {
+        let spi = &mut self.spi;
+        poll_fn(|cx| spi.poll_transfer(cx, byte)).await
+    }
+
+    /// Receives bytes into `buf` while sending `0xFF`.
+    async fn receive_into(&mut self, buf: &mut [u8]) -> Result<(), T::Error> {
+        for byte in buf {
+            *byte = self.transfer(0xff).await?;
+        }
+        Ok(())
+    }
+
+    async fn command(&mut self, cmd: u8, arg: u32) -> Result<u8, SdError<T, CS>> {
+        self.phase = Phase::Idle;
+        poll_fn(|cx| self.poll_command(cx, cmd, arg)).await
+    }
+
+    /// Sends a command and checks that it is answered with `r1`.
+    async fn expect(&mut self, cmd: u8, arg: u32, r1: u8) -> Result<(), SdError<T, CS>> {
+        match self.command(cmd, arg).await? {
+            response if response == r1 => Ok(()),
+            response => Err(Error::Command(response)),
+        }
+    }
+
+    async fn receive_data(&mut self, buf: &mut [u8]) -> Result<(), SdError<T, CS>> {
+        self.started = Instant::now();
+        self.phase = Phase::Token;
+        poll_fn(|cx| self.poll_receive_data(cx, buf)).await
+    }
+
+    async fn wait_busy(&mut self) -> Result<(), SdError<T, CS>> {
+        self.started = Instant::now();
+        self.phase = Phase::Busy;
+        poll_fn(|cx| self.poll_busy(cx)).await
+    }
+
+    /// Releases the card after an operation, which is aborted if it failed.
+    async fn finish<R>(&mut self, result: Result<R, SdError<T, CS>>) -> Result<R, SdError<T, CS>> {
+        self.phase = Phase::Idle;
+        self.cs.set_high().map_err(Error::Pin)?;
+        let value = result?;
+        self.transfer(0xff).await?;
+        Ok(value)
+    }
+
+    /// Locks the bus for an operation, unless it is already locked.
+    fn poll_lock(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.locked {
+            ready!(self.spi.poll_lock(cx));
+            self.locked = true;
+        }
+        Poll::Ready(())
+    }
+
+    /// Ends the current operation, deselecting the card and unlocking the bus.
+    fn release(&mut self) {
+        self.phase = Phase::Idle;
+        if self.locked {
+            self.locked = false;
+            // Let a byte in progress finish before deselecting the card.
+            self.spi.unlock();
+            let _ = self.cs.set_high();
+        }
+    }
+
+    /// Selects the card and sends a command, returning the R1 response.
+    fn poll_command(
+        &mut self,
+        cx: &mut Context<'_>,
+        cmd: u8,
+        arg: u32,
+    ) -> Poll<Result<u8, SdError<T, CS>>> {
+        loop {
+            match self.phase {
+                Phase::Idle => {
+                    ready!(self.poll_lock(cx));
+                    self.cs.set_low().map_err(Error::Pin)?;
+                    self.phase = Phase::Command { pos: 0 };
+                }
+                Phase::Command { pos } => {
+                    let byte = match pos {
+                        0 => 0xff,
+                        pos => command_bytes(cmd, arg)[pos as usize - 1],
+                    };
+                    ready!(self.spi.poll_transfer(cx, byte))?;
+                    self.phase = match pos {
+                        6 => Phase::Response { tries: 0 },
+                        pos => Phase::Command { pos: pos + 1 },
+                    };
+                }
+                Phase::Response { tries } => {
+                    let r1 = ready!(self.spi.poll_transfer(cx, 0xff))?;
+                    // The response to CMD12 follows a stuff byte.
+                    if r1 & 0x80 == 0 && !(cmd == CMD12 && tries == 0) {
+                        return Poll::Ready(Ok(r1));
+                    }
+                    if tries == 10 {
+                        return Poll::Ready(Err(Error::Timeout));
+                    }
+                    self.phase = Phase::Response { tries: tries + 1 };
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// Receives a data block into `buf` and checks its CRC.
+    fn poll_receive_data(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<(), SdError<T, CS>>> {
+        loop {
+            match self.phase {
+                Phase::Token => match ready!(self.spi.poll_transfer(cx, 0xff))? {
+                    DATA_TOKEN => self.phase = Phase::Receive { pos: 0 },
+                    0xff if self.started.elapsed() < READ_TIMEOUT => {
+                        cx.waker().wake_by_ref();
+                        return Poll::Pending;
+                    }
+                    0xff => return Poll::Ready(Err(Error::Timeout)),
+                    token => return Poll::Ready(Err(Error::Read(token))),
+                },
+                Phase::Receive { pos } => {
+                    let byte = ready!(self.spi.poll_transfer(cx, 0xff))?;
+                    match buf.get_mut(pos) {
+                        Some(slot) => *slot = byte,
+                        None => self.data_crc[pos - buf.len()] = byte,
+                    }
+                    if pos + 1 < buf.len() + 2 {
+                        self.phase = Phase::Receive { pos: pos + 1 };
+                        continue;
+                    }
+                    let crc = (self.data_crc[0] as u16) << 8 | self.data_crc[1] as u16;
+                    if self.crc && crc16_xmodem(buf) != crc {
+                        return Poll::Ready(Err(Error::Crc));
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// Sends a data block starting with `token`, and waits until it has been written.
+    fn poll_send_data(
+        &mut self,
+        cx: &mut Context<'_>,
+        token: u8,
+        buf: &Block,
+    ) -> Poll<Result<(), SdError<T, CS>>> {
+        loop {
+            match self.phase {
+                Phase::SendToken => {
+                    ready!(self.spi.poll_transfer(cx, token))?;
+                    let crc = if self.crc { crc16_xmodem(buf) } else { 0xffff };
+                    self.data_crc = [(crc >> 8) as u8, crc as u8];
+                    self.phase = Phase::Send { pos: 0 };
+                }
+                Phase::Send { pos } => {
+                    let byte = match buf.get(pos) {
+                        Some(&byte) => byte,
+                        None => self.data_crc[pos - BLOCK_SIZE],
+                    };
+                    ready!(self.spi.poll_transfer(cx, byte))?;
+                    self.phase = match pos + 1 {
+                        end if end == BLOCK_SIZE + 2 => Phase::DataResponse,
+                        pos => Phase::Send { pos },
+                    };
+                }
+                Phase::DataResponse => {
+                    let response = ready!(self.spi.poll_transfer(cx, 0xff))?;
+                    if response & 0x1f != DATA_ACCEPTED {
+                        return Poll::Ready(Err(Error::Write(response)));
+                    }
+                    self.started = Instant::now();
+                    self.phase = Phase::Busy;
+                }
+                Phase::Busy => return self.poll_busy(cx),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// Waits until the card no longer holds MISO low.
+    fn poll_busy(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), SdError<T, CS>>> {
+        if ready!(self.spi.poll_transfer(cx, 0xff))? != 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if self.started.elapsed() >= WRITE_TIMEOUT {
+            return Poll::Ready(Err(Error::Timeout));
+        }
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+
+    /// Runs `op` until it completes, then releases the card. Failed operations are aborted.
+    fn poll_operation(
+        &mut self,
+        cx: &mut Context<'_>,
+        op: impl FnOnce(&mut Self, &mut Context<'_>) -> Poll<Result<(), SdError<T, CS>>>,
+    ) -> Poll<Result<(), SdError<T, CS>>> {
+        if !matches!(self.phase, Phase::Release) {
+            let result = ready!(op(self, cx));
+            if let Err(err) = result.and_then(|()| self.cs.set_high().map_err(Error::Pin)) {
+                self.release();
+                return Poll::Ready(Err(err));
+            }
+            self.phase = Phase::Release;
+        }
+        let result = ready!(self.spi.poll_transfer(cx, 0xff));
+        self.release();
+        Poll::Ready(result.map(drop).map_err(Error::Spi))
+    }
+}
+
+/// An async operation on a card, which releases the card and the bus when it completes, fails
+/// or is dropped.
+struct Operation<'a, T: SpiDevice, CS: OutputPin>(&'a mut Sd<T, CS>);
+
+impl<'a, T: SpiDevice, CS: OutputPin> Operation<'a, T, CS> {
+    /// Starts an operation, discarding an operation of the block device which was abandoned.
+    fn new(sd: &'a mut Sd<T, CS>) -> Self {
+        sd.release();
+        Operation(sd)
+    }
+}
+
+impl<T: SpiDevice, CS: OutputPin> Deref for Operation<'_, T, CS> {
+    type Target = Sd<T, CS>;
+
+    fn deref(&self) -> &Sd<T, CS> {
+        self.0
+    }
+}
+
+impl<T: SpiDevice, CS: OutputPin> DerefMut for Operation<'_, T, CS> {
+    fn deref_mut(&mut self) -> &mut Sd<T, CS> {
+        self.0
+    }
+}
+
+impl<T: SpiDevice, CS: OutputPin> Drop for Operation<'_, T, CS> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+impl<T, CS> BlockDevice for Sd<T, CS>
+where
+    T: SpiDevice + Unpin,
+    CS: OutputPin + Unpin,
+{
+    type Error = SdError<T, CS>;
+
+    fn block_count(&self) -> u32 {
+        self.blocks
+    }
+
+    fn poll_read_block(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        index: u32,
+        buf: &mut Block,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.poll_operation(cx, |this, cx| loop {
+            match this.phase {
+                Phase::Idle | Phase::Command { .. } | Phase::Response { .. } => {
+                    match ready!(this.poll_command(cx, CMD17, this.address(index)))? {
+                        0 => {
+                            this.started = Instant::now();
+                            this.phase = Phase::Token;
+                        }
+                        r1 => return Poll::Ready(Err(Error::Command(r1))),
+                    }
+                }
+                _ => return this.poll_receive_data(cx, buf),
+            }
+        })
+    }
+
+    fn poll_write_block(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        index: u32,
+        buf: &Block,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.poll_operation(cx, |this, cx| loop {
+            match this.phase {
+                Phase::Idle | Phase::Command { .. } | Phase::Response { .. } => {
+                    match ready!(this.poll_command(cx, CMD24, this.address(index)))? {
+                        0 => this.phase = Phase::SendToken,
+                        r1 => return Poll::Ready(Err(Error::Command(r1))),
+                    }
+                }
+                _ => return this.poll_send_data(cx, DATA_TOKEN, buf),
+            }
+        })
+    }
+}
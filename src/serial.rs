@@ -0,0 +1,621 @@
+//! Interrupt-driven driver for `USART0`.
+//!
+//! Unlike [`AsyncSerial`](crate::AsyncSerial), which polls an `avr-hal` serial port, this driver
+//! configures the USART itself, buffers received and transmitted data in interrupt handlers and
+//! reports line errors. Received data and errors are queued together, so an [`Error`] is
+//! returned by the read at the point in the stream where it occurred.
+//!
+//! ```ignore
+//! let serial = Serial::new(dp.USART0, Config {
+//!     baudrate: 19200,
+//!     parity: Parity::Even,
+//!     ..Config::default()
+//! });
+//! let (mut rx, mut tx) = serial.split();
+//! ```
+//!
+//! The interrupt handlers are left to the application, which forwards them to this module:
+//!
+//! ```ignore
+//! #[avr_device::interrupt(atmega328p)]
+//! fn USART_RX() {
+//!     async_avr::serial::on_rx_interrupt();
+//! }
+//!
+//! #[avr_device::interrupt(atmega328p)]
+//! fn USART_UDRE() {
+//!     async_avr::serial::on_udre_interrupt();
+//! }
+//!
+//! #[avr_device::interrupt(atmega328p)]
+//! fn USART_TX() {
+//!     async_avr::serial::on_tx_interrupt();
+//! }
+//! ```
+
+use crate::io::{AsyncRead, AsyncWrite};
+use crate::power::{self, Busy, Peripheral};
+use crate::waker::WakerCell;
+use crate::CPU_FREQUENCY;
+use avr_device::atmega328p::{Peripherals, USART0};
+use avr_device::interrupt::{self, Mutex};
+use core::cell::Cell;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::future::poll_fn;
+
+mod rs485;
+pub use rs485::{Rs485, Rs485Error};
+
+const UCSR0A_TXC0: u8 = 1 << 6;
+const UCSR0A_FE0: u8 = 1 << 4;
+const UCSR0A_DOR0: u8 = 1 << 3;
+const UCSR0A_UPE0: u8 = 1 << 2;
+const UCSR0A_U2X0: u8 = 1 << 1;
+
+const UCSR0B_RXCIE0: u8 = 1 << 7;
+const UCSR0B_TXCIE0: u8 = 1 << 6;
+const UCSR0B_UDRIE0: u8 = 1 << 5;
+const UCSR0B_RXEN0: u8 = 1 << 4;
+const UCSR0B_TXEN0: u8 = 1 << 3;
+const UCSR0B_UCSZ02: u8 = 1 << 2;
+const UCSR0B_RXB80: u8 = 1 << 1;
+const UCSR0B_TXB80: u8 = 1 << 0;
+
+const UBRR0_MAX: u32 = 0x0fff;
+
+/// Number of data bits per character.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+    /// Nine data bits. Use [`Rx::read_word`] and [`Tx::write_word`] to access the ninth bit; the
+    /// byte-oriented `AsyncRead` and `AsyncWrite` implementations ignore it.
+    Nine,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// Baudrate and framing of the USART.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Config {
+    /// Clamped to the range the USART can generate, from about 490 baud to 2 Mbaud.
+    pub baudrate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl Default for Config {
+    /// 57600 baud, 8N1.
+    fn default() -> Self {
+        Config {
+            baudrate: 57600,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+impl Config {
+    /// Value of `UCSR0C` for this configuration.
+    fn ucsr0c(&self) -> u8 {
+        let upm = match self.parity {
+            Parity::None => 0b00,
+            Parity::Even => 0b10,
+            Parity::Odd => 0b11,
+        };
+        let usbs = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1,
+        };
+        let ucsz = match self.data_bits {
+            DataBits::Five => 0b00,
+            DataBits::Six => 0b01,
+            DataBits::Seven => 0b10,
+            DataBits::Eight | DataBits::Nine => 0b11,
+        };
+        upm << 4 | usbs << 3 | ucsz << 1
+    }
+
+    /// Value of `UBRR0` in double speed mode, saturated to the range of the register.
+    fn ubrr(&self) -> u16 {
+        let divisor = (CPU_FREQUENCY / 4)
+            .checked_div(self.baudrate)
+            .unwrap_or(u32::MAX);
+        (divisor.saturating_sub(1) / 2).min(UBRR0_MAX) as u16
+    }
+}
+
+/// The kind of a receive [`Error`].
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ErrorKind {
+    /// A character did not end with a valid stop bit. The character was discarded.
+    Framing,
+    /// A character had the wrong parity. The character was discarded.
+    Parity,
+    /// The USART received characters faster than the receive interrupt could handle them, so
+    /// at least one character was lost.
+    Overrun,
+    /// The receive buffer was full, so at least one character was lost.
+    BufferOverflow,
+}
+
+/// A receive error, along with the position in the received stream where it occurred.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Error {
+    pub kind: ErrorKind,
+    /// Number of characters successfully read before the error occurred.
+    pub position: u32,
+}
+
+// Entries in the receive buffer hold a character in the low 9 bits, or an error marker in the
+// high bits.
+const ENTRY_ERROR_SHIFT: u8 = 12;
+const ENTRY_DATA_MASK: u16 = 0x1ff;
+
+fn error_entry(kind: ErrorKind) -> u16 {
+    let code = match kind {
+        ErrorKind::Framing => 1,
+        ErrorKind::Parity => 2,
+        ErrorKind::Overrun => 3,
+        ErrorKind::BufferOverflow => 4,
+    };
+    code << ENTRY_ERROR_SHIFT
+}
+
+fn entry_error(entry: u16) -> Option<ErrorKind> {
+    match entry >> ENTRY_ERROR_SHIFT {
+        0 => None,
+        1 => Some(ErrorKind::Framing),
+        2 => Some(ErrorKind::Parity),
+        3 => Some(ErrorKind::Overrun),
+        _ => Some(ErrorKind::BufferOverflow),
+    }
+}
+
+const BUFFER_SIZE: usize = 32;
+
+struct Ring {
+    buf: [Cell<u16>; BUFFER_SIZE],
+    head: Cell<u8>,
+    len: Cell<u8>,
+}
+
+impl Ring {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY_ENTRY: Cell<u16> = Cell::new(0);
+
+    const fn new() -> Self {
+        Ring {
+            buf: [Self::EMPTY_ENTRY; BUFFER_SIZE],
+            head: Cell::new(0),
+            len: Cell::new(0),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len.get() as usize
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn space(&self) -> usize {
+        BUFFER_SIZE - self.len()
+    }
+
+    fn push(&self, entry: u16) -> bool {
+        if self.space() == 0 {
+            return false;
+        }
+        let tail = (self.head.get() as usize + self.len()) % BUFFER_SIZE;
+        self.buf[tail].set(entry);
+        self.len.set(self.len.get() + 1);
+        true
+    }
+
+    fn peek(&self) -> Option<u16> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.buf[self.head.get() as usize].get())
+        }
+    }
+
+    fn pop(&self) -> Option<u16> {
+        let entry = self.peek()?;
+        self.head
+            .set(((self.head.get() as usize + 1) % BUFFER_SIZE) as u8);
+        self.len.set(self.len.get() - 1);
+        Some(entry)
+    }
+
+    fn clear(&self) {
+        self.head.set(0);
+        self.len.set(0);
+    }
+}
+
+static RX: Mutex<Ring> = Mutex::new(Ring::new());
+/// Set when a character was dropped because the receive buffer was full, until the error could
+/// be queued.
+static RX_LOST: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static TX: Mutex<Ring> = Mutex::new(Ring::new());
+/// Set once the last queued character has left the shift register.
+static TX_DONE: Mutex<Cell<bool>> = Mutex::new(Cell::new(true));
+static RX_WAKER: WakerCell = WakerCell::new();
+static TX_WAKER: WakerCell = WakerCell::new();
+
+/// An interrupt-driven serial port on `USART0`.
+pub struct Serial {
+    rx: Rx,
+    tx: Tx,
+}
+
+impl Serial {
+    /// Configures `USART0` according to `config` and enables the receiver and transmitter.
+    ///
+    /// The `TXD` pin is driven by the USART as soon as the transmitter is enabled, so it does not
+    /// need to be configured as an output.
+    pub fn new(usart: USART0, config: Config) -> Self {
+        interrupt::free(|cs| {
+            RX.borrow(cs).clear();
+            TX.borrow(cs).clear();
+            RX_LOST.borrow(cs).set(false);
+            TX_DONE.borrow(cs).set(true);
+        });
+        let busy = power::busy(Peripheral::Usart0);
+        usart.ucsr0b.write(|w| unsafe { w.bits(0) });
+        usart.ubrr0.write(|w| unsafe { w.bits(config.ubrr()) });
+        usart.ucsr0a.write(|w| unsafe { w.bits(UCSR0A_U2X0) });
+        usart.ucsr0c.write(|w| unsafe { w.bits(config.ucsr0c()) });
+        let ucsz02 = if config.data_bits == DataBits::Nine {
+            UCSR0B_UCSZ02
+        } else {
+            0
+        };
+        usart
+            .ucsr0b
+            .write(|w| unsafe { w.bits(UCSR0B_RXCIE0 | UCSR0B_RXEN0 | UCSR0B_TXEN0 | ucsz02) });
+        Serial {
+            rx: Rx {
+                position: 0,
+                _busy: busy,
+            },
+            tx: Tx {
+                _busy: power::busy(Peripheral::Usart0),
+            },
+        }
+    }
+
+    /// Splits the serial port into its receiving and transmitting halves.
+    pub fn split(self) -> (Rx, Tx) {
+        (self.rx, self.tx)
+    }
+}
+
+impl AsyncRead for Serial {
+    type Error = Error;
+
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.rx).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Serial {
+    type Error = Error;
+
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.tx).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.tx).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.tx).poll_close(cx)
+    }
+}
+
+/// The receiving half of a [`Serial`] port.
+pub struct Rx {
+    position: u32,
+    _busy: Busy,
+}
+
+impl Rx {
+    /// Reads received characters into `buf` using `store(index, character)`.
+    ///
+    /// Stops before an error, so that errors are only ever returned by a read that did not
+    /// return any characters.
+    fn poll_read_with(
+        &mut self,
+        cx: &mut Context<'_>,
+        len: usize,
+        mut store: impl FnMut(usize, u16),
+    ) -> Poll<Result<usize, Error>> {
+        if len == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        RX_WAKER.register(cx.waker());
+        let result = interrupt::free(|cs| {
+            let rx = RX.borrow(cs);
+            let mut n = 0;
+            while n < len {
+                let entry = match rx.peek() {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                if let Some(kind) = entry_error(entry) {
+                    if n == 0 {
+                        rx.pop();
+                        return Err(kind);
+                    }
+                    break;
+                }
+                rx.pop();
+                store(n, entry & ENTRY_DATA_MASK);
+                n += 1;
+            }
+            Ok(n)
+        });
+        match result {
+            Ok(0) => Poll::Pending,
+            Ok(n) => {
+                self.position = self.position.wrapping_add(n as u32);
+                Poll::Ready(Ok(n))
+            }
+            Err(kind) => Poll::Ready(Err(Error {
+                kind,
+                position: self.position,
+            })),
+        }
+    }
+
+    /// Attempts to read received characters, including their ninth bit, into `buf`.
+    pub fn poll_read_words(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u16],
+    ) -> Poll<Result<usize, Error>> {
+        self.poll_read_with(cx, buf.len(), |i, word| buf[i] = word)
+    }
+
+    /// Reads a single character, including its ninth bit.
+    pub async fn read_word(&mut self) -> Result<u16, Error> {
+        let mut word = [0];
+        poll_fn(|cx| self.poll_read_words(cx, &mut word)).await?;
+        Ok(word[0])
+    }
+
+    /// Discards all received characters and errors that have not been read yet.
+    pub fn clear(&mut self) {
+        interrupt::free(|cs| {
+            RX.borrow(cs).clear();
+            RX_LOST.borrow(cs).set(false);
+        })
+    }
+}
+
+impl AsyncRead for Rx {
+    type Error = Error;
+
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Error>> {
+        self.get_mut()
+            .poll_read_with(cx, buf.len(), |i, word| buf[i] = word as u8)
+    }
+}
+
+/// The transmitting half of a [`Serial`] port.
+pub struct Tx {
+    _busy: Busy,
+}
+
+impl Tx {
+    fn poll_write_with(
+        &mut self,
+        cx: &mut Context<'_>,
+        len: usize,
+        load: impl Fn(usize) -> u16,
+    ) -> Poll<Result<usize, Error>> {
+        if len == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        TX_WAKER.register(cx.waker());
+        let n = interrupt::free(|cs| {
+            let tx = TX.borrow(cs);
+            let n = len.min(tx.space());
+            for i in 0..n {
+                tx.push(load(i));
+            }
+            if n > 0 {
+                let dp = unsafe { Peripherals::steal() };
+                dp.USART0
+                    .ucsr0b
+                    .modify(|r, w| unsafe { w.bits(r.bits() | UCSR0B_UDRIE0) });
+            }
+            n
+        });
+        if n == 0 {
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    /// Attempts to queue characters, including their ninth bit, for transmission.
+    pub fn poll_write_words(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u16],
+    ) -> Poll<Result<usize, Error>> {
+        self.poll_write_with(cx, buf.len(), |i| buf[i])
+    }
+
+    /// Queues a single character, including its ninth bit, for transmission.
+    pub async fn write_word(&mut self, word: u16) -> Result<(), Error> {
+        poll_fn(|cx| self.poll_write_words(cx, &[word])).await?;
+        Ok(())
+    }
+
+    /// Attempts to wait until all queued characters, including their stop bits, have been
+    /// shifted out.
+    ///
+    /// Unlike [`poll_flush`](AsyncWrite::poll_flush), which completes once the last character
+    /// was handed to the USART, this waits for the Transmit Complete interrupt.
+    pub fn poll_transmit_complete(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        TX_WAKER.register(cx.waker());
+        interrupt::free(|cs| {
+            if TX.borrow(cs).is_empty() && TX_DONE.borrow(cs).get() {
+                return Poll::Ready(());
+            }
+            let dp = unsafe { Peripherals::steal() };
+            dp.USART0
+                .ucsr0b
+                .modify(|r, w| unsafe { w.bits(r.bits() | UCSR0B_TXCIE0) });
+            Poll::Pending
+        })
+    }
+
+    /// Waits until all queued characters, including their stop bits, have been shifted out.
+    pub async fn transmit_complete(&mut self) {
+        poll_fn(|cx| self.poll_transmit_complete(cx)).await
+    }
+}
+
+impl AsyncWrite for Tx {
+    type Error = Error;
+
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        self.get_mut()
+            .poll_write_with(cx, buf.len(), |i| buf[i] as u16)
+    }
+
+    /// Waits until all queued characters have been handed to the USART.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        TX_WAKER.register(cx.waker());
+        if interrupt::free(|cs| TX.borrow(cs).is_empty()) {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// Receives a character. Must be called from the `USART_RX` interrupt handler.
+pub fn on_rx_interrupt() {
+    interrupt::free(|cs| {
+        let dp = unsafe { Peripherals::steal() };
+        // The status flags and the ninth bit must be read before UDR0.
+        let status = dp.USART0.ucsr0a.read().bits();
+        let ucsr0b = dp.USART0.ucsr0b.read().bits();
+        let data = dp.USART0.udr0.read().bits();
+        let word = if ucsr0b & UCSR0B_RXB80 != 0 {
+            0x100 | data as u16
+        } else {
+            data as u16
+        };
+
+        let rx = RX.borrow(cs);
+        let lost = RX_LOST.borrow(cs);
+        if lost.get() {
+            if !rx.push(error_entry(ErrorKind::BufferOverflow)) {
+                return;
+            }
+            lost.set(false);
+        }
+        let queued = if status & UCSR0A_FE0 != 0 {
+            rx.push(error_entry(ErrorKind::Framing))
+        } else if status & UCSR0A_UPE0 != 0 {
+            rx.push(error_entry(ErrorKind::Parity))
+        } else if status & UCSR0A_DOR0 != 0 {
+            // The characters before this one were lost, but this one is intact.
+            rx.space() >= 2 && rx.push(error_entry(ErrorKind::Overrun)) && rx.push(word)
+        } else {
+            rx.push(word)
+        };
+        if !queued {
+            lost.set(true);
+        }
+    });
+    RX_WAKER.wake();
+}
+
+/// Transmits the next character. Must be called from the `USART_UDRE` interrupt handler.
+pub fn on_udre_interrupt() {
+    interrupt::free(|cs| {
+        let dp = unsafe { Peripherals::steal() };
+        let tx = TX.borrow(cs);
+        if let Some(word) = tx.pop() {
+            // The ninth bit must be written before UDR0.
+            dp.USART0.ucsr0b.modify(|r, w| unsafe {
+                if word & 0x100 != 0 {
+                    w.bits(r.bits() | UCSR0B_TXB80)
+                } else {
+                    w.bits(r.bits() & !UCSR0B_TXB80)
+                }
+            });
+            // Writing a one clears TXC0; the error flags must be written as zero.
+            dp.USART0
+                .ucsr0a
+                .modify(|r, w| unsafe { w.bits(r.bits() & UCSR0A_U2X0 | UCSR0A_TXC0) });
+            TX_DONE.borrow(cs).set(false);
+            dp.USART0.udr0.write(|w| unsafe { w.bits(word as u8) });
+        }
+        if tx.is_empty() {
+            dp.USART0
+                .ucsr0b
+                .modify(|r, w| unsafe { w.bits(r.bits() & !UCSR0B_UDRIE0) });
+        }
+    });
+    TX_WAKER.wake();
+}
+
+/// Marks the transmission as complete. Must be called from the `USART_TX` interrupt handler.
+pub fn on_tx_interrupt() {
+    interrupt::free(|cs| {
+        TX_DONE.borrow(cs).set(true);
+        let dp = unsafe { Peripherals::steal() };
+        dp.USART0
+            .ucsr0b
+            .modify(|r, w| unsafe { w.bits(r.bits() & !UCSR0B_TXCIE0) });
+    });
+    TX_WAKER.wake();
+}
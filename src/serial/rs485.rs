@@ -0,0 +1,122 @@
+use super::{Error, Rx, Serial, Tx};
+use crate::io::{AsyncRead, AsyncWrite};
+use avr_hal_generic::hal::digital::v2::OutputPin;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::ready;
+
+/// An error of an [`Rs485`] port.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Rs485Error<E> {
+    Serial(Error),
+    /// Driving the driver enable pin failed.
+    Pin(E),
+}
+
+impl<E> From<Error> for Rs485Error<E> {
+    fn from(err: Error) -> Self {
+        Rs485Error::Serial(err)
+    }
+}
+
+/// A half-duplex RS-485 port on top of a [`Serial`] port.
+///
+/// The driver enable pin `DE` (usually tied to the inverted receiver enable `RE`) is asserted
+/// before the first character of a message is queued and released once
+/// [`flush`](crate::io::AsyncWriteExt::flush) has waited for the last stop bit to leave the shift
+/// register, so every message must be flushed.
+///
+/// If the receiver stays enabled while transmitting, the port receives its own messages. With
+/// echo suppression enabled, as many received characters as were transmitted are discarded.
+pub struct Rs485<P> {
+    rx: Rx,
+    tx: Tx,
+    de: P,
+    transmitting: bool,
+    suppress_echo: bool,
+    echo: usize,
+}
+
+impl<P: OutputPin> Rs485<P> {
+    /// Creates an RS-485 port driving `de`, which is released immediately.
+    pub fn new(serial: Serial, mut de: P) -> Result<Self, P::Error> {
+        de.set_low()?;
+        let (rx, tx) = serial.split();
+        Ok(Rs485 {
+            rx,
+            tx,
+            de,
+            transmitting: false,
+            suppress_echo: false,
+            echo: 0,
+        })
+    }
+
+    /// Enables or disables discarding the local echo of transmitted characters.
+    pub fn set_echo_suppression(&mut self, suppress: bool) {
+        self.suppress_echo = suppress;
+        self.echo = 0;
+    }
+
+    /// Releases the serial port and the driver enable pin.
+    pub fn free(self) -> (Rx, Tx, P) {
+        (self.rx, self.tx, self.de)
+    }
+}
+
+impl<P: OutputPin + Unpin> AsyncRead for Rs485<P> {
+    type Error = Rs485Error<P::Error>;
+
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Self::Error>> {
+        let this = &mut *self;
+        while this.echo > 0 {
+            let mut discard = [0; 8];
+            let len = this.echo.min(discard.len());
+            let n = ready!(Pin::new(&mut this.rx).poll_read(cx, &mut discard[..len]))?;
+            this.echo -= n;
+        }
+        Poll::Ready(Ok(ready!(Pin::new(&mut this.rx).poll_read(cx, buf))?))
+    }
+}
+
+impl<P: OutputPin + Unpin> AsyncWrite for Rs485<P> {
+    type Error = Rs485Error<P::Error>;
+
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Self::Error>> {
+        let this = &mut *self;
+        if !this.transmitting && !buf.is_empty() {
+            this.de.set_high().map_err(Rs485Error::Pin)?;
+            this.transmitting = true;
+        }
+        let n = ready!(Pin::new(&mut this.tx).poll_write(cx, buf))?;
+        if this.suppress_echo {
+            this.echo += n;
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    /// Waits until the message has been transmitted completely, then releases the driver enable
+    /// pin.
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = &mut *self;
+        ready!(Pin::new(&mut this.tx).poll_flush(cx))?;
+        ready!(this.tx.poll_transmit_complete(cx));
+        if this.transmitting {
+            this.de.set_low().map_err(Rs485Error::Pin)?;
+            this.transmitting = false;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
@@ -0,0 +1,316 @@
+//! An interactive command line over any [`AsyncRead`] and [`AsyncWrite`] pair.
+//!
+//! The shell reads a line with basic editing: backspace deletes the last character, Ctrl-C
+//! discards the line, and the up and down arrow keys browse the last `H` lines. The line is then
+//! split into whitespace separated arguments, where double quotes group words containing spaces,
+//! and the first argument is looked up in a static table of [`Command`]s. The built in `help`
+//! command lists all commands with their help text.
+//!
+//! The handler of the command is returned to the caller together with the arguments and the
+//! output, so commands are ordinary async code and a long running command does not block other
+//! tasks:
+//!
+//! ```ignore
+//! #[derive(Copy, Clone)]
+//! enum Cmd { Led, Echo }
+//!
+//! static COMMANDS: &[Command<Cmd>] = &[
+//!     Command { name: "led", help: "led on|off", handler: Cmd::Led },
+//!     Command { name: "echo", help: "print the arguments", handler: Cmd::Echo },
+//! ];
+//!
+//! let mut shell = Shell::<_, _, _, 64, 4>::new(rx, tx, COMMANDS, "> ");
+//! loop {
+//!     let command = shell.next_command().await?;
+//!     match command.handler {
+//!         Cmd::Led => led(command.args, command.output).await?,
+//!         Cmd::Echo => echo(command.args, command.output).await?,
+//!     }
+//! }
+//! ```
+
+use crate::io::bytes::ByteReader;
+use crate::io::{AsyncRead, AsyncWrite, AsyncWriteExt, FrameError, WriteAllError};
+
+const CTRL_C: u8 = 0x03;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+const ESC: u8 = 0x1b;
+
+/// An error of a [`Shell`], wrapping the error type `E` of the input and output.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Error<E> {
+    Io(E),
+    /// The input returned end of file.
+    UnexpectedEof,
+    /// The output did not accept any more data.
+    WriteZero,
+}
+
+impl<E> From<WriteAllError<E>> for Error<E> {
+    fn from(err: WriteAllError<E>) -> Self {
+        match err {
+            WriteAllError::WriteZero => Error::WriteZero,
+            WriteAllError::Other(err) => Error::Io(err),
+        }
+    }
+}
+
+impl<E> From<FrameError<E>> for Error<E> {
+    fn from(err: FrameError<E>) -> Self {
+        match err {
+            FrameError::Io(err) => Error::Io(err),
+            FrameError::WriteZero => Error::WriteZero,
+            _ => Error::UnexpectedEof,
+        }
+    }
+}
+
+/// An entry of the command table.
+#[derive(Debug, Copy, Clone)]
+pub struct Command<T> {
+    pub name: &'static str,
+    /// A one line description, printed by `help`.
+    pub help: &'static str,
+    /// Returned to the caller when the command is entered, such as an enum variant.
+    pub handler: T,
+}
+
+/// The arguments of a command, not including the command name.
+#[derive(Debug, Clone)]
+pub struct Args<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Args<'a> {
+    /// Splits `line`, which only contains printable ASCII characters.
+    fn new(line: &'a [u8]) -> Self {
+        Args {
+            rest: core::str::from_utf8(line).unwrap_or(""),
+        }
+    }
+
+    /// Returns the unparsed rest of the line.
+    pub fn as_str(&self) -> &'a str {
+        self.rest.trim_start_matches(' ')
+    }
+}
+
+impl<'a> Iterator for Args<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let rest = self.rest.trim_start_matches(' ');
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            self.rest = quoted.get(end + 1..).unwrap_or("");
+            return Some(&quoted[..end]);
+        }
+        if rest.is_empty() {
+            self.rest = rest;
+            return None;
+        }
+        let end = rest.find(' ').unwrap_or(rest.len());
+        self.rest = &rest[end..];
+        Some(&rest[..end])
+    }
+}
+
+/// A command entered by the user.
+pub struct Invocation<'a, T, W> {
+    pub handler: T,
+    pub args: Args<'a>,
+    /// The output of the shell, for the command to print its results.
+    pub output: &'a mut W,
+}
+
+/// An interactive shell reading from `R` and echoing to `W`, with lines of up to `N` characters
+/// and a history of `H` lines.
+pub struct Shell<R, W, T: 'static, const N: usize, const H: usize> {
+    input: ByteReader<R>,
+    output: W,
+    commands: &'static [Command<T>],
+    prompt: &'static str,
+    line: [u8; N],
+    len: usize,
+    history: [[u8; N]; H],
+    history_len: [usize; H],
+    /// Number of lines in `history`.
+    history_count: usize,
+    /// Index in `history` where the next line is stored.
+    history_next: usize,
+    /// How many lines back the history is browsed, or 0 while editing a new line.
+    browsing: usize,
+    /// Number of bytes of an escape sequence received so far.
+    escape: u8,
+    /// Whether the last byte was a carriage return, so that a following line feed is ignored.
+    after_cr: bool,
+}
+
+impl<R, W, T, const N: usize, const H: usize> Shell<R, W, T, N, H>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite<Error = R::Error> + Unpin,
+    T: Copy + 'static,
+{
+    pub fn new(input: R, output: W, commands: &'static [Command<T>], prompt: &'static str) -> Self {
+        Shell {
+            input: ByteReader::new(input),
+            output,
+            commands,
+            prompt,
+            line: [0; N],
+            len: 0,
+            history: [[0; N]; H],
+            history_len: [0; H],
+            history_count: 0,
+            history_next: 0,
+            browsing: 0,
+            escape: 0,
+            after_cr: false,
+        }
+    }
+
+    /// Releases the input and output. Input that was received but not processed yet is lost.
+    pub fn free(self) -> (R, W) {
+        (self.input.into_inner(), self.output)
+    }
+
+    /// Prints the prompt and reads lines until one contains a command, which is returned.
+    ///
+    /// Empty lines are ignored, and `help` and unknown commands are answered by the shell.
+    pub async fn next_command(&mut self) -> Result<Invocation<'_, T, W>, Error<R::Error>> {
+        let handler = self.read_command().await?;
+        let mut args = Args::new(&self.line[..self.len]);
+        args.next();
+        Ok(Invocation {
+            handler,
+            args,
+            output: &mut self.output,
+        })
+    }
+
+    /// Reads lines until one contains a command from the table, and returns its handler.
+    async fn read_command(&mut self) -> Result<T, Error<R::Error>> {
+        loop {
+            self.read_line().await?;
+            let name = match Args::new(&self.line[..self.len]).next() {
+                Some(name) => name,
+                None => continue,
+            };
+            if let Some(command) = self.commands.iter().find(|c| c.name == name) {
+                return Ok(command.handler);
+            }
+            if name == "help" {
+                print_help(&mut self.output, self.commands).await?;
+            } else {
+                self.output.write_all(b"unknown command: ").await?;
+                self.output.write_all(name.as_bytes()).await?;
+                self.output.write_all(b", try help\r\n").await?;
+            }
+        }
+    }
+
+    /// Prints the prompt and reads a line into `line`, adding it to the history.
+    async fn read_line(&mut self) -> Result<(), Error<R::Error>> {
+        self.len = 0;
+        self.browsing = 0;
+        self.escape = 0;
+        self.output.write_all(self.prompt.as_bytes()).await?;
+        loop {
+            self.output.flush().await.map_err(Error::Io)?;
+            let byte = self.input.next().await?;
+            let after_cr = self.after_cr;
+            self.after_cr = byte == b'\r';
+            if self.escape > 0 {
+                self.escape_sequence(byte).await?;
+                continue;
+            }
+            match byte {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    self.output.write_all(b"\r\n").await?;
+                    self.push_history();
+                    return Ok(());
+                }
+                BACKSPACE | DELETE if self.len > 0 => {
+                    self.len -= 1;
+                    self.output.write_all(b"\x08 \x08").await?;
+                }
+                CTRL_C => {
+                    self.len = 0;
+                    self.browsing = 0;
+                    self.output.write_all(b"^C\r\n").await?;
+                    self.output.write_all(self.prompt.as_bytes()).await?;
+                }
+                ESC => self.escape = 1,
+                b' '..=b'~' if self.len < N => {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                    self.output.write_all(&[byte]).await?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Handles a byte of an ANSI escape sequence, of which only the arrow keys up and down are
+    /// supported.
+    async fn escape_sequence(&mut self, byte: u8) -> Result<(), Error<R::Error>> {
+        if self.escape == 1 {
+            self.escape = if byte == b'[' { 2 } else { 0 };
+            return Ok(());
+        }
+        self.escape = 0;
+        match byte {
+            b'A' if self.browsing < self.history_count => self.browsing += 1,
+            b'B' if self.browsing > 0 => self.browsing -= 1,
+            _ => return Ok(()),
+        }
+        if self.browsing == 0 {
+            self.len = 0;
+        } else {
+            let index = (self.history_next + H - self.browsing) % H;
+            self.len = self.history_len[index];
+            self.line[..self.len].copy_from_slice(&self.history[index][..self.len]);
+        }
+        self.output.write_all(b"\r").await?;
+        self.output.write_all(self.prompt.as_bytes()).await?;
+        self.output.write_all(&self.line[..self.len]).await?;
+        self.output.write_all(b"\x1b[K").await?;
+        Ok(())
+    }
+
+    fn push_history(&mut self) {
+        if H == 0 || self.len == 0 {
+            return;
+        }
+        let line = &self.line[..self.len];
+        if self.history_count > 0 {
+            let last = (self.history_next + H - 1) % H;
+            if &self.history[last][..self.history_len[last]] == line {
+                return;
+            }
+        }
+        self.history[self.history_next][..self.len].copy_from_slice(line);
+        self.history_len[self.history_next] = self.len;
+        self.history_next = (self.history_next + 1) % H;
+        self.history_count = (self.history_count + 1).min(H);
+    }
+}
+
+async fn print_help<W, T>(output: &mut W, commands: &[Command<T>]) -> Result<(), Error<W::Error>>
+where
+    W: AsyncWrite + Unpin,
+{
+    let width = commands.iter().map(|c| c.name.len()).max().unwrap_or(0);
+    for command in commands {
+        output.write_all(command.name.as_bytes()).await?;
+        for _ in command.name.len()..width + 2 {
+            output.write_all(b" ").await?;
+        }
+        output.write_all(command.help.as_bytes()).await?;
+        output.write_all(b"\r\n").await?;
+    }
+    Ok(())
+}
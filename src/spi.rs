@@ -1,21 +1,112 @@
 use crate::io;
 
 use avr_hal_generic::hal;
+use avr_hal_generic::hal::digital::v2::OutputPin;
 use avr_hal_generic::nb;
+use core::cell::{Cell, RefCell};
 use core::pin::Pin;
 use core::task::{Context, Poll};
+use futures_util::future::poll_fn;
 
-pub struct AsyncSpi<T>(T);
+/// An `avr-hal` SPI bus with async transfers.
+///
+/// The bus has no interrupt to wait for, so while a transfer is in progress, the task is woken
+/// again right away and the executor keeps polling instead of sleeping. At the usual clock
+/// rates a byte takes only a few microseconds, which makes sleeping pointless anyway.
+pub struct AsyncSpi<T> {
+    spi: T,
+    /// Whether a byte of `poll_transfer` has been sent and its answer not been read yet.
+    sent: bool,
+}
 
 impl<T> AsyncSpi<T> {
     pub fn new(serial: T) -> Self {
         serial.into()
     }
+
+    pub fn free(self) -> T {
+        self.spi
+    }
 }
 
 impl<T> From<T> for AsyncSpi<T> {
     fn from(serial: T) -> Self {
-        AsyncSpi(serial)
+        AsyncSpi {
+            spi: serial,
+            sent: false,
+        }
+    }
+}
+
+impl<T: hal::spi::FullDuplex<u8>> AsyncSpi<T> {
+    /// Sends `byte` and receives the byte clocked in at the same time.
+    ///
+    /// Once this returned `Poll::Pending`, it must be called again until it is ready, and the
+    /// byte passed to later calls is ignored.
+    pub fn poll_transfer(&mut self, cx: &mut Context<'_>, byte: u8) -> Poll<Result<u8, T::Error>> {
+        if !self.sent {
+            match self.spi.send(byte) {
+                Ok(()) => self.sent = true,
+                Err(nb::Error::WouldBlock) => {
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                Err(nb::Error::Other(err)) => return Poll::Ready(Err(err)),
+            }
+        }
+        match self.spi.read() {
+            Ok(byte) => {
+                self.sent = false;
+                Poll::Ready(Ok(byte))
+            }
+            Err(nb::Error::WouldBlock) => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Err(nb::Error::Other(err)) => {
+                self.sent = false;
+                Poll::Ready(Err(err))
+            }
+        }
+    }
+
+    /// Sends `byte` and returns the byte received at the same time.
+    pub async fn transfer(&mut self, byte: u8) -> Result<u8, T::Error> {
+        poll_fn(|cx| self.poll_transfer(cx, byte)).await
+    }
+
+    /// Waits for the answer to a byte of `poll_transfer` which was abandoned while pending, so
+    /// that the next transfer sends its own byte.
+    fn finish(&mut self) {
+        if self.sent {
+            // The byte takes only a few microseconds, and its error does not matter anymore.
+            while let Err(nb::Error::WouldBlock) = self.spi.read() {}
+            self.sent = false;
+        }
+    }
+
+    /// Sends the bytes of `buf`, replacing each with the byte received at the same time.
+    pub async fn transfer_in_place(&mut self, buf: &mut [u8]) -> Result<(), T::Error> {
+        for byte in buf {
+            *byte = self.transfer(*byte).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends `data`, discarding the received bytes.
+    pub async fn send_all(&mut self, data: &[u8]) -> Result<(), T::Error> {
+        for &byte in data {
+            self.transfer(byte).await?;
+        }
+        Ok(())
+    }
+
+    /// Receives bytes into `buf` while sending `0xFF`.
+    pub async fn receive_into(&mut self, buf: &mut [u8]) -> Result<(), T::Error> {
+        for byte in buf {
+            *byte = self.transfer(0xff).await?;
+        }
+        Ok(())
     }
 }
 
@@ -24,16 +115,19 @@ impl<T: hal::spi::FullDuplex<u8> + Unpin> io::AsyncRead for AsyncSpi<T> {
 
     fn poll_read(
         mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<Result<usize, T::Error>> {
         if let Some(ptr) = buf.first_mut() {
-            match self.0.read() {
+            match self.spi.read() {
                 Ok(byte) => {
                     *ptr = byte;
                     Poll::Ready(Ok(1))
                 }
-                Err(nb::Error::WouldBlock) => Poll::Pending,
+                Err(nb::Error::WouldBlock) => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
                 Err(nb::Error::Other(err)) => Poll::Ready(Err(err)),
             }
         } else {
@@ -47,13 +141,16 @@ impl<T: hal::spi::FullDuplex<u8> + Unpin> io::AsyncWrite for AsyncSpi<T> {
 
     fn poll_write(
         mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, T::Error>> {
         if let Some(byte) = buf.first() {
-            match self.0.send(*byte) {
+            match self.spi.send(*byte) {
                 Ok(()) => Poll::Ready(Ok(1)),
-                Err(nb::Error::WouldBlock) => Poll::Pending,
+                Err(nb::Error::WouldBlock) => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
                 Err(nb::Error::Other(err)) => Poll::Ready(Err(err)),
             }
         } else {
@@ -69,3 +166,192 @@ impl<T: hal::spi::FullDuplex<u8> + Unpin> io::AsyncWrite for AsyncSpi<T> {
         Poll::Ready(Ok(()))
     }
 }
+
+/// An SPI bus which drivers lock for the duration of each transaction.
+///
+/// Drivers generic over this trait, like [`Nrf24`](crate::nrf24::Nrf24), take an [`AsyncSpi`]
+/// for a bus of their own, or a [`SharedSpi`] to share it with other drivers.
+pub trait SpiDevice {
+    type Error;
+
+    /// Locks the bus, or returns `Poll::Pending` while another driver holds it.
+    fn poll_lock(&mut self, cx: &mut Context<'_>) -> Poll<()>;
+
+    /// Unlocks the bus after a transaction, first waiting for a byte whose transfer was
+    /// abandoned, because the future of a driver was dropped.
+    fn unlock(&mut self);
+
+    /// Sends `byte` and receives the byte clocked in at the same time, while the bus is locked,
+    /// like [`AsyncSpi::poll_transfer`].
+    fn poll_transfer(&mut self, cx: &mut Context<'_>, byte: u8) -> Poll<Result<u8, Self::Error>>;
+}
+
+impl<T: hal::spi::FullDuplex<u8>> SpiDevice for AsyncSpi<T> {
+    type Error = T::Error;
+
+    fn poll_lock(&mut self, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+
+    fn unlock(&mut self) {
+        self.finish();
+    }
+
+    fn poll_transfer(&mut self, cx: &mut Context<'_>, byte: u8) -> Poll<Result<u8, T::Error>> {
+        AsyncSpi::poll_transfer(self, cx, byte)
+    }
+}
+
+/// An SPI bus shared by several drivers through [`SharedSpi`] handles.
+///
+/// ```ignore
+/// let bus = SpiBus::new(spi);
+/// let mut radio = Nrf24::new(SharedSpi::new(&bus), csn, ce, irq);
+/// let mut other = Nrf24::new(SharedSpi::new(&bus), csn2, ce2, irq2);
+/// ```
+pub struct SpiBus<T> {
+    spi: RefCell<AsyncSpi<T>>,
+    locked: Cell<bool>,
+}
+
+impl<T> SpiBus<T> {
+    pub fn new(spi: T) -> Self {
+        SpiBus {
+            spi: RefCell::new(AsyncSpi::new(spi)),
+            locked: Cell::new(false),
+        }
+    }
+
+    pub fn free(self) -> T {
+        self.spi.into_inner().free()
+    }
+}
+
+/// A handle to a [`SpiBus`] shared by several drivers.
+///
+/// A driver keeps the bus locked from selecting its device until deselecting it, so the
+/// transactions of the drivers do not interleave even though they await every byte. Like the
+/// transfers of [`AsyncSpi`], waiting for the lock wakes the task again right away, which costs
+/// little because transactions take only a few microseconds.
+pub struct SharedSpi<'a, T> {
+    bus: &'a SpiBus<T>,
+    /// Whether this handle holds the lock.
+    locked: bool,
+}
+
+impl<'a, T> SharedSpi<'a, T> {
+    pub fn new(bus: &'a SpiBus<T>) -> Self {
+        SharedSpi { bus, locked: false }
+    }
+}
+
+impl<T> Clone for SharedSpi<'_, T> {
+    /// Returns another handle to the bus, which does not hold the lock.
+    fn clone(&self) -> Self {
+        SharedSpi::new(self.bus)
+    }
+}
+
+impl<T: hal::spi::FullDuplex<u8>> SpiDevice for SharedSpi<'_, T> {
+    type Error = T::Error;
+
+    fn poll_lock(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.locked {
+            if self.bus.locked.get() {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.bus.locked.set(true);
+            self.locked = true;
+        }
+        Poll::Ready(())
+    }
+
+    fn unlock(&mut self) {
+        if self.locked {
+            self.bus.spi.borrow_mut().finish();
+            self.bus.locked.set(false);
+            self.locked = false;
+        }
+    }
+
+    fn poll_transfer(&mut self, cx: &mut Context<'_>, byte: u8) -> Poll<Result<u8, T::Error>> {
+        debug_assert!(self.locked);
+        self.bus.spi.borrow_mut().poll_transfer(cx, byte)
+    }
+}
+
+impl<T> Drop for SharedSpi<'_, T> {
+    fn drop(&mut self) {
+        if self.locked {
+            self.bus.locked.set(false);
+        }
+    }
+}
+
+/// A transaction with the device selected by `cs`, during which the bus is locked.
+///
+/// Dropping the transaction before [`end`](Transaction::end), because a driver returned early
+/// or its future was dropped, deselects the device and unlocks the bus as well.
+pub(crate) struct Transaction<'a, D: SpiDevice, CS: OutputPin> {
+    spi: &'a mut D,
+    cs: &'a mut CS,
+    active: bool,
+}
+
+impl<'a, D: SpiDevice, CS: OutputPin> Transaction<'a, D, CS> {
+    /// Waits for the bus and selects the device.
+    pub async fn begin(
+        spi: &'a mut D,
+        cs: &'a mut CS,
+    ) -> Result<Transaction<'a, D, CS>, CS::Error> {
+        poll_fn(|cx| spi.poll_lock(cx)).await;
+        let transaction = Transaction {
+            spi,
+            cs,
+            active: true,
+        };
+        transaction.cs.set_low()?;
+        Ok(transaction)
+    }
+
+    /// Sends `byte` and returns the byte received at the same time.
+    pub async fn transfer(&mut self, byte: u8) -> Result<u8, D::Error> {
+        let spi = &mut *self.spi;
+        poll_fn(|cx| spi.poll_transfer(cx, byte)).await
+    }
+
+    /// Sends the bytes of `buf`, replacing each with the byte received at the same time.
+    pub async fn transfer_in_place(&mut self, buf: &mut [u8]) -> Result<(), D::Error> {
+        for byte in buf {
+            *byte = self.transfer(*byte).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends `data`, discarding the received bytes.
+    pub async fn send_all(&mut self, data: &[u8]) -> Result<(), D::Error> {
+        for &byte in data {
+            self.transfer(byte).await?;
+        }
+        Ok(())
+    }
+
+    /// Deselects the device and unlocks the bus.
+    pub fn end(mut self) -> Result<(), CS::Error> {
+        self.active = false;
+        let result = self.cs.set_high();
+        self.spi.unlock();
+        result
+    }
+}
+
+impl<D: SpiDevice, CS: OutputPin> Drop for Transaction<'_, D, CS> {
+    fn drop(&mut self) {
+        if self.active {
+            // Let a byte in progress finish before deselecting the device.
+            self.spi.unlock();
+            let _ = self.cs.set_high();
+        }
+    }
+}
@@ -0,0 +1,589 @@
+//! Semtech SX1276/77/78/79 LoRa radios, such as the RFM95 and Ra-02 modules.
+//!
+//! ```ignore
+//! let mut radio = Sx127x::new(AsyncSpi::new(spi), nss, ExtInt::<0>::new(Sense::Rising));
+//! radio.init(&Config { frequency: 433_175_000, ..Config::default() }).await?;
+//! radio.transmit(b"hello").await?;
+//! let packet = radio.receive_single(&mut buf, Duration::from_millis(500)).await?;
+//! ```
+//!
+//! Like [`nrf24`](crate::nrf24), the driver takes an [`AsyncSpi`](crate::AsyncSpi) for a bus
+//! of its own, or a [`SharedSpi`](crate::SharedSpi) to share the bus with other drivers, which
+//! is locked for each register transaction. Waiting for a packet to be sent or received is
+//! asynchronous, on the `DIO0` pin of the radio, which must be connected to `INT0` or `INT1`.
+//! The reset pin is left to the application.
+
+use crate::exti::{ExtInt, Sense};
+use crate::spi::{SpiDevice, Transaction};
+use crate::time::{delay, with_timeout, Duration};
+use avr_hal_generic::hal::digital::v2::OutputPin;
+
+const REG_FIFO: u8 = 0x00;
+const REG_OP_MODE: u8 = 0x01;
+const REG_FRF_MSB: u8 = 0x06;
+const REG_PA_CONFIG: u8 = 0x09;
+const REG_OCP: u8 = 0x0b;
+const REG_LNA: u8 = 0x0c;
+const REG_FIFO_ADDR_PTR: u8 = 0x0d;
+const REG_FIFO_TX_BASE_ADDR: u8 = 0x0e;
+const REG_FIFO_RX_BASE_ADDR: u8 = 0x0f;
+const REG_FIFO_RX_CURRENT_ADDR: u8 = 0x10;
+const REG_IRQ_FLAGS: u8 = 0x12;
+const REG_RX_NB_BYTES: u8 = 0x13;
+const REG_PKT_SNR_VALUE: u8 = 0x19;
+const REG_RSSI_VALUE: u8 = 0x1b;
+const REG_MODEM_CONFIG_1: u8 = 0x1d;
+const REG_MODEM_CONFIG_2: u8 = 0x1e;
+const REG_SYMB_TIMEOUT_LSB: u8 = 0x1f;
+const REG_PREAMBLE_MSB: u8 = 0x20;
+const REG_PAYLOAD_LENGTH: u8 = 0x22;
+const REG_MODEM_CONFIG_3: u8 = 0x26;
+const REG_DETECTION_OPTIMIZE: u8 = 0x31;
+const REG_DETECTION_THRESHOLD: u8 = 0x37;
+const REG_SYNC_WORD: u8 = 0x39;
+const REG_DIO_MAPPING_1: u8 = 0x40;
+const REG_VERSION: u8 = 0x42;
+const REG_PA_DAC: u8 = 0x4d;
+
+/// Set in the address byte of register writes.
+const WRITE: u8 = 0x80;
+
+const MODE_LONG_RANGE: u8 = 1 << 7;
+const MODE_LOW_FREQUENCY: u8 = 1 << 3;
+const MODE_SLEEP: u8 = 0b000;
+const MODE_STANDBY: u8 = 0b001;
+const MODE_TX: u8 = 0b011;
+const MODE_RX_CONTINUOUS: u8 = 0b101;
+const MODE_RX_SINGLE: u8 = 0b110;
+
+const IRQ_RX_DONE: u8 = 1 << 6;
+const IRQ_PAYLOAD_CRC_ERROR: u8 = 1 << 5;
+const IRQ_TX_DONE: u8 = 1 << 3;
+const IRQ_ALL: u8 = 0xff;
+
+const DIO0_RX_DONE: u8 = 0b00 << 6;
+const DIO0_TX_DONE: u8 = 0b01 << 6;
+
+const MODEM_CONFIG_2_RX_PAYLOAD_CRC_ON: u8 = 1 << 2;
+const MODEM_CONFIG_3_LOW_DATA_RATE_OPTIMIZE: u8 = 1 << 3;
+const MODEM_CONFIG_3_AGC_AUTO_ON: u8 = 1 << 2;
+const PA_CONFIG_PA_BOOST: u8 = 1 << 7;
+const OCP_ON: u8 = 1 << 5;
+const LNA_BOOST_HF: u8 = 0b11;
+
+/// The silicon revision of all SX127x radios.
+const VERSION: u8 = 0x12;
+/// The crystal frequency, from which the carrier frequency is derived.
+const F_XOSC: u64 = 32_000_000;
+/// Frequencies below this use the low frequency port of the SX1276, or the SX1278.
+const LOW_FREQUENCY_LIMIT: u32 = 525_000_000;
+
+/// The largest payload of a packet.
+pub const MAX_PAYLOAD: usize = 255;
+
+/// An error of an [`Sx127x`] radio, wrapping the error types `S` of the SPI and `P` of the
+/// chip select pin.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Error<S, P> {
+    Spi(S),
+    Pin(P),
+    /// The radio did not answer with the version of an SX127x.
+    NotFound,
+    /// A packet was received with a wrong CRC, and was discarded.
+    Crc,
+    /// The payload is empty or longer than [`MAX_PAYLOAD`] bytes.
+    PayloadSize,
+    /// The received payload did not fit into the buffer. The rest was discarded.
+    BufferTooSmall,
+    /// No packet was received in time.
+    Timeout,
+}
+
+impl<S, P> From<S> for Error<S, P> {
+    fn from(err: S) -> Self {
+        Error::Spi(err)
+    }
+}
+
+type LoraError<T, NSS> = Error<<T as SpiDevice>::Error, <NSS as OutputPin>::Error>;
+
+/// Signal bandwidth. Narrower bandwidths reach further, but take longer and need more accurate
+/// crystals.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Bandwidth {
+    Khz7_8,
+    Khz10_4,
+    Khz15_6,
+    Khz20_8,
+    Khz31_25,
+    Khz41_7,
+    Khz62_5,
+    Khz125,
+    Khz250,
+    Khz500,
+}
+
+impl Bandwidth {
+    fn bits(self) -> u8 {
+        self as u8
+    }
+
+    fn hz(self) -> u32 {
+        match self {
+            Bandwidth::Khz7_8 => 7_800,
+            Bandwidth::Khz10_4 => 10_400,
+            Bandwidth::Khz15_6 => 15_600,
+            Bandwidth::Khz20_8 => 20_800,
+            Bandwidth::Khz31_25 => 31_250,
+            Bandwidth::Khz41_7 => 41_700,
+            Bandwidth::Khz62_5 => 62_500,
+            Bandwidth::Khz125 => 125_000,
+            Bandwidth::Khz250 => 250_000,
+            Bandwidth::Khz500 => 500_000,
+        }
+    }
+}
+
+/// The rate of the forward error correction, as data bits per coded bits.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum CodingRate {
+    Cr4_5,
+    Cr4_6,
+    Cr4_7,
+    Cr4_8,
+}
+
+impl CodingRate {
+    fn bits(self) -> u8 {
+        match self {
+            CodingRate::Cr4_5 => 0b001,
+            CodingRate::Cr4_6 => 0b010,
+            CodingRate::Cr4_7 => 0b011,
+            CodingRate::Cr4_8 => 0b100,
+        }
+    }
+}
+
+/// Radio parameters. All but the transmit power must match on both ends of a link.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Config {
+    /// The carrier frequency in Hz.
+    pub frequency: u32,
+    /// The spreading factor, from 7 to 12. Each step doubles the time on air.
+    pub spreading_factor: u8,
+    pub bandwidth: Bandwidth,
+    pub coding_rate: CodingRate,
+    /// Transmit power in dBm, from 2 to 20 on the `PA_BOOST` pin, or 0 to 14 on the `RFO` pin.
+    pub tx_power: i8,
+    /// Whether the antenna is connected to the `PA_BOOST` pin, as on most modules, or to the
+    /// `RFO` pin.
+    pub pa_boost: bool,
+    /// Length of the preamble in symbols, from 6.
+    pub preamble_length: u16,
+    /// Separates networks. `0x34` is reserved for LoRaWAN.
+    pub sync_word: u8,
+    /// Whether packets carry a CRC, which the receiver checks.
+    pub crc: bool,
+}
+
+impl Default for Config {
+    /// 868.1 MHz with spreading factor 7 at 125 kHz, coding rate 4/5, 17 dBm on `PA_BOOST`,
+    /// 8 symbol preamble, private sync word `0x12` and CRC.
+    fn default() -> Self {
+        Config {
+            frequency: 868_100_000,
+            spreading_factor: 7,
+            bandwidth: Bandwidth::Khz125,
+            coding_rate: CodingRate::Cr4_5,
+            tx_power: 17,
+            pa_boost: true,
+            preamble_length: 8,
+            sync_word: 0x12,
+            crc: true,
+        }
+    }
+}
+
+/// Metadata of a received packet.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Packet {
+    /// Length of the payload.
+    pub len: usize,
+    /// Signal strength in dBm.
+    pub rssi: i16,
+    /// Signal to noise ratio in dB. LoRa receives packets down to about -20 dB.
+    pub snr: i8,
+}
+
+/// The SPI bus and chip select pin, split from the interrupt so registers can be accessed
+/// while waiting.
+struct Bus<T, NSS> {
+    spi: T,
+    nss: NSS,
+}
+
+impl<T: SpiDevice, NSS: OutputPin> Bus<T, NSS> {
+    async fn read_register(&mut self, register: u8) -> Result<u8, LoraError<T, NSS>> {
+        let mut value = [0];
+        self.read(register, &mut value).await?;
+        Ok(value[0])
+    }
+
+    async fn write_register(&mut self, register: u8, value: u8) -> Result<(), LoraError<T, NSS>> {
+        self.write(register, &[value]).await
+    }
+
+    /// Reads consecutive registers starting at `register`, or from the FIFO.
+    async fn read(&mut self, register: u8, buf: &mut [u8]) -> Result<(), LoraError<T, NSS>> {
+        let mut transaction = Transaction::begin(&mut self.spi, &mut self.nss)
+            .await
+            .map_err(Error::Pin)?;
+        transaction.transfer(register).await?;
+        for byte in buf {
+            *byte = transaction.transfer(0).await?;
+        }
+        transaction.end().map_err(Error::Pin)
+    }
+
+    /// Writes consecutive registers starting at `register`, or into the FIFO.
+    async fn write(&mut self, register: u8, data: &[u8]) -> Result<(), LoraError<T, NSS>> {
+        let mut transaction = Transaction::begin(&mut self.spi, &mut self.nss)
+            .await
+            .map_err(Error::Pin)?;
+        transaction.transfer(WRITE | register).await?;
+        transaction.send_all(data).await?;
+        transaction.end().map_err(Error::Pin)
+    }
+}
+
+/// An SX127x radio in LoRa mode on the SPI device `T`, with the chip select pin `NSS` and its
+/// `DIO0` pin connected to the external interrupt `INT<N>`.
+pub struct Sx127x<T, NSS, const N: u8> {
+    bus: Bus<T, NSS>,
+    dio0: ExtInt<N>,
+    spreading_factor: u8,
+    bandwidth: Bandwidth,
+    low_frequency: bool,
+    receiving: bool,
+}
+
+impl<T, NSS, const N: u8> Sx127x<T, NSS, N>
+where
+    T: SpiDevice,
+    NSS: OutputPin,
+{
+    /// Creates a driver, switching the interrupt to trigger on a rising edge of `DIO0`.
+    pub fn new(spi: T, nss: NSS, mut dio0: ExtInt<N>) -> Self {
+        dio0.set_sense(Sense::Rising);
+        Sx127x {
+            bus: Bus { spi, nss },
+            dio0,
+            spreading_factor: 7,
+            bandwidth: Bandwidth::Khz125,
+            low_frequency: false,
+            receiving: false,
+        }
+    }
+
+    /// Releases the SPI, the pin and the interrupt.
+    pub fn free(self) -> (T, NSS, ExtInt<N>) {
+        (self.bus.spi, self.bus.nss, self.dio0)
+    }
+
+    /// Switches the radio to LoRa mode, configures it and leaves it in standby mode.
+    ///
+    /// The radio needs 10 ms after power on or reset before it can be configured.
+    pub async fn init(&mut self, config: &Config) -> Result<(), LoraError<T, NSS>> {
+        self.bus.nss.set_high().map_err(Error::Pin)?;
+        if self.bus.read_register(REG_VERSION).await? != VERSION {
+            return Err(Error::NotFound);
+        }
+        // LoRa mode can only be entered from sleep mode.
+        self.bus.write_register(REG_OP_MODE, MODE_SLEEP).await?;
+        self.set_mode(MODE_SLEEP).await?;
+        self.receiving = false;
+
+        self.set_frequency(config.frequency).await?;
+        // Both directions use the whole FIFO, as only one is active at a time.
+        self.bus.write_register(REG_FIFO_TX_BASE_ADDR, 0).await?;
+        self.bus.write_register(REG_FIFO_RX_BASE_ADDR, 0).await?;
+        let lna = self.bus.read_register(REG_LNA).await?;
+        self.bus.write_register(REG_LNA, lna | LNA_BOOST_HF).await?;
+        self.bus
+            .write_register(REG_DETECTION_OPTIMIZE, 0xc3)
+            .await?;
+        self.bus
+            .write_register(REG_DETECTION_THRESHOLD, 0x0a)
+            .await?;
+
+        self.spreading_factor = config.spreading_factor.clamp(7, 12);
+        self.bandwidth = config.bandwidth;
+        self.bus
+            .write_register(
+                REG_MODEM_CONFIG_1,
+                self.bandwidth.bits() << 4 | config.coding_rate.bits() << 1,
+            )
+            .await?;
+        let crc = if config.crc {
+            MODEM_CONFIG_2_RX_PAYLOAD_CRC_ON
+        } else {
+            0
+        };
+        // The longest symbol timeout for single receptions, which are bounded by a timer.
+        self.bus
+            .write_register(REG_MODEM_CONFIG_2, self.spreading_factor << 4 | crc | 0b11)
+            .await?;
+        self.bus.write_register(REG_SYMB_TIMEOUT_LSB, 0xff).await?;
+        self.update_modem_config_3().await?;
+        self.bus
+            .write(
+                REG_PREAMBLE_MSB,
+                &config.preamble_length.max(6).to_be_bytes(),
+            )
+            .await?;
+        self.bus
+            .write_register(REG_SYNC_WORD, config.sync_word)
+            .await?;
+        self.set_tx_power(config.tx_power, config.pa_boost).await?;
+        self.standby().await
+    }
+
+    /// Sets the carrier frequency in Hz. Stops receiving.
+    pub async fn set_frequency(&mut self, frequency: u32) -> Result<(), LoraError<T, NSS>> {
+        self.receiving = false;
+        self.low_frequency = frequency < LOW_FREQUENCY_LIMIT;
+        self.set_mode(MODE_STANDBY).await?;
+        let frf = ((frequency as u64) << 19) / F_XOSC;
+        self.bus
+            .write(REG_FRF_MSB, &(frf as u32).to_be_bytes()[1..])
+            .await
+    }
+
+    /// Sets the spreading factor, from 7 to 12.
+    pub async fn set_spreading_factor(
+        &mut self,
+        spreading_factor: u8,
+    ) -> Result<(), LoraError<T, NSS>> {
+        self.spreading_factor = spreading_factor.clamp(7, 12);
+        let config = self.bus.read_register(REG_MODEM_CONFIG_2).await?;
+        self.bus
+            .write_register(
+                REG_MODEM_CONFIG_2,
+                config & 0x0f | self.spreading_factor << 4,
+            )
+            .await?;
+        self.update_modem_config_3().await
+    }
+
+    pub async fn set_bandwidth(&mut self, bandwidth: Bandwidth) -> Result<(), LoraError<T, NSS>> {
+        self.bandwidth = bandwidth;
+        let config = self.bus.read_register(REG_MODEM_CONFIG_1).await?;
+        self.bus
+            .write_register(REG_MODEM_CONFIG_1, config & 0x0f | bandwidth.bits() << 4)
+            .await?;
+        self.update_modem_config_3().await
+    }
+
+    pub async fn set_coding_rate(
+        &mut self,
+        coding_rate: CodingRate,
+    ) -> Result<(), LoraError<T, NSS>> {
+        let config = self.bus.read_register(REG_MODEM_CONFIG_1).await?;
+        self.bus
+            .write_register(
+                REG_MODEM_CONFIG_1,
+                config & !0b1110 | coding_rate.bits() << 1,
+            )
+            .await
+    }
+
+    /// Sets the transmit power in dBm, on the `PA_BOOST` pin from 2 to 20, or on the `RFO` pin
+    /// from 0 to 14.
+    pub async fn set_tx_power(
+        &mut self,
+        power: i8,
+        pa_boost: bool,
+    ) -> Result<(), LoraError<T, NSS>> {
+        let (pa_config, pa_dac, ocp_trim) = if !pa_boost {
+            // The maximum power of 15 dBm, minus 15 plus the output power.
+            (0x70 | power.clamp(0, 14) as u8, 0x84, 11)
+        } else if power > 17 {
+            // The high power DAC adds 3 dBm, and needs a higher current limit of 120 mA.
+            (PA_CONFIG_PA_BOOST | (power.min(20) - 5) as u8, 0x87, 15)
+        } else {
+            (PA_CONFIG_PA_BOOST | (power.max(2) - 2) as u8, 0x84, 11)
+        };
+        self.bus.write_register(REG_PA_CONFIG, pa_config).await?;
+        self.bus.write_register(REG_PA_DAC, pa_dac).await?;
+        self.bus.write_register(REG_OCP, OCP_ON | ocp_trim).await
+    }
+
+    /// Sends a packet, waiting until it has been sent. Stops receiving.
+    pub async fn transmit(&mut self, payload: &[u8]) -> Result<(), LoraError<T, NSS>> {
+        if payload.is_empty() || payload.len() > MAX_PAYLOAD {
+            return Err(Error::PayloadSize);
+        }
+        self.standby().await?;
+        self.bus
+            .write_register(REG_DIO_MAPPING_1, DIO0_TX_DONE)
+            .await?;
+        self.bus.write_register(REG_FIFO_ADDR_PTR, 0).await?;
+        self.bus.write(REG_FIFO, payload).await?;
+        self.bus
+            .write_register(REG_PAYLOAD_LENGTH, payload.len() as u8)
+            .await?;
+        self.bus.write_register(REG_IRQ_FLAGS, IRQ_ALL).await?;
+        self.set_mode(MODE_TX).await?;
+        // The radio returns to standby mode by itself.
+        self.wait_irq(IRQ_TX_DONE).await?;
+        self.bus.write_register(REG_IRQ_FLAGS, IRQ_ALL).await?;
+        Ok(())
+    }
+
+    /// Receives a packet into `buf`.
+    ///
+    /// Starts receiving continuously, and keeps receiving after returning until the next
+    /// [`transmit`] or [`standby`], so that a packet arriving between calls is not missed.
+    ///
+    /// [`transmit`]: Sx127x::transmit
+    /// [`standby`]: Sx127x::standby
+    pub async fn receive(&mut self, buf: &mut [u8]) -> Result<Packet, LoraError<T, NSS>> {
+        if !self.receiving {
+            self.start_receiving(MODE_RX_CONTINUOUS).await?;
+            self.receiving = true;
+        }
+        self.wait_irq(IRQ_RX_DONE).await?;
+        self.read_packet(buf).await
+    }
+
+    /// Receives a single packet into `buf`, giving up after `timeout`. The radio is in standby
+    /// mode afterwards.
+    pub async fn receive_single(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Packet, LoraError<T, NSS>> {
+        self.standby().await?;
+        self.start_receiving(MODE_RX_SINGLE).await?;
+        match with_timeout(delay(timeout), self.wait_irq(IRQ_RX_DONE)).await {
+            Ok(result) => {
+                result?;
+            }
+            Err(_) => {
+                self.standby().await?;
+                return Err(Error::Timeout);
+            }
+        }
+        let result = self.read_packet(buf).await;
+        self.standby().await?;
+        result
+    }
+
+    /// Returns the current signal strength in dBm, while receiving.
+    pub async fn rssi(&mut self) -> Result<i16, LoraError<T, NSS>> {
+        let rssi = self.bus.read_register(REG_RSSI_VALUE).await?;
+        Ok(self.rssi_offset() + rssi as i16)
+    }
+
+    /// Stops receiving, keeping the oscillator running.
+    pub async fn standby(&mut self) -> Result<(), LoraError<T, NSS>> {
+        self.receiving = false;
+        self.set_mode(MODE_STANDBY).await
+    }
+
+    /// Puts the radio to sleep, keeping its configuration but not the FIFO. Any other method
+    /// wakes it up again.
+    pub async fn sleep(&mut self) -> Result<(), LoraError<T, NSS>> {
+        self.receiving = false;
+        self.set_mode(MODE_SLEEP).await
+    }
+
+    async fn start_receiving(&mut self, mode: u8) -> Result<(), LoraError<T, NSS>> {
+        self.set_mode(MODE_STANDBY).await?;
+        self.bus
+            .write_register(REG_DIO_MAPPING_1, DIO0_RX_DONE)
+            .await?;
+        self.bus.write_register(REG_IRQ_FLAGS, IRQ_ALL).await?;
+        self.set_mode(mode).await
+    }
+
+    /// Reads the received packet from the FIFO and clears the interrupt flags.
+    async fn read_packet(&mut self, buf: &mut [u8]) -> Result<Packet, LoraError<T, NSS>> {
+        let flags = self.bus.read_register(REG_IRQ_FLAGS).await?;
+        self.bus.write_register(REG_IRQ_FLAGS, IRQ_ALL).await?;
+        if flags & IRQ_PAYLOAD_CRC_ERROR != 0 {
+            return Err(Error::Crc);
+        }
+        let len = self.bus.read_register(REG_RX_NB_BYTES).await? as usize;
+        let start = self.bus.read_register(REG_FIFO_RX_CURRENT_ADDR).await?;
+        self.bus.write_register(REG_FIFO_ADDR_PTR, start).await?;
+        let received = len.min(buf.len());
+        self.bus.read(REG_FIFO, &mut buf[..received]).await?;
+
+        // The packet SNR is followed by the packet RSSI.
+        let mut values = [0; 2];
+        self.bus.read(REG_PKT_SNR_VALUE, &mut values).await?;
+        // The SNR is in steps of 0.25 dB.
+        let snr = values[0] as i8;
+        let packet_rssi = values[1] as i16;
+        let rssi = if snr < 0 {
+            self.rssi_offset() + packet_rssi + snr as i16 / 4
+        } else {
+            self.rssi_offset() + packet_rssi * 16 / 15
+        };
+        if received < len {
+            return Err(Error::BufferTooSmall);
+        }
+        Ok(Packet {
+            len,
+            rssi,
+            snr: snr / 4,
+        })
+    }
+
+    /// Waits until one of the interrupt flags `mask` is set, returning all flags.
+    async fn wait_irq(&mut self, mask: u8) -> Result<u8, LoraError<T, NSS>> {
+        loop {
+            // Create the future before checking the flags, so an edge in between is not missed.
+            let wait = self.dio0.wait();
+            let flags = self.bus.read_register(REG_IRQ_FLAGS).await?;
+            if flags & mask != 0 {
+                return Ok(flags);
+            }
+            wait.await;
+        }
+    }
+
+    /// Enables the low data rate optimization, required when symbols are longer than 16 ms.
+    async fn update_modem_config_3(&mut self) -> Result<(), LoraError<T, NSS>> {
+        let symbol_time_us = (1_000_000u32 << self.spreading_factor) / self.bandwidth.hz();
+        let ldro = if symbol_time_us > 16_000 {
+            MODEM_CONFIG_3_LOW_DATA_RATE_OPTIMIZE
+        } else {
+            0
+        };
+        self.bus
+            .write_register(REG_MODEM_CONFIG_3, MODEM_CONFIG_3_AGC_AUTO_ON | ldro)
+            .await
+    }
+
+    async fn set_mode(&mut self, mode: u8) -> Result<(), LoraError<T, NSS>> {
+        let low_frequency = if self.low_frequency {
+            MODE_LOW_FREQUENCY
+        } else {
+            0
+        };
+        self.bus
+            .write_register(REG_OP_MODE, MODE_LONG_RANGE | low_frequency | mode)
+            .await
+    }
+
+    /// The offset of the RSSI registers, which depends on the frequency band.
+    fn rssi_offset(&self) -> i16 {
+        if self.low_frequency {
+            -164
+        } else {
+            -157
+        }
+    }
+}
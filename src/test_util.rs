@@ -0,0 +1,184 @@
+//! Helpers for host tests: an executor with a simulated clock, delays on that clock, and an
+//! in-memory serial line.
+
+use crate::io::{AsyncRead, AsyncWrite};
+use crate::time::{Delay, Duration};
+use core::convert::Infallible;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::task::noop_waker;
+use pin_utils::pin_mut;
+use std::boxed::Box;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::thread_local;
+
+thread_local! {
+    /// The simulated time in ticks.
+    static NOW: Cell<u32> = Cell::new(0);
+    /// The earliest deadline of the delays polled since the clock last advanced.
+    static NEXT: Cell<Option<u32>> = Cell::new(None);
+    /// Whether data moved over a line since the futures were last polled.
+    static PROGRESS: Cell<bool> = Cell::new(false);
+}
+
+/// Returns the simulated time in ticks.
+pub fn now() -> u32 {
+    NOW.with(Cell::get)
+}
+
+/// Makes [`block_on`] poll again right away, for example while a simulated device is busy for
+/// a moment.
+pub fn progress() {
+    PROGRESS.with(|progress| progress.set(true));
+}
+
+/// Runs `future` to completion on the simulated clock.
+///
+/// The future is polled again right away while data is moving over the lines or [`progress`]
+/// was called. Once nothing happens anymore, the clock jumps to the earliest pending delay, so
+/// data always arrives before any timeout and idle time takes no real time.
+///
+/// # Panics
+///
+/// Panics if the future is stuck, with no data moving and no delay pending.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    NOW.with(|now| now.set(0));
+    NEXT.with(|next| next.set(None));
+    pin_mut!(future);
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        PROGRESS.with(|progress| progress.set(false));
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        if PROGRESS.with(Cell::get) {
+            continue;
+        }
+        let next = NEXT
+            .with(Cell::take)
+            .expect("future stuck without a pending delay");
+        NOW.with(|now| now.set(next));
+    }
+}
+
+/// Delays on the simulated clock of [`block_on`].
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ManualDelay;
+
+impl Delay for ManualDelay {
+    type Delay = ManualTimer;
+
+    fn delay(&mut self, duration: Duration) -> ManualTimer {
+        ManualTimer {
+            deadline: now() + duration.ticks(),
+        }
+    }
+}
+
+/// Future for [`ManualDelay::delay`].
+pub struct ManualTimer {
+    deadline: u32,
+}
+
+impl Future for ManualTimer {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+        if now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        NEXT.with(|next| {
+            let earliest = next.get().map_or(self.deadline, |n| n.min(self.deadline));
+            next.set(Some(earliest));
+        });
+        Poll::Pending
+    }
+}
+
+type Queue = Rc<RefCell<VecDeque<u8>>>;
+
+/// One end of an in-memory serial line, created by [`line`].
+pub struct End {
+    rx: Queue,
+    tx: Queue,
+    /// Passes each written byte, or corrupts or drops it.
+    fault: Option<Box<dyn FnMut(u8) -> Option<u8>>>,
+}
+
+/// Creates both ends of a serial line. Bytes written to one end are read from the other.
+pub fn line() -> (End, End) {
+    let a = Queue::default();
+    let b = Queue::default();
+    (
+        End {
+            rx: a.clone(),
+            tx: b.clone(),
+            fault: None,
+        },
+        End {
+            rx: b,
+            tx: a,
+            fault: None,
+        },
+    )
+}
+
+impl End {
+    /// Passes every byte written from now on through `fault`, which returns the byte to send,
+    /// or `None` to lose it.
+    pub fn set_fault(&mut self, fault: impl FnMut(u8) -> Option<u8> + 'static) {
+        self.fault = Some(Box::new(fault));
+    }
+}
+
+impl AsyncRead for End {
+    type Error = Infallible;
+
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Infallible>> {
+        let mut rx = self.rx.borrow_mut();
+        if rx.is_empty() && !buf.is_empty() {
+            return Poll::Pending;
+        }
+        let n = buf.len().min(rx.len());
+        for (byte, received) in buf.iter_mut().zip(rx.drain(..n)) {
+            *byte = received;
+        }
+        progress();
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for End {
+    type Error = Infallible;
+
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Infallible>> {
+        let this = &mut *self;
+        let mut tx = this.tx.borrow_mut();
+        match &mut this.fault {
+            Some(fault) => tx.extend(buf.iter().filter_map(|&byte| fault(byte))),
+            None => tx.extend(buf),
+        }
+        progress();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+}
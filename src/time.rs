@@ -0,0 +1,324 @@
+//! Timekeeping and delays on Timer0.
+//!
+//! [`init`] starts Timer0 in fast PWM mode with a prescaler of 64, the same configuration the
+//! Arduino core uses, so `OC0A` and `OC0B` remain usable as PWM outputs. Every overflow
+//! (1.024 ms) extends the counter in software and wakes expired [`Timer`]s.
+//!
+//! The overflow interrupt handler is left to the application, which forwards it to
+//! [`on_interrupt`]:
+//!
+//! ```ignore
+//! #[avr_device::interrupt(atmega328p)]
+//! fn TIMER0_OVF() {
+//!     async_avr::time::on_interrupt();
+//! }
+//! ```
+//!
+//! Conversions and sums of durations saturate rather than overflow, at about 4.7 hours, or 71
+//! minutes in microseconds.
+
+use crate::power::{self, Busy, Peripheral};
+use crate::waker::WakerCell;
+use crate::CPU_FREQUENCY;
+use avr_device::atmega328p::{Peripherals, TC0};
+use avr_device::interrupt::{self, Mutex};
+use core::cell::Cell;
+use core::future::Future;
+use core::ops::{Add, AddAssign, Sub};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::future::{select, Either};
+use pin_utils::pin_mut;
+
+/// Timer0 ticks per second.
+pub const TICKS_PER_SECOND: u32 = CPU_FREQUENCY / 64;
+
+const TCCR0A_WGM: u8 = 0b11;
+const TCCR0B_CS_64: u8 = 0b011;
+const TIMSK0_TOIE0: u8 = 1 << 0;
+const TIFR0_TOV0: u8 = 1 << 0;
+
+/// A span of time, counted in Timer0 ticks of 4 µs.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Default)]
+pub struct Duration(u32);
+
+impl Duration {
+    pub const fn from_ticks(ticks: u32) -> Self {
+        Duration(ticks)
+    }
+
+    pub const fn from_micros(micros: u32) -> Self {
+        Duration(micros / (1_000_000 / TICKS_PER_SECOND))
+    }
+
+    pub const fn from_millis(millis: u32) -> Self {
+        Duration(millis.saturating_mul(TICKS_PER_SECOND / 1000))
+    }
+
+    pub const fn from_secs(secs: u32) -> Self {
+        Duration(secs.saturating_mul(TICKS_PER_SECOND))
+    }
+
+    pub const fn ticks(self) -> u32 {
+        self.0
+    }
+
+    pub const fn as_micros(self) -> u32 {
+        self.0.saturating_mul(1_000_000 / TICKS_PER_SECOND)
+    }
+
+    pub const fn as_millis(self) -> u32 {
+        self.0 / (TICKS_PER_SECOND / 1000)
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, rhs: Duration) -> Duration {
+        Duration(self.0.saturating_add(rhs.0))
+    }
+}
+
+impl AddAssign for Duration {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+/// A point in time, as measured by Timer0 since [`init`].
+///
+/// The underlying counter wraps around after about 4.7 hours; comparisons between instants are
+/// only meaningful for instants less than half of that apart.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Instant(u32);
+
+impl Instant {
+    pub fn now() -> Self {
+        interrupt::free(|cs| {
+            let dp = unsafe { Peripherals::steal() };
+            let mut overflows = OVERFLOWS.borrow(cs).get();
+            let count = dp.TC0.tcnt0.read().bits();
+            // An overflow that happened after interrupts were disabled has not been counted yet.
+            if dp.TC0.tifr0.read().bits() & TIFR0_TOV0 != 0 && count < u8::MAX {
+                overflows = overflows.wrapping_add(1);
+            }
+            Instant(overflows << 8 | count as u32)
+        })
+    }
+
+    pub fn ticks(self) -> u32 {
+        self.0
+    }
+
+    /// The time elapsed from `earlier` to `self`.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration(self.0.wrapping_sub(earlier.0))
+    }
+
+    /// The time elapsed since `self`.
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+
+    fn is_reached(self) -> bool {
+        Instant::now().0.wrapping_sub(self.0) < 1 << 31
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0.wrapping_add(rhs.0))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+const ALARMS: usize = 8;
+
+static OVERFLOWS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static DEADLINES: Mutex<[Cell<Option<Instant>>; ALARMS]> = Mutex::new([
+    Cell::new(None),
+    Cell::new(None),
+    Cell::new(None),
+    Cell::new(None),
+    Cell::new(None),
+    Cell::new(None),
+    Cell::new(None),
+    Cell::new(None),
+]);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_WAKER: WakerCell = WakerCell::new();
+static WAKERS: [WakerCell; ALARMS] = [NO_WAKER; ALARMS];
+
+/// Starts Timer0 as the time base for [`Instant`] and [`Timer`].
+pub fn init(tc0: TC0) {
+    interrupt::free(|cs| {
+        OVERFLOWS.borrow(cs).set(0);
+        tc0.tccr0a
+            .modify(|r, w| unsafe { w.bits(r.bits() | TCCR0A_WGM) });
+        tc0.tccr0b.write(|w| unsafe { w.bits(TCCR0B_CS_64) });
+        tc0.tcnt0.write(|w| unsafe { w.bits(0) });
+        tc0.tifr0.write(|w| unsafe { w.bits(TIFR0_TOV0) });
+        tc0.timsk0.write(|w| unsafe { w.bits(TIMSK0_TOIE0) });
+    })
+}
+
+/// A future which resolves once a deadline has been reached.
+///
+/// The task is woken from the Timer0 overflow interrupt, so timers resolve up to 1.024 ms after
+/// their deadline. While a timer is pending, the [power manager](crate::power) keeps Timer0
+/// running.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Timer {
+    deadline: Instant,
+    alarm: Option<usize>,
+    _busy: Option<Busy>,
+}
+
+impl Timer {
+    /// Creates a timer which resolves at `deadline`.
+    pub fn at(deadline: Instant) -> Self {
+        Timer {
+            deadline,
+            alarm: None,
+            _busy: None,
+        }
+    }
+
+    /// Creates a timer which resolves after `duration` has passed.
+    pub fn after(duration: Duration) -> Self {
+        Self::at(Instant::now() + duration)
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    fn release(&mut self) {
+        if let Some(alarm) = self.alarm.take() {
+            interrupt::free(|cs| DEADLINES.borrow(cs)[alarm].set(None));
+        }
+        self._busy = None;
+    }
+}
+
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.deadline.is_reached() {
+            self.release();
+            return Poll::Ready(());
+        }
+        if self.alarm.is_none() {
+            let deadline = self.deadline;
+            self.alarm = interrupt::free(|cs| {
+                let deadlines = DEADLINES.borrow(cs);
+                let alarm = deadlines.iter().position(|d| d.get().is_none())?;
+                deadlines[alarm].set(Some(deadline));
+                Some(alarm)
+            });
+            self._busy = Some(power::busy(Peripheral::Timer0));
+        }
+        match self.alarm {
+            Some(alarm) => WAKERS[alarm].register(cx.waker()),
+            // All alarms are in use; fall back to polling.
+            None => cx.waker().wake_by_ref(),
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Creates a future which resolves after `duration` has passed.
+pub fn delay(duration: Duration) -> Timer {
+    Timer::after(duration)
+}
+
+/// A source of delays.
+///
+/// Protocol implementations take their delays and timeouts from a `Delay` rather than creating
+/// [`Timer`]s directly, so they can run on other time bases, such as a simulated clock in host
+/// tests.
+pub trait Delay {
+    type Delay: Future<Output = ()>;
+
+    /// Creates a future which resolves after `duration` has passed.
+    fn delay(&mut self, duration: Duration) -> Self::Delay;
+}
+
+/// Delays measured with Timer0.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct TimerDelay;
+
+impl Delay for TimerDelay {
+    type Delay = Timer;
+
+    fn delay(&mut self, duration: Duration) -> Timer {
+        Timer::after(duration)
+    }
+}
+
+/// The error returned by [`with_timeout`] when the timeout elapsed first.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct TimeoutError;
+
+/// Runs `future` until it completes or `timeout` resolves, whichever happens first.
+///
+/// ```ignore
+/// let result = with_timeout(delay(Duration::from_millis(100)), rx.read(&mut buf)).await;
+/// ```
+pub async fn with_timeout<F: Future, T: Future<Output = ()>>(
+    timeout: T,
+    future: F,
+) -> Result<F::Output, TimeoutError> {
+    pin_mut!(future, timeout);
+    match select(future, timeout).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(TimeoutError),
+    }
+}
+
+/// Counts an overflow of Timer0 and wakes expired timers. Must be called from the `TIMER0_OVF`
+/// interrupt handler.
+pub fn on_interrupt() {
+    let expired = interrupt::free(|cs| {
+        let overflows = OVERFLOWS.borrow(cs);
+        overflows.set(overflows.get().wrapping_add(1));
+        let mut expired = 0u8;
+        for (i, deadline) in DEADLINES.borrow(cs).iter().enumerate() {
+            if let Some(deadline) = deadline.get() {
+                if deadline.is_reached() {
+                    expired |= 1 << i;
+                }
+            }
+        }
+        expired
+    });
+    for (i, waker) in WAKERS.iter().enumerate() {
+        if expired & 1 << i != 0 {
+            waker.wake();
+        }
+    }
+}
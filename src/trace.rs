@@ -0,0 +1,215 @@
+//! Executor instrumentation, enabled with the `trace` feature.
+//!
+//! For every task slot of an [`Executor`](crate::Executor) or
+//! [`InterruptExecutor`](crate::InterruptExecutor), the executor records how often the task was
+//! polled, how long those polls took and how long the task waited between being woken and being
+//! polled. Durations are measured with [`time::Instant`](crate::time::Instant), so
+//! [`time::init`](crate::time::init) must have been called for them to be meaningful.
+//!
+//! Stack usage is measured by filling the unused part of SRAM with a known pattern in
+//! [`paint_stack`] and later checking how much of that pattern was overwritten.
+
+use crate::io::{AsyncWrite, AsyncWriteExt, WriteAllError};
+use crate::time::{Duration, Instant};
+use avr_device::interrupt;
+use core::cell::Cell;
+use core::ptr;
+
+/// Statistics of a single task.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct TaskStats {
+    /// Number of times the task was polled.
+    pub polls: u32,
+    /// Total time spent polling the task.
+    pub busy: Duration,
+    /// Longest time a single poll took.
+    pub max_poll: Duration,
+    /// Longest time between the task being woken and being polled.
+    pub max_latency: Duration,
+}
+
+pub(crate) struct TaskTrace {
+    spawned: Cell<bool>,
+    woken_at: Cell<Option<Instant>>,
+    stats: Cell<TaskStats>,
+}
+
+impl TaskTrace {
+    pub const fn new() -> Self {
+        TaskTrace {
+            spawned: Cell::new(false),
+            woken_at: Cell::new(None),
+            stats: Cell::new(TaskStats {
+                polls: 0,
+                busy: Duration::from_ticks(0),
+                max_poll: Duration::from_ticks(0),
+                max_latency: Duration::from_ticks(0),
+            }),
+        }
+    }
+
+    /// Clears the statistics when a new task is spawned into the slot.
+    pub fn reset(&self) {
+        interrupt::free(|_| {
+            self.spawned.set(true);
+            self.woken_at.set(None);
+            self.stats.set(TaskStats::default());
+        })
+    }
+
+    /// Records the first wakeup since the task was last polled.
+    pub fn woken(&self) {
+        interrupt::free(|_| {
+            if self.woken_at.get().is_none() {
+                self.woken_at.set(Some(Instant::now()));
+            }
+        })
+    }
+
+    pub fn poll_started(&self) -> Instant {
+        let now = Instant::now();
+        interrupt::free(|_| {
+            if let Some(woken_at) = self.woken_at.take() {
+                let mut stats = self.stats.get();
+                stats.max_latency = stats.max_latency.max(now - woken_at);
+                self.stats.set(stats);
+            }
+        });
+        now
+    }
+
+    pub fn poll_finished(&self, started: Instant) {
+        let duration = started.elapsed();
+        interrupt::free(|_| {
+            let mut stats = self.stats.get();
+            stats.polls = stats.polls.wrapping_add(1);
+            stats.busy += duration;
+            stats.max_poll = stats.max_poll.max(duration);
+            self.stats.set(stats);
+        })
+    }
+
+    pub fn stats(&self) -> Option<TaskStats> {
+        interrupt::free(|_| {
+            if self.spawned.get() {
+                Some(self.stats.get())
+            } else {
+                None
+            }
+        })
+    }
+}
+
+const STACK_PAINT: u8 = 0xc5;
+const RAMEND: usize = 0x08ff;
+/// Address of the stack pointer in data space.
+const SP: *const u16 = 0x5d as *const u16;
+
+extern "C" {
+    /// End of the statically allocated data, provided by the linker script.
+    static __heap_start: u8;
+}
+
+fn heap_start() -> usize {
+    unsafe { &__heap_start as *const u8 as usize }
+}
+
+/// Fills the SRAM between the end of static data and the current stack pointer with a known
+/// pattern.
+///
+/// Call this once at the very start of `main`; [`stack_usage`] reports how deep the stack has
+/// grown since.
+#[inline(never)]
+pub fn paint_stack() {
+    interrupt::free(|_| {
+        let sp = unsafe { ptr::read_volatile(SP) } as usize;
+        for addr in heap_start()..sp {
+            unsafe { ptr::write_volatile(addr as *mut u8, STACK_PAINT) };
+        }
+    })
+}
+
+/// Stack usage reported by [`stack_usage`].
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct StackUsage {
+    /// Deepest the stack has ever been, in bytes.
+    pub used: usize,
+    /// Bytes between the end of static data and the deepest stack frame that were never
+    /// touched.
+    pub free: usize,
+}
+
+/// Returns the stack high-water mark since [`paint_stack`] was called.
+pub fn stack_usage() -> StackUsage {
+    let start = heap_start();
+    let free = (start..=RAMEND)
+        .take_while(|&addr| unsafe { ptr::read_volatile(addr as *const u8) } == STACK_PAINT)
+        .count();
+    StackUsage {
+        used: RAMEND + 1 - start - free,
+        free,
+    }
+}
+
+/// A single line of the report, formatted with `ufmt`.
+struct Line {
+    buf: [u8; 80],
+    len: usize,
+}
+
+impl Line {
+    fn new() -> Self {
+        Line {
+            buf: [0; 80],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl ufmt::uWrite for Line {
+    type Error = ();
+
+    fn write_str(&mut self, s: &str) -> Result<(), ()> {
+        // Truncate lines that do not fit rather than failing the whole report.
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+pub(crate) async fn report<'t, W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    tasks: impl Iterator<Item = &'t TaskTrace>,
+) -> Result<(), WriteAllError<W::Error>> {
+    for (index, task) in tasks.enumerate() {
+        let stats = match task.stats() {
+            Some(stats) => stats,
+            None => continue,
+        };
+        let mut line = Line::new();
+        let _ = ufmt::uwrite!(
+            &mut line,
+            "task {}: polls={} busy={}ms max_poll={}us max_latency={}us\n",
+            index,
+            stats.polls,
+            stats.busy.as_millis(),
+            stats.max_poll.as_micros(),
+            stats.max_latency.as_micros()
+        );
+        writer.write_all(line.as_bytes()).await?;
+    }
+    let stack = stack_usage();
+    let mut line = Line::new();
+    let _ = ufmt::uwrite!(
+        &mut line,
+        "stack: used={} free={}\n",
+        stack.used,
+        stack.free
+    );
+    writer.write_all(line.as_bytes()).await
+}
@@ -0,0 +1,410 @@
+//! The TWI (I²C) peripheral as a slave, answering a master such as a Raspberry Pi.
+//!
+//! ```ignore
+//! let mut slave = TwiSlave::new(dp.TWI, Config::new(0x42));
+//! loop {
+//!     match slave.listen().await {
+//!         Request::Read(mut read) => {
+//!             let value = sensors[read.register() as usize];
+//!             read.write(&value.to_be_bytes()).await;
+//!         }
+//!         Request::Write(mut write) => {
+//!             let mut buf = [0; 4];
+//!             let len = write.read(&mut buf).await;
+//!             // ...
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Requests follow the usual register protocol: a write starts with the register, followed by
+//! the data, and a read returns data from the register written last, usually in a write without
+//! data followed by a repeated start. The register is incremented for each byte of data, so a
+//! read continues where the last transfer ended. [`TwiSlave::serve`] implements this with a
+//! slice of registers.
+//!
+//! The TWI stretches the clock until its interrupt flag is cleared. The interrupt handler only
+//! wakes the task and leaves the flag set, so the master waits for the task to answer, however
+//! long that takes. The handler is left to the application, which forwards it to this module:
+//!
+//! ```ignore
+//! #[avr_device::interrupt(atmega328p)]
+//! fn TWI() {
+//!     async_avr::twi_slave::on_interrupt();
+//! }
+//! ```
+
+use crate::power::{self, Busy, Peripheral};
+use crate::waker::WakerCell;
+use avr_device::atmega328p::{Peripherals, TWI};
+use core::task::Poll;
+use futures_util::future::poll_fn;
+
+const TWCR_TWINT: u8 = 1 << 7;
+const TWCR_TWEA: u8 = 1 << 6;
+const TWCR_TWSTO: u8 = 1 << 4;
+const TWCR_TWEN: u8 = 1 << 2;
+const TWCR_TWIE: u8 = 1 << 0;
+const TWAR_TWGCE: u8 = 1 << 0;
+
+const STATUS_BUS_ERROR: u8 = 0x00;
+const STATUS_ADDRESSED_WRITE: u8 = 0x60;
+const STATUS_ADDRESSED_WRITE_LOST: u8 = 0x68;
+const STATUS_GENERAL_CALL: u8 = 0x70;
+const STATUS_GENERAL_CALL_LOST: u8 = 0x78;
+const STATUS_DATA_RECEIVED: u8 = 0x80;
+const STATUS_DATA_RECEIVED_NACK: u8 = 0x88;
+const STATUS_GENERAL_CALL_DATA: u8 = 0x90;
+const STATUS_GENERAL_CALL_DATA_NACK: u8 = 0x98;
+const STATUS_STOP: u8 = 0xa0;
+const STATUS_ADDRESSED_READ: u8 = 0xa8;
+const STATUS_ADDRESSED_READ_LOST: u8 = 0xb0;
+const STATUS_DATA_SENT_ACK: u8 = 0xb8;
+const STATUS_DATA_SENT_NACK: u8 = 0xc0;
+const STATUS_LAST_DATA_SENT: u8 = 0xc8;
+
+/// Sent when the master reads more data than the task provides.
+const FILL: u8 = 0xff;
+
+static WAKER: WakerCell = WakerCell::new();
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Config {
+    /// The 7-bit address.
+    pub address: u8,
+    /// Whether writes to the general call address 0 are received as well.
+    pub general_call: bool,
+}
+
+impl Config {
+    pub fn new(address: u8) -> Self {
+        Config {
+            address,
+            general_call: false,
+        }
+    }
+}
+
+/// A request of the master, which waits until it has been answered.
+pub enum Request<'a> {
+    Read(Read<'a>),
+    Write(Write<'a>),
+}
+
+/// The registers written by a request answered by [`TwiSlave::serve`].
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Written {
+    /// The first register written.
+    pub register: u8,
+    /// The number of bytes written, including those beyond the registers, which are dropped.
+    pub len: u8,
+    /// Whether the write was a general call, whose first byte is a command rather than a
+    /// register.
+    pub general_call: bool,
+}
+
+/// The TWI as a slave. It stays powered while the slave exists, so the MCU only sleeps in
+/// [`SleepMode::Idle`](crate::power::SleepMode::Idle).
+pub struct TwiSlave {
+    twi: TWI,
+    /// The status while the interrupt flag is set, waiting for an answer.
+    status: Option<u8>,
+    register: u8,
+    _busy: Busy,
+}
+
+impl TwiSlave {
+    /// Enables the TWI with the address of `config`. SDA and SCL need pull-ups, which the
+    /// master usually provides.
+    pub fn new(twi: TWI, config: Config) -> Self {
+        let busy = power::busy(Peripheral::Twi);
+        let general_call = if config.general_call { TWAR_TWGCE } else { 0 };
+        twi.twar
+            .write(|w| unsafe { w.bits(config.address << 1 | general_call) });
+        twi.twamr.write(|w| unsafe { w.bits(0) });
+        twi.twcr
+            .write(|w| unsafe { w.bits(TWCR_TWEA | TWCR_TWEN | TWCR_TWIE) });
+        TwiSlave {
+            twi,
+            status: None,
+            register: 0,
+            _busy: busy,
+        }
+    }
+
+    /// Disables the TWI and releases it.
+    pub fn free(self) -> TWI {
+        self.twi.twcr.write(|w| unsafe { w.bits(0) });
+        self.twi
+    }
+
+    /// Waits for the next request of the master.
+    ///
+    /// A write of only a register selects the register for the next read, and is not returned.
+    pub async fn listen(&mut self) -> Request<'_> {
+        loop {
+            match self.event().await {
+                STATUS_ADDRESSED_WRITE | STATUS_ADDRESSED_WRITE_LOST => {
+                    self.release(true);
+                    if self.event().await != STATUS_DATA_RECEIVED {
+                        continue;
+                    }
+                    self.register = self.twi.twdr.read().bits();
+                    self.release(true);
+                    match self.event().await {
+                        STATUS_DATA_RECEIVED => break,
+                        STATUS_STOP => self.release(true),
+                        _ => {}
+                    }
+                }
+                STATUS_GENERAL_CALL | STATUS_GENERAL_CALL_LOST => {
+                    self.release(true);
+                    if self.event().await != STATUS_GENERAL_CALL_DATA {
+                        continue;
+                    }
+                    let command = self.twi.twdr.read().bits();
+                    self.release(true);
+                    return Request::Write(Write {
+                        register: command,
+                        general_call: true,
+                        finished: false,
+                        slave: self,
+                    });
+                }
+                STATUS_ADDRESSED_READ | STATUS_ADDRESSED_READ_LOST => {
+                    return Request::Read(Read {
+                        register: self.register,
+                        finished: false,
+                        slave: self,
+                    })
+                }
+                STATUS_BUS_ERROR => {
+                    // Recovers by releasing the lines, without sending a stop condition.
+                    self.status = None;
+                    self.twi.twcr.write(|w| unsafe {
+                        w.bits(TWCR_TWINT | TWCR_TWSTO | TWCR_TWEA | TWCR_TWEN | TWCR_TWIE)
+                    });
+                }
+                STATUS_DATA_SENT_ACK => {
+                    // The rest of a read which was dropped.
+                    self.twi.twdr.write(|w| unsafe { w.bits(FILL) });
+                    self.release(false);
+                }
+                // Stops, the ends of reads, and data of writes which were dropped.
+                _ => self.release(true),
+            }
+        }
+        Request::Write(Write {
+            register: self.register,
+            general_call: false,
+            finished: false,
+            slave: self,
+        })
+    }
+
+    /// Answers requests with `registers`, until the master writes to them.
+    ///
+    /// Reads beyond the registers return `0xff`, and writes beyond them are dropped. General
+    /// calls are returned as well, with the command byte as the register.
+    pub async fn serve(&mut self, registers: &mut [u8]) -> Written {
+        loop {
+            match self.listen().await {
+                Request::Read(mut read) => {
+                    let start = (read.register() as usize).min(registers.len());
+                    read.write(&registers[start..]).await;
+                }
+                Request::Write(mut write) => {
+                    let register = write.register();
+                    let general_call = write.is_general_call();
+                    let mut len = 0u8;
+                    while let Some(byte) = write.byte().await {
+                        if !general_call {
+                            if let Some(r) = registers.get_mut(register as usize + len as usize) {
+                                *r = byte;
+                            }
+                        }
+                        len = len.wrapping_add(1);
+                    }
+                    return Written {
+                        register,
+                        len,
+                        general_call,
+                    };
+                }
+            }
+        }
+    }
+
+    /// Waits until the interrupt flag is set, and returns the status.
+    async fn event(&mut self) -> u8 {
+        if let Some(status) = self.status {
+            return status;
+        }
+        let twi = &self.twi;
+        let status = poll_fn(|cx| {
+            WAKER.register(cx.waker());
+            if twi.twcr.read().bits() & TWCR_TWINT != 0 {
+                Poll::Ready(twi.twsr.read().bits() & 0xf8)
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+        self.status = Some(status);
+        status
+    }
+
+    /// Clears the interrupt flag, which lets the master continue, acknowledging the next byte
+    /// received or expecting to send more if `ack`.
+    fn release(&mut self, ack: bool) {
+        self.status = None;
+        let ack = if ack { TWCR_TWEA } else { 0 };
+        self.twi
+            .twcr
+            .write(|w| unsafe { w.bits(TWCR_TWINT | ack | TWCR_TWEN | TWCR_TWIE) });
+    }
+}
+
+/// A read of the master, which the task answers with data.
+///
+/// If it is dropped before the master has read enough, `0xff` is sent for the rest.
+pub struct Read<'a> {
+    slave: &'a mut TwiSlave,
+    register: u8,
+    finished: bool,
+}
+
+impl Read<'_> {
+    /// The register the data is read from.
+    pub fn register(&self) -> u8 {
+        self.register
+    }
+
+    /// Sends `byte`, and returns whether the master reads more.
+    pub async fn send(&mut self, byte: u8) -> bool {
+        if self.finished {
+            return false;
+        }
+        match self.slave.event().await {
+            STATUS_ADDRESSED_READ | STATUS_ADDRESSED_READ_LOST | STATUS_DATA_SENT_ACK => {
+                self.slave.twi.twdr.write(|w| unsafe { w.bits(byte) });
+                self.slave.release(true);
+                self.slave.register = self.slave.register.wrapping_add(1);
+            }
+            STATUS_DATA_SENT_NACK | STATUS_LAST_DATA_SENT => {
+                self.slave.release(true);
+                self.finished = true;
+                return false;
+            }
+            // Left to `listen`.
+            _ => {
+                self.finished = true;
+                return false;
+            }
+        }
+        // Whether the master acknowledged the byte.
+        self.slave.event().await == STATUS_DATA_SENT_ACK
+    }
+
+    /// Sends `data` until the master has read enough, and returns the number of bytes it read.
+    /// If the master reads more, `0xff` is sent for the rest.
+    pub async fn write(&mut self, data: &[u8]) -> usize {
+        for (i, &byte) in data.iter().enumerate() {
+            if !self.send(byte).await {
+                return i + 1;
+            }
+        }
+        data.len()
+    }
+}
+
+impl Drop for Read<'_> {
+    fn drop(&mut self) {
+        if let Some(STATUS_ADDRESSED_READ)
+        | Some(STATUS_ADDRESSED_READ_LOST)
+        | Some(STATUS_DATA_SENT_ACK) = self.slave.status
+        {
+            // The master is waiting for data.
+            self.slave.twi.twdr.write(|w| unsafe { w.bits(FILL) });
+            self.slave.release(false);
+        }
+    }
+}
+
+/// A write of the master, whose data the task receives.
+///
+/// If it is dropped before the master has finished, the rest of the data is dropped.
+pub struct Write<'a> {
+    slave: &'a mut TwiSlave,
+    register: u8,
+    general_call: bool,
+    finished: bool,
+}
+
+impl Write<'_> {
+    /// The register the data is written to, or the command of a general call.
+    pub fn register(&self) -> u8 {
+        self.register
+    }
+
+    pub fn is_general_call(&self) -> bool {
+        self.general_call
+    }
+
+    /// Receives the next byte, or `None` when the master has finished.
+    pub async fn byte(&mut self) -> Option<u8> {
+        if self.finished {
+            return None;
+        }
+        match self.slave.event().await {
+            STATUS_DATA_RECEIVED
+            | STATUS_DATA_RECEIVED_NACK
+            | STATUS_GENERAL_CALL_DATA
+            | STATUS_GENERAL_CALL_DATA_NACK => {
+                let byte = self.slave.twi.twdr.read().bits();
+                self.slave.release(true);
+                if !self.general_call {
+                    self.slave.register = self.slave.register.wrapping_add(1);
+                }
+                Some(byte)
+            }
+            status => {
+                if status == STATUS_STOP {
+                    self.slave.release(true);
+                }
+                self.finished = true;
+                None
+            }
+        }
+    }
+
+    /// Receives data into `buf` until it is full or the master has finished, and returns the
+    /// number of bytes received.
+    pub async fn read(&mut self, buf: &mut [u8]) -> usize {
+        for (i, slot) in buf.iter_mut().enumerate() {
+            match self.byte().await {
+                Some(byte) => *slot = byte,
+                None => return i,
+            }
+        }
+        buf.len()
+    }
+}
+
+impl Drop for Write<'_> {
+    fn drop(&mut self) {
+        if let Some(STATUS_DATA_RECEIVED) | Some(STATUS_GENERAL_CALL_DATA) = self.slave.status {
+            self.slave.release(true);
+        }
+    }
+}
+
+/// Wakes the task waiting for the TWI. Must be called from the `TWI` interrupt handler.
+pub fn on_interrupt() {
+    let dp = unsafe { Peripherals::steal() };
+    // Leaves the interrupt flag set, so the clock is stretched until the task answers.
+    dp.TWI
+        .twcr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !(TWCR_TWINT | TWCR_TWIE)) });
+    WAKER.wake();
+}
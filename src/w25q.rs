@@ -0,0 +1,600 @@
+//! Winbond W25Qxx SPI NOR flash, and compatible chips with 3 byte addresses.
+//!
+//! ```ignore
+//! let mut flash = W25q::new(AsyncSpi::new(spi), cs);
+//! flash.init().await?;
+//! flash.erase_sector(0).await?;
+//! flash.write(0, b"hello").await?;
+//! ```
+//!
+//! Like all NOR flash, programming can only clear bits, so data must be erased before it is
+//! overwritten. While the flash is busy programming or erasing, its status is polled with
+//! [`Timer`]s, so other tasks run and the MCU can sleep in between.
+//!
+//! Like [`nrf24`](crate::nrf24), the driver takes an [`AsyncSpi`](crate::AsyncSpi) for a bus
+//! of its own, or a [`SharedSpi`](crate::SharedSpi) to share the bus with other drivers, which
+//! is locked for each command, so other drivers can use it while the flash is busy.
+
+use crate::block::{Block, BlockDevice, BLOCK_SIZE};
+use crate::io::{AsyncRead, AsyncWrite};
+use crate::spi::{SpiDevice, Transaction};
+use crate::time::{Duration, Timer};
+use avr_hal_generic::hal::digital::v2::OutputPin;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::ready;
+
+/// Size of a page, the most that can be programmed at once.
+pub const PAGE_SIZE: usize = 256;
+/// Size of a sector, the least that can be erased.
+pub const SECTOR_SIZE: u32 = 4096;
+/// Size of an erase block.
+pub const ERASE_BLOCK_SIZE: u32 = 65536;
+
+const WRITE_ENABLE: u8 = 0x06;
+const READ_STATUS_1: u8 = 0x05;
+const READ_STATUS_2: u8 = 0x35;
+const WRITE_STATUS: u8 = 0x01;
+const READ_DATA: u8 = 0x03;
+const PAGE_PROGRAM: u8 = 0x02;
+const SECTOR_ERASE: u8 = 0x20;
+const BLOCK_ERASE: u8 = 0xd8;
+const CHIP_ERASE: u8 = 0xc7;
+const POWER_DOWN: u8 = 0xb9;
+const RELEASE_POWER_DOWN: u8 = 0xab;
+const JEDEC_ID: u8 = 0x9f;
+
+const STATUS_BUSY: u8 = 1 << 0;
+const STATUS_WRITE_ENABLED: u8 = 1 << 1;
+const STATUS_BLOCK_PROTECT: u8 = 0b111 << 2;
+
+/// How often the status is polled while a page is programmed, which takes about 0.7 ms.
+const PROGRAM_POLL: Duration = Duration::from_millis(1);
+/// How often the status is polled while erasing, which takes 45 ms for a sector and several
+/// seconds for the whole chip.
+const ERASE_POLL: Duration = Duration::from_millis(10);
+/// The time the flash needs to leave power-down mode.
+const RESUME_DELAY: Duration = Duration::from_ticks(2);
+
+/// An error of a [`W25q`] flash, wrapping the error types `S` of the SPI and `P` of the chip
+/// select pin.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Error<S, P> {
+    Spi(S),
+    Pin(P),
+    /// The JEDEC ID does not describe a supported capacity, or no flash answered.
+    UnknownDevice,
+    /// The address is beyond the end of the flash.
+    OutOfBounds,
+}
+
+impl<S, P> From<S> for Error<S, P> {
+    fn from(err: S) -> Self {
+        Error::Spi(err)
+    }
+}
+
+type FlashError<T, CS> = Error<<T as SpiDevice>::Error, <CS as OutputPin>::Error>;
+
+/// The identification of a flash chip.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct JedecId {
+    /// 0xEF for Winbond.
+    pub manufacturer: u8,
+    pub memory_type: u8,
+    /// Log2 of the size in bytes, such as 0x16 for the 4 MiB of a W25Q32.
+    pub capacity: u8,
+}
+
+/// The contents of status registers 1 and 2.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Status {
+    pub status1: u8,
+    pub status2: u8,
+}
+
+impl Status {
+    /// Returns whether programming or erasing is in progress.
+    pub fn is_busy(&self) -> bool {
+        self.status1 & STATUS_BUSY != 0
+    }
+
+    /// Returns whether the write enable latch is set.
+    pub fn is_write_enabled(&self) -> bool {
+        self.status1 & STATUS_WRITE_ENABLED != 0
+    }
+
+    /// Returns the block protect bits BP0 to BP2, which select the protected part of the flash.
+    pub fn block_protection(&self) -> u8 {
+        (self.status1 & STATUS_BLOCK_PROTECT) >> 2
+    }
+
+    /// Returns whether any part of the flash is write protected.
+    pub fn is_write_protected(&self) -> bool {
+        self.block_protection() != 0
+    }
+}
+
+/// The data phase of a transaction.
+enum Data<'a> {
+    None,
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+impl Data<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Data::None => 0,
+            Data::Read(buf) => buf.len(),
+            Data::Write(data) => data.len(),
+        }
+    }
+}
+
+fn address_command(command: u8, address: u32) -> [u8; 4] {
+    [
+        command,
+        (address >> 16) as u8,
+        (address >> 8) as u8,
+        address as u8,
+    ]
+}
+
+/// A W25Qxx flash connected to SPI, selected by the chip select pin `CS`.
+///
+/// Implements [`BlockDevice`] with blocks of 512 bytes. Writing the first block of a sector
+/// erases the whole sector, so the blocks of a sector must be written in ascending order, as
+/// when storing a log or a firmware image, and no block of a sector may be rewritten without
+/// rewriting the sector from its start. For access at byte granularity, use a [`Cursor`].
+///
+/// The async methods run each command as a transaction of their own, while the block device,
+/// the cursor and their futures keep the progress of an operation in the driver, so they must
+/// be polled until they complete. A later async method discards an abandoned operation.
+pub struct W25q<T, CS> {
+    spi: T,
+    cs: CS,
+    capacity: u32,
+    /// Whether the bus is locked and the flash selected for the current transaction.
+    selected: bool,
+    /// The number of bytes of the current transaction transferred so far.
+    pos: usize,
+    /// The step of the current program or erase operation.
+    step: u8,
+    /// The step of the current block write.
+    stage: u8,
+    /// Delays the next poll of the status while the flash is busy.
+    timer: Option<Timer>,
+}
+
+impl<T, CS> W25q<T, CS>
+where
+    T: SpiDevice,
+    CS: OutputPin,
+{
+    pub fn new(spi: T, cs: CS) -> Self {
+        W25q {
+            spi,
+            cs,
+            capacity: 0,
+            selected: false,
+            pos: 0,
+            step: 0,
+            stage: 0,
+            timer: None,
+        }
+    }
+
+    /// Releases the SPI and the chip select pin.
+    pub fn free(self) -> (T, CS) {
+        (self.spi, self.cs)
+    }
+
+    /// Wakes the flash from power-down mode and determines its capacity from the JEDEC ID.
+    pub async fn init(&mut self) -> Result<JedecId, FlashError<T, CS>> {
+        self.cs.set_high().map_err(Error::Pin)?;
+        self.wake_up().await?;
+        let id = self.jedec_id().await?;
+        match id.capacity {
+            0x10..=0x18 => self.capacity = 1 << id.capacity,
+            _ => return Err(Error::UnknownDevice),
+        }
+        Ok(id)
+    }
+
+    /// Returns the size of the flash in bytes, once it has been initialized.
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    pub async fn jedec_id(&mut self) -> Result<JedecId, FlashError<T, CS>> {
+        let mut id = [0; 3];
+        self.transaction(&[JEDEC_ID], Data::Read(&mut id)).await?;
+        Ok(JedecId {
+            manufacturer: id[0],
+            memory_type: id[1],
+            capacity: id[2],
+        })
+    }
+
+    /// Reads the flash starting at `address` into `buf`.
+    pub async fn read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), FlashError<T, CS>> {
+        self.check_bounds(address, buf.len())?;
+        let command = address_command(READ_DATA, address);
+        self.transaction(&command, Data::Read(buf)).await
+    }
+
+    /// Programs `data` starting at `address`, which must have been erased.
+    pub async fn write(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError<T, CS>> {
+        self.check_bounds(address, data.len())?;
+        let mut address = address;
+        let mut data = data;
+        while !data.is_empty() {
+            // Programming wraps around at the end of a page.
+            let len = data.len().min(PAGE_SIZE - address as usize % PAGE_SIZE);
+            let command = address_command(PAGE_PROGRAM, address);
+            self.modify(&command, &data[..len], PROGRAM_POLL).await?;
+            address += len as u32;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Erases the sector containing `address`.
+    pub async fn erase_sector(&mut self, address: u32) -> Result<(), FlashError<T, CS>> {
+        self.erase(SECTOR_ERASE, address).await
+    }
+
+    /// Erases the 64 KiB block containing `address`.
+    pub async fn erase_block(&mut self, address: u32) -> Result<(), FlashError<T, CS>> {
+        self.erase(BLOCK_ERASE, address).await
+    }
+
+    /// Erases the whole flash.
+    pub async fn erase_chip(&mut self) -> Result<(), FlashError<T, CS>> {
+        self.modify(&[CHIP_ERASE], &[], ERASE_POLL).await
+    }
+
+    async fn erase(&mut self, command: u8, address: u32) -> Result<(), FlashError<T, CS>> {
+        self.check_bounds(address, 1)?;
+        let command = address_command(command, address);
+        self.modify(&command, &[], ERASE_POLL).await
+    }
+
+    pub async fn status(&mut self) -> Result<Status, FlashError<T, CS>> {
+        let mut status1 = [0];
+        let mut status2 = [0];
+        self.transaction(&[READ_STATUS_1], Data::Read(&mut status1))
+            .await?;
+        self.transaction(&[READ_STATUS_2], Data::Read(&mut status2))
+            .await?;
+        Ok(Status {
+            status1: status1[0],
+            status2: status2[0],
+        })
+    }
+
+    /// Sets the block protect bits BP0 to BP2, keeping the rest of the status registers.
+    pub async fn set_block_protection(&mut self, bits: u8) -> Result<(), FlashError<T, CS>> {
+        let status = self.status().await?;
+        let status1 = status.status1 & !STATUS_BLOCK_PROTECT | (bits << 2) & STATUS_BLOCK_PROTECT;
+        // Older chips clear status register 2 if only register 1 is written.
+        let command = [WRITE_STATUS, status1, status.status2];
+        self.modify(&command, &[], PROGRAM_POLL).await
+    }
+
+    /// Enters power-down mode, in which the flash ignores all commands except
+    /// [`wake_up`](W25q::wake_up).
+    pub async fn power_down(&mut self) -> Result<(), FlashError<T, CS>> {
+        self.transaction(&[POWER_DOWN], Data::None).await
+    }
+
+    /// Leaves power-down mode.
+    pub async fn wake_up(&mut self) -> Result<(), FlashError<T, CS>> {
+        self.transaction(&[RELEASE_POWER_DOWN], Data::None).await?;
+        Timer::after(RESUME_DELAY).await;
+        Ok(())
+    }
+
+    /// Returns a cursor reading and programming the flash starting at `position`.
+    pub fn cursor(&mut self, position: u32) -> Cursor<'_, T, CS> {
+        Cursor {
+            flash: self,
+            position,
+        }
+    }
+
+    fn check_bounds(&self, address: u32, len: usize) -> Result<(), FlashError<T, CS>> {
+        match address.checked_add(len as u32) {
+            Some(end) if end <= self.capacity => Ok(()),
+            _ => Err(Error::OutOfBounds),
+        }
+    }
+
+    /// Selects the flash, sends `header` followed by or receiving `data`, and deselects it,
+    /// first discarding an operation which was abandoned before it completed.
+    async fn transaction(
+        &mut self,
+        header: &[u8],
+        data: Data<'_>,
+    ) -> Result<(), FlashError<T, CS>> {
+        self.abort();
+        let mut spi = Transaction::begin(&mut self.spi, &mut self.cs)
+            .await
+            .map_err(Error::Pin)?;
+        spi.send_all(header).await?;
+        match data {
+            Data::None => {}
+            Data::Read(buf) => {
+                for byte in buf {
+                    *byte = spi.transfer(0).await?;
+                }
+            }
+            Data::Write(data) => spi.send_all(data).await?,
+        }
+        spi.end().map_err(Error::Pin)
+    }
+
+    /// Enables writing, sends the program, erase or status command `header` followed by
+    /// `data`, and waits until the flash is done, polling the status every `interval`.
+    async fn modify(
+        &mut self,
+        header: &[u8],
+        data: &[u8],
+        interval: Duration,
+    ) -> Result<(), FlashError<T, CS>> {
+        self.transaction(&[WRITE_ENABLE], Data::None).await?;
+        self.transaction(header, Data::Write(data)).await?;
+        loop {
+            let mut status = [0];
+            self.transaction(&[READ_STATUS_1], Data::Read(&mut status))
+                .await?;
+            if status[0] & STATUS_BUSY == 0 {
+                return Ok(());
+            }
+            Timer::after(interval).await;
+        }
+    }
+
+    /// Ends the current transaction, deselecting the flash and unlocking the bus.
+    fn deselect(&mut self) -> Result<(), FlashError<T, CS>> {
+        self.pos = 0;
+        if !self.selected {
+            return Ok(());
+        }
+        self.selected = false;
+        let result = self.cs.set_high().map_err(Error::Pin);
+        self.spi.unlock();
+        result
+    }
+
+    /// Discards the progress of an operation of the block device or a cursor.
+    fn abort(&mut self) {
+        let _ = self.deselect();
+        self.step = 0;
+        self.stage = 0;
+        self.timer = None;
+    }
+
+    /// Like [`transaction`](W25q::transaction), keeping the progress in the driver.
+    fn poll_transaction(
+        &mut self,
+        cx: &mut Context<'_>,
+        header: &[u8],
+        mut data: Data<'_>,
+    ) -> Poll<Result<(), FlashError<T, CS>>> {
+        if !self.selected {
+            ready!(self.spi.poll_lock(cx));
+            self.selected = true;
+            self.cs.set_low().map_err(|err| {
+                let _ = self.deselect();
+                Error::Pin(err)
+            })?;
+        }
+        while self.pos < header.len() + data.len() {
+            let pos = self.pos;
+            let byte = match (header.get(pos), &data) {
+                (Some(&byte), _) => byte,
+                (None, Data::Write(data)) => data[pos - header.len()],
+                _ => 0,
+            };
+            let received = match ready!(self.spi.poll_transfer(cx, byte)) {
+                Ok(received) => received,
+                Err(err) => {
+                    self.deselect()?;
+                    return Poll::Ready(Err(Error::Spi(err)));
+                }
+            };
+            if let (Data::Read(buf), Some(i)) = (&mut data, pos.checked_sub(header.len())) {
+                buf[i] = received;
+            }
+            self.pos += 1;
+        }
+        Poll::Ready(self.deselect())
+    }
+
+    /// Like the end of [`modify`](W25q::modify), keeping the progress in the driver.
+    fn poll_busy(
+        &mut self,
+        cx: &mut Context<'_>,
+        interval: Duration,
+    ) -> Poll<Result<(), FlashError<T, CS>>> {
+        loop {
+            if let Some(timer) = &mut self.timer {
+                ready!(Pin::new(timer).poll(cx));
+                self.timer = None;
+            }
+            let mut status = [0];
+            ready!(self.poll_transaction(cx, &[READ_STATUS_1], Data::Read(&mut status)))?;
+            if status[0] & STATUS_BUSY == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            self.timer = Some(Timer::after(interval));
+        }
+    }
+
+    /// Like [`modify`](W25q::modify), keeping the progress in the driver.
+    fn poll_modify(
+        &mut self,
+        cx: &mut Context<'_>,
+        header: &[u8],
+        data: &[u8],
+        interval: Duration,
+    ) -> Poll<Result<(), FlashError<T, CS>>> {
+        loop {
+            let result = match self.step {
+                0 => ready!(self.poll_transaction(cx, &[WRITE_ENABLE], Data::None)),
+                1 => ready!(self.poll_transaction(cx, header, Data::Write(data))),
+                _ => ready!(self.poll_busy(cx, interval)),
+            };
+            if result.is_err() || self.step == 2 {
+                self.step = 0;
+                return Poll::Ready(result);
+            }
+            self.step += 1;
+        }
+    }
+}
+
+impl<T, CS> BlockDevice for W25q<T, CS>
+where
+    T: SpiDevice + Unpin,
+    CS: OutputPin + Unpin,
+{
+    type Error = FlashError<T, CS>;
+
+    fn block_count(&self) -> u32 {
+        self.capacity / BLOCK_SIZE as u32
+    }
+
+    fn poll_read_block(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        index: u32,
+        buf: &mut Block,
+    ) -> Poll<Result<(), Self::Error>> {
+        if index >= self.block_count() {
+            return Poll::Ready(Err(Error::OutOfBounds));
+        }
+        let command = address_command(READ_DATA, index * BLOCK_SIZE as u32);
+        self.poll_transaction(cx, &command, Data::Read(buf))
+    }
+
+    fn poll_write_block(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        index: u32,
+        buf: &Block,
+    ) -> Poll<Result<(), Self::Error>> {
+        if index >= self.block_count() {
+            return Poll::Ready(Err(Error::OutOfBounds));
+        }
+        let address = index * BLOCK_SIZE as u32;
+        loop {
+            let result = match self.stage {
+                0 if address & (SECTOR_SIZE - 1) != 0 => Ok(()),
+                0 => {
+                    let command = address_command(SECTOR_ERASE, address);
+                    ready!(self.poll_modify(cx, &command, &[], ERASE_POLL))
+                }
+                page => {
+                    let offset = (page as usize - 1) * PAGE_SIZE;
+                    let command = address_command(PAGE_PROGRAM, address + offset as u32);
+                    let data = &buf[offset..offset + PAGE_SIZE];
+                    ready!(self.poll_modify(cx, &command, data, PROGRAM_POLL))
+                }
+            };
+            if result.is_err() || self.stage as usize == BLOCK_SIZE / PAGE_SIZE {
+                self.stage = 0;
+                return Poll::Ready(result);
+            }
+            self.stage += 1;
+        }
+    }
+}
+
+/// Reads and programs a [`W25q`] flash as a stream of bytes.
+///
+/// Reads and writes end at the end of the flash. As with [`W25q::write`], the written range
+/// must have been erased.
+pub struct Cursor<'a, T, CS> {
+    flash: &'a mut W25q<T, CS>,
+    position: u32,
+}
+
+impl<T, CS> Cursor<'_, T, CS> {
+    /// Returns the address of the next byte read or written.
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: u32) {
+        self.position = position;
+    }
+
+    /// Returns how many bytes can be accessed at most, up to `len`.
+    fn available(&self, len: usize) -> usize {
+        len.min(self.flash.capacity.saturating_sub(self.position) as usize)
+    }
+}
+
+impl<T, CS> AsyncRead for Cursor<'_, T, CS>
+where
+    T: SpiDevice,
+    CS: OutputPin,
+{
+    type Error = FlashError<T, CS>;
+
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Self::Error>> {
+        let len = self.available(buf.len());
+        if len == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        let command = address_command(READ_DATA, self.position);
+        ready!(self
+            .flash
+            .poll_transaction(cx, &command, Data::Read(&mut buf[..len])))?;
+        self.position += len as u32;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl<T, CS> AsyncWrite for Cursor<'_, T, CS>
+where
+    T: SpiDevice,
+    CS: OutputPin,
+{
+    type Error = FlashError<T, CS>;
+
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Self::Error>> {
+        let page_end = PAGE_SIZE - self.position as usize % PAGE_SIZE;
+        let len = self.available(buf.len().min(page_end));
+        if len == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        let command = address_command(PAGE_PROGRAM, self.position);
+        ready!(self
+            .flash
+            .poll_modify(cx, &command, &buf[..len], PROGRAM_POLL))?;
+        self.position += len as u32;
+        Poll::Ready(Ok(len))
+    }
+
+    /// Does nothing, as writes wait until the data has been programmed.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
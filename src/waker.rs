@@ -0,0 +1,34 @@
+use avr_device::interrupt::{self, Mutex};
+use core::cell::Cell;
+use core::task::Waker;
+
+/// A slot holding the waker of the task waiting on an interrupt.
+///
+/// Futures call [`register`](WakerCell::register) before returning `Poll::Pending`, and the
+/// interrupt handler calls [`wake`](WakerCell::wake) once the event they are waiting on occurs.
+pub(crate) struct WakerCell(Mutex<Cell<Option<Waker>>>);
+
+impl WakerCell {
+    pub const fn new() -> Self {
+        WakerCell(Mutex::new(Cell::new(None)))
+    }
+
+    /// Stores `waker`, replacing any waker registered before it.
+    pub fn register(&self, waker: &Waker) {
+        interrupt::free(|cs| {
+            let cell = self.0.borrow(cs);
+            let waker = match cell.take() {
+                Some(old) if old.will_wake(waker) => old,
+                _ => waker.clone(),
+            };
+            cell.set(Some(waker));
+        })
+    }
+
+    /// Wakes and removes the registered waker, if any.
+    pub fn wake(&self) {
+        if let Some(waker) = interrupt::free(|cs| self.0.borrow(cs).take()) {
+            waker.wake();
+        }
+    }
+}
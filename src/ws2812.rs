@@ -0,0 +1,82 @@
+//! WS2812 (NeoPixel) addressable LEDs, with the SPI peripheral generating the bit pulses.
+//!
+//! ```ignore
+//! let mut leds = Ws2812::new(spi);
+//! leds.write_frame(&[Rgb::new(255, 0, 0), Rgb::new(0, 255, 0)]).await?;
+//! ```
+//!
+//! The data input of the first LED is connected to `MOSI`, and the SPI must run at 4 MHz
+//! (`OscfOver4`) in mode 0 with the most significant bit first. Each bit for the LEDs is sent as
+//! four SPI bits, a pulse of 250 ns for zeros and 750 ns for ones. The LEDs see all traffic on
+//! `MOSI`, so the bus cannot be shared with other devices.
+//!
+//! Pauses between LEDs are harmless as long as they are much shorter than the latch period, so
+//! interrupts are only disabled while the 24 bits of a single LED are sent, for about 30 µs.
+//! That is shorter than the time it takes to receive two characters up to 500 kbaud, which the
+//! USART buffers in hardware, so the interrupt-driven [`serial`](crate::serial) driver keeps
+//! receiving while a frame is written.
+
+use crate::time::{delay, Duration};
+use avr_device::interrupt;
+use avr_hal_generic::hal::spi::FullDuplex;
+use avr_hal_generic::nb;
+
+/// How long the data line must stay low for the LEDs to latch the frame. Older WS2812 need
+/// 50 µs, newer revisions of the WS2812B up to 280 µs.
+const LATCH: Duration = Duration::from_micros(300);
+
+/// The SPI bytes for pairs of bits, most significant first.
+const PATTERNS: [u8; 4] = [0b1000_1000, 0b1000_1110, 0b1110_1000, 0b1110_1110];
+
+/// The color of an LED.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Rgb { r, g, b }
+    }
+}
+
+/// A strip of WS2812 LEDs on the SPI `T`.
+pub struct Ws2812<T> {
+    spi: T,
+}
+
+impl<T: FullDuplex<u8>> Ws2812<T> {
+    pub fn new(spi: T) -> Self {
+        Ws2812 { spi }
+    }
+
+    pub fn free(self) -> T {
+        self.spi
+    }
+
+    /// Sends the colors of `frame` to the LEDs, starting with the one closest to the MCU, and
+    /// waits until they have latched them.
+    pub async fn write_frame(&mut self, frame: &[Rgb]) -> Result<(), T::Error> {
+        for led in frame {
+            let mut encoded = [0; 12];
+            // The LEDs expect green first.
+            for (chunk, &color) in encoded.chunks_mut(4).zip(&[led.g, led.r, led.b]) {
+                for (i, byte) in chunk.iter_mut().enumerate() {
+                    *byte = PATTERNS[(color >> (6 - 2 * i) & 0b11) as usize];
+                }
+            }
+            let spi = &mut self.spi;
+            interrupt::free(|_| -> Result<(), T::Error> {
+                for &byte in &encoded {
+                    nb::block!(spi.send(byte))?;
+                    nb::block!(spi.read())?;
+                }
+                Ok(())
+            })?;
+        }
+        delay(LATCH).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,376 @@
+//! XMODEM-CRC and XMODEM-1K file transfer, and YMODEM batch headers.
+//!
+//! The [`Receiver`] writes the received data into any [`AsyncWrite`], such as an EEPROM or
+//! flash writer, so files of any size can be received with a single block buffer. Blocks are
+//! only written once their CRC has been checked. The [`Sender`] is the other end, for example
+//! to test the receiver on the host.
+//!
+//! ```ignore
+//! let mut receiver = Receiver::new(serial, TimerDelay, Config::default());
+//! let mut block = [0; 128];
+//! let len = receiver.receive(&mut flash_writer, &mut block).await?;
+//! ```
+
+use crate::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadExactError, WriteAllError,
+};
+use crate::time::{with_timeout, Delay, Duration};
+
+pub use receiver::{FileInfo, Receiver};
+pub use sender::Sender;
+
+mod receiver;
+mod sender;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// Requests a transfer with CRC instead of the original checksum.
+const CRC: u8 = b'C';
+/// Pads the last block of a file.
+const SUB: u8 = 0x1a;
+
+/// An error of a transfer, wrapping the error type `E` of the port and `F` of the data sink or
+/// source.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Error<E, F> {
+    Io(E),
+    /// Writing received data or reading data to send failed.
+    Data(F),
+    /// The port returned end of file.
+    UnexpectedEof,
+    /// The port or the data sink did not accept any more data.
+    WriteZero,
+    /// The other end did not answer, or too many blocks in a row were corrupted.
+    Timeout,
+    /// The other end cancelled the transfer.
+    Cancelled,
+    /// A block was received out of order, so the transfer was cancelled.
+    OutOfSequence,
+    /// The other end sent a 1024 byte block, which does not fit into the buffer, so the
+    /// transfer was cancelled.
+    BlockTooLarge,
+}
+
+impl<E, F> From<WriteAllError<E>> for Error<E, F> {
+    fn from(err: WriteAllError<E>) -> Self {
+        match err {
+            WriteAllError::WriteZero => Error::WriteZero,
+            WriteAllError::Other(err) => Error::Io(err),
+        }
+    }
+}
+
+/// Parameters of a transfer.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Config {
+    /// How long to wait for the other end before repeating the last message.
+    pub timeout: Duration,
+    /// How often a message is repeated before the transfer fails.
+    pub retries: u8,
+}
+
+impl Default for Config {
+    /// A timeout of 3 seconds and 10 retries.
+    fn default() -> Self {
+        Config {
+            timeout: Duration::from_secs(3),
+            retries: 10,
+        }
+    }
+}
+
+/// Reads a single byte, or returns `None` if none arrives within `timeout`.
+async fn read_byte<S, D, F>(
+    port: &mut S,
+    delay: &mut D,
+    timeout: Duration,
+) -> Result<Option<u8>, Error<S::Error, F>>
+where
+    S: AsyncRead + Unpin,
+    D: Delay,
+{
+    let mut byte = [0];
+    match with_timeout(delay.delay(timeout), port.read(&mut byte)).await {
+        Ok(Ok(0)) => Err(Error::UnexpectedEof),
+        Ok(Ok(_)) => Ok(Some(byte[0])),
+        Ok(Err(err)) => Err(Error::Io(err)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Sends `message` and flushes the port.
+async fn send<S, F>(port: &mut S, message: &[u8]) -> Result<(), Error<S::Error, F>>
+where
+    S: AsyncWrite + Unpin,
+{
+    port.write_all(message).await?;
+    port.flush().await.map_err(Error::Io)
+}
+
+/// Cancels the transfer.
+async fn cancel<S, F>(port: &mut S) -> Result<(), Error<S::Error, F>>
+where
+    S: AsyncWrite + Unpin,
+{
+    send(port, &[CAN, CAN]).await
+}
+
+/// Discards received bytes until the line has been silent for a second.
+async fn purge<S, D, F>(port: &mut S, delay: &mut D) -> Result<(), Error<S::Error, F>>
+where
+    S: AsyncRead + Unpin,
+    D: Delay,
+{
+    while read_byte(port, delay, Duration::from_secs(1))
+        .await?
+        .is_some()
+    {}
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc::crc16_xmodem;
+    use crate::test_util::{block_on, line, now, End, ManualDelay};
+    use core::convert::Infallible;
+    use core::pin::Pin;
+    use core::task::{Context, Poll};
+    use futures_util::future::join;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// A file in memory, read from the start or appended to.
+    #[derive(Default)]
+    struct File {
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    impl File {
+        fn new(data: &[u8]) -> Self {
+            File {
+                data: data.to_vec(),
+                pos: 0,
+            }
+        }
+    }
+
+    impl AsyncRead for File {
+        type Error = Infallible;
+
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<Result<usize, Infallible>> {
+            let n = buf.len().min(self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl AsyncWrite for File {
+        type Error = Infallible;
+
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize, Infallible>> {
+            self.data.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    type Transfer = Result<u32, Error<Infallible, Infallible>>;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    /// Loses the `n`th byte written, counting from 0.
+    fn lose_nth(n: usize) -> impl FnMut(u8) -> Option<u8> {
+        let mut seen = 0;
+        move |byte| {
+            seen += 1;
+            if seen == n + 1 {
+                None
+            } else {
+                Some(byte)
+            }
+        }
+    }
+
+    /// Sends `data` from a [`Sender`] with a block buffer of `send_buf` bytes to a [`Receiver`]
+    /// with one of `receive_buf` bytes, returning the results of both and the received data.
+    fn transfer(
+        sender: End,
+        receiver: End,
+        data: &[u8],
+        send_buf: usize,
+        receive_buf: usize,
+    ) -> (Transfer, Transfer, Vec<u8>) {
+        let config = Config::default();
+        let mut sender = Sender::new(sender, ManualDelay, config);
+        let mut receiver = Receiver::new(receiver, ManualDelay, config);
+        let mut source = File::new(data);
+        let mut sink = File::default();
+        let (sent, received) = block_on(join(
+            async { sender.send(&mut source, &mut vec![0; send_buf]).await },
+            async { receiver.receive(&mut sink, &mut vec![0; receive_buf]).await },
+        ));
+        (sent, received, sink.data)
+    }
+
+    /// Returns the padded data as written by the receiver.
+    fn padded(data: &[u8], block: usize) -> Vec<u8> {
+        let mut padded = data.to_vec();
+        padded.resize((data.len() + block - 1) / block * block, SUB);
+        padded
+    }
+
+    #[test]
+    fn round_trip() {
+        let (a, b) = line();
+        let data = data(1000);
+        let (sent, received, written) = transfer(a, b, &data, 128, 128);
+        assert_eq!(sent, Ok(1000));
+        assert_eq!(received, Ok(1024));
+        assert_eq!(written, padded(&data, 128));
+    }
+
+    #[test]
+    fn round_trip_1k() {
+        let (a, b) = line();
+        let data = data(3000);
+        let (sent, received, written) = transfer(a, b, &data, 1024, 1024);
+        assert_eq!(sent, Ok(3000));
+        assert_eq!(received, Ok(3072));
+        assert_eq!(written, padded(&data, 1024));
+    }
+
+    #[test]
+    fn block_too_large() {
+        let (a, b) = line();
+        let (sent, received, written) = transfer(a, b, &data(3000), 1024, 128);
+        assert_eq!(sent, Err(Error::Cancelled));
+        assert_eq!(received, Err(Error::BlockTooLarge));
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn lost_ack_repeats_block() {
+        let (a, mut b) = line();
+        // Lose the acknowledgement of the first block, after the CRC request.
+        b.set_fault(lose_nth(1));
+        let data = data(300);
+        let (sent, received, written) = transfer(a, b, &data, 128, 128);
+        assert_eq!(sent, Ok(300));
+        assert_eq!(received, Ok(384));
+        assert_eq!(written, padded(&data, 128));
+        // The sender repeated the block after the timeout, and it was only written once.
+        assert!(now() >= Config::default().timeout.ticks());
+    }
+
+    #[test]
+    fn corrupted_block_is_repeated() {
+        let (mut a, b) = line();
+        a.set_fault({
+            let mut seen = 0;
+            move |byte| {
+                seen += 1;
+                // Corrupt the data of the second block.
+                if seen == 133 + 10 {
+                    Some(byte ^ 1)
+                } else {
+                    Some(byte)
+                }
+            }
+        });
+        let data = data(300);
+        let (sent, received, written) = transfer(a, b, &data, 128, 128);
+        assert_eq!(sent, Ok(300));
+        assert_eq!(received, Ok(384));
+        assert_eq!(written, padded(&data, 128));
+    }
+
+    /// Encodes a block of 128 bytes, padded with zeroes like a YMODEM header.
+    fn block(number: u8, data: &[u8]) -> Vec<u8> {
+        let mut payload = data.to_vec();
+        payload.resize(128, 0);
+        let crc = crc16_xmodem(&payload);
+        let mut block = vec![SOH, number, !number];
+        block.extend_from_slice(&payload);
+        block.extend_from_slice(&[(crc >> 8) as u8, crc as u8]);
+        block
+    }
+
+    async fn expect(port: &mut End, expected: u8) {
+        let mut byte = [0];
+        port.read_exact(&mut byte).await.unwrap();
+        assert_eq!(byte[0], expected);
+    }
+
+    #[test]
+    fn ymodem_lost_header_ack() {
+        let config = Config::default();
+        let (mut a, mut b) = line();
+        // Lose the acknowledgement of the header, after the CRC request.
+        b.set_fault(lose_nth(1));
+        let mut receiver = Receiver::new(b, ManualDelay, config);
+        let data = data(300);
+        let mut sink = File::default();
+        let mut name = [0; 16];
+        let ((file, end), sent) = block_on(join(
+            async {
+                let mut buf = [0; 128];
+                let file = receiver
+                    .receive_ymodem(&mut sink, &mut buf, &mut name)
+                    .await;
+                let end = receiver
+                    .receive_ymodem(&mut sink, &mut buf, &mut name)
+                    .await;
+                (file, end)
+            },
+            async {
+                let header = block(0, b"test.bin\x00300 0");
+                expect(&mut a, CRC).await;
+                a.write_all(&header).await.unwrap();
+                // Without an acknowledgement, the header is sent again.
+                expect(&mut a, CRC).await;
+                a.write_all(&header).await.unwrap();
+                expect(&mut a, ACK).await;
+                let mut sender = Sender::new(&mut a, ManualDelay, config);
+                let sent = sender.send(&mut File::new(&data), &mut [0; 128]).await;
+                // An empty name ends the batch.
+                expect(&mut a, CRC).await;
+                a.write_all(&block(0, &[])).await.unwrap();
+                expect(&mut a, ACK).await;
+                sent
+            },
+        ));
+        assert_eq!(sent, Ok(300));
+        let info = FileInfo {
+            name_len: 8,
+            size: Some(300),
+        };
+        assert_eq!(file, Ok(Some(info)));
+        assert_eq!(&name[..8], b"test.bin");
+        assert_eq!(sink.data, data);
+        assert_eq!(end, Ok(None));
+    }
+}
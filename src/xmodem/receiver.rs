@@ -0,0 +1,251 @@
+use super::*;
+use crate::crc::crc16_xmodem;
+
+/// A received packet, with the data of blocks at the start of the buffer.
+enum Packet {
+    Block { number: u8, len: usize },
+    Eot,
+}
+
+/// The file name and size from a YMODEM header.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct FileInfo {
+    /// Length of the file name in the name buffer, which is truncated if it does not fit.
+    pub name_len: usize,
+    /// Size of the file in bytes, if the sender included it.
+    pub size: Option<u32>,
+}
+
+/// Receives files with XMODEM-CRC, XMODEM-1K or YMODEM.
+///
+/// Only transfers with CRC are supported. The block buffer passed to the receive methods must
+/// hold 1024 bytes to accept 1K blocks, and at least 128 bytes otherwise.
+pub struct Receiver<S, D> {
+    port: S,
+    delay: D,
+    config: Config,
+}
+
+impl<S, D> Receiver<S, D>
+where
+    S: AsyncRead + AsyncWrite<Error = <S as AsyncRead>::Error> + Unpin,
+    D: Delay,
+{
+    pub fn new(port: S, delay: D, config: Config) -> Self {
+        Receiver {
+            port,
+            delay,
+            config,
+        }
+    }
+
+    /// Releases the port and delay.
+    pub fn free(self) -> (S, D) {
+        (self.port, self.delay)
+    }
+
+    /// Receives a file with XMODEM into `sink`, returning the number of bytes written.
+    ///
+    /// XMODEM does not transfer the file size, so the padding of the last block is written as
+    /// well.
+    pub async fn receive<W>(
+        &mut self,
+        sink: &mut W,
+        buf: &mut [u8],
+    ) -> Result<u32, Error<<S as AsyncRead>::Error, W::Error>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.receive_data(sink, buf, None, false).await
+    }
+
+    /// Receives the next file of a YMODEM batch into `sink`, storing its name in `name`.
+    ///
+    /// Returns `None` once the sender has ended the batch. If the header contains the file size,
+    /// the padding of the last block is not written.
+    pub async fn receive_ymodem<W>(
+        &mut self,
+        sink: &mut W,
+        buf: &mut [u8],
+        name: &mut [u8],
+    ) -> Result<Option<FileInfo>, Error<<S as AsyncRead>::Error, W::Error>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut errors = 0;
+        let len = loop {
+            send(&mut self.port, &[CRC]).await?;
+            match self.read_packet(buf).await? {
+                Some(Packet::Block { number: 0, len }) => break len,
+                Some(Packet::Block { .. }) => {
+                    cancel(&mut self.port).await?;
+                    return Err(Error::OutOfSequence);
+                }
+                // The end of the previous file, whose acknowledgement was lost.
+                Some(Packet::Eot) => send(&mut self.port, &[ACK]).await?,
+                None => {
+                    errors += 1;
+                    if errors > self.config.retries {
+                        cancel(&mut self.port).await?;
+                        return Err(Error::Timeout);
+                    }
+                }
+            }
+        };
+        send(&mut self.port, &[ACK]).await?;
+
+        // The header holds the name, a NUL, and the size in decimal followed by optional fields.
+        let header = &buf[..len];
+        let name_end = header.iter().position(|&b| b == 0).unwrap_or(len);
+        if name_end == 0 {
+            return Ok(None);
+        }
+        let name_len = name_end.min(name.len());
+        name[..name_len].copy_from_slice(&header[..name_len]);
+        let mut size = None;
+        for &digit in header[name_end..].iter().skip(1) {
+            if !digit.is_ascii_digit() {
+                break;
+            }
+            let value: u32 = size.unwrap_or(0);
+            size = Some(value.wrapping_mul(10).wrapping_add((digit - b'0') as u32));
+        }
+
+        self.receive_data(sink, buf, size, true).await?;
+        Ok(Some(FileInfo { name_len, size }))
+    }
+
+    /// Receives the data blocks of a file, writing at most `size` bytes to `sink`.
+    ///
+    /// YMODEM senders expect the first EOT to be answered with a NAK.
+    async fn receive_data<W>(
+        &mut self,
+        sink: &mut W,
+        buf: &mut [u8],
+        size: Option<u32>,
+        ymodem: bool,
+    ) -> Result<u32, Error<<S as AsyncRead>::Error, W::Error>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut response = CRC;
+        let mut expected: u8 = 1;
+        let mut errors = 0;
+        let mut written = 0u32;
+        let mut eot_nak = false;
+        loop {
+            send(&mut self.port, &[response]).await?;
+            let packet = match self.read_packet(buf).await? {
+                Some(packet) => packet,
+                None => {
+                    errors += 1;
+                    if errors > self.config.retries {
+                        cancel(&mut self.port).await?;
+                        return Err(Error::Timeout);
+                    }
+                    // Keep asking for a CRC transfer until the first block arrives.
+                    if response != CRC {
+                        response = NAK;
+                    }
+                    continue;
+                }
+            };
+            match packet {
+                Packet::Eot if ymodem && !eot_nak => {
+                    eot_nak = true;
+                    response = NAK;
+                }
+                Packet::Eot => {
+                    send(&mut self.port, &[ACK]).await?;
+                    sink.flush().await.map_err(Error::Data)?;
+                    return Ok(written);
+                }
+                Packet::Block { number, .. }
+                    if response != CRC && number == expected.wrapping_sub(1) =>
+                {
+                    // A repeated block, whose acknowledgement was lost.
+                    response = ACK;
+                }
+                Packet::Block { number: 0, .. } if ymodem && expected == 1 => {
+                    // A repeated YMODEM header, whose acknowledgement was lost. The sender waits
+                    // for another CRC request before the first block.
+                    send(&mut self.port, &[ACK]).await?;
+                }
+                Packet::Block { number, len } => {
+                    if number != expected {
+                        cancel(&mut self.port).await?;
+                        return Err(Error::OutOfSequence);
+                    }
+                    let len = match size {
+                        Some(size) => (size - written).min(len as u32) as usize,
+                        None => len,
+                    };
+                    sink.write_all(&buf[..len]).await.map_err(|err| match err {
+                        WriteAllError::WriteZero => Error::WriteZero,
+                        WriteAllError::Other(err) => Error::Data(err),
+                    })?;
+                    written += len as u32;
+                    expected = expected.wrapping_add(1);
+                    errors = 0;
+                    response = ACK;
+                }
+            }
+        }
+    }
+
+    /// Receives a block or EOT into `buf`.
+    ///
+    /// Returns `None` if nothing arrived in time or the packet was corrupted, in which case the
+    /// line is purged so that the sender can repeat the packet.
+    async fn read_packet<F>(
+        &mut self,
+        buf: &mut [u8],
+    ) -> Result<Option<Packet>, Error<<S as AsyncRead>::Error, F>> {
+        let timeout = self.config.timeout;
+        let len = match read_byte(&mut self.port, &mut self.delay, timeout).await? {
+            Some(SOH) => 128,
+            Some(STX) => 1024,
+            Some(EOT) => return Ok(Some(Packet::Eot)),
+            Some(CAN) => {
+                // A single CAN may be line noise.
+                return match read_byte(&mut self.port, &mut self.delay, timeout).await? {
+                    Some(CAN) => Err(Error::Cancelled),
+                    _ => Ok(None),
+                };
+            }
+            Some(_) => {
+                purge(&mut self.port, &mut self.delay).await?;
+                return Ok(None);
+            }
+            None => return Ok(None),
+        };
+        if len > buf.len() {
+            cancel(&mut self.port).await?;
+            return Err(Error::BlockTooLarge);
+        }
+        let mut header = [0; 2];
+        let mut crc = [0; 2];
+        let port = &mut self.port;
+        let complete = with_timeout(self.delay.delay(timeout), async {
+            port.read_exact(&mut header).await?;
+            port.read_exact(&mut buf[..len]).await?;
+            port.read_exact(&mut crc).await
+        })
+        .await;
+        match complete {
+            Ok(Ok(())) => {}
+            Ok(Err(ReadExactError::UnexpectedEof)) => return Err(Error::UnexpectedEof),
+            Ok(Err(ReadExactError::Other(err))) => return Err(Error::Io(err)),
+            Err(_) => return Ok(None),
+        }
+        let crc = (crc[0] as u16) << 8 | crc[1] as u16;
+        if header[0] != !header[1] || crc16_xmodem(&buf[..len]) != crc {
+            purge(&mut self.port, &mut self.delay).await?;
+            return Ok(None);
+        }
+        Ok(Some(Packet::Block {
+            number: header[0],
+            len,
+        }))
+    }
+}
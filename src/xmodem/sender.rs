@@ -0,0 +1,127 @@
+use super::*;
+use crate::crc::crc16_xmodem;
+
+/// Sends files with XMODEM-CRC, or XMODEM-1K if the block buffer holds 1024 bytes.
+pub struct Sender<S, D> {
+    port: S,
+    delay: D,
+    config: Config,
+}
+
+impl<S, D> Sender<S, D>
+where
+    S: AsyncRead + AsyncWrite<Error = <S as AsyncRead>::Error> + Unpin,
+    D: Delay,
+{
+    pub fn new(port: S, delay: D, config: Config) -> Self {
+        Sender {
+            port,
+            delay,
+            config,
+        }
+    }
+
+    /// Releases the port and delay.
+    pub fn free(self) -> (S, D) {
+        (self.port, self.delay)
+    }
+
+    /// Sends everything `source` returns until end of file, returning the number of bytes sent
+    /// without padding.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is shorter than 128 bytes.
+    pub async fn send<R>(
+        &mut self,
+        source: &mut R,
+        buf: &mut [u8],
+    ) -> Result<u32, Error<<S as AsyncRead>::Error, R::Error>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let (header, len) = if buf.len() >= 1024 {
+            (STX, 1024)
+        } else {
+            assert!(buf.len() >= 128);
+            (SOH, 128)
+        };
+        let buf = &mut buf[..len];
+
+        // Wait for the receiver to request a transfer with CRC.
+        let mut errors = 0;
+        while self.read_response().await? != Some(CRC) {
+            errors += 1;
+            if errors > self.config.retries {
+                return Err(Error::Timeout);
+            }
+        }
+
+        let mut number: u8 = 1;
+        let mut sent = 0u32;
+        loop {
+            let mut filled = 0;
+            while filled < len {
+                match source.read(&mut buf[filled..]).await.map_err(Error::Data)? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            if filled == 0 {
+                break;
+            }
+            for byte in &mut buf[filled..] {
+                *byte = SUB;
+            }
+            let crc = crc16_xmodem(buf);
+            let mut errors = 0;
+            loop {
+                self.port.write_all(&[header, number, !number]).await?;
+                self.port.write_all(buf).await?;
+                send(&mut self.port, &[(crc >> 8) as u8, crc as u8]).await?;
+                if self.acknowledged(&mut errors).await? {
+                    break;
+                }
+            }
+            sent += filled as u32;
+            number = number.wrapping_add(1);
+        }
+
+        let mut errors = 0;
+        loop {
+            send(&mut self.port, &[EOT]).await?;
+            if self.acknowledged(&mut errors).await? {
+                return Ok(sent);
+            }
+        }
+    }
+
+    /// Waits for the response to a message, returning whether it was acknowledged. Counts the
+    /// failed attempts in `errors` and cancels the transfer if there are too many.
+    async fn acknowledged<F>(
+        &mut self,
+        errors: &mut u8,
+    ) -> Result<bool, Error<<S as AsyncRead>::Error, F>> {
+        if self.read_response().await? == Some(ACK) {
+            return Ok(true);
+        }
+        *errors += 1;
+        if *errors > self.config.retries {
+            cancel(&mut self.port).await?;
+            return Err(Error::Timeout);
+        }
+        Ok(false)
+    }
+
+    /// Reads the response of the receiver, or returns `None` if there is none in time.
+    async fn read_response<F>(&mut self) -> Result<Option<u8>, Error<<S as AsyncRead>::Error, F>> {
+        let timeout = self.config.timeout;
+        match read_byte(&mut self.port, &mut self.delay, timeout).await? {
+            Some(CAN) => match read_byte(&mut self.port, &mut self.delay, timeout).await? {
+                Some(CAN) => Err(Error::Cancelled),
+                _ => Ok(None),
+            },
+            response => Ok(response),
+        }
+    }
+}